@@ -1,6 +1,45 @@
 #[macro_use]
 extern crate criterion;
 
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+/// The system allocator, counting the allocations made by a thread while it
+/// runs `count_allocations`. Other allocations, e.g., those of the timed
+/// benchmark iterations, are not counted.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| {
+            if let Some(n) = count.get() {
+                count.set(Some(n + 1));
+            }
+        });
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { System.dealloc(ptr, layout) }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// The number of allocations made by a single run of the function.
+fn count_allocations<T>(f: impl FnOnce() -> T) -> usize {
+    ALLOCATIONS.with(|count| count.set(Some(0)));
+    let result = f();
+    let allocations = ALLOCATIONS.with(|count| count.take()).unwrap_or(0);
+    drop(result);
+    allocations
+}
+
 mod nop {
     use criterion::Criterion;
     pub fn nop_bench(_c: &mut Criterion) {}
@@ -92,6 +131,155 @@ mod dedup {
     dedup_bench!(big_bench_dedup_sha256, Sha256, "SHA256", 32, 4_194_304);
 }
 
+macro_rules! dedup_queue_bench {
+    ($f:ident, $queue:ty, $queue_name:expr) => {
+        pub fn $f(c: &mut Criterion) {
+            let mut group = c.benchmark_group(format!("{} deduplication", $queue_name));
+            for &size in &[1024, 4096, 1024 * 16, 1024 * 32] {
+                let mut queue = <$queue>::new(size);
+                for _ in 0..size {
+                    queue.check_and_insert(&generate_random_data(MSG_SIZE)).unwrap();
+                }
+                // the messages are generated beforehand so that only the deduplication
+                // is measured
+                let messages =
+                    (0..size).map(|_| generate_random_data(MSG_SIZE)).collect::<Vec<_>>();
+                let mut messages = messages.iter().cycle();
+
+                group.throughput(Throughput::Elements(1));
+                group.bench_function(BenchmarkId::from_parameter(size), |b| {
+                    b.iter(|| queue.check_and_insert(messages.next().unwrap()).unwrap())
+                });
+            }
+            group.finish();
+        }
+    };
+}
+
+mod dedup_queues {
+    use concordium_node::{
+        connection::{
+            DeduplicationQueue, DeduplicationQueueSha256, DeduplicationQueueXxHash64,
+            DeduplicationSetXxHash64,
+        },
+        test_utils::generate_random_data,
+    };
+    use criterion::{BenchmarkId, Criterion, Throughput};
+
+    /// The size of the deduplicated messages, which is roughly that of a
+    /// transaction.
+    const MSG_SIZE: usize = 250;
+
+    dedup_queue_bench!(bench_xxhash64_queue, DeduplicationQueueXxHash64, "XxHash64 queue");
+    dedup_queue_bench!(bench_sha256_queue, DeduplicationQueueSha256, "SHA256 queue");
+    dedup_queue_bench!(bench_xxhash64_set, DeduplicationSetXxHash64, "XxHash64 set");
+}
+
+mod broadcast {
+    use crate::count_allocations;
+    use concordium_node::{
+        common::get_current_stamp,
+        netmsg,
+        network::{
+            frames::{PacketFrames, ReceivedFrame},
+            NetworkId, NetworkMessage, NetworkPacket, NetworkPayload, PacketDestination,
+            PayloadCompression,
+        },
+        stats_export_service::StatsExportService,
+        test_utils::generate_random_data,
+    };
+    use criterion::{BenchmarkId, Criterion, Throughput};
+    use std::sync::Arc;
+
+    const SIZES: [usize; 4] = [256, 4096, 64 * 1024, 1024 * 1024];
+
+    /// Serializing a packet the node broadcasts, by copying the payload into a
+    /// `NetworkPacket`, as before, and by serializing the payload directly.
+    pub fn bench_broadcast(c: &mut Criterion) {
+        let stats = StatsExportService::new(Vec::new()).unwrap();
+        let network_id = NetworkId::from(100);
+        let mut group = c.benchmark_group("broadcast serialization");
+        for &size in &SIZES {
+            let payload: Arc<[u8]> = Arc::from(generate_random_data(size));
+            let copy_and_serialize = || {
+                let message = netmsg!(NetworkPacket, NetworkPacket {
+                    destination: PacketDestination::Broadcast(Vec::new()),
+                    network_id,
+                    message: payload.to_vec(),
+                    compression: PayloadCompression::None,
+                });
+                let mut serialized = Vec::with_capacity(256);
+                message.serialize(&mut serialized).unwrap();
+                Arc::<[u8]>::from(serialized)
+            };
+            let serialize_once = || {
+                PacketFrames::serialize(
+                    &PacketDestination::Broadcast(Vec::new()),
+                    network_id,
+                    &payload,
+                    usize::MAX,
+                    &stats,
+                )
+                .unwrap()
+            };
+            // serializing the payload directly saves copying it
+            assert!(count_allocations(serialize_once) < count_allocations(copy_and_serialize));
+
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_function(BenchmarkId::new("copy and serialize", size), |b| {
+                b.iter(copy_and_serialize)
+            });
+            group.bench_function(BenchmarkId::new("serialize once", size), |b| {
+                b.iter(serialize_once)
+            });
+        }
+        group.finish();
+    }
+
+    /// Relaying a received broadcast, by deserializing and serializing it
+    /// again, as before, and by reusing the received frame.
+    pub fn bench_relay(c: &mut Criterion) {
+        let stats = StatsExportService::new(Vec::new()).unwrap();
+        let network_id = NetworkId::from(100);
+        let mut group = c.benchmark_group("broadcast relay");
+        for &size in &SIZES {
+            let payload = generate_random_data(size);
+            let frames = PacketFrames::serialize(
+                &PacketDestination::Broadcast(Vec::new()),
+                network_id,
+                &payload,
+                usize::MAX,
+                &stats,
+            )
+            .unwrap();
+            let received = ReceivedFrame {
+                bytes: Arc::clone(frames.for_wire_version(1)),
+                network_id,
+                compressed: false,
+            };
+            let deserialize_and_serialize = || {
+                let mut message = NetworkMessage::deserialize(&received.bytes).unwrap();
+                if let NetworkPayload::NetworkPacket(ref mut packet) = message.payload {
+                    packet.destination = PacketDestination::Broadcast(Vec::new());
+                }
+                let mut serialized = Vec::with_capacity(256);
+                message.serialize(&mut serialized).unwrap();
+                Arc::<[u8]>::from(serialized)
+            };
+            let reuse_frame = || PacketFrames::relay(&received, &payload).unwrap();
+            // relaying the received frame does not copy it
+            assert!(count_allocations(reuse_frame) < count_allocations(deserialize_and_serialize));
+
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_function(BenchmarkId::new("deserialize and serialize", size), |b| {
+                b.iter(deserialize_and_serialize)
+            });
+            group.bench_function(BenchmarkId::new("reuse frame", size), |b| b.iter(reuse_frame));
+        }
+        group.finish();
+    }
+}
+
 mod s11n {
     pub mod fbs {
         bench_s11n!("flatbuffers");
@@ -100,6 +288,15 @@ mod s11n {
 
 criterion_group!(s11n_fbs_benches, s11n::fbs::bench_s11n);
 
+criterion_group!(broadcast_benches, broadcast::bench_broadcast, broadcast::bench_relay);
+
+criterion_group!(
+    dedup_queue_benches,
+    dedup_queues::bench_xxhash64_queue,
+    dedup_queues::bench_sha256_queue,
+    dedup_queues::bench_xxhash64_set
+);
+
 #[cfg(feature = "dedup_benchmarks")]
 criterion_group!(
     dedup_benches,
@@ -113,4 +310,4 @@ criterion_group!(
 #[cfg(not(feature = "dedup_benchmarks"))]
 criterion_group!(dedup_benches, nop::nop_bench);
 
-criterion_main!(s11n_fbs_benches, dedup_benches,);
+criterion_main!(s11n_fbs_benches, broadcast_benches, dedup_queue_benches, dedup_benches,);
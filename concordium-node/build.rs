@@ -28,6 +28,7 @@ fn main() -> std::io::Result<()> {
     // Build GRPC
 
     let proto_root_input = format!("{}/../concordium-base/concordium-grpc-api", cargo_dir);
+    let node_proto_root_input = format!("{}/proto", cargo_dir);
 
     #[cfg(not(feature = "static"))]
     {
@@ -122,17 +123,24 @@ fn main() -> std::io::Result<()> {
     #[cfg(feature = "static")]
     link_static_libs()?;
 
-    build_grpc2(&proto_root_input)?;
+    build_grpc2(&proto_root_input, &node_proto_root_input)?;
     Ok(())
 }
 
 // Compile the types for GRPC2 API and generate a service description for the
-// GRPC2 interface.
-fn build_grpc2(proto_root_input: &str) -> std::io::Result<()> {
+// GRPC2 interface. The messages of the node's administrative endpoints that are
+// not part of the API in concordium-base are defined in the node's own proto
+// file, in the same package.
+fn build_grpc2(proto_root_input: &str, node_proto_root_input: &str) -> std::io::Result<()> {
     {
         let types = format!("{}/v2/concordium/types.proto", proto_root_input);
+        let node_admin = format!("{}/v2/concordium/node_admin.proto", node_proto_root_input);
         println!("cargo:rerun-if-changed={}", types);
-        prost_build::compile_protos(&[types], &[proto_root_input])?;
+        println!("cargo:rerun-if-changed={}", node_admin);
+        prost_build::compile_protos(&[types, node_admin], &[
+            proto_root_input,
+            node_proto_root_input,
+        ])?;
     }
 
     // Because we serialize messages in Haskell we need to construct the service
@@ -585,6 +593,114 @@ fn build_grpc2(proto_root_input: &str) -> std::io::Result<()> {
                 .codec_path("tonic::codec::ProstCodec")
                 .build(),
         )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("get_peer_allowlist")
+                .route_name("GetPeerAllowlist")
+                .input_type("crate::grpc2::types::Empty")
+                .output_type("crate::grpc2::types::PeerAllowlist")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("reload_peer_allowlist")
+                .route_name("ReloadPeerAllowlist")
+                .input_type("crate::grpc2::types::Empty")
+                .output_type("crate::grpc2::types::PeerAllowlist")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("get_peers_details")
+                .route_name("GetPeersDetails")
+                .input_type("crate::grpc2::types::Empty")
+                .output_type("crate::grpc2::types::PeersDetails")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("get_bans")
+                .route_name("GetBans")
+                .input_type("crate::grpc2::types::Empty")
+                .output_type("crate::grpc2::types::Bans")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("add_ban")
+                .route_name("AddBan")
+                .input_type("crate::grpc2::types::Ban")
+                .output_type("crate::grpc2::types::Empty")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("remove_ban")
+                .route_name("RemoveBan")
+                .input_type("crate::grpc2::types::Ban")
+                .output_type("crate::grpc2::types::Empty")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("export_ban_list")
+                .route_name("ExportBanList")
+                .input_type("crate::grpc2::types::Empty")
+                .output_type("crate::grpc2::types::BanList")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("import_ban_list")
+                .route_name("ImportBanList")
+                .input_type("crate::grpc2::types::BanList")
+                .output_type("crate::grpc2::types::Empty")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("get_address_book")
+                .route_name("GetAddressBook")
+                .input_type("crate::grpc2::types::Empty")
+                .output_type("crate::grpc2::types::AddressBook")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("get_reconnect_backoff")
+                .route_name("GetReconnectBackoff")
+                .input_type("crate::grpc2::types::Empty")
+                .output_type("crate::grpc2::types::ReconnectBackoff")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("get_shutdown_progress")
+                .route_name("GetShutdownProgress")
+                .input_type("crate::grpc2::types::Empty")
+                .output_type("crate::grpc2::types::ShutdownProgress")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("get_catch_up_state")
+                .route_name("GetCatchUpState")
+                .input_type("crate::grpc2::types::Empty")
+                .output_type("crate::grpc2::types::CatchUpState")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
         .method(
             tonic_build::manual::Method::builder()
                 .name("dump_start")
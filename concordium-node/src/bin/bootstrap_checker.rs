@@ -42,7 +42,7 @@ fn main() -> anyhow::Result<()> {
     );
 
     let (node, server, poll) =
-        P2PNode::new(conf.common.id, &conf, PeerType::Node, stats_export_service, regenesis_arc)
+        P2PNode::new(&conf, PeerType::Node, stats_export_service, regenesis_arc)
             .context("Failed to create the node.")?;
 
     spawn(&node, server, poll, None);
@@ -42,14 +42,9 @@ async fn main() -> anyhow::Result<()> {
         "Bootstrapper can't run without specifying genesis hashes."
     );
 
-    let (node, server, poll) = P2PNode::new(
-        conf.common.id,
-        &conf,
-        PeerType::Bootstrapper,
-        stats_export_service.clone(),
-        regenesis_arc,
-    )
-    .context("Failed to create the network node.")?;
+    let (node, server, poll) =
+        P2PNode::new(&conf, PeerType::Bootstrapper, stats_export_service.clone(), regenesis_arc)
+            .context("Failed to create the network node.")?;
 
     start_push_gateway(&conf.prometheus, &node.stats, node.id());
 
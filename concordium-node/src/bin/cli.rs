@@ -11,12 +11,12 @@ static A: System = System;
 
 use anyhow::Context;
 use concordium_node::{
-    common::PeerType,
+    common::{P2PNodeId, PeerType},
     configuration as config,
     consensus_ffi::{
         consensus::{
-            ConsensusContainer, ConsensusLogLevel, Regenesis, CALLBACK_QUEUE,
-            CONSENSUS_QUEUE_DEPTH_IN_HI, CONSENSUS_QUEUE_DEPTH_OUT_HI,
+            queue_depths, set_queue_depths, ConsensusContainer, ConsensusLogLevel,
+            ConsensusQueueDepths, Regenesis, CALLBACK_QUEUE,
         },
         ffi,
         helpers::QueueMsg,
@@ -29,13 +29,9 @@ use concordium_node::{
     },
     plugins::{self, consensus::*},
     read_or_die, spawn_or_die,
-    stats_export_service::{
-        instantiate_stats_export_engine, StatsConsensusCollector, StatsExportService,
-    },
+    stats_export_service::{instantiate_stats_export_engine, StatsConsensusCollector},
     utils::get_config_and_logging_setup,
 };
-use mio::{net::TcpListener, Poll};
-use rand::Rng;
 use reqwest::Client;
 use std::{path::Path, sync::Arc, thread::JoinHandle};
 #[cfg(unix)]
@@ -51,20 +47,35 @@ use std::net::SocketAddr;
 async fn main() -> anyhow::Result<()> {
     let (conf, mut app_prefs) = get_config_and_logging_setup()?;
 
+    // The depths of the consensus queues must be set before the queues are used.
+    set_queue_depths(ConsensusQueueDepths {
+        inbound_high:  conf.connection.consensus_inbound_queue_depth_high,
+        inbound_low:   conf.connection.consensus_inbound_queue_depth_low,
+        outbound_high: conf.connection.consensus_outbound_queue_depth_high,
+        outbound_low:  conf.connection.consensus_outbound_queue_depth_low,
+    })?;
+
     let stats_export_service = instantiate_stats_export_engine(&conf.prometheus)?;
     let regenesis_arc: Arc<Regenesis> = Arc::new(Default::default());
 
     // The P2PNode thread
     let (node, server, poll) =
-        instantiate_node(&conf, &mut app_prefs, stats_export_service, regenesis_arc.clone())
+        P2PNode::new(&conf, PeerType::Node, stats_export_service, regenesis_arc.clone())
             .context("Failed to create the node.")?;
     // Set the startime in the stats.
     node.stats.node_startup_timestamp.set(node.start_time.timestamp_millis());
+    migrate_persisted_node_id(&mut app_prefs, node.id());
 
     // Setup task with signal handling before doing any irreversible operations
     // to avoid being interrupted in the middle of sensitive operations, e.g.,
     // creating the database.
     let (shutdown_sender, mut shutdown_receiver) = setup_shutdown_signal_handling();
+    #[cfg(unix)]
+    {
+        if node.is_permissioned() {
+            setup_allowlist_reload_signal_handling(node.clone())?;
+        }
+    }
 
     {
         let shutdown_sender = shutdown_sender.clone();
@@ -216,6 +227,15 @@ async fn main() -> anyhow::Result<()> {
         establish_connections(&conf, &node)?;
     }
 
+    // Keep the bans in sync with the shared ban list, if so configured.
+    if let Some(source) = conf.connection.ban_list_sync_source.clone() {
+        tokio::spawn(concordium_node::p2p::ban_list::sync_ban_list(
+            Arc::clone(&node),
+            source,
+            std::time::Duration::from_secs(conf.connection.ban_list_sync_interval),
+        ));
+    }
+
     // Start baking
     consensus.start_baker();
 
@@ -291,6 +311,20 @@ fn setup_shutdown_signal_handling() -> (broadcast::Sender<()>, broadcast::Receiv
     (sender2, receiver)
 }
 
+/// Reload the peer allowlist whenever the node receives SIGHUP.
+#[cfg(unix)]
+fn setup_allowlist_reload_signal_handling(node: Arc<P2PNode>) -> anyhow::Result<()> {
+    let mut hangup_stream = unix_signal::signal(unix_signal::SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup_stream.recv().await.is_some() {
+            if let Err(e) = node.reload_peer_allowlist() {
+                error!("Could not reload the peer allowlist: {:#}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
 /// Construct a future for shutdown signals (for unix: SIGINT and SIGTERM) (for
 /// windows: ctrl c and ctrl break). The signal handler is set when the future
 /// is polled and until then the default signal handler.
@@ -314,47 +348,41 @@ async fn get_shutdown_signal() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn instantiate_node(
-    conf: &config::Config,
-    app_prefs: &mut config::AppPreferences,
-    stats_export_service: Arc<StatsExportService>,
-    regenesis_arc: Arc<Regenesis>,
-) -> anyhow::Result<(Arc<P2PNode>, TcpListener, Poll)> {
-    // If the node id is supplied on the command line (in the conf argument) use it.
-    // Otherwise try to look it up from the persistent config.
-    let node_id = match conf.common.id {
-        None => {
-            let maybe_id =
-                app_prefs.get_config(config::APP_PREFERENCES_PERSISTED_NODE_ID).context(
-                    "Could not read ID from persistent config.\nFix or delete the \
-                     `main.config.json` file in the configuration directory.",
-                )?;
-            // we generate a fresh id here if it is not already present so that it can be
-            // stored in the persistent config, even though this is duplicating
-            // the logic from the node.
-            maybe_id.unwrap_or_else(|| rand::thread_rng().gen())
-        }
-        Some(id) => id,
-    };
-
-    // Failing to persist the node id does not stop the node starting.
-    // This failure is unlikely.
-    if !app_prefs.set_config(config::APP_PREFERENCES_PERSISTED_NODE_ID, Some(node_id)) {
-        error!("Failed to persist own node id.");
-    };
-
-    P2PNode::new(Some(node_id), conf, PeerType::Node, stats_export_service, regenesis_arc)
+/// Earlier versions persisted a random node id in the app preferences. The
+/// node id is now derived from the node's static key, so the old id cannot be
+/// kept. It is read once to report the change and then removed from the
+/// preferences, so that the migration only happens on the first startup after
+/// the upgrade.
+fn migrate_persisted_node_id(app_prefs: &mut config::AppPreferences, node_id: P2PNodeId) {
+    match app_prefs.get_config::<P2PNodeId>(config::APP_PREFERENCES_PERSISTED_NODE_ID) {
+        Ok(None) => return,
+        Ok(Some(old_id)) if old_id != node_id => warn!(
+            "The node id changed from {} to {}, since it is now derived from the node's static \
+             key.",
+            old_id, node_id
+        ),
+        Ok(Some(_)) => {}
+        Err(e) => warn!("Could not read the persisted node id of an earlier version: {:#}", e),
+    }
+    // Failing to remove the old id only means that the migration is repeated.
+    if !app_prefs.set_config::<P2PNodeId>(config::APP_PREFERENCES_PERSISTED_NODE_ID, None) {
+        error!("Failed to remove the persisted node id of an earlier version.");
+    }
 }
 
 /// Establish initial connections to peers on the network.
 /// 1. Try connect to the provided "given_nodes" if configured.
-/// 2. Try connect to the peers that the node was last connected to (if any).
-/// 3. Try connect to the bootstrapper configured (if any) in order to advertise
+/// 2. Try connect to the anchors saved when the node last shut down (if any).
+/// 3. Try connect to the peers that the node was last connected to (if any).
+/// 4. Try connect to the bootstrapper configured (if any) in order to advertise
 /// ourselves such that the boostrapper node can provide us to freshly joining
 /// peers.
 fn establish_connections(conf: &config::Config, node: &Arc<P2PNode>) -> anyhow::Result<()> {
     info!("Starting the P2P layer");
     connect_to_config_nodes(node);
+    if let Err(e) = anchors::connect_to_anchors(node) {
+        warn!("Could not connect to the anchors {}", e);
+    }
     if let Err(e) = peers::connect_to_stored_nodes(node) {
         warn!("Could not connect to stored peers {}", e);
     }
@@ -389,6 +417,7 @@ fn start_consensus_message_threads(
     let mut threads: Vec<JoinHandle<()>> = Default::default();
 
     let node_ref = Arc::clone(node);
+    let queue_depths = queue_depths();
     threads.push(spawn_or_die!("inbound consensus requests", {
         let consensus_receiver_high_priority =
             CALLBACK_QUEUE.inbound.receiver_high_priority.lock().unwrap();
@@ -409,7 +438,7 @@ fn start_consensus_message_threads(
                 .set(consensus_receiver_high_priority.len() as i64);
             // instead of using `try_iter()` we specifically only loop over the max numbers
             // possible to ever be in the queue
-            for _ in 0..CONSENSUS_QUEUE_DEPTH_IN_HI {
+            for _ in 0..queue_depths.inbound_high {
                 if let Ok(message) = consensus_receiver_high_priority.try_recv() {
                     let stop_loop = !handle_queue_stop(message, "inbound", |msg| {
                         handle_consensus_inbound_msg(&node_ref, &consensus, msg)
@@ -479,7 +508,7 @@ fn start_consensus_message_threads(
                 .set(consensus_receiver_high_priority.len() as i64);
             // instead of using `try_iter()` we specifically only loop over the max numbers
             // possible to ever be in the queue
-            for _ in 0..CONSENSUS_QUEUE_DEPTH_OUT_HI {
+            for _ in 0..queue_depths.outbound_high {
                 if let Ok(message) = consensus_receiver_high_priority.try_recv() {
                     let stop_loop = !handle_queue_stop(message, "outbound", |msg| {
                         handle_consensus_outbound_msg(&node_ref, msg)
@@ -32,6 +32,53 @@ impl fmt::Display for PeerType {
     }
 }
 
+/// The capabilities a peer advertises in its handshake, as a bitfield. Bits
+/// that are not known to this version of the node are retained, so that they
+/// are passed on unchanged in peer lists.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PeerCapabilities(pub u64);
+
+impl PeerCapabilities {
+    /// The peer keeps the full history of the chain and can serve catch-up
+    /// from genesis.
+    pub const ARCHIVAL: PeerCapabilities = PeerCapabilities(1);
+    /// The peer exposes its gRPC V2 API publicly.
+    pub const PUBLIC_GRPC2: PeerCapabilities = PeerCapabilities(1 << 1);
+    /// The peer relays transactions to its own peers.
+    pub const RELAYS_TRANSACTIONS: PeerCapabilities = PeerCapabilities(1 << 2);
+
+    /// Whether all the capabilities in `other` are also in `self`.
+    pub fn contains(self, other: PeerCapabilities) -> bool { self.0 & other.0 == other.0 }
+
+    /// Add the capabilities in `other`.
+    pub fn insert(&mut self, other: PeerCapabilities) { self.0 |= other.0; }
+}
+
+/// Display the known capabilities as a comma separated list.
+impl Display for PeerCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = [
+            (PeerCapabilities::ARCHIVAL, "archival"),
+            (PeerCapabilities::PUBLIC_GRPC2, "public-grpc2"),
+            (PeerCapabilities::RELAYS_TRANSACTIONS, "relays-transactions"),
+        ]
+        .iter()
+        .filter(|(capability, _)| self.contains(*capability))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// The service endpoints a peer advertises in its handshake, in addition to
+/// its peer-to-peer port. The endpoints are reachable on the peer's IP address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PeerServices {
+    /// The port of the peer's public gRPC V2 API, if it advertises one.
+    pub grpc2_port: Option<u16>,
+}
+
 /// Identifier we assign to a peer when they connect. This is a purely local
 /// identifier that is never transmitted over the network, but it is used
 /// internally to keep track of peers (e.g., during catchup) and to ban them.
@@ -144,6 +191,11 @@ pub struct RemotePeer {
     /// advertised as part of the peer list we serve.
     pub external_port: u16,
     pub peer_type:     PeerType,
+    /// Capabilities communicated to us by the node as part of the handshake.
+    pub capabilities:  PeerCapabilities,
+    /// Service endpoints communicated to us by the node as part of the
+    /// handshake.
+    pub services:      PeerServices,
 }
 
 // This instance is only used for storing peers in buckets, in which case
@@ -171,6 +223,8 @@ impl RemotePeer {
             id,
             addr: self.external_addr(),
             peer_type: self.peer_type,
+            capabilities: self.capabilities,
+            services: self.services,
         })
     }
 
@@ -178,18 +232,28 @@ impl RemotePeer {
     pub fn external_addr(&self) -> SocketAddr {
         SocketAddr::new(self.addr.ip(), self.external_port)
     }
+
+    /// Gets the address of the peer's public gRPC V2 API, if it advertises
+    /// one.
+    pub fn grpc2_address(&self) -> Option<SocketAddr> {
+        self.services.grpc2_port.map(|port| SocketAddr::new(self.addr.ip(), port))
+    }
 }
 
 /// Information about a peer that is transmitted over the network.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct P2PPeer {
     /// The peer's chosen identifier.
-    pub id:        P2PNodeId,
+    pub id:           P2PNodeId,
     /// The peer's address. Note that this is the address they advertise as part
     /// of the handshake, and thus it is ostensibly the address where it
     /// listens to for new connections.
-    pub addr:      SocketAddr,
-    pub peer_type: PeerType,
+    pub addr:         SocketAddr,
+    pub peer_type:    PeerType,
+    /// The capabilities the peer advertised in its handshake.
+    pub capabilities: PeerCapabilities,
+    /// The service endpoints the peer advertised in its handshake.
+    pub services:     PeerServices,
 }
 
 impl P2PPeer {
@@ -210,40 +274,49 @@ impl Display for P2PPeer {
 #[derive(Debug)]
 pub struct PeerStats {
     /// The peer's self identifier. Only used for reporting.
-    pub self_id:        P2PNodeId,
-    pub addr:           SocketAddr,
-    pub external_port:  u16,
+    pub self_id:           P2PNodeId,
+    pub addr:              SocketAddr,
+    pub external_port:     u16,
     /// Our identifier for the remote peer.
-    pub local_id:       RemotePeerId,
-    pub peer_type:      PeerType,
-    pub latency:        u64,
-    pub msgs_sent:      u64,
-    pub msgs_received:  u64,
-    pub bytes_sent:     u64,
-    pub bytes_received: u64,
+    pub local_id:          RemotePeerId,
+    pub peer_type:         PeerType,
+    pub capabilities:      PeerCapabilities,
+    pub services:          PeerServices,
+    pub latency:           u64,
+    pub msgs_sent:         u64,
+    pub msgs_received:     u64,
+    pub bytes_sent:        u64,
+    pub bytes_received:    u64,
+    /// Milliseconds messages to the peer have waited for upload budget.
+    pub send_throttled_ms: u64,
+    /// Milliseconds reading from the peer was paused for lack of download
+    /// budget.
+    pub recv_throttled_ms: u64,
+    /// The peer's reputation score.
+    pub score:             i64,
 }
 
 impl PeerStats {
-    /// Creates a new peer stats object.
-    pub fn new(
-        local_id: RemotePeerId,
-        self_id: P2PNodeId,
-        addr: SocketAddr,
-        external_port: u16,
-        peer_type: PeerType,
-        conn_stats: &ConnectionStats,
-    ) -> PeerStats {
+    /// Creates a new peer stats object. The remote peer must be
+    /// post-handshake.
+    pub fn new(remote_peer: &RemotePeer, conn_stats: &ConnectionStats) -> PeerStats {
         PeerStats {
-            local_id,
-            self_id,
-            addr,
-            external_port,
-            peer_type,
-            latency: conn_stats.get_latency(),
-            msgs_sent: conn_stats.messages_sent.load(AtomicOrdering::Relaxed),
-            msgs_received: conn_stats.messages_received.load(AtomicOrdering::Relaxed),
-            bytes_sent: conn_stats.bytes_sent.load(AtomicOrdering::Relaxed),
-            bytes_received: conn_stats.bytes_received.load(AtomicOrdering::Relaxed),
+            local_id:          remote_peer.local_id,
+            // safe - always available post-handshake
+            self_id:           remote_peer.self_id.unwrap(),
+            addr:              remote_peer.addr,
+            external_port:     remote_peer.external_port,
+            peer_type:         remote_peer.peer_type,
+            capabilities:      remote_peer.capabilities,
+            services:          remote_peer.services,
+            latency:           conn_stats.get_latency(),
+            msgs_sent:         conn_stats.messages_sent.load(AtomicOrdering::Relaxed),
+            msgs_received:     conn_stats.messages_received.load(AtomicOrdering::Relaxed),
+            bytes_sent:        conn_stats.bytes_sent.load(AtomicOrdering::Relaxed),
+            bytes_received:    conn_stats.bytes_received.load(AtomicOrdering::Relaxed),
+            send_throttled_ms: conn_stats.send_throttled_ms.load(AtomicOrdering::Relaxed),
+            recv_throttled_ms: conn_stats.recv_throttled_ms.load(AtomicOrdering::Relaxed),
+            score:             conn_stats.score.load(AtomicOrdering::Relaxed),
         }
     }
 
@@ -254,4 +327,10 @@ impl PeerStats {
     pub fn external_address(&self) -> SocketAddr {
         SocketAddr::new(self.addr.ip(), self.external_port)
     }
+
+    /// Gets the address of the peer's public gRPC V2 API, if it advertises
+    /// one.
+    pub fn grpc2_address(&self) -> Option<SocketAddr> {
+        self.services.grpc2_port.map(|port| SocketAddr::new(self.addr.ip(), port))
+    }
 }
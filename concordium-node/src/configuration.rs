@@ -2,8 +2,12 @@
 
 use crate::{
     common::P2PNodeId,
-    connection::DeduplicationHashAlgorithm,
-    network::{WireProtocolVersion, WIRE_PROTOCOL_VERSIONS},
+    connection::{
+        inbound_limits::PacketRateLimit, outbound_queues::PacketQueueLimit,
+        DeduplicationHashAlgorithm,
+    },
+    network::WireProtocolVersion,
+    p2p::{ban_list::BanListSource, mesh::RelayMode},
 };
 use anyhow::{ensure, Context};
 use app_dirs2::*;
@@ -35,11 +39,12 @@ pub(crate) fn is_compatible_version(other: &semver::Version) -> bool {
 
 /// Check that the other wire version is compatible with ours. This returns
 /// the highest wire protocol version that is supported by both nodes (since
-/// `network::WIRE_PROTOCOL_VERSIONS` is in descending order).
+/// `ours` is in descending order, like `network::WIRE_PROTOCOL_VERSIONS`).
 pub(crate) fn is_compatible_wire_version(
+    ours: &[WireProtocolVersion],
     other: &[WireProtocolVersion],
 ) -> Option<WireProtocolVersion> {
-    WIRE_PROTOCOL_VERSIONS.iter().find(|&&ours| other.iter().any(|&theirs| theirs == ours)).copied()
+    ours.iter().find(|&&ours| other.iter().any(|&theirs| theirs == ours)).copied()
 }
 
 /// The maximum size of objects accepted from the network.
@@ -50,11 +55,11 @@ pub const PROTOCOL_MAX_TRANSACTION_SIZE: usize = 600 * 1024; // 600 kB.
 
 const APP_PREFERENCES_MAIN: &str = "main.config";
 const APP_PREFERENCES_KEY_VERSION: &str = "VERSION";
-/// Used for a persistent node id setup.
+/// Used by earlier versions for a persistent node id. It is only read once to
+/// migrate to the node id derived from the node's static key.
 pub const APP_PREFERENCES_PERSISTED_NODE_ID: &str = "PERSISTED_NODE_ID";
-
-/// Maximum time allowed for a peer to catch up with, in milliseconds.
-pub const MAX_CATCH_UP_TIME: u64 = 300_000;
+/// Name of the file in the data directory that holds the node's static key.
+pub const NODE_KEY_FILE_NAME: &str = "node.key";
 
 // dump queue depths
 #[cfg(feature = "network_dump")]
@@ -578,6 +583,22 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_MAX_ALLOWED_NODES_PERCENTAGE"
     )]
     pub max_allowed_nodes_percentage: u16,
+    #[structopt(
+        long = "reserved-outbound-slots",
+        help = "The number of peer slots (out of the maximum allowed nodes) that inbound \
+                connections cannot use",
+        default_value = "2",
+        env = "CONCORDIUM_NODE_CONNECTION_RESERVED_OUTBOUND_SLOTS"
+    )]
+    pub reserved_outbound_slots: u16,
+    #[structopt(
+        long = "anchor-count",
+        help = "The number of the best outbound peers that are remembered on shutdown and \
+                connected to first on the next startup",
+        default_value = "2",
+        env = "CONCORDIUM_NODE_CONNECTION_ANCHOR_COUNT"
+    )]
+    pub anchor_count: usize,
     #[structopt(
         long = "no-bootstrap",
         help = "Do not bootstrap via DNS",
@@ -590,6 +611,47 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_CLEAR_BANS"
     )]
     pub clear_bans: bool,
+    #[structopt(
+        long = "import-bans",
+        help = "Merge the bans in the given JSON ban list into the ban database on start.",
+        env = "CONCORDIUM_NODE_CONNECTION_IMPORT_BANS"
+    )]
+    pub import_bans: Option<PathBuf>,
+    #[structopt(
+        long = "export-bans",
+        help = "Write the bans in force to the given file as a JSON ban list on start.",
+        env = "CONCORDIUM_NODE_CONNECTION_EXPORT_BANS"
+    )]
+    pub export_bans: Option<PathBuf>,
+    #[structopt(
+        long = "ban-list-sync-source",
+        help = "Periodically merge the bans in the JSON ban list at the given path or HTTP(S) URL \
+                into the ban database.",
+        env = "CONCORDIUM_NODE_CONNECTION_BAN_LIST_SYNC_SOURCE"
+    )]
+    pub ban_list_sync_source: Option<BanListSource>,
+    #[structopt(
+        long = "ban-list-sync-interval",
+        help = "How often (in seconds) to synchronise the ban list from the sync source.",
+        default_value = "300",
+        env = "CONCORDIUM_NODE_CONNECTION_BAN_LIST_SYNC_INTERVAL"
+    )]
+    pub ban_list_sync_interval: u64,
+    #[structopt(
+        long = "peer-exchange-list-size",
+        help = "The maximum number of peers shared by a node in response to a GetPeers request",
+        default_value = "10",
+        env = "CONCORDIUM_NODE_CONNECTION_PEER_EXCHANGE_LIST_SIZE"
+    )]
+    pub peer_exchange_list_size: usize,
+    #[structopt(
+        long = "peer-exchange-interval",
+        help = "The minimum time (in seconds) between two responses to GetPeers requests from the \
+                same peer. Requests that come sooner are ignored.",
+        default_value = "20",
+        env = "CONCORDIUM_NODE_CONNECTION_PEER_EXCHANGE_INTERVAL"
+    )]
+    pub peer_exchange_interval: u64,
     #[structopt(
         long = "relay-broadcast-percentage",
         help = "The percentage of peers to relay broadcasted messages to",
@@ -597,6 +659,23 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_RELAY_BROADCAST_PERCENTAGE"
     )]
     pub relay_broadcast_percentage: f64,
+    #[structopt(
+        long = "relay-mode",
+        help = "How broadcasts are relayed to peers [flood|mesh]. With flood they are pushed to \
+                all the peers, or to the share of them set with --relay-broadcast-percentage. \
+                With mesh transactions and blocks are pushed to a stable set of --mesh-degree \
+                peers per network and only announced to the other peers",
+        default_value = "flood",
+        env = "CONCORDIUM_NODE_CONNECTION_RELAY_MODE"
+    )]
+    pub relay_mode: RelayMode,
+    #[structopt(
+        long = "mesh-degree",
+        help = "The number of peers broadcasts are pushed to per network in the mesh relay mode",
+        default_value = "6",
+        env = "CONCORDIUM_NODE_CONNECTION_MESH_DEGREE"
+    )]
+    pub mesh_degree: usize,
     #[structopt(
         long = "connect-to",
         short = "c",
@@ -611,6 +690,34 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_DISALLOW_MULTIPLE_PEERS_ON_SAME_IP"
     )]
     pub disallow_multiple_peers_on_ip: bool,
+    #[structopt(
+        long = "max-peers-per-ipv4-16",
+        help = "The maximum number of peers in the same IPv4 /16 subnet. Given addresses are \
+                exempt.",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_PEERS_PER_IPV4_16"
+    )]
+    pub max_peers_per_ipv4_16: Option<usize>,
+    #[structopt(
+        long = "max-peers-per-ipv4-24",
+        help = "The maximum number of peers in the same IPv4 /24 subnet. Given addresses are \
+                exempt.",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_PEERS_PER_IPV4_24"
+    )]
+    pub max_peers_per_ipv4_24: Option<usize>,
+    #[structopt(
+        long = "max-peers-per-ipv6-32",
+        help = "The maximum number of peers in the same IPv6 /32 subnet. Given addresses are \
+                exempt.",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_PEERS_PER_IPV6_32"
+    )]
+    pub max_peers_per_ipv6_32: Option<usize>,
+    #[structopt(
+        long = "max-peers-per-ipv6-48",
+        help = "The maximum number of peers in the same IPv6 /48 subnet. Given addresses are \
+                exempt.",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_PEERS_PER_IPV6_48"
+    )]
+    pub max_peers_per_ipv6_48: Option<usize>,
     #[structopt(
         long = "dns-resolver",
         help = "DNS resolver to use",
@@ -668,6 +775,22 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_CATCH_UP_BATCH_LIMIT"
     )]
     pub catch_up_batch_limit: i64,
+    #[structopt(
+        long = "catch-up-parallelism",
+        help = "The maximum number of peers to catch up with at the same time.",
+        default_value = "2",
+        env = "CONCORDIUM_NODE_CONNECTION_CATCH_UP_PARALLELISM"
+    )]
+    pub catch_up_parallelism: usize,
+    #[structopt(
+        long = "catch-up-timeout",
+        help = "Time (in ms) allowed for catching up with a peer. A peer that takes longer is \
+                moved to the back of the catch-up queue, is not selected again for as long as \
+                this timeout, and is dropped if it times out 3 times in a row.",
+        default_value = "300000",
+        env = "CONCORDIUM_NODE_CONNECTION_CATCH_UP_TIMEOUT"
+    )]
+    pub catch_up_timeout: u64,
     #[structopt(
         long = "thread-pool-size",
         help = "The size of the threadpool processing connection events in parallel",
@@ -718,7 +841,9 @@ pub struct ConnectionConfig {
     pub events_queue_size: usize,
     #[structopt(
         long = "deduplication-hashing-algorithm",
-        help = "Hash algorithm used for deduplication [xxhash64|sha256]",
+        help = "Hash algorithm used for deduplication [xxhash64|sha256|xxhash64-set]. With \
+                xxhash64-set the hashes are also kept in a set, so that checking for duplicates \
+                takes constant time instead of time linear in the size of the queues",
         default_value = "xxhash64",
         env = "CONCORDIUM_NODE_CONNECTION_DEDUPLICATION_HASHING_ALGORITHM"
     )]
@@ -737,6 +862,192 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CLEAR_PERSISTED_PEERS"
     )]
     pub clear_persisted_peers: bool,
+    #[structopt(
+        long = "address-book-max-age",
+        help = "Remove peers that have not been seen for this many seconds from the address book",
+        default_value = "604800",
+        env = "CONCORDIUM_NODE_CONNECTION_ADDRESS_BOOK_MAX_AGE"
+    )]
+    pub address_book_max_age: u64,
+    #[structopt(
+        long = "reconnect-backoff-initial",
+        help = "The number of seconds to wait before dialling an address again after a failed \
+                connection attempt. The delay doubles with every further failure.",
+        default_value = "30",
+        env = "CONCORDIUM_NODE_CONNECTION_RECONNECT_BACKOFF_INITIAL"
+    )]
+    pub reconnect_backoff_initial: u64,
+    #[structopt(
+        long = "reconnect-backoff-max",
+        help = "The maximum number of seconds to wait before dialling an address again after \
+                failed connection attempts",
+        default_value = "3600",
+        env = "CONCORDIUM_NODE_CONNECTION_RECONNECT_BACKOFF_MAX"
+    )]
+    pub reconnect_backoff_max: u64,
+    #[structopt(
+        long = "shutdown-drain-timeout",
+        help = "The maximum number of milliseconds to spend writing the messages queued for peers \
+                when shutting down, before the connections are closed",
+        default_value = "5000",
+        env = "CONCORDIUM_NODE_CONNECTION_SHUTDOWN_DRAIN_TIMEOUT"
+    )]
+    pub shutdown_drain_timeout: u64,
+    #[structopt(
+        long = "peer-allowlist",
+        help = "Run in permissioned mode and only complete handshakes with peers listed in the \
+                given file. The file contains one entry per line, either a hex encoded static key \
+                of a peer or a node id.",
+        env = "CONCORDIUM_NODE_CONNECTION_PEER_ALLOWLIST"
+    )]
+    pub peer_allowlist: Option<PathBuf>,
+    #[structopt(
+        long = "packet-compression-threshold",
+        help = "Packets with payloads larger than this many bytes are compressed when sent to \
+                peers that support compression.",
+        default_value = "4096",
+        env = "CONCORDIUM_NODE_CONNECTION_PACKET_COMPRESSION_THRESHOLD"
+    )]
+    pub packet_compression_threshold: usize,
+    #[structopt(
+        long = "advertise-archival",
+        help = "Advertise to peers that the node keeps the full history of the chain.",
+        env = "CONCORDIUM_NODE_CONNECTION_ADVERTISE_ARCHIVAL"
+    )]
+    pub advertise_archival: bool,
+    #[structopt(
+        long = "advertise-grpc2-port",
+        help = "Advertise to peers that the node exposes its gRPC V2 API publicly on the given \
+                port of its external IP address.",
+        env = "CONCORDIUM_NODE_CONNECTION_ADVERTISE_GRPC2_PORT"
+    )]
+    pub advertise_grpc2_port: Option<u16>,
+    #[structopt(
+        long = "upload-limit-high",
+        help = "Limit on the rate (in bytes per second) at which high priority messages are sent \
+                to all peers together.",
+        env = "CONCORDIUM_NODE_CONNECTION_UPLOAD_LIMIT_HIGH"
+    )]
+    pub upload_limit_high: Option<u64>,
+    #[structopt(
+        long = "upload-limit-low",
+        help = "Limit on the rate (in bytes per second) at which normal priority messages are \
+                sent to all peers together.",
+        env = "CONCORDIUM_NODE_CONNECTION_UPLOAD_LIMIT_LOW"
+    )]
+    pub upload_limit_low: Option<u64>,
+    #[structopt(
+        long = "download-limit",
+        help = "Limit on the rate (in bytes per second) at which data is read from all peers \
+                together.",
+        env = "CONCORDIUM_NODE_CONNECTION_DOWNLOAD_LIMIT"
+    )]
+    pub download_limit: Option<u64>,
+    #[structopt(
+        long = "peer-upload-limit-high",
+        help = "Limit on the rate (in bytes per second) at which high priority messages are sent \
+                to a single peer.",
+        env = "CONCORDIUM_NODE_CONNECTION_PEER_UPLOAD_LIMIT_HIGH"
+    )]
+    pub peer_upload_limit_high: Option<u64>,
+    #[structopt(
+        long = "peer-upload-limit-low",
+        help = "Limit on the rate (in bytes per second) at which normal priority messages are \
+                sent to a single peer.",
+        env = "CONCORDIUM_NODE_CONNECTION_PEER_UPLOAD_LIMIT_LOW"
+    )]
+    pub peer_upload_limit_low: Option<u64>,
+    #[structopt(
+        long = "peer-download-limit",
+        help = "Limit on the rate (in bytes per second) at which data is read from a single peer.",
+        env = "CONCORDIUM_NODE_CONNECTION_PEER_DOWNLOAD_LIMIT"
+    )]
+    pub peer_download_limit: Option<u64>,
+    #[structopt(
+        long = "inbound-packet-limits",
+        help = "Limits on the number of packets of each type a single peer may send, as a comma \
+                separated list of TYPE:COUNT/SECONDS. The types are block, transaction, \
+                finalization-record, finalization-message and catch-up-status. Packets beyond the \
+                limit are dropped.",
+        default_value = "catch-up-status:600/60",
+        env = "CONCORDIUM_NODE_CONNECTION_INBOUND_PACKET_LIMITS",
+        use_delimiter = true
+    )]
+    pub inbound_packet_limits: Vec<PacketRateLimit>,
+    #[structopt(
+        long = "inbound-limit-violations",
+        help = "The number of intervals in which a peer may exceed the inbound packet limit of a \
+                packet type before it is dropped and soft-banned. Every interval within the limit \
+                of a type forgives one earlier violation of that limit.",
+        default_value = "3",
+        env = "CONCORDIUM_NODE_CONNECTION_INBOUND_LIMIT_VIOLATIONS"
+    )]
+    pub inbound_limit_violations: u32,
+    #[structopt(
+        long = "outbound-queue-limits",
+        help = "The weights and capacities of the queues of outgoing packets of each type, as a \
+                comma separated list of TYPE:WEIGHT/BYTES. The queues of a peer get to send bytes \
+                in proportion to their weights, and packets that would make a queue hold more \
+                than its capacity are dropped. Direct packets, e.g. catch-up responses, share one \
+                queue with the weight of blocks, which is not capped so that no response is \
+                dropped. Messages that are not packets, and the types that are not listed, get \
+                weight 1 and a capacity of 16MiB.",
+        default_value = "block:8/67108864,transaction:4/33554432,finalization-record:4/16777216,\
+                         finalization-message:8/16777216,catch-up-status:2/4194304",
+        env = "CONCORDIUM_NODE_CONNECTION_OUTBOUND_QUEUE_LIMITS",
+        use_delimiter = true
+    )]
+    pub outbound_queue_limits: Vec<PacketQueueLimit>,
+    #[structopt(
+        long = "consensus-inbound-queue-depth-high",
+        help = "The maximum number of blocks, finalization messages and catch-up messages \
+                received from peers that are queued for consensus. Once the queue is half full, a \
+                peer may only hold its fair share of it.",
+        default_value = "16384",
+        env = "CONCORDIUM_NODE_CONNECTION_CONSENSUS_INBOUND_QUEUE_DEPTH_HIGH"
+    )]
+    pub consensus_inbound_queue_depth_high: usize,
+    #[structopt(
+        long = "consensus-inbound-queue-depth-low",
+        help = "The maximum number of transactions received from peers that are queued for \
+                consensus. Once the queue is half full, a peer may only hold its fair share of it.",
+        default_value = "32768",
+        env = "CONCORDIUM_NODE_CONNECTION_CONSENSUS_INBOUND_QUEUE_DEPTH_LOW"
+    )]
+    pub consensus_inbound_queue_depth_low: usize,
+    #[structopt(
+        long = "consensus-outbound-queue-depth-high",
+        help = "The maximum number of messages produced by consensus that are queued for sending \
+                to peers.",
+        default_value = "8192",
+        env = "CONCORDIUM_NODE_CONNECTION_CONSENSUS_OUTBOUND_QUEUE_DEPTH_HIGH"
+    )]
+    pub consensus_outbound_queue_depth_high: usize,
+    #[structopt(
+        long = "consensus-outbound-queue-depth-low",
+        help = "The maximum number of transactions received through the API that are queued for \
+                sending to peers.",
+        default_value = "16384",
+        env = "CONCORDIUM_NODE_CONNECTION_CONSENSUS_OUTBOUND_QUEUE_DEPTH_LOW"
+    )]
+    pub consensus_outbound_queue_depth_low: usize,
+    #[structopt(
+        long = "reputation-ban-threshold",
+        help = "Peers whose reputation score falls to this value are dropped and soft-banned. \
+                Scores range from -1000 to 1000 and start at 0.",
+        default_value = "-500",
+        allow_hyphen_values = true,
+        env = "CONCORDIUM_NODE_CONNECTION_REPUTATION_BAN_THRESHOLD"
+    )]
+    pub reputation_ban_threshold: i64,
+    #[structopt(
+        long = "reputation-latency-threshold",
+        help = "Peers whose latency (in milliseconds) is above this value have their reputation \
+                score lowered during every housekeeping round.",
+        default_value = "1000",
+        env = "CONCORDIUM_NODE_CONNECTION_REPUTATION_LATENCY_THRESHOLD"
+    )]
+    pub reputation_latency_threshold: u64,
 }
 
 #[derive(StructOpt, Debug)]
@@ -751,8 +1062,8 @@ pub struct CommonConfig {
     #[structopt(
         long = "id",
         short = "i",
-        help = "Set forced node id (64 bit unsigned integer in zero padded HEX. Must be 16 \
-                characters long)",
+        help = "Deprecated and ignored. The node id is derived from the node's static key which \
+                is stored in the data directory.",
         env = "CONCORDIUM_NODE_ID"
     )]
     pub id: Option<P2PNodeId>,
@@ -849,6 +1160,13 @@ pub struct CommonConfig {
         env = "CONCORDIUM_NODE_BUCKET_CLEANUP_INTERVAL"
     )]
     pub bucket_cleanup_interval: u64,
+    #[structopt(
+        long = "bucket-size",
+        help = "The maximum number of peers kept in each of the distance buckets of a regular node",
+        default_value = "20",
+        env = "CONCORDIUM_NODE_BUCKET_SIZE"
+    )]
+    pub bucket_size: usize,
 }
 
 // Client's parameters.
@@ -997,19 +1315,60 @@ pub fn parse_config() -> anyhow::Result<Config> {
             conf.connection.desired_nodes,
             max_allowed_nodes
         );
+        ensure!(
+            conf.connection.reserved_outbound_slots <= max_allowed_nodes,
+            "The reserved outbound slots can't be more than what max allowed nodes is set to"
+        );
     }
 
+    ensure!(
+        conf.connection.reserved_outbound_slots <= conf.connection.desired_nodes,
+        "The reserved outbound slots can't be more than what desired nodes is set to"
+    );
+
     ensure!(
         conf.connection.hard_connection_limit >= conf.connection.desired_nodes,
         "Hard connection limit can't be less than what desired nodes is set to"
     );
 
+    for (name, limit) in [
+        ("upload-limit-high", conf.connection.upload_limit_high),
+        ("upload-limit-low", conf.connection.upload_limit_low),
+        ("download-limit", conf.connection.download_limit),
+        ("peer-upload-limit-high", conf.connection.peer_upload_limit_high),
+        ("peer-upload-limit-low", conf.connection.peer_upload_limit_low),
+        ("peer-download-limit", conf.connection.peer_download_limit),
+    ] {
+        ensure!(limit != Some(0), "{} must be positive; leave it unset for no limit", name);
+    }
+
+    for (name, limit) in [
+        ("max-peers-per-ipv4-16", conf.connection.max_peers_per_ipv4_16),
+        ("max-peers-per-ipv4-24", conf.connection.max_peers_per_ipv4_24),
+        ("max-peers-per-ipv6-32", conf.connection.max_peers_per_ipv6_32),
+        ("max-peers-per-ipv6-48", conf.connection.max_peers_per_ipv6_48),
+    ] {
+        ensure!(limit != Some(0), "{} must be positive; leave it unset for no limit", name);
+    }
+
     ensure!(
         conf.connection.relay_broadcast_percentage >= 0.0
             && conf.connection.relay_broadcast_percentage <= 1.0,
         "Percentage of peers to relay broadcasted packets to, must be between 0.0 and 1.0"
     );
 
+    ensure!(conf.connection.mesh_degree > 0, "The mesh degree must be positive");
+
+    ensure!(conf.connection.catch_up_parallelism > 0, "The catch-up parallelism must be positive");
+
+    ensure!(
+        conf.connection.consensus_inbound_queue_depth_high > 0
+            && conf.connection.consensus_inbound_queue_depth_low > 0
+            && conf.connection.consensus_outbound_queue_depth_high > 0
+            && conf.connection.consensus_outbound_queue_depth_low > 0,
+        "The depths of the consensus queues must be positive"
+    );
+
     ensure!(
         conf.cli.baker.maximum_block_size <= 4_000_000_000
             && ((f64::from(conf.cli.baker.maximum_block_size) * 0.9).ceil()) as u32
@@ -1033,6 +1392,13 @@ pub fn parse_config() -> anyhow::Result<Config> {
         "wait-until-minimum-nodes must be lower than or equal to peer-list-size"
     );
 
+    ensure!(conf.common.bucket_size > 0, "bucket-size must be at least 1");
+
+    ensure!(
+        conf.connection.reconnect_backoff_initial <= conf.connection.reconnect_backoff_max,
+        "reconnect-backoff-initial must be lower than or equal to reconnect-backoff-max"
+    );
+
     ensure!(
         conf.connection.max_normal_keep_alive
             >= conf.connection.housekeeping_interval * (KEEP_ALIVE_FACTOR as u64),
@@ -0,0 +1,259 @@
+//! Per-peer limits on the rate of incoming packets of each type.
+//!
+//! Every connection counts the packets of each type it receives in fixed time
+//! windows. Packets beyond the configured limit are dropped, and a peer that
+//! exceeds the limit of a packet type in too many windows is expelled and
+//! soft-banned. Every window in which a peer stays within the limit of a type
+//! forgives one earlier violation of that limit, so that occasional bursts over
+//! a long-lived connection do not add up. The violations are counted per type,
+//! so staying within the limit of one type does not make up for exceeding the
+//! limit of another.
+//!
+//! Packets are checked before they are deduplicated, so duplicates of packets
+//! that were already received from other peers count towards the limits too.
+//! Checking them afterwards would mark packets that are then dropped as
+//! received, so that the same packet would be dropped as a duplicate when it
+//! arrives from other peers as well. The limits of the broadcast packet types
+//! should therefore allow for every peer relaying each broadcast once.
+
+use crate::consensus_ffi::helpers::PacketType;
+use anyhow::{bail, Context};
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// The number of packet types, used to index the per-type counters.
+pub(crate) const NUM_PACKET_TYPES: usize = 5;
+
+/// The name of a packet type as used in the configuration and metrics.
+pub fn packet_type_name(packet_type: PacketType) -> &'static str {
+    match packet_type {
+        PacketType::Block => "block",
+        PacketType::Transaction => "transaction",
+        PacketType::FinalizationRecord => "finalization-record",
+        PacketType::FinalizationMessage => "finalization-message",
+        PacketType::CatchUpStatus => "catch-up-status",
+    }
+}
+
+/// Parse the name of a packet type as used in the configuration.
+pub fn parse_packet_type(name: &str) -> anyhow::Result<PacketType> {
+    match name.trim() {
+        "block" => Ok(PacketType::Block),
+        "transaction" => Ok(PacketType::Transaction),
+        "finalization-record" => Ok(PacketType::FinalizationRecord),
+        "finalization-message" => Ok(PacketType::FinalizationMessage),
+        "catch-up-status" => Ok(PacketType::CatchUpStatus),
+        other => bail!("Unknown packet type {}.", other),
+    }
+}
+
+/// A limit on the number of packets of a given type a peer may send in a
+/// given interval.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketRateLimit {
+    pub packet_type: PacketType,
+    pub max_packets: u32,
+    pub interval:    Duration,
+}
+
+impl FromStr for PacketRateLimit {
+    type Err = anyhow::Error;
+
+    /// Parse a limit of the form `TYPE:COUNT/SECONDS`, e.g.,
+    /// `catch-up-status:60/60`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, limit) = s.split_once(':').context("Expected TYPE:COUNT/SECONDS.")?;
+        let packet_type = parse_packet_type(name)?;
+        let (count, secs) = limit.split_once('/').context("Expected TYPE:COUNT/SECONDS.")?;
+        let max_packets = count.trim().parse().context("Invalid packet count.")?;
+        let secs: u64 = secs.trim().parse().context("Invalid interval.")?;
+        if secs == 0 {
+            bail!("The interval must be at least one second.");
+        }
+        Ok(Self {
+            packet_type,
+            max_packets,
+            interval: Duration::from_secs(secs),
+        })
+    }
+}
+
+/// The outcome of checking an incoming packet against the limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InboundCheck {
+    /// The packet is within the limits.
+    Allowed,
+    /// The packet exceeds the limit and should be dropped.
+    Dropped,
+    /// The packet exceeds the limit and the peer has exceeded the limits too
+    /// often, so it should be expelled.
+    Expel,
+}
+
+/// The state of the limit for one packet type.
+#[derive(Debug)]
+struct Window {
+    limit:        PacketRateLimit,
+    start:        Instant,
+    count:        u32,
+    /// Whether the limit was exceeded in the current window.
+    was_exceeded: bool,
+    /// The number of windows in which the peer exceeded the limit, minus the
+    /// number of later windows in which it stayed within the limit.
+    violations:   u32,
+}
+
+/// The inbound packet limits of a single connection.
+#[derive(Debug)]
+pub struct InboundRateLimiter {
+    windows:        [Option<Window>; NUM_PACKET_TYPES],
+    max_violations: u32,
+}
+
+impl InboundRateLimiter {
+    /// Create a limiter with the given limits. The peer is to be expelled once
+    /// it exceeds the limit of a packet type in `max_violations` windows.
+    pub fn new(limits: &[PacketRateLimit], max_violations: u32) -> Self {
+        let mut windows: [Option<Window>; NUM_PACKET_TYPES] = Default::default();
+        let now = Instant::now();
+        for limit in limits {
+            windows[limit.packet_type as usize] = Some(Window {
+                limit:        *limit,
+                start:        now,
+                count:        0,
+                was_exceeded: false,
+                violations:   0,
+            });
+        }
+        Self {
+            windows,
+            max_violations,
+        }
+    }
+
+    /// Register an incoming packet of the given type.
+    pub fn check(&mut self, packet_type: PacketType) -> InboundCheck {
+        let window = match self.windows[packet_type as usize] {
+            Some(ref mut window) => window,
+            None => return InboundCheck::Allowed,
+        };
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(window.start);
+        if elapsed >= window.limit.interval {
+            // every window that ended without exceeding the limit, including the
+            // ones without any packets, forgives one violation
+            let ended_windows = elapsed.as_millis() / window.limit.interval.as_millis().max(1);
+            let clean_windows = ended_windows - u128::from(window.was_exceeded);
+            let clean_windows = u32::try_from(clean_windows).unwrap_or(u32::MAX);
+            window.violations = window.violations.saturating_sub(clean_windows);
+            window.start = now;
+            window.count = 0;
+            window.was_exceeded = false;
+        }
+        window.count = window.count.saturating_add(1);
+        if window.count <= window.limit.max_packets {
+            return InboundCheck::Allowed;
+        }
+        if !window.was_exceeded {
+            window.was_exceeded = true;
+            window.violations += 1;
+        }
+        if window.violations >= self.max_violations {
+            InboundCheck::Expel
+        } else {
+            InboundCheck::Dropped
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inbound_rate_limiter() -> anyhow::Result<()> {
+        let limit: PacketRateLimit = "catch-up-status:2/60".parse()?;
+        assert_eq!(limit, PacketRateLimit {
+            packet_type: PacketType::CatchUpStatus,
+            max_packets: 2,
+            interval:    Duration::from_secs(60),
+        });
+        assert!("catch-up-status:2/0".parse::<PacketRateLimit>().is_err());
+        assert!("unknown:2/60".parse::<PacketRateLimit>().is_err());
+
+        let mut limiter = InboundRateLimiter::new(&[limit], 2);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Allowed);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Allowed);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Dropped);
+        // further excess packets in the same window count as a single violation
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Dropped);
+        // packet types without a limit are always allowed
+        assert_eq!(limiter.check(PacketType::Block), InboundCheck::Allowed);
+
+        // exceeding the limit in another window leads to an expulsion
+        limiter.windows[PacketType::CatchUpStatus as usize].as_mut().unwrap().start -=
+            Duration::from_secs(60);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Allowed);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Allowed);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Expel);
+        Ok(())
+    }
+
+    /// The violations of the limit of the given type.
+    fn violations(limiter: &InboundRateLimiter, packet_type: PacketType) -> u32 {
+        limiter.windows[packet_type as usize].as_ref().unwrap().violations
+    }
+
+    /// Move the start of the current window of the given type `secs` seconds
+    /// into the past.
+    fn rewind(limiter: &mut InboundRateLimiter, packet_type: PacketType, secs: u64) {
+        limiter.windows[packet_type as usize].as_mut().unwrap().start -= Duration::from_secs(secs);
+    }
+
+    #[test]
+    fn test_inbound_violations_decay() -> anyhow::Result<()> {
+        let limit: PacketRateLimit = "catch-up-status:1/60".parse()?;
+        let mut limiter = InboundRateLimiter::new(&[limit], 2);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Allowed);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Dropped);
+        assert_eq!(violations(&limiter, PacketType::CatchUpStatus), 1);
+
+        // a window within the limit forgives the earlier violation
+        rewind(&mut limiter, PacketType::CatchUpStatus, 60);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Allowed);
+        rewind(&mut limiter, PacketType::CatchUpStatus, 60);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Allowed);
+        assert_eq!(violations(&limiter, PacketType::CatchUpStatus), 0);
+
+        // so a single further violation does not lead to an expulsion
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Dropped);
+        assert_eq!(violations(&limiter, PacketType::CatchUpStatus), 1);
+
+        // idle windows forgive violations as well
+        rewind(&mut limiter, PacketType::CatchUpStatus, 180);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Allowed);
+        assert_eq!(violations(&limiter, PacketType::CatchUpStatus), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inbound_violations_are_per_type() -> anyhow::Result<()> {
+        let limits: [PacketRateLimit; 2] =
+            ["catch-up-status:1/60".parse()?, "transaction:10/1".parse()?];
+        let mut limiter = InboundRateLimiter::new(&limits, 2);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Allowed);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Dropped);
+
+        // clean windows of the transaction limit do not forgive the violation of
+        // the catch-up status limit
+        rewind(&mut limiter, PacketType::Transaction, 60);
+        assert_eq!(limiter.check(PacketType::Transaction), InboundCheck::Allowed);
+        assert_eq!(violations(&limiter, PacketType::CatchUpStatus), 1);
+        rewind(&mut limiter, PacketType::CatchUpStatus, 60);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Allowed);
+        assert_eq!(limiter.check(PacketType::CatchUpStatus), InboundCheck::Expel);
+        Ok(())
+    }
+}
@@ -5,10 +5,13 @@ use mio::net::TcpStream;
 use noiseexplorer_xx::{
     consts::{DHLEN, MAC_LENGTH},
     noisesession::NoiseSession,
-    types::Keypair,
 };
 
-use crate::{configuration::PROTOCOL_MAX_MESSAGE_SIZE, p2p::maintenance::P2PNode};
+use super::{rate_limit::ConnectionRateLimits, MessageSendingPriority};
+use crate::{
+    configuration::PROTOCOL_MAX_MESSAGE_SIZE,
+    p2p::{identity::NodePublicKey, maintenance::P2PNode},
+};
 
 use std::{
     cmp,
@@ -16,6 +19,7 @@ use std::{
     convert::TryInto,
     io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
     mem,
+    net::Shutdown,
     sync::{Arc, Weak},
 };
 
@@ -31,6 +35,9 @@ pub const HANDSHAKE_SIZE_LIMIT: usize = 1024;
 pub const PSK: &[u8] = b"b6461bd246843f70ac1328401405b2b4e725994d7d144a75bff1a04a247d64b7";
 /// The size of the initial socket write queue allocation.
 const WRITE_QUEUE_ALLOC: usize = 1024 * 1024;
+/// The number of bytes waiting to be written to the socket above which normal
+/// priority messages are kept in their queues.
+const WRITE_BACKLOG_LIMIT: usize = 4 * 1024 * 1024;
 
 /// A single encrypted message currently being read from the socket.
 #[derive(Default)]
@@ -101,6 +108,9 @@ pub enum ReadResult {
     WouldBlock,
     /// The read returned 0 bytes, indicating a closed socket.
     Closed,
+    /// The connection is out of its download budget; reading should be resumed
+    /// later even if there is no new readiness event for the socket.
+    Throttled,
 }
 
 /// The `Connection`'s socket, noise session and some helper objects.
@@ -123,6 +133,8 @@ pub struct ConnectionLowLevel {
     is_initialized: bool,
     /// If specified, the linger value to set for the socket
     so_linger:      Option<u16>,
+    /// The byte-rate limits of the connection.
+    rate_limits:    ConnectionRateLimits,
 }
 
 macro_rules! recv_xx_msg {
@@ -180,7 +192,11 @@ impl ConnectionLowLevel {
         ConnectionLowLevel {
             handler: Arc::downgrade(handler),
             socket,
-            noise_session: NoiseSession::init_session(is_initiator, PROLOGUE, Keypair::default()),
+            noise_session: NoiseSession::init_session(
+                is_initiator,
+                PROLOGUE,
+                handler.identity.keypair(),
+            ),
             noise_buffer: vec![0u8; NOISE_MAX_MESSAGE_LEN].into_boxed_slice(),
             socket_buffer: SocketBuffer::new(read_size),
             incoming_msg: IncomingMessage::default(),
@@ -189,6 +205,10 @@ impl ConnectionLowLevel {
             is_writable: false,
             is_initialized: false,
             so_linger,
+            rate_limits: ConnectionRateLimits::new(
+                &handler.config.peer_rate_limits,
+                Arc::clone(&handler.connection_handler.global_rate_limits),
+            ),
         }
     }
 
@@ -312,6 +332,16 @@ impl ConnectionLowLevel {
         Ok(payload)
     }
 
+    /// The static public key of the remote end of the connection. This is only
+    /// meaningful once the remote end has sent its static key in the noise
+    /// handshake, i.e., when its handshake request has been received.
+    pub fn remote_static_key(&self) -> NodePublicKey {
+        self.noise_session.get_remote_static_public_key().as_bytes()
+    }
+
+    /// Whether the node initiated the connection.
+    pub fn is_initiator(&self) -> bool { self.noise_session.is_initiator() }
+
     #[inline]
     /// Checks whether the low-level noise handshake is complete.
     fn is_post_handshake(&self) -> bool {
@@ -333,6 +363,11 @@ impl ConnectionLowLevel {
         // if there's any carryover bytes to be read from the socket buffer,
         // process them before reading from the socket again
         if self.socket_buffer.remaining == 0 {
+            // the handshake is never throttled so that connections can always be
+            // established
+            if self.is_post_handshake() && !self.rate_limits.may_receive() {
+                return Ok(ReadResult::Throttled);
+            }
             let len = self.read_size() - self.socket_buffer.offset;
             match self.socket.read(self.socket_buffer.slice_mut(len)) {
                 Ok(0) => return Ok(ReadResult::Closed),
@@ -342,6 +377,7 @@ impl ConnectionLowLevel {
                     //     ByteSize(num_bytes as u64).to_string_as(true)
                     // );
                     self.socket_buffer.remaining = num_bytes;
+                    self.rate_limits.on_received(num_bytes);
                 }
                 Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(ReadResult::WouldBlock),
                 Err(e) => return Err(e.into()),
@@ -519,9 +555,22 @@ impl ConnectionLowLevel {
         // trace!("Connection became writable. {:?}", self.socket);
     }
 
-    /// Enqueue a message to be written to the socket.
+    /// Check whether the upload budget allows sending a message with the
+    /// given priority now.
     #[inline]
-    pub fn write_to_socket(&mut self, input: Arc<[u8]>) -> anyhow::Result<()> {
+    pub fn may_send(&mut self, priority: MessageSendingPriority) -> bool {
+        self.rate_limits.may_send(priority)
+    }
+
+    /// Enqueue a message to be written to the socket, charging it to the
+    /// upload budget of the given priority.
+    #[inline]
+    pub fn write_to_socket(
+        &mut self,
+        input: Arc<[u8]>,
+        priority: MessageSendingPriority,
+    ) -> anyhow::Result<()> {
+        self.rate_limits.on_sent(priority, input.len());
         self.encrypt_and_enqueue(&input)
     }
 
@@ -631,6 +680,24 @@ impl ConnectionLowLevel {
         Ok(())
     }
 
+    /// The number of bytes that are enqueued but not yet written to the
+    /// socket.
+    #[inline]
+    pub fn queued_bytes(&self) -> usize { self.output_queue.len() }
+
+    /// Whether so many bytes are waiting to be written to the socket that
+    /// normal priority messages should not be added yet.
+    #[inline]
+    pub fn is_write_backlogged(&self) -> bool { self.output_queue.len() >= WRITE_BACKLOG_LIMIT }
+
+    /// Shut down the writing half of the socket so that the peer sees the
+    /// connection closed cleanly once the bytes already written reach it.
+    pub fn shutdown_socket(&self) {
+        if let Err(e) = self.socket.shutdown(Shutdown::Write) {
+            debug!("Could not shut down the socket {:?}: {}", self.socket, e);
+        }
+    }
+
     /// Get the desired socket read size.
     #[inline]
     fn read_size(&self) -> usize { self.socket_buffer.buf.len() }
@@ -2,32 +2,45 @@
 
 use crate::{
     common::{
+        get_current_stamp,
         p2p_peer::{PeerStats, RemotePeerId},
         PeerType,
     },
     configuration::{is_compatible_version, is_compatible_wire_version, MAX_PEER_NETWORKS},
     connection::{ConnChange, Connection},
     network::{
-        Handshake, NetworkMessage, NetworkPacket, NetworkPayload, NetworkRequest, NetworkResponse,
-        PacketDestination,
+        frames::ReceivedFrame, Disconnect, DisconnectReason, Handshake, NetworkMessage,
+        NetworkPacket, NetworkPayload, NetworkRequest, NetworkResponse, ObjectHash,
+        PacketDestination, WIRE_PROTOCOL_AUTHENTICATED_ID_VERSION, WIRE_PROTOCOL_INVENTORY_VERSION,
+    },
+    p2p::{
+        address_book::PeerSource, bans::PersistedBanId, identity::node_id_from_public_key,
+        inventory::MAX_INVENTORY_HASHES,
     },
     plugins::consensus::*,
-    read_or_die,
+    read_or_die, write_or_die,
 };
 use anyhow::{bail, ensure};
+use std::sync::Arc;
 
 impl Connection {
-    /// Processes a network message based on its type.
+    /// Processes a network message based on its type. If the message is a
+    /// broadcast packet, `received_frame` is the frame it was received in.
     pub fn handle_incoming_message(
         &mut self,
         msg: NetworkMessage,
+        received_frame: Option<ReceivedFrame>,
         conn_stats: &[PeerStats],
     ) -> anyhow::Result<()> {
-        // the handshake should be the first incoming network message
+        // the handshake should be the first incoming network message, but a peer
+        // that rejects our handshake may tell us why before it completes
         let peer_id = match msg.payload {
             NetworkPayload::NetworkRequest(NetworkRequest::Handshake(handshake), ..) => {
                 return self.handle_handshake_req(handshake, conn_stats);
             }
+            NetworkPayload::NetworkRequest(NetworkRequest::Disconnect(disconnect), ..) => {
+                return self.handle_disconnect_req(disconnect);
+            }
             _ => {
                 ensure!(
                     self.is_post_handshake(),
@@ -39,7 +52,8 @@ impl Connection {
         };
 
         match msg.payload {
-            NetworkPayload::NetworkRequest(NetworkRequest::Handshake(_), ..) => {
+            NetworkPayload::NetworkRequest(NetworkRequest::Handshake(_), ..)
+            | NetworkPayload::NetworkRequest(NetworkRequest::Disconnect(_), ..) => {
                 // already handled at the beginning
                 Ok(())
             }
@@ -53,10 +67,35 @@ impl Connection {
             }
             NetworkPayload::NetworkRequest(NetworkRequest::GetPeers(networks), ..) => {
                 debug!("Got a GetPeers request from peer {}", peer_id);
-                self.send_peer_list_resp(networks, conn_stats)
+                if self.is_peer_list_resp_allowed() {
+                    self.send_peer_list_resp(networks, conn_stats)
+                } else {
+                    debug!("Ignoring a GetPeers request from peer {} that came too soon", peer_id);
+                    Ok(())
+                }
             }
             NetworkPayload::NetworkResponse(NetworkResponse::PeerList(peers), ..) => {
                 debug!("Got a PeerList ({} peers) from peer {}", peers.len(), peer_id);
+                let source = match self.remote_peer_type() {
+                    PeerType::Bootstrapper => PeerSource::Bootstrapper,
+                    PeerType::Node => PeerSource::PeerExchange,
+                };
+                if let Err(e) = self.handler.record_learned_peers(&peers, source) {
+                    error!("Could not record the shared peers in the address book: {}", e);
+                }
+                // keep the shared peers in the buckets too, so that they can be tried if
+                // the node loses all its peers and the bootstrappers are unreachable
+                {
+                    let networks = read_or_die!(self.handler.networks()).clone();
+                    let mut buckets = write_or_die!(self.handler.buckets());
+                    for peer in peers.iter().filter(|peer| peer.peer_type == PeerType::Node) {
+                        buckets.insert_learned(
+                            *peer,
+                            networks.clone(),
+                            &self.handler.stats.peer_bucket_size,
+                        );
+                    }
+                }
                 self.handler.register_conn_change(ConnChange::NewPeers(peers));
                 Ok(())
             }
@@ -68,9 +107,17 @@ impl Connection {
                 debug!("Got a LeaveNetwork request from peer {}", peer_id);
                 self.remove_remote_end_network(network)
             }
+            NetworkPayload::NetworkRequest(NetworkRequest::Inventory(hashes), ..) => {
+                trace!("Got an Inventory ({} objects) from peer {}", hashes.len(), peer_id);
+                self.handle_inventory_req(hashes)
+            }
+            NetworkPayload::NetworkRequest(NetworkRequest::GetObjects(hashes), ..) => {
+                trace!("Got a GetObjects request ({} objects) from peer {}", hashes.len(), peer_id);
+                self.handle_get_objects_req(hashes)
+            }
             NetworkPayload::NetworkPacket(pac, ..) => {
                 // packet receipt is logged later, along with its contents
-                self.handle_incoming_packet(pac, peer_id)
+                self.handle_incoming_packet(pac, peer_id, received_frame)
             }
         }
     }
@@ -88,22 +135,44 @@ impl Connection {
         if handshake.wire_versions.is_empty() {
             bail!("Rejecting handshake: Handshake message lacked wire versions.");
         }
-        let wire_version =
-            if let Some(wire_version) = is_compatible_wire_version(&handshake.wire_versions) {
-                wire_version
-            } else if handshake.wire_versions.len() > 10 {
-                bail!("Rejecting handshake: incompatible wire protocol versions received.",);
-            } else {
-                bail!(
-                    "Rejecting handshake: incompatible wire protocol versions ({:?}).",
-                    handshake.wire_versions
-                );
-            };
+        let wire_version = if let Some(wire_version) =
+            is_compatible_wire_version(self.handler.wire_versions(), &handshake.wire_versions)
+        {
+            wire_version
+        } else if handshake.wire_versions.len() > 10 {
+            bail!("Rejecting handshake: incompatible wire protocol versions received.",);
+        } else {
+            bail!(
+                "Rejecting handshake: incompatible wire protocol versions ({:?}).",
+                handshake.wire_versions
+            );
+        };
         if handshake.networks.len() > MAX_PEER_NETWORKS {
             bail!("Rejecting handshake: too many networks.");
         }
-
+        // the noise handshake authenticated the peer's static key, so the node id it
+        // claims must be the one derived from that key. Peers that only support
+        // older wire versions use a throwaway key, so their id cannot be checked.
+        let remote_key = self.low_level.remote_static_key();
+        let authenticated_id = node_id_from_public_key(&remote_key);
+        if wire_version >= WIRE_PROTOCOL_AUTHENTICATED_ID_VERSION
+            && handshake.remote_id != authenticated_id
         {
+            bail!(
+                "Rejecting handshake: the claimed node id ({}) does not match the peer's static \
+                 key (id {}).",
+                handshake.remote_id,
+                authenticated_id
+            );
+        }
+        self.handler.check_peer_allowed(&remote_key)?;
+        // if we fail to read the database we allow the connection, like for IP bans
+        if self.handler.is_banned(PersistedBanId::Node(handshake.remote_id)).unwrap_or(false) {
+            self.write_disconnect(wire_version, Disconnect::new(DisconnectReason::Banned, None));
+            bail!("Rejecting handshake: the peer {} is banned.", handshake.remote_id);
+        }
+
+        let genesis_mismatch = {
             let our_blocks = read_or_die!(self.handler.config.regenesis_arc.blocks);
             // we will consider that the list of regenesis blocks is sorted
             // by height, so we check sequentially.
@@ -112,15 +181,20 @@ impl Connection {
                 .zip(handshake.genesis_blocks.iter())
                 .enumerate()
                 .find(|(_, (a, b))| a != b);
-            if let Some((i, (ours, theirs))) = common_blocks {
-                bail!(
+            common_blocks.map(|(i, (ours, theirs))| {
+                format!(
                     "Rejecting handshake: Didn't find a common prefix on the genesis block \
                      hashes. Difference: our block: {}, their block {} at position {}.",
-                    ours,
-                    theirs,
-                    i
-                );
-            }
+                    ours, theirs, i
+                )
+            })
+        };
+        if let Some(msg) = genesis_mismatch {
+            self.write_disconnect(
+                wire_version,
+                Disconnect::new(DisconnectReason::IncompatibleGenesis, None),
+            );
+            bail!(msg);
         }
 
         self.promote_to_post_handshake(
@@ -128,6 +202,8 @@ impl Connection {
             handshake.remote_port,
             &handshake.networks,
             wire_version,
+            handshake.capabilities,
+            handshake.services,
         );
 
         if self.handler.peer_type() == PeerType::Bootstrapper {
@@ -138,6 +214,66 @@ impl Connection {
         Ok(())
     }
 
+    fn handle_disconnect_req(&self, disconnect: Disconnect) -> anyhow::Result<()> {
+        debug!("Got a Disconnect request from {}", self);
+        // the address of an incoming connection is only known to be the peer's
+        // own after the handshake
+        if self.is_post_handshake() || self.low_level.is_initiator() {
+            self.handler.record_disconnect(self.remote_peer.external_addr(), disconnect, true);
+        }
+        // the peer is closing the connection, so it is not notified in return
+        self.handler.register_conn_change(ConnChange::RemovalByToken(self.token(), None));
+        Ok(())
+    }
+
+    /// Ask the peer for the announced objects the node has neither seen nor
+    /// asked for yet.
+    fn handle_inventory_req(&mut self, hashes: Vec<ObjectHash>) -> anyhow::Result<()> {
+        self.ensure_inventory_supported(&hashes)?;
+        // bootstrappers disregard packets, so they don't ask for any
+        if self.handler.self_peer.peer_type == PeerType::Bootstrapper {
+            return Ok(());
+        }
+        let wanted = self.handler.wanted_objects(self.remote_peer.local_id, &hashes);
+        if wanted.is_empty() {
+            return Ok(());
+        }
+        self.send_get_objects(wanted)
+    }
+
+    /// Send the peer the requested objects the node announced and still has.
+    /// Requests beyond the rate limit are ignored, and so are objects the peer
+    /// was sent already.
+    fn handle_get_objects_req(&mut self, hashes: Vec<ObjectHash>) -> anyhow::Result<()> {
+        self.ensure_inventory_supported(&hashes)?;
+        if !self.is_objects_request_allowed(hashes.len()) {
+            debug!("Peer {} asks for too many objects; ignoring the request", self);
+            return Ok(());
+        }
+        let peer_id = self.remote_peer.local_id;
+        for hash in hashes {
+            if let Some(frames) = self.handler.take_announced_object(&hash, peer_id) {
+                let frame = Arc::clone(frames.for_wire_version(self.wire_version));
+                self.async_send_packet(frame, frames.packet_type());
+            }
+        }
+        Ok(())
+    }
+
+    fn ensure_inventory_supported(&self, hashes: &[ObjectHash]) -> anyhow::Result<()> {
+        ensure!(
+            self.wire_version >= WIRE_PROTOCOL_INVENTORY_VERSION,
+            "Received an inventory request over wire protocol version {}.",
+            self.wire_version
+        );
+        ensure!(
+            hashes.len() <= MAX_INVENTORY_HASHES,
+            "Received an inventory request with {} hashes.",
+            hashes.len()
+        );
+        Ok(())
+    }
+
     /// Check whether the connection has completed the handshake.
     pub(crate) fn is_post_handshake(&self) -> bool { self.remote_peer.self_id.is_some() }
 
@@ -147,10 +283,18 @@ impl Connection {
         &self,
         pac: NetworkPacket,
         peer_id: RemotePeerId,
+        received_frame: Option<ReceivedFrame>,
     ) -> anyhow::Result<()> {
         let is_broadcast = matches!(pac.destination, PacketDestination::Broadcast(..));
 
         // Ignore the deserialized p2p node ids to be excluded from the wire.
-        handle_pkt_out(&self.handler, vec![peer_id], peer_id, pac.message, is_broadcast)
+        handle_pkt_out(
+            &self.handler,
+            vec![peer_id],
+            peer_id,
+            pac.message,
+            is_broadcast,
+            received_frame,
+        )
     }
 }
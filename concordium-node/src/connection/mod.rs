@@ -1,7 +1,10 @@
 //! Connection handling.
 
+pub mod inbound_limits;
 mod low_level;
 pub mod message_handlers;
+pub mod outbound_queues;
+pub mod rate_limit;
 #[cfg(test)]
 mod tests;
 
@@ -16,33 +19,46 @@ use crate::dumper::DumpItem;
 use crate::{
     common::{
         get_current_stamp,
-        p2p_peer::{P2PPeer, PeerStats},
+        p2p_peer::{P2PPeer, PeerCapabilities, PeerServices, PeerStats},
         P2PNodeId, PeerType, RemotePeer,
     },
     configuration::MAX_PEER_NETWORKS,
-    connection::low_level::ReadResult,
+    connection::{
+        inbound_limits::{packet_type_name, InboundCheck, InboundRateLimiter},
+        low_level::ReadResult,
+        outbound_queues::{MessageQueues, OutboundQueue},
+        rate_limit::ThrottleTimer,
+    },
     netmsg,
     network::{
-        NetworkId, NetworkMessage, NetworkPacket, NetworkPayload, NetworkRequest, NetworkResponse,
-        Networks, WireProtocolVersion, WIRE_PROTOCOL_CURRENT_VERSION,
+        buckets::is_learned_peer, compression::decompress_packet, frames::ReceivedFrame,
+        Disconnect, NetworkId, NetworkMessage, NetworkPacket, NetworkPayload, NetworkRequest,
+        NetworkResponse, Networks, ObjectHash, PacketDestination, PayloadCompression,
+        WireProtocolVersion, WIRE_PROTOCOL_COMPRESSION_VERSION, WIRE_PROTOCOL_CURRENT_VERSION,
+        WIRE_PROTOCOL_DISCONNECT_VERSION,
+    },
+    p2p::{
+        bans::SoftBanReason,
+        inventory::MAX_REQUESTED_OBJECTS_PER_SEC,
+        reputation::{apply_reputation_event, ReputationEvent},
+        P2PNode,
     },
-    p2p::P2PNode,
     read_or_die, write_or_die,
 };
 
 use crate::consensus_ffi::helpers::PacketType;
 
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     convert::TryFrom,
     fmt,
     net::SocketAddr,
-    ops::{Index, IndexMut},
     str::FromStr,
     sync::{
         atomic::{AtomicI64, AtomicU64, Ordering},
         Arc, RwLock,
     },
+    time::{Duration, Instant},
 };
 
 /// Designates the sending priority of outgoing messages.
@@ -63,6 +79,9 @@ pub enum DeduplicationHashAlgorithm {
     XxHash64,
     // SHA256
     Sha256,
+    /// XxHash64, with the hashes also kept in a set so that lookups take
+    /// constant time
+    XxHash64Set,
 }
 
 impl FromStr for DeduplicationHashAlgorithm {
@@ -72,6 +91,7 @@ impl FromStr for DeduplicationHashAlgorithm {
         match algorithm {
             "xxhash64" => Ok(DeduplicationHashAlgorithm::XxHash64),
             "sha256" => Ok(DeduplicationHashAlgorithm::Sha256),
+            "xxhash64-set" => Ok(DeduplicationHashAlgorithm::XxHash64Set),
             _ => bail!("Could not parse deduplication hashing algorithm"),
         }
     }
@@ -105,13 +125,16 @@ impl DeduplicationQueueXxHash64 {
     }
 
     /// Hash an input given as a byte slice
-    fn hash(&self, input: &[u8]) -> u64 {
-        use std::hash::Hasher;
-        use twox_hash::XxHash64;
-        let mut hasher = XxHash64::with_seed(self.seed);
-        hasher.write(input);
-        hasher.finish()
-    }
+    fn hash(&self, input: &[u8]) -> u64 { xxhash64(self.seed, input) }
+}
+
+/// Hash an input given as a byte slice with XxHash64 and the given seed.
+fn xxhash64(seed: u64, input: &[u8]) -> u64 {
+    use std::hash::Hasher;
+    use twox_hash::XxHash64;
+    let mut hasher = XxHash64::with_seed(seed);
+    hasher.write(input);
+    hasher.finish()
 }
 
 impl DeduplicationQueue for DeduplicationQueueXxHash64 {
@@ -180,19 +203,90 @@ impl DeduplicationQueue for DeduplicationQueueSha256 {
     }
 }
 
+/// XxHash64 deduplication struct whose operations take constant time.
+///
+/// The hashes are kept in a ring buffer in the order they were inserted, so
+/// that the oldest one can be evicted once the capacity is reached, and in a
+/// map from the hash to its insertion number, which is used for the lookups.
+/// An invalidated hash is only removed from the map; the insertion number
+/// ensures that evicting its stale entry from the ring buffer does not remove
+/// the hash if it was inserted again in the meantime.
+pub struct DeduplicationSetXxHash64 {
+    /// Random seed generated per queue when constructed
+    seed:     u64,
+    /// The maximum number of hashes kept
+    capacity: usize,
+    /// The hashes with their insertion numbers, oldest first
+    queue:    VecDeque<(u64, u64)>,
+    /// The insertion numbers of the hashes that are not invalidated
+    index:    HashMap<u64, u64>,
+    /// The insertion number of the next hash
+    next:     u64,
+}
+
+impl DeduplicationSetXxHash64 {
+    /// Constructs a new XxHash64 deduplication set with a random seed
+    pub fn new(capacity: usize) -> Self {
+        use rand::Rng;
+        Self {
+            seed: rand::thread_rng().gen::<u64>(),
+            capacity,
+            queue: VecDeque::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+            next: 0,
+        }
+    }
+
+    /// Hash an input given as a byte slice
+    fn hash(&self, input: &[u8]) -> u64 { xxhash64(self.seed, input) }
+}
+
+impl DeduplicationQueue for DeduplicationSetXxHash64 {
+    fn check_and_insert(&mut self, input: &[u8]) -> anyhow::Result<bool> {
+        let num = self.hash(input);
+        if self.index.contains_key(&num) {
+            trace!("Message XxHash64 {:x} is a duplicate", num);
+            return Ok(true);
+        }
+        trace!("Message XxHash64 {:x} is unique, adding to dedup set", num);
+        if self.capacity == 0 {
+            return Ok(false);
+        }
+        if self.queue.len() == self.capacity {
+            if let Some((old_num, old_insertion)) = self.queue.pop_front() {
+                if self.index.get(&old_num) == Some(&old_insertion) {
+                    self.index.remove(&old_num);
+                }
+            }
+        }
+        self.queue.push_back((num, self.next));
+        self.index.insert(num, self.next);
+        self.next += 1;
+        Ok(false)
+    }
+
+    fn invalidate_if_exists(&mut self, input: &[u8]) {
+        let num = self.hash(input);
+        // the stale entry stays in the queue until it is evicted
+        self.index.remove(&num);
+    }
+}
+
 /// Contains the circular queues of hashes of different consensus objects
-/// for deduplication purposes.
+/// for deduplication purposes, and of the hashes of announced objects.
 pub struct DeduplicationQueues {
     pub finalizations: RwLock<Box<dyn DeduplicationQueue>>,
     pub transactions:  RwLock<Box<dyn DeduplicationQueue>>,
     pub blocks:        RwLock<Box<dyn DeduplicationQueue>>,
     pub fin_records:   RwLock<Box<dyn DeduplicationQueue>>,
+    /// The hashes of the announced objects the node has seen or asked for.
+    pub inventory:     RwLock<Box<dyn DeduplicationQueue>>,
 }
 
 impl DeduplicationQueues {
     /// Creates the deduplication queues of specified sizes: short for blocks
-    /// and finalization records and long for finalization messages and
-    /// transactions.
+    /// and finalization records and long for finalization messages,
+    /// transactions and announced objects.
     pub fn new(algorithm: DeduplicationHashAlgorithm, long_size: usize, short_size: usize) -> Self {
         match algorithm {
             DeduplicationHashAlgorithm::XxHash64 => Self {
@@ -200,12 +294,21 @@ impl DeduplicationQueues {
                 transactions:  RwLock::new(Box::new(DeduplicationQueueXxHash64::new(long_size))),
                 blocks:        RwLock::new(Box::new(DeduplicationQueueXxHash64::new(short_size))),
                 fin_records:   RwLock::new(Box::new(DeduplicationQueueXxHash64::new(short_size))),
+                inventory:     RwLock::new(Box::new(DeduplicationQueueXxHash64::new(long_size))),
             },
             DeduplicationHashAlgorithm::Sha256 => Self {
                 finalizations: RwLock::new(Box::new(DeduplicationQueueSha256::new(long_size))),
                 transactions:  RwLock::new(Box::new(DeduplicationQueueSha256::new(long_size))),
                 blocks:        RwLock::new(Box::new(DeduplicationQueueSha256::new(short_size))),
                 fin_records:   RwLock::new(Box::new(DeduplicationQueueSha256::new(short_size))),
+                inventory:     RwLock::new(Box::new(DeduplicationQueueSha256::new(long_size))),
+            },
+            DeduplicationHashAlgorithm::XxHash64Set => Self {
+                finalizations: RwLock::new(Box::new(DeduplicationSetXxHash64::new(long_size))),
+                transactions:  RwLock::new(Box::new(DeduplicationSetXxHash64::new(long_size))),
+                blocks:        RwLock::new(Box::new(DeduplicationSetXxHash64::new(short_size))),
+                fin_records:   RwLock::new(Box::new(DeduplicationSetXxHash64::new(short_size))),
+                inventory:     RwLock::new(Box::new(DeduplicationSetXxHash64::new(long_size))),
             },
         }
     }
@@ -235,6 +338,12 @@ pub struct ConnectionStats {
     pub bytes_received:    AtomicU64,
     /// Number of bytes sent.
     pub bytes_sent:        AtomicU64,
+    /// Milliseconds spent with messages waiting for upload budget.
+    pub send_throttled_ms: AtomicU64,
+    /// Milliseconds spent with reading paused for lack of download budget.
+    pub recv_throttled_ms: AtomicU64,
+    /// The peer's reputation score.
+    pub score:             AtomicI64,
 }
 
 impl ConnectionStats {
@@ -250,6 +359,9 @@ impl ConnectionStats {
             messages_received:  AtomicU64::new(0),
             bytes_received:     AtomicU64::new(0),
             bytes_sent:         AtomicU64::new(0),
+            send_throttled_ms:  AtomicU64::new(0),
+            recv_throttled_ms:  AtomicU64::new(0),
+            score:              AtomicI64::new(0),
         }
     }
 
@@ -291,8 +403,9 @@ impl ConnectionStats {
 
 /// Specifies the type of change to be applied to the list of connections.
 pub enum ConnChange {
-    /// To be soft-banned by ip and removed from the list of connections.
-    ExpulsionByToken(Token),
+    /// To be soft-banned by ip for the given reason and removed from the list
+    /// of connections.
+    ExpulsionByToken(Token, SoftBanReason),
     /// Prospect node address to attempt to connect to.
     NewConn {
         /// address to connect to
@@ -306,58 +419,12 @@ pub enum ConnChange {
     NewPeers(Vec<P2PPeer>),
     /// Promotion to post-handshake.
     Promotion(Token),
-    /// To be removed from the list of connections.
-    RemovalByToken(Token),
-    RemoveAllByTokens(Vec<Token>),
-}
-
-/// Message queues, indexed by priority.
-pub struct MessageQueues {
-    pub low:  VecDeque<Arc<[u8]>>,
-    pub high: VecDeque<Arc<[u8]>>,
-}
-
-impl Index<MessageSendingPriority> for MessageQueues {
-    type Output = VecDeque<Arc<[u8]>>;
-
-    fn index(&self, priority: MessageSendingPriority) -> &Self::Output {
-        match priority {
-            MessageSendingPriority::Normal => &self.low,
-            MessageSendingPriority::High => &self.high,
-        }
-    }
-}
-
-impl IndexMut<MessageSendingPriority> for MessageQueues {
-    fn index_mut(&mut self, priority: MessageSendingPriority) -> &mut Self::Output {
-        match priority {
-            MessageSendingPriority::Normal => &mut self.low,
-            MessageSendingPriority::High => &mut self.high,
-        }
-    }
-}
-
-impl MessageQueues {
-    /// Create queues with the specified initial capacities.
-    pub fn new(low_capacity: usize, high_capacity: usize) -> Self {
-        Self {
-            low:  VecDeque::with_capacity(low_capacity),
-            high: VecDeque::with_capacity(high_capacity),
-        }
-    }
-
-    /// Add a message to the queue with the appropriate priority.
-    pub fn enqueue(&mut self, priority: MessageSendingPriority, message: Arc<[u8]>) {
-        self[priority].push_back(message);
-    }
-
-    /// Dequeue a message, taking from the high priority queue first.
-    pub fn dequeue(&mut self) -> Option<Arc<[u8]>> {
-        match self.high.pop_front() {
-            Some(message) => Some(message),
-            None => self.low.pop_front(),
-        }
-    }
+    /// To be removed from the list of connections. The peer is notified with
+    /// the `Disconnect`, if any, before the connection is closed.
+    RemovalByToken(Token, Option<Disconnect>),
+    /// To be removed from the list of connections, notifying the peers with
+    /// the `Disconnect` before the connections are closed.
+    RemoveAllByTokens(Vec<Token>, Disconnect),
 }
 
 /// A collection of objects related to the connection to a single peer.
@@ -375,6 +442,17 @@ pub struct Connection {
     pub pending_messages:    MessageQueues,
     /// The wire protocol version for communicating on the connection.
     pub wire_version:        WireProtocolVersion,
+    /// Tracks the time messages wait for upload budget.
+    send_throttle:           ThrottleTimer,
+    /// Tracks the time reading is paused for lack of download budget.
+    recv_throttle:           ThrottleTimer,
+    /// Limits on the rate of incoming packets by type.
+    inbound_limiter:         InboundRateLimiter,
+    /// When a PeerList was last sent to the connection.
+    last_peer_list_resp:     Option<Instant>,
+    /// The start of the current second and the number of objects the peer
+    /// asked for in it.
+    requested_objects:       (Instant, usize),
 }
 
 impl PartialEq for Connection {
@@ -429,10 +507,18 @@ impl Connection {
             low_level,
             remote_end_networks: Default::default(),
             stats,
-            pending_messages: MessageQueues::new(1024, 128),
+            pending_messages: MessageQueues::new(&handler.config.outbound_queue_limits),
             // When we create the connection, we set the wire protocol version
             // to the current version, but this is overwritten in the handshake.
             wire_version: WIRE_PROTOCOL_CURRENT_VERSION,
+            send_throttle: Default::default(),
+            recv_throttle: Default::default(),
+            inbound_limiter: InboundRateLimiter::new(
+                &handler.config.inbound_packet_limits,
+                handler.config.inbound_limit_violations,
+            ),
+            last_peer_list_resp: None,
+            requested_objects: (Instant::now(), 0),
         })
     }
 
@@ -458,9 +544,13 @@ impl Connection {
     /// Obtain the timestamp of when the connection was interacted with last.
     pub fn last_seen(&self) -> u64 { self.stats.last_seen.load(Ordering::Relaxed) }
 
+    /// Whether reading from the connection is paused for lack of download
+    /// budget. Such a connection has to be read from again even without a new
+    /// readiness event.
+    pub fn is_read_throttled(&self) -> bool { self.recv_throttle.is_throttled() }
+
     #[inline]
     fn is_packet_duplicate(&self, packet: &mut NetworkPacket) -> anyhow::Result<bool> {
-        use super::network::PacketDestination;
         let packet_type = if let Some(tag) = packet.message.first().copied() {
             PacketType::try_from(tag)?
         } else {
@@ -494,6 +584,40 @@ impl Connection {
         Ok(is_duplicate)
     }
 
+    /// Check the incoming packet against the inbound rate limits. Returns
+    /// whether the packet should be dropped. A peer that keeps exceeding the
+    /// limits is expelled.
+    fn is_packet_rate_limited(&mut self, packet: &NetworkPacket) -> anyhow::Result<bool> {
+        let packet_type = if let Some(tag) = packet.message.first().copied() {
+            PacketType::try_from(tag)?
+        } else {
+            bail!("Invalid message type.")
+        };
+        let check = self.inbound_limiter.check(packet_type);
+        if check == InboundCheck::Allowed {
+            return Ok(false);
+        }
+        self.handler
+            .stats
+            .inbound_rate_limited_packets
+            .with_label_values(&[packet_type_name(packet_type)])
+            .inc();
+        self.handler.bad_events.inc_rate_limited_messages(self.remote_peer.local_id);
+        apply_reputation_event(
+            &self.handler,
+            self.token(),
+            &self.stats,
+            ReputationEvent::RateLimited,
+        );
+        if check == InboundCheck::Expel {
+            self.handler.register_conn_change(ConnChange::ExpulsionByToken(
+                self.token(),
+                SoftBanReason::RateLimitExceeded(packet_type),
+            ));
+        }
+        Ok(true)
+    }
+
     /// Keeps reading from the socket as long as there is data to be read
     /// and the operation is not blocking.
     /// The return value indicates if the connection is still open.
@@ -503,8 +627,15 @@ impl Connection {
             match self.low_level.read_from_socket()? {
                 ReadResult::Complete(msg) => self.process_message(Arc::from(msg), conn_stats)?,
                 ReadResult::Incomplete => {}
-                ReadResult::WouldBlock => return Ok(true),
+                ReadResult::WouldBlock => {
+                    self.recv_throttle.update(false, &self.stats.recv_throttled_ms);
+                    return Ok(true);
+                }
                 ReadResult::Closed => return Ok(false),
+                ReadResult::Throttled => {
+                    self.recv_throttle.update(true, &self.stats.recv_throttled_ms);
+                    return Ok(true);
+                }
             }
         }
     }
@@ -529,19 +660,44 @@ impl Connection {
 
         let mut message = NetworkMessage::deserialize(&bytes)?;
 
+        let mut received_frame = None;
         if let NetworkPayload::NetworkPacket(ref mut packet) = message.payload {
             // disregard packets when in bootstrapper mode
             if self.handler.self_peer.peer_type == PeerType::Bootstrapper {
                 return Ok(());
             }
+            let compressed = packet.compression != PayloadCompression::None;
+            if compressed {
+                ensure!(
+                    self.wire_version >= WIRE_PROTOCOL_COMPRESSION_VERSION,
+                    "Received a compressed packet over wire protocol version {}.",
+                    self.wire_version
+                );
+                decompress_packet(packet, &self.handler.stats)?;
+            }
+            // the limits are checked before deduplication, so that a dropped packet
+            // is not recorded as received; duplicates count towards the limits
+            if self.is_packet_rate_limited(packet)? {
+                return Ok(());
+            }
             // deduplicate the incoming packet payload
             if self.is_packet_duplicate(packet)? {
                 return Ok(());
             }
+            // broadcasts are relayed in the frame they were received in, and
+            // their announcements are ignored from now on
+            if let PacketDestination::Broadcast(..) = packet.destination {
+                self.handler.record_received_object(&packet.message);
+                received_frame = Some(ReceivedFrame {
+                    bytes: Arc::clone(&bytes),
+                    network_id: packet.network_id,
+                    compressed,
+                });
+            }
         }
 
         // process the incoming message
-        self.handle_incoming_message(message, conn_stats)
+        self.handle_incoming_message(message, received_frame, conn_stats)
     }
 
     /// Concludes the connection's handshake process.
@@ -551,9 +707,13 @@ impl Connection {
         peer_port: u16,
         nets: &Networks,
         wire_version: WireProtocolVersion,
+        capabilities: PeerCapabilities,
+        services: PeerServices,
     ) {
         self.remote_peer.self_id = Some(id);
         self.remote_peer.external_port = peer_port;
+        self.remote_peer.capabilities = capabilities;
+        self.remote_peer.services = services;
         self.handler.stats.connected_peers.inc();
         self.handler.stats.total_peers.inc();
         if self.remote_peer.peer_type == PeerType::Bootstrapper {
@@ -563,15 +723,53 @@ impl Connection {
         self.wire_version = wire_version;
         self.handler.register_conn_change(ConnChange::Promotion(self.token()));
         debug!(
-            "Concluded handshake with peer {} (their id {}); wire protocol version {}",
-            self.remote_peer.local_id, id, wire_version
+            "Concluded handshake with peer {} (their id {}); wire protocol version {}; \
+             capabilities [{}]",
+            self.remote_peer.local_id, id, wire_version, capabilities
         );
     }
 
+    /// The number of messages queued for the connection, and the number of
+    /// bytes that are still to be written to it, including those of the queued
+    /// messages.
+    pub fn pending_output(&self) -> (usize, usize) {
+        let (messages, bytes) = self.pending_messages.pending();
+        (messages, bytes + self.low_level.queued_bytes())
+    }
+
     /// Queues a message to be sent to the connection.
     #[inline]
     pub fn async_send(&mut self, message: Arc<[u8]>, priority: MessageSendingPriority) {
-        self.pending_messages.enqueue(priority, message);
+        self.enqueue_message(priority, OutboundQueue::Other, message);
+    }
+
+    /// Queues a broadcast packet of the given type to be sent to the
+    /// connection. If the queue for the packet type is full, the packet is
+    /// dropped.
+    #[inline]
+    pub fn async_send_packet(&mut self, message: Arc<[u8]>, packet_type: Option<PacketType>) {
+        let queue = packet_type.map_or(OutboundQueue::Other, OutboundQueue::Packet);
+        self.enqueue_message(MessageSendingPriority::Normal, queue, message);
+    }
+
+    /// Queues a direct packet to be sent to the connection. Direct packets are
+    /// sent in the order they are queued. If their queue is full, the packet
+    /// is dropped.
+    #[inline]
+    pub fn async_send_direct_packet(&mut self, message: Arc<[u8]>) {
+        self.enqueue_message(MessageSendingPriority::Normal, OutboundQueue::Direct, message);
+    }
+
+    fn enqueue_message(
+        &mut self,
+        priority: MessageSendingPriority,
+        queue: OutboundQueue,
+        message: Arc<[u8]>,
+    ) {
+        if !self.pending_messages.enqueue(priority, queue, message) {
+            self.handler.stats.outbound_dropped_packets.with_label_values(&[queue.label()]).inc();
+            self.handler.bad_events.inc_dropped_outbound_messages(self.remote_peer.local_id);
+        }
     }
 
     /// Update the timestamp of when the connection was seen last.
@@ -586,11 +784,9 @@ impl Connection {
     pub fn populate_remote_end_networks(&mut self, peer: RemotePeer, networks: &Networks) {
         self.remote_end_networks.extend(networks.iter());
 
-        // Since buckets are only used if the node itself is running as a bootstrapper
-        // we don't want to add anything to buckets otherwise.
-        if self.handler.peer_type() == PeerType::Bootstrapper
-            && self.remote_peer.peer_type != PeerType::Bootstrapper
-        {
+        // Bootstrappers are not shared with other peers, so there is no point in
+        // keeping them in the buckets.
+        if self.remote_peer.peer_type != PeerType::Bootstrapper {
             write_or_die!(self.handler.buckets()).insert_into_bucket(
                 peer,
                 networks.to_owned(),
@@ -647,6 +843,27 @@ impl Connection {
         Ok(())
     }
 
+    /// Ask the connection for the objects with the given hashes.
+    pub fn send_get_objects(&mut self, hashes: Vec<ObjectHash>) -> anyhow::Result<()> {
+        let request = netmsg!(NetworkRequest, NetworkRequest::GetObjects(hashes));
+        let mut serialized = Vec::with_capacity(128);
+        request.serialize(&mut serialized)?;
+        self.async_send(Arc::from(serialized), MessageSendingPriority::Normal);
+        Ok(())
+    }
+
+    /// Check whether the peer may ask for the given number of objects, i.e.,
+    /// whether it stays within `MAX_REQUESTED_OBJECTS_PER_SEC`, and count them.
+    pub(crate) fn is_objects_request_allowed(&mut self, count: usize) -> bool {
+        let (start, requested) = &mut self.requested_objects;
+        if start.elapsed() >= Duration::from_secs(1) {
+            *start = Instant::now();
+            *requested = 0;
+        }
+        *requested += count;
+        *requested <= MAX_REQUESTED_OBJECTS_PER_SEC
+    }
+
     /// Send a pong to the connection.
     pub fn send_pong(&mut self) -> anyhow::Result<()> {
         trace!("Sending a pong to {}", self);
@@ -659,6 +876,44 @@ impl Connection {
         Ok(())
     }
 
+    /// Notify the peer that the connection is about to be closed, and why. This
+    /// is best-effort: the message is written to the socket right away,
+    /// bypassing the queue of pending messages, and failures are ignored.
+    /// Connections that have not completed the handshake are not notified.
+    pub fn send_disconnect(&mut self, disconnect: Disconnect) {
+        if self.is_post_handshake() {
+            self.handler.record_disconnect(self.remote_peer.external_addr(), disconnect, false);
+            self.write_disconnect(self.wire_version, disconnect);
+        }
+    }
+
+    /// Write a `Disconnect` request directly to the socket if the given wire
+    /// protocol version supports it.
+    fn write_disconnect(&mut self, wire_version: WireProtocolVersion, disconnect: Disconnect) {
+        if wire_version < WIRE_PROTOCOL_DISCONNECT_VERSION {
+            return;
+        }
+        trace!("Sending a Disconnect to {}", self);
+
+        let msg = netmsg!(NetworkRequest, NetworkRequest::Disconnect(disconnect));
+        let mut serialized = Vec::with_capacity(64);
+        let res = msg.serialize(&mut serialized).and_then(|_| {
+            self.low_level.write_to_socket(Arc::from(serialized), MessageSendingPriority::High)?;
+            self.low_level.flush_socket()
+        });
+        if let Err(e) = res {
+            debug!("Could not send a Disconnect to {}: {}", self, e);
+        }
+    }
+
+    /// Check whether enough time has passed since the last PeerList sent to
+    /// the connection for it to be sent another one.
+    pub fn is_peer_list_resp_allowed(&self) -> bool {
+        self.last_peer_list_resp.map_or(true, |last| {
+            last.elapsed() >= Duration::from_secs(self.handler.config.peer_exchange_interval)
+        })
+    }
+
     /// Send a response to a request for peers to the connection.
     pub fn send_peer_list_resp(
         &mut self,
@@ -675,6 +930,7 @@ impl Connection {
                         requestor,
                         self.handler.config.bootstrapper_peer_list_size,
                         &nets,
+                        &self.handler.config.subnet_limits,
                     )
                     .iter()
                     .filter_map(RemotePeer::peer)
@@ -690,14 +946,26 @@ impl Connection {
                 }
             }
             PeerType::Node => {
-                let nodes = conn_stats
+                use rand::seq::SliceRandom;
+                // Only the peers in the buckets that completed a handshake with us are
+                // shared, since only their genesis blocks are known to be compatible with
+                // ours; the peers learned from PeerLists are not passed on. Peers we are
+                // currently connected to are shared first, and soft-banned peers are not
+                // shared at all.
+                let mut candidates =
+                    read_or_die!(self.handler.buckets()).get_all_nodes(Some(requestor), &nets);
+                candidates.retain(|peer| {
+                    !is_learned_peer(peer)
+                        && !self.handler.connection_handler.is_soft_banned(peer.external_addr())
+                });
+                candidates.shuffle(&mut rand::thread_rng());
+                candidates.sort_by_key(|peer| {
+                    !conn_stats.iter().any(|stat| stat.local_id == peer.local_id)
+                });
+                let nodes = candidates
                     .iter()
-                    .filter(|stat| stat.local_id != requestor)
-                    .map(|stat| P2PPeer {
-                        id:        stat.self_id,
-                        addr:      stat.external_address(),
-                        peer_type: stat.peer_type,
-                    })
+                    .filter_map(RemotePeer::peer)
+                    .take(self.handler.config.peer_exchange_list_size)
                     .collect::<Vec<_>>();
 
                 if !nodes.is_empty() {
@@ -710,6 +978,7 @@ impl Connection {
 
         if let Some(resp) = peer_list_resp {
             debug!("Sending a PeerList to peer {}", requestor);
+            self.last_peer_list_resp = Some(Instant::now());
 
             let mut serialized = Vec::with_capacity(256);
             resp.serialize(&mut serialized)?;
@@ -723,16 +992,32 @@ impl Connection {
     }
 
     /// Processes a queue with pending messages, writing them to the socket.
+    /// Messages for which there is no upload budget are left in the queue.
     #[inline]
     pub fn send_pending_messages(&mut self) -> anyhow::Result<()> {
-        while let Some(msg) = self.pending_messages.dequeue() {
+        let mut throttled = false;
+        loop {
+            let low_level = &mut self.low_level;
+            let (priority, msg) = match self.pending_messages.dequeue(|priority| {
+                // normal priority messages stay in their queues while the socket is
+                // backlogged, so that they are dequeued fairly once it drains
+                if priority == MessageSendingPriority::Normal && low_level.is_write_backlogged() {
+                    return false;
+                }
+                let may_send = low_level.may_send(priority);
+                throttled |= !may_send;
+                may_send
+            }) {
+                Some(next) => next,
+                None => break,
+            };
             trace!(
                 "Attempting to send {} to {}",
                 ByteSize(msg.len() as u64).to_string_as(true),
                 self
             );
 
-            self.low_level.write_to_socket(msg.clone())?;
+            self.low_level.write_to_socket(msg.clone(), priority)?;
 
             self.handler.connection_handler.total_sent.fetch_add(1, Ordering::Relaxed);
             self.handler.stats.packets_sent.inc();
@@ -745,6 +1030,7 @@ impl Connection {
                 self.send_to_dump(msg, false);
             }
         }
+        self.send_throttle.update(throttled, &self.stats.send_throttled_ms);
 
         Ok(())
     }
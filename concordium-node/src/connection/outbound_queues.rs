@@ -0,0 +1,344 @@
+//! Per-connection queues of outgoing messages.
+//!
+//! High priority messages, e.g., handshakes and pings, are sent before all
+//! the others. Every other broadcast packet is put in the sub-queue for its
+//! type. Direct packets, i.e., catch-up requests and responses, must arrive in
+//! the order they were sent, so they share one sub-queue whatever their types,
+//! with the weight of the block sub-queue, since catch-up responses mostly
+//! consist of blocks. Messages that are not packets have a sub-queue of their
+//! own. The sub-queues are served by deficit round-robin: in every round a
+//! sub-queue may send a number of bytes proportional to its weight, so that a
+//! burst of one type, e.g., the blocks sent during catch-up, cannot starve the
+//! others. The number of bytes a sub-queue holds is capped, and messages that
+//! would exceed the cap are dropped. The exception is the sub-queue of direct
+//! packets: a dropped catch-up response would leave a gap the peer cannot
+//! detect, so it is not capped. Its size is bounded by consensus instead,
+//! which answers each catch-up request with a limited number of messages.
+
+use super::{
+    inbound_limits::{packet_type_name, parse_packet_type, NUM_PACKET_TYPES},
+    MessageSendingPriority,
+};
+use crate::consensus_ffi::helpers::PacketType;
+use anyhow::{bail, Context};
+use std::{collections::VecDeque, str::FromStr, sync::Arc};
+
+/// The weight of the sub-queues without a configured limit.
+const DEFAULT_WEIGHT: u32 = 1;
+
+/// The maximum number of bytes held by the sub-queues without a configured
+/// limit.
+const DEFAULT_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// The number of bytes a sub-queue may send in a round per unit of weight.
+const QUANTUM_BYTES: usize = 16 * 1024;
+
+/// The number of normal priority sub-queues: one per packet type, one for
+/// direct packets and one for messages that are not packets.
+const NUM_SUB_QUEUES: usize = NUM_PACKET_TYPES + 2;
+
+/// The normal priority sub-queue a message is put in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutboundQueue {
+    /// A broadcast packet of the given type.
+    Packet(PacketType),
+    /// A direct packet, whatever its type.
+    Direct,
+    /// A message that is not a packet.
+    Other,
+}
+
+impl OutboundQueue {
+    fn index(self) -> usize {
+        match self {
+            OutboundQueue::Packet(packet_type) => packet_type as usize,
+            OutboundQueue::Direct => NUM_PACKET_TYPES,
+            OutboundQueue::Other => NUM_PACKET_TYPES + 1,
+        }
+    }
+
+    /// The name of the sub-queue as used in the metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            OutboundQueue::Packet(packet_type) => packet_type_name(packet_type),
+            OutboundQueue::Direct => "direct",
+            OutboundQueue::Other => "other",
+        }
+    }
+}
+
+/// The weight and the capacity of the outbound queue for a packet type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketQueueLimit {
+    pub packet_type: PacketType,
+    pub weight:      u32,
+    pub max_bytes:   usize,
+}
+
+impl FromStr for PacketQueueLimit {
+    type Err = anyhow::Error;
+
+    /// Parse a limit of the form `TYPE:WEIGHT/BYTES`, e.g.,
+    /// `transaction:4/33554432`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, limit) = s.split_once(':').context("Expected TYPE:WEIGHT/BYTES.")?;
+        let packet_type = parse_packet_type(name)?;
+        let (weight, max_bytes) = limit.split_once('/').context("Expected TYPE:WEIGHT/BYTES.")?;
+        let weight = weight.trim().parse().context("Invalid weight.")?;
+        if weight == 0 {
+            bail!("The weight must be at least 1.");
+        }
+        let max_bytes = max_bytes.trim().parse().context("Invalid number of bytes.")?;
+        Ok(Self {
+            packet_type,
+            weight,
+            max_bytes,
+        })
+    }
+}
+
+/// A queue of normal priority messages of one type.
+struct SubQueue {
+    messages:  VecDeque<Arc<[u8]>>,
+    /// The total size of the queued messages.
+    bytes:     usize,
+    /// The maximum number of bytes the queue holds, or `None` if it is not
+    /// capped.
+    max_bytes: Option<usize>,
+    /// The number of bytes added to the deficit in every round.
+    quantum:   usize,
+    /// The number of bytes the queue may still send in the current round.
+    deficit:   usize,
+}
+
+impl SubQueue {
+    fn new(weight: u32, max_bytes: Option<usize>) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            bytes: 0,
+            max_bytes,
+            quantum: weight as usize * QUANTUM_BYTES,
+            deficit: 0,
+        }
+    }
+}
+
+/// Message queues of a connection.
+pub struct MessageQueues {
+    pub high: VecDeque<Arc<[u8]>>,
+    /// The sub-queues of the normal priority messages, indexed by
+    /// `OutboundQueue::index`.
+    normal:   Vec<SubQueue>,
+    /// The index of the sub-queue that is currently served.
+    current:  usize,
+}
+
+impl MessageQueues {
+    /// Create queues with the given limits for the packet types. The packet
+    /// types without a limit, and the messages that are not packets, get the
+    /// default weight and capacity. Direct packets get the weight of blocks
+    /// and no capacity.
+    pub fn new(limits: &[PacketQueueLimit]) -> Self {
+        let mut normal = (0..NUM_SUB_QUEUES)
+            .map(|_| SubQueue::new(DEFAULT_WEIGHT, Some(DEFAULT_MAX_BYTES)))
+            .collect::<Vec<_>>();
+        normal[OutboundQueue::Direct.index()] = SubQueue::new(DEFAULT_WEIGHT, None);
+        for limit in limits {
+            let queue = OutboundQueue::Packet(limit.packet_type);
+            normal[queue.index()] = SubQueue::new(limit.weight, Some(limit.max_bytes));
+            if limit.packet_type == PacketType::Block {
+                normal[OutboundQueue::Direct.index()] = SubQueue::new(limit.weight, None);
+            }
+        }
+        Self {
+            high: VecDeque::with_capacity(128),
+            normal,
+            current: 0,
+        }
+    }
+
+    /// Add a message to the queue with the appropriate priority and, for
+    /// normal priority, the given sub-queue. Returns `false` if the message
+    /// was dropped because the sub-queue is full. A message is never dropped
+    /// if its sub-queue is empty, so that messages larger than the cap can
+    /// still be sent, and direct packets are never dropped.
+    pub fn enqueue(
+        &mut self,
+        priority: MessageSendingPriority,
+        queue: OutboundQueue,
+        message: Arc<[u8]>,
+    ) -> bool {
+        if priority == MessageSendingPriority::High {
+            self.high.push_back(message);
+            return true;
+        }
+        let queue = &mut self.normal[queue.index()];
+        if let Some(max_bytes) = queue.max_bytes {
+            if !queue.messages.is_empty() && queue.bytes + message.len() > max_bytes {
+                return false;
+            }
+        }
+        queue.bytes += message.len();
+        queue.messages.push_back(message);
+        true
+    }
+
+    /// Dequeue a message, taking from the high priority queue first and
+    /// serving the normal priority sub-queues by deficit round-robin. Queues
+    /// of a priority for which `may_send` does not hold are skipped.
+    pub fn dequeue(
+        &mut self,
+        mut may_send: impl FnMut(MessageSendingPriority) -> bool,
+    ) -> Option<(MessageSendingPriority, Arc<[u8]>)> {
+        if !self.high.is_empty() && may_send(MessageSendingPriority::High) {
+            return self.high.pop_front().map(|message| (MessageSendingPriority::High, message));
+        }
+        if self.normal.iter().all(|queue| queue.messages.is_empty())
+            || !may_send(MessageSendingPriority::Normal)
+        {
+            return None;
+        }
+        loop {
+            let queue = &mut self.normal[self.current];
+            if let Some(len) = queue.messages.front().map(|message| message.len()) {
+                if len <= queue.deficit {
+                    queue.deficit -= len;
+                    queue.bytes -= len;
+                    let message = queue.messages.pop_front();
+                    // an emptied queue does not keep its deficit for later rounds
+                    if queue.messages.is_empty() {
+                        queue.deficit = 0;
+                    }
+                    return message.map(|message| (MessageSendingPriority::Normal, message));
+                }
+            }
+            self.current = (self.current + 1) % self.normal.len();
+            let next = &mut self.normal[self.current];
+            if !next.messages.is_empty() {
+                next.deficit += next.quantum;
+            }
+        }
+    }
+
+    /// The number of queued messages and their total size in bytes.
+    pub fn pending(&self) -> (usize, usize) {
+        let messages =
+            self.high.len() + self.normal.iter().map(|queue| queue.messages.len()).sum::<usize>();
+        let bytes = self.high.iter().map(|message| message.len()).sum::<usize>()
+            + self.normal.iter().map(|queue| queue.bytes).sum::<usize>();
+        (messages, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outbound_queues() -> anyhow::Result<()> {
+        let limit: PacketQueueLimit = "block:3/40000".parse()?;
+        assert_eq!(limit, PacketQueueLimit {
+            packet_type: PacketType::Block,
+            weight:      3,
+            max_bytes:   40000,
+        });
+        assert!("block:0/40000".parse::<PacketQueueLimit>().is_err());
+        assert!("unknown:1/40000".parse::<PacketQueueLimit>().is_err());
+
+        let mut queues = MessageQueues::new(&[limit]);
+        let block = Arc::<[u8]>::from(vec![0u8; 16 * 1024]);
+        let transaction = Arc::<[u8]>::from(vec![1u8; 8 * 1024]);
+        // the third block does not fit in the capacity of the block queue
+        let mut enqueue_block = || {
+            queues.enqueue(
+                MessageSendingPriority::Normal,
+                OutboundQueue::Packet(PacketType::Block),
+                block.clone(),
+            )
+        };
+        assert!(enqueue_block());
+        assert!(enqueue_block());
+        assert!(!enqueue_block());
+        for _ in 0..8 {
+            assert!(queues.enqueue(
+                MessageSendingPriority::Normal,
+                OutboundQueue::Packet(PacketType::Transaction),
+                transaction.clone()
+            ));
+        }
+        assert!(queues.enqueue(
+            MessageSendingPriority::High,
+            OutboundQueue::Other,
+            Arc::from(&[2u8][..])
+        ));
+        assert_eq!(queues.pending(), (11, 2 * 16 * 1024 + 8 * 8 * 1024 + 1));
+
+        // high priority messages go first, and the normal priority sub-queues
+        // get bytes in proportion to their weights
+        let sent = std::iter::from_fn(|| queues.dequeue(|_| true))
+            .map(|(priority, message)| (priority, message[0]))
+            .collect::<Vec<_>>();
+        assert_eq!(sent[0], (MessageSendingPriority::High, 2));
+        let order = sent[1..].iter().map(|&(_, tag)| tag).collect::<Vec<_>>();
+        assert_eq!(order, vec![1, 1, 0, 0, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(queues.pending(), (0, 0));
+
+        // direct packets are sent in the order they were queued
+        for tag in 4..8u8 {
+            assert!(queues.enqueue(
+                MessageSendingPriority::Normal,
+                OutboundQueue::Direct,
+                Arc::from(vec![tag; 8 * 1024])
+            ));
+        }
+        let sent = std::iter::from_fn(|| queues.dequeue(|_| true))
+            .map(|(_, message)| message[0])
+            .collect::<Vec<_>>();
+        assert_eq!(sent, vec![4, 5, 6, 7]);
+
+        // nothing is sent if the priority may not send
+        assert!(queues.enqueue(
+            MessageSendingPriority::Normal,
+            OutboundQueue::Other,
+            Arc::from(&[3u8][..])
+        ));
+        assert!(queues.dequeue(|priority| priority == MessageSendingPriority::High).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_catch_up_burst_is_not_dropped() -> anyhow::Result<()> {
+        let mut queues = MessageQueues::new(&["block:3/40000".parse()?]);
+        // a catch-up burst of five times the capacity of the block queue
+        let burst = (0..25u8).map(|tag| Arc::from(vec![tag; 8 * 1024])).collect::<Vec<_>>();
+        for message in &burst {
+            assert!(queues.enqueue(
+                MessageSendingPriority::Normal,
+                OutboundQueue::Direct,
+                Arc::clone(message)
+            ));
+        }
+        // broadcast blocks are still capped meanwhile
+        let block = Arc::<[u8]>::from(vec![100u8; 16 * 1024]);
+        let mut enqueue_block = || {
+            queues.enqueue(
+                MessageSendingPriority::Normal,
+                OutboundQueue::Packet(PacketType::Block),
+                block.clone(),
+            )
+        };
+        assert!(enqueue_block());
+        assert!(enqueue_block());
+        assert!(!enqueue_block());
+        assert_eq!(queues.pending(), (27, 25 * 8 * 1024 + 2 * 16 * 1024));
+
+        // the whole burst is sent, in order
+        let sent = std::iter::from_fn(|| queues.dequeue(|_| true))
+            .map(|(_, message)| message[0])
+            .filter(|&tag| tag != 100)
+            .collect::<Vec<_>>();
+        assert_eq!(sent, (0..25).collect::<Vec<_>>());
+        assert_eq!(queues.pending(), (0, 0));
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! Byte-rate limits on the connections' read and write paths.
+//!
+//! Limits are enforced with token buckets, both per connection and globally
+//! for all connections of the node. Outgoing traffic has separate budgets for
+//! high and normal priority messages, so that e.g. a peer catching up cannot
+//! starve pings and handshake traffic.
+//!
+//! A connection that is out of budget simply stops reading from, or writing
+//! to, its socket until the budget is refilled; the poll loop retries it on
+//! every iteration, so the event loop is never blocked.
+
+use super::MessageSendingPriority;
+use crate::lock_or_die;
+use std::{
+    cmp,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// Byte-rate limits, in bytes per second. `None` means no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimitConfig {
+    /// The limit on outgoing high priority messages.
+    pub upload_high: Option<u64>,
+    /// The limit on outgoing normal priority messages.
+    pub upload_low:  Option<u64>,
+    /// The limit on incoming data.
+    pub download:    Option<u64>,
+}
+
+/// A token bucket limiting the rate at which bytes are transferred. The bucket
+/// holds at most one second's worth of tokens. It is allowed to go into debt,
+/// so that a message larger than the bucket can still be transferred as long
+/// as there is some budget, after which nothing else is transferred until the
+/// debt has been paid off.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// The refill rate in bytes per second.
+    rate:        u64,
+    /// The number of available tokens; negative if the bucket is in debt.
+    tokens:      i64,
+    /// The last time tokens were added to the bucket.
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket with the given rate in bytes per second.
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as i64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let new_tokens = elapsed.as_micros().saturating_mul(u128::from(self.rate)) / 1_000_000;
+        // only move the refill time if tokens were added, so that frequent checks
+        // don't lose the fractional tokens
+        if new_tokens > 0 {
+            let new_tokens = cmp::min(new_tokens, i64::MAX as u128) as i64;
+            self.tokens = cmp::min(self.tokens.saturating_add(new_tokens), self.rate as i64);
+            self.last_refill = now;
+        }
+    }
+
+    /// Check whether there is any budget left.
+    pub fn has_budget(&mut self) -> bool {
+        self.refill(Instant::now());
+        self.tokens > 0
+    }
+
+    /// Take the given number of bytes out of the budget.
+    pub fn consume(&mut self, bytes: usize) {
+        self.tokens = self.tokens.saturating_sub(bytes as i64);
+    }
+}
+
+fn has_budget(bucket: &mut Option<TokenBucket>) -> bool {
+    bucket.as_mut().map_or(true, TokenBucket::has_budget)
+}
+
+fn has_shared_budget(bucket: &Option<Mutex<TokenBucket>>) -> bool {
+    bucket.as_ref().map_or(true, |bucket| lock_or_die!(bucket).has_budget())
+}
+
+fn consume(bucket: &mut Option<TokenBucket>, bytes: usize) {
+    if let Some(bucket) = bucket {
+        bucket.consume(bytes);
+    }
+}
+
+fn consume_shared(bucket: &Option<Mutex<TokenBucket>>, bytes: usize) {
+    if let Some(bucket) = bucket {
+        lock_or_die!(bucket).consume(bytes);
+    }
+}
+
+/// The rate limits shared by all the connections of the node.
+#[derive(Debug, Default)]
+pub struct GlobalRateLimits {
+    upload_high: Option<Mutex<TokenBucket>>,
+    upload_low:  Option<Mutex<TokenBucket>>,
+    download:    Option<Mutex<TokenBucket>>,
+}
+
+impl GlobalRateLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            upload_high: config.upload_high.map(|rate| Mutex::new(TokenBucket::new(rate))),
+            upload_low:  config.upload_low.map(|rate| Mutex::new(TokenBucket::new(rate))),
+            download:    config.download.map(|rate| Mutex::new(TokenBucket::new(rate))),
+        }
+    }
+}
+
+/// The rate limits of a single connection, together with the global ones.
+#[derive(Debug)]
+pub struct ConnectionRateLimits {
+    global:      Arc<GlobalRateLimits>,
+    upload_high: Option<TokenBucket>,
+    upload_low:  Option<TokenBucket>,
+    download:    Option<TokenBucket>,
+}
+
+impl ConnectionRateLimits {
+    pub fn new(config: &RateLimitConfig, global: Arc<GlobalRateLimits>) -> Self {
+        Self {
+            global,
+            upload_high: config.upload_high.map(TokenBucket::new),
+            upload_low: config.upload_low.map(TokenBucket::new),
+            download: config.download.map(TokenBucket::new),
+        }
+    }
+
+    /// Check whether a message with the given priority may be sent now.
+    pub fn may_send(&mut self, priority: MessageSendingPriority) -> bool {
+        match priority {
+            MessageSendingPriority::High => {
+                has_budget(&mut self.upload_high) && has_shared_budget(&self.global.upload_high)
+            }
+            MessageSendingPriority::Normal => {
+                has_budget(&mut self.upload_low) && has_shared_budget(&self.global.upload_low)
+            }
+        }
+    }
+
+    /// Record that a message of the given size and priority was sent.
+    pub fn on_sent(&mut self, priority: MessageSendingPriority, bytes: usize) {
+        match priority {
+            MessageSendingPriority::High => {
+                consume(&mut self.upload_high, bytes);
+                consume_shared(&self.global.upload_high, bytes);
+            }
+            MessageSendingPriority::Normal => {
+                consume(&mut self.upload_low, bytes);
+                consume_shared(&self.global.upload_low, bytes);
+            }
+        }
+    }
+
+    /// Check whether data may be read from the socket now.
+    pub fn may_receive(&mut self) -> bool {
+        has_budget(&mut self.download) && has_shared_budget(&self.global.download)
+    }
+
+    /// Record that the given number of bytes were read from the socket.
+    pub fn on_received(&mut self, bytes: usize) {
+        consume(&mut self.download, bytes);
+        consume_shared(&self.global.download, bytes);
+    }
+}
+
+/// Keeps track of the time a connection spends being throttled.
+#[derive(Debug, Default)]
+pub struct ThrottleTimer {
+    /// When the throttling time was last accounted for, if the connection is
+    /// currently throttled.
+    since: Option<Instant>,
+}
+
+impl ThrottleTimer {
+    /// Whether the connection is currently throttled.
+    pub fn is_throttled(&self) -> bool { self.since.is_some() }
+
+    /// Update the throttling state, adding the time spent throttled since the
+    /// last update to `total_ms`.
+    pub fn update(&mut self, throttled: bool, total_ms: &AtomicU64) {
+        let now = Instant::now();
+        if let Some(since) = self.since {
+            let elapsed = now.saturating_duration_since(since).as_millis() as u64;
+            total_ms.fetch_add(elapsed, Ordering::Relaxed);
+        }
+        self.since = if throttled {
+            Some(now)
+        } else {
+            None
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(1000);
+        assert!(bucket.has_budget());
+        // a transfer larger than the bucket puts it in debt
+        bucket.consume(1500);
+        assert!(!bucket.has_budget());
+        // refilling over time pays off the debt
+        bucket.last_refill -= Duration::from_millis(600);
+        assert!(bucket.has_budget());
+        // but the bucket never holds more than one second worth of tokens
+        bucket.last_refill -= Duration::from_secs(10);
+        bucket.refill(Instant::now());
+        assert_eq!(bucket.tokens, 1000);
+    }
+
+    #[test]
+    fn test_connection_rate_limits() {
+        let global = Arc::new(GlobalRateLimits::new(&RateLimitConfig {
+            upload_high: None,
+            upload_low:  Some(100),
+            download:    None,
+        }));
+        let mut limits = ConnectionRateLimits::new(
+            &RateLimitConfig {
+                upload_high: Some(100),
+                upload_low:  None,
+                download:    None,
+            },
+            global,
+        );
+        limits.on_sent(MessageSendingPriority::High, 200);
+        assert!(!limits.may_send(MessageSendingPriority::High));
+        assert!(limits.may_send(MessageSendingPriority::Normal));
+        limits.on_sent(MessageSendingPriority::Normal, 200);
+        assert!(!limits.may_send(MessageSendingPriority::Normal));
+        limits.on_received(1_000_000);
+        assert!(limits.may_receive());
+    }
+}
@@ -2,12 +2,17 @@ use itertools::Itertools;
 
 use crate::{
     common::PeerType,
+    connection::{
+        DeduplicationQueue, DeduplicationQueueSha256, DeduplicationQueueXxHash64,
+        DeduplicationSetXxHash64,
+    },
     consensus_ffi::helpers::PacketType,
     network::NetworkId,
     p2p::connectivity::send_broadcast_message,
+    read_or_die,
     test_utils::{
-        await_handshakes, connect, dummy_regenesis_blocks, make_node_and_sync, next_available_port,
-        stop_node_delete_dirs,
+        await_condition, await_handshakes, connect, dummy_regenesis_blocks, make_node_and_sync,
+        make_unbound_node_and_sync, next_available_port, stop_node_delete_dirs,
     },
 };
 
@@ -71,3 +76,63 @@ fn basic_connectivity() {
         stop_node_delete_dirs(dp, node);
     }
 }
+
+#[test]
+fn handshake_with_unbound_peer() {
+    let (node, dp) = make_node_and_sync(
+        next_available_port(),
+        vec![NID],
+        PeerType::Node,
+        dummy_regenesis_blocks(),
+    )
+    .unwrap();
+    // a peer whose node id is not derived from its key, which only supports wire
+    // protocol version 1
+    let (peer, peer_dp) = make_unbound_node_and_sync(
+        next_available_port(),
+        vec![NID],
+        PeerType::Node,
+        dummy_regenesis_blocks(),
+    )
+    .unwrap();
+    assert_eq!(peer.wire_versions(), &[1]);
+    connect(&peer, &node);
+
+    for (ours, theirs) in [(&node, &peer), (&peer, &node)] {
+        let (remote_id, wire_version) = await_condition(|| {
+            read_or_die!(ours.connections())
+                .values()
+                .find(|conn| conn.is_post_handshake())
+                .map(|conn| (conn.remote_peer.self_id, conn.wire_version))
+        })
+        .expect("The handshake with the unbound peer did not complete.");
+        assert_eq!(remote_id, Some(theirs.id()));
+        assert_eq!(wire_version, 1);
+    }
+
+    stop_node_delete_dirs(dp, node);
+    stop_node_delete_dirs(peer_dp, peer);
+}
+
+fn check_deduplication(queue: &mut dyn DeduplicationQueue) -> anyhow::Result<()> {
+    // the capacity of the queue is 2
+    assert!(!queue.check_and_insert(b"a")?);
+    assert!(queue.check_and_insert(b"a")?);
+    assert!(!queue.check_and_insert(b"b")?);
+    // an invalidated message is no longer a duplicate
+    queue.invalidate_if_exists(b"a");
+    assert!(!queue.check_and_insert(b"a")?);
+    assert!(queue.check_and_insert(b"a")?);
+    // the oldest message is evicted
+    assert!(!queue.check_and_insert(b"c")?);
+    assert!(!queue.check_and_insert(b"b")?);
+    assert!(queue.check_and_insert(b"c")?);
+    Ok(())
+}
+
+#[test]
+fn deduplication_queues() -> anyhow::Result<()> {
+    check_deduplication(&mut DeduplicationQueueXxHash64::new(2))?;
+    check_deduplication(&mut DeduplicationQueueSha256::new(2))?;
+    check_deduplication(&mut DeduplicationSetXxHash64::new(2))
+}
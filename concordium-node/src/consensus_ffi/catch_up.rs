@@ -44,40 +44,163 @@ pub enum PeerStatus {
     UpToDate   = 0,
 }
 
+impl PeerStatus {
+    /// The label of the status in metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            PeerStatus::CatchingUp => "catching-up",
+            PeerStatus::Pending => "pending",
+            PeerStatus::UpToDate => "up-to-date",
+        }
+    }
+}
+
+/// A snapshot of the catch-up state of a peer, for introspection.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PeerCatchUpInfo {
+    pub status:           PeerStatus,
+    /// The timestamp at which we started catching up with the peer, if we
+    /// are catching up with it.
+    pub catch_up_started: Option<u64>,
+    /// The number of consecutive catch-up timeouts of the peer.
+    pub timeouts:         u32,
+    /// The position of the peer in the pending queue (starting from 0), if it
+    /// is pending.
+    pub queue_position:   Option<usize>,
+}
+
+/// The number of consecutive catch-up timeouts after which a peer is dropped.
+/// Until then a peer that times out is only demoted: it is put at the back of
+/// the pending queue and is not selected again until its cooldown is over.
+pub const MAX_CATCH_UP_TIMEOUTS: u32 = 3;
+
 #[derive(Default)]
 pub struct PeerList {
     /// The state of each peer.
-    pub peer_states:    HashMap<RemotePeerId, PeerStatus, BuildNoHashHasher<PeerId>>,
-    /// The timestamp at which we last tried to catch up with a peer.
-    pub catch_up_stamp: u64,
-    /// The peer that we are currently catching up with (if any).
-    pub catch_up_peer:  Option<RemotePeerId>,
+    pub peer_states:       HashMap<RemotePeerId, PeerStatus, BuildNoHashHasher<PeerId>>,
+    /// The peers that we are currently catching up with, together with the
+    /// timestamps at which we started catching up with them.
+    pub catch_up_peers:    HashMap<RemotePeerId, u64, BuildNoHashHasher<PeerId>>,
+    /// The number of consecutive catch-up timeouts of each peer that timed
+    /// out.
+    pub catch_up_timeouts: HashMap<RemotePeerId, u32, BuildNoHashHasher<PeerId>>,
+    /// The timestamps until which demoted peers are not selected for catching
+    /// up.
+    pub demoted_until:     HashMap<RemotePeerId, u64, BuildNoHashHasher<PeerId>>,
     /// Queue of pending peers.
-    pub pending_queue:  VecDeque<RemotePeerId>,
+    pub pending_queue:     VecDeque<RemotePeerId>,
 }
 
 impl PeerList {
-    /// Pull the next pending peer from the queue and mark it as catching-up.
-    /// This does not alter catch_up_stamp, but it does set catch_up_peer.
-    /// pending_queue should only contain peers that are actually pending,
-    /// (according to peer_states) but this is checked when they are dequeued
-    /// and if a non-pending peer is encountered it is simply removed from
+    /// Whether the peer was demoted and its cooldown is not over at `now`.
+    fn is_cooling_down(&self, peer: &RemotePeerId, now: u64) -> bool {
+        self.demoted_until.get(peer).map_or(false, |&until| now < until)
+    }
+
+    /// Pull the first pending peer that is not cooling down from the queue,
+    /// mark it as catching-up and record that we started catching up with it
+    /// at the given timestamp. Peers that are cooling down keep their place in
     /// the queue.
-    pub fn next_pending(&mut self) -> Option<RemotePeerId> {
-        let mut next = self.pending_queue.pop_front();
-        while let Some(peer) = next {
-            if let Some(state) = self.peer_states.get_mut(&peer) {
-                if let PeerStatus::Pending = *state {
-                    // The peer is actually pending.
-                    *state = PeerStatus::CatchingUp;
-                    break;
+    /// pending_queue should only contain peers that are actually pending,
+    /// (according to peer_states) but this is checked here and non-pending
+    /// peers are simply removed from the queue.
+    pub fn next_pending(&mut self, now: u64) -> Option<RemotePeerId> {
+        let peer_states = &self.peer_states;
+        self.pending_queue.retain(|peer| peer_states.get(peer) == Some(&PeerStatus::Pending));
+        let position =
+            self.pending_queue.iter().position(|peer| !self.is_cooling_down(peer, now))?;
+        let peer = self.pending_queue.remove(position)?;
+        self.peer_states.insert(peer, PeerStatus::CatchingUp);
+        self.demoted_until.remove(&peer);
+        self.catch_up_peers.insert(peer, now);
+        Some(peer)
+    }
+
+    /// Whether there is anything to do for the catch-up at `now`: a catch-up
+    /// that took longer than `timeout` milliseconds, or a pending peer that
+    /// can be selected while we catch up with fewer than `parallelism` peers.
+    /// This only needs shared access, so that the write lock on the list can
+    /// be avoided when there is nothing to do.
+    pub fn needs_update(&self, now: u64, timeout: u64, parallelism: usize) -> bool {
+        self.catch_up_peers.values().any(|&started| now > started + timeout)
+            || (self.catch_up_peers.len() < parallelism
+                && self.pending_queue.iter().any(|peer| {
+                    self.peer_states.get(peer) == Some(&PeerStatus::Pending)
+                        && !self.is_cooling_down(peer, now)
+                }))
+    }
+
+    /// Record that the peer responded to our catch-up status. Returns the
+    /// time (in milliseconds) it took, if we were catching up with the peer.
+    pub fn finish_catch_up(&mut self, peer: RemotePeerId, now: u64) -> Option<u64> {
+        self.catch_up_timeouts.remove(&peer);
+        self.catch_up_peers.remove(&peer).map(|started| now.saturating_sub(started))
+    }
+
+    /// The peers that we started catching up with more than `timeout`
+    /// milliseconds before `now`.
+    pub fn timed_out_peers(&self, now: u64, timeout: u64) -> Vec<RemotePeerId> {
+        self.catch_up_peers
+            .iter()
+            .filter(|(_, &started)| now > started + timeout)
+            .map(|(&peer, _)| peer)
+            .collect()
+    }
+
+    /// Stop catching up with a peer that timed out and put it at the back of
+    /// the pending queue, where it is skipped until the timestamp `until`.
+    /// Returns the number of consecutive timeouts of the peer.
+    pub fn demote(&mut self, peer: RemotePeerId, until: u64) -> u32 {
+        self.catch_up_peers.remove(&peer);
+        self.demoted_until.insert(peer, until);
+        if let Some(state) = self.peer_states.get_mut(&peer) {
+            if *state != PeerStatus::Pending {
+                *state = PeerStatus::Pending;
+                self.pending_queue.push_back(peer);
+            }
+        }
+        let timeouts = self.catch_up_timeouts.entry(peer).or_insert(0);
+        *timeouts += 1;
+        *timeouts
+    }
+
+    /// Forget a peer, e.g., because its connection was dropped. It is removed
+    /// from the pending queue when it is dequeued.
+    pub fn remove_peer(&mut self, peer: RemotePeerId) {
+        self.peer_states.remove(&peer);
+        self.catch_up_peers.remove(&peer);
+        self.catch_up_timeouts.remove(&peer);
+        self.demoted_until.remove(&peer);
+    }
+
+    /// The catch-up state of each peer. Entries of the pending queue for peers
+    /// that are no longer pending, or that are duplicates, do not count
+    /// towards the queue positions.
+    pub fn catch_up_info(
+        &self,
+    ) -> HashMap<RemotePeerId, PeerCatchUpInfo, BuildNoHashHasher<PeerId>> {
+        let mut info = self
+            .peer_states
+            .iter()
+            .map(|(&peer, &status)| {
+                (peer, PeerCatchUpInfo {
+                    status,
+                    catch_up_started: self.catch_up_peers.get(&peer).copied(),
+                    timeouts: self.catch_up_timeouts.get(&peer).copied().unwrap_or(0),
+                    queue_position: None,
+                })
+            })
+            .collect::<HashMap<_, _, BuildNoHashHasher<PeerId>>>();
+        let mut position = 0;
+        for peer in self.pending_queue.iter() {
+            if let Some(peer_info) = info.get_mut(peer) {
+                if peer_info.status == PeerStatus::Pending && peer_info.queue_position.is_none() {
+                    peer_info.queue_position = Some(position);
+                    position += 1;
                 }
             }
-            // The peer is not actually pending.
-            next = self.pending_queue.pop_front();
         }
-        self.catch_up_peer = next;
-        next
+        info
     }
 
     /// Mark all of the up-to-date peers as pending.
@@ -93,7 +216,90 @@ impl PeerList {
     /// Clear all pending peers.
     pub fn clear(&mut self) {
         self.peer_states.clear();
-        self.catch_up_peer = None;
+        self.catch_up_peers.clear();
+        self.catch_up_timeouts.clear();
+        self.demoted_until.clear();
         self.pending_queue.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parallel_catch_up() {
+        let peer = |id: usize| RemotePeerId::from(id);
+        let mut peers = PeerList::default();
+        for id in 0..3 {
+            peers.peer_states.insert(peer(id), PeerStatus::Pending);
+            peers.pending_queue.push_back(peer(id));
+        }
+
+        // catch up with two peers at once
+        assert_eq!(peers.next_pending(1000), Some(peer(0)));
+        assert_eq!(peers.next_pending(2000), Some(peer(1)));
+        assert_eq!(peers.catch_up_peers.len(), 2);
+        assert_eq!(peers.peer_states[&peer(1)], PeerStatus::CatchingUp);
+
+        // the first peer responds, the second one times out and is demoted
+        // behind the remaining pending peer
+        assert_eq!(peers.finish_catch_up(peer(0), 1500), Some(500));
+        assert_eq!(peers.timed_out_peers(2500, 1000), Vec::new());
+        assert_eq!(peers.timed_out_peers(3500, 1000), vec![peer(1)]);
+        assert_eq!(peers.demote(peer(1), 4500), 1);
+        assert!(peers.catch_up_peers.is_empty());
+        assert!(peers.needs_update(3500, 1000, 2));
+        assert_eq!(peers.next_pending(3500), Some(peer(2)));
+
+        // the demoted peer is not selected again until its cooldown is over
+        assert!(!peers.needs_update(3500, 1000, 2));
+        assert_eq!(peers.next_pending(3500), None);
+        assert_eq!(peers.peer_states[&peer(1)], PeerStatus::Pending);
+        assert!(peers.needs_update(4500, 1000, 2));
+        assert_eq!(peers.next_pending(4500), Some(peer(1)));
+        assert_eq!(peers.demote(peer(1), 5000), 2);
+
+        // a response resets the timeouts, and removed peers are skipped
+        assert_eq!(peers.next_pending(5000), Some(peer(1)));
+        assert_eq!(peers.finish_catch_up(peer(1), 5100), Some(100));
+        assert!(peers.catch_up_timeouts.is_empty());
+        assert_eq!(peers.finish_catch_up(peer(1), 5200), None);
+        peers.demote(peer(2), 6000);
+        peers.remove_peer(peer(2));
+        assert_eq!(peers.next_pending(7000), None);
+        assert!(peers.catch_up_timeouts.is_empty());
+        assert!(peers.demoted_until.is_empty());
+    }
+
+    #[test]
+    fn test_catch_up_info() {
+        let peer = |id: usize| RemotePeerId::from(id);
+        let mut peers = PeerList::default();
+        for id in 0..4 {
+            peers.peer_states.insert(peer(id), PeerStatus::Pending);
+            peers.pending_queue.push_back(peer(id));
+        }
+        assert_eq!(peers.next_pending(1000), Some(peer(0)));
+        assert_eq!(peers.next_pending(1000), Some(peer(1)));
+        peers.demote(peer(1), 2000);
+        // a stale entry of a peer that is no longer pending
+        peers.peer_states.insert(peer(2), PeerStatus::UpToDate);
+
+        let info = peers.catch_up_info();
+        assert_eq!(info[&peer(0)], PeerCatchUpInfo {
+            status:           PeerStatus::CatchingUp,
+            catch_up_started: Some(1000),
+            timeouts:         0,
+            queue_position:   None,
+        });
+        assert_eq!(info[&peer(1)], PeerCatchUpInfo {
+            status:           PeerStatus::Pending,
+            catch_up_started: None,
+            timeouts:         1,
+            queue_position:   Some(1),
+        });
+        assert_eq!(info[&peer(2)].queue_position, None);
+        assert_eq!(info[&peer(3)].queue_position, Some(0));
+    }
+}
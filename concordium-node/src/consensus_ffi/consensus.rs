@@ -1,18 +1,25 @@
-use crate::consensus_ffi::{
-    ffi::{
-        consensus_runner, get_consensus_ptr, startBaker, stopBaker, stopConsensus,
-        StartConsensusConfig,
+use crate::{
+    common::p2p_peer::RemotePeerId,
+    consensus_ffi::{
+        ffi::{
+            consensus_runner, get_consensus_ptr, startBaker, stopBaker, stopConsensus,
+            StartConsensusConfig,
+        },
+        helpers::{PacketType, QueueMsg, QueueReceiver, QueueSyncSender, RelayOrStopSenderHelper},
+        messaging::ConsensusMessage,
     },
-    helpers::{QueueReceiver, QueueSyncSender, RelayOrStopSenderHelper},
-    messaging::ConsensusMessage,
+    lock_or_die,
 };
+use anyhow::anyhow;
 use concordium_base::hashes::BlockHash;
+use crossbeam_channel::TrySendError;
 use std::{
+    collections::HashMap,
     convert::TryFrom,
     path::Path,
     sync::{
         atomic::{AtomicBool, AtomicPtr, Ordering},
-        Arc, Mutex, RwLock,
+        Arc, Mutex, OnceLock, RwLock,
     },
 };
 
@@ -44,24 +51,111 @@ pub const CONSENSUS_QUEUE_DEPTH_OUT_LO: usize = 16 * 1024;
 pub const CONSENSUS_QUEUE_DEPTH_IN_HI: usize = 16 * 1024;
 pub const CONSENSUS_QUEUE_DEPTH_IN_LO: usize = 32 * 1024;
 
+/// The maximum number of messages in each of the consensus queues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsensusQueueDepths {
+    pub inbound_high:  usize,
+    pub inbound_low:   usize,
+    pub outbound_high: usize,
+    pub outbound_low:  usize,
+}
+
+impl Default for ConsensusQueueDepths {
+    fn default() -> Self {
+        Self {
+            inbound_high:  CONSENSUS_QUEUE_DEPTH_IN_HI,
+            inbound_low:   CONSENSUS_QUEUE_DEPTH_IN_LO,
+            outbound_high: CONSENSUS_QUEUE_DEPTH_OUT_HI,
+            outbound_low:  CONSENSUS_QUEUE_DEPTH_OUT_LO,
+        }
+    }
+}
+
+static QUEUE_DEPTHS: OnceLock<ConsensusQueueDepths> = OnceLock::new();
+
+/// Set the depths of the consensus queues. This has to happen before the
+/// queues are first used, as they are created with the depths set then.
+pub fn set_queue_depths(depths: ConsensusQueueDepths) -> anyhow::Result<()> {
+    QUEUE_DEPTHS.set(depths).map_err(|_| anyhow!("The consensus queues are already created."))
+}
+
+/// The depths of the consensus queues, which are the defaults unless they were
+/// set before the queues were created.
+pub fn queue_depths() -> ConsensusQueueDepths { *QUEUE_DEPTHS.get_or_init(Default::default) }
+
+/// The number of messages each peer has in an inbound consensus queue.
+#[derive(Default)]
+struct PeerShares {
+    held: Mutex<HashMap<RemotePeerId, usize>>,
+}
+
+impl PeerShares {
+    /// Reserve a place for a message from the given peer in a queue with the
+    /// given depth and number of queued messages. Once the queue is half full,
+    /// a peer that already holds its fair share of the queue, i.e., the depth
+    /// divided by the number of peers, cannot add more messages, so that a
+    /// single peer cannot fill the queue.
+    fn try_reserve(&self, peer: RemotePeerId, queued: usize, depth: usize, peers: usize) -> bool {
+        let mut held = lock_or_die!(self.held);
+        let count = held.entry(peer).or_default();
+        if queued >= depth / 2 && *count >= depth / peers.max(1) {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Release the place of a message from the given peer that was taken off
+    /// the queue.
+    fn release(&self, peer: RemotePeerId) {
+        let mut held = lock_or_die!(self.held);
+        if let Some(count) = held.get_mut(&peer) {
+            *count -= 1;
+            if *count == 0 {
+                held.remove(&peer);
+            }
+        }
+    }
+}
+
 pub struct ConsensusInboundQueues {
     pub receiver_high_priority: Mutex<QueueReceiver<ConsensusMessage>>,
     pub sender_high_priority:   QueueSyncSender<ConsensusMessage>,
     pub receiver_low_priority:  Mutex<QueueReceiver<ConsensusMessage>>,
     pub sender_low_priority:    QueueSyncSender<ConsensusMessage>,
+    depth_high:                 usize,
+    depth_low:                  usize,
+    shares_high:                PeerShares,
+    shares_low:                 PeerShares,
 }
 
-impl Default for ConsensusInboundQueues {
-    fn default() -> Self {
-        let (sender_high_priority, receiver_high_priority) =
-            crossbeam_channel::bounded(CONSENSUS_QUEUE_DEPTH_IN_HI);
-        let (sender_low_priority, receiver_low_priority) =
-            crossbeam_channel::bounded(CONSENSUS_QUEUE_DEPTH_IN_LO);
+impl ConsensusInboundQueues {
+    fn new(depth_high: usize, depth_low: usize) -> Self {
+        let (sender_high_priority, receiver_high_priority) = crossbeam_channel::bounded(depth_high);
+        let (sender_low_priority, receiver_low_priority) = crossbeam_channel::bounded(depth_low);
         Self {
             receiver_high_priority: Mutex::new(receiver_high_priority),
             sender_high_priority,
             receiver_low_priority: Mutex::new(receiver_low_priority),
             sender_low_priority,
+            depth_high,
+            depth_low,
+            shares_high: Default::default(),
+            shares_low: Default::default(),
+        }
+    }
+
+    /// The sender, the depth and the peer shares of the queue for messages of
+    /// the given type. Transactions go to the low priority queue and all the
+    /// other messages to the high priority one.
+    fn queue_for(
+        &self,
+        packet_type: PacketType,
+    ) -> (&QueueSyncSender<ConsensusMessage>, usize, &PeerShares) {
+        if packet_type == PacketType::Transaction {
+            (&self.sender_low_priority, self.depth_low, &self.shares_low)
+        } else {
+            (&self.sender_high_priority, self.depth_high, &self.shares_high)
         }
     }
 }
@@ -73,12 +167,10 @@ pub struct ConsensusOutboundQueues {
     pub sender_low_priority:    QueueSyncSender<ConsensusMessage>,
 }
 
-impl Default for ConsensusOutboundQueues {
-    fn default() -> Self {
-        let (sender_high_priority, receiver_high_priority) =
-            crossbeam_channel::bounded(CONSENSUS_QUEUE_DEPTH_OUT_HI);
-        let (sender_low_priority, receiver_low_priority) =
-            crossbeam_channel::bounded(CONSENSUS_QUEUE_DEPTH_OUT_LO);
+impl ConsensusOutboundQueues {
+    fn new(depth_high: usize, depth_low: usize) -> Self {
+        let (sender_high_priority, receiver_high_priority) = crossbeam_channel::bounded(depth_high);
+        let (sender_low_priority, receiver_low_priority) = crossbeam_channel::bounded(depth_low);
         Self {
             receiver_high_priority: Mutex::new(receiver_high_priority),
             sender_high_priority,
@@ -88,19 +180,40 @@ impl Default for ConsensusOutboundQueues {
     }
 }
 
-#[derive(Default)]
 pub struct ConsensusQueues {
     pub inbound:  ConsensusInboundQueues,
     pub outbound: ConsensusOutboundQueues,
 }
 
 impl ConsensusQueues {
-    pub fn send_in_high_priority_message(&self, message: ConsensusMessage) -> anyhow::Result<()> {
-        self.inbound.sender_high_priority.send_msg(message).map_err(|e| e.into())
+    pub fn new(depths: ConsensusQueueDepths) -> Self {
+        Self {
+            inbound:  ConsensusInboundQueues::new(depths.inbound_high, depths.inbound_low),
+            outbound: ConsensusOutboundQueues::new(depths.outbound_high, depths.outbound_low),
+        }
+    }
+
+    /// Queue a message received from a peer for consensus, given the number of
+    /// peers the node has. A message that does not fit in the queue, or that
+    /// would take more than the peer's fair share of it, fails with
+    /// `TrySendError::Full`.
+    pub fn send_in_message(&self, message: ConsensusMessage, peers: usize) -> anyhow::Result<()> {
+        let (sender, depth, shares) = self.inbound.queue_for(message.variant);
+        let source = message.source_peer();
+        if !shares.try_reserve(source, sender.len(), depth, peers) {
+            return Err(TrySendError::Full(QueueMsg::Relay(message)).into());
+        }
+        sender.send_msg(message).map_err(|e| {
+            shares.release(source);
+            e.into()
+        })
     }
 
-    pub fn send_in_low_priority_message(&self, message: ConsensusMessage) -> anyhow::Result<()> {
-        self.inbound.sender_low_priority.send_msg(message).map_err(|e| e.into())
+    /// Record that a message received from a peer was taken off its inbound
+    /// queue, freeing its place in the peer's share of the queue.
+    pub fn on_in_message_dequeued(&self, message: &ConsensusMessage) {
+        let (_, _, shares) = self.inbound.queue_for(message.variant);
+        shares.release(message.source_peer());
     }
 
     pub fn send_out_message(&self, message: ConsensusMessage) -> anyhow::Result<()> {
@@ -112,6 +225,8 @@ impl ConsensusQueues {
     }
 
     pub fn clear(&self) {
+        lock_or_die!(self.inbound.shares_high.held).clear();
+        lock_or_die!(self.inbound.shares_low.held).clear();
         if let Ok(ref mut q) = self.outbound.receiver_low_priority.try_lock() {
             debug!(
                 "Drained the Consensus outbound low priority queue for {} element(s)",
@@ -148,7 +263,7 @@ impl ConsensusQueues {
 }
 
 lazy_static! {
-    pub static ref CALLBACK_QUEUE: ConsensusQueues = ConsensusQueues::default();
+    pub static ref CALLBACK_QUEUE: ConsensusQueues = ConsensusQueues::new(queue_depths());
 }
 
 /// If a consensus instance is
@@ -303,3 +418,29 @@ impl Regenesis {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inbound_queue_peer_shares() {
+        let shares = PeerShares::default();
+        let (greedy, other) = (RemotePeerId::from(1usize), RemotePeerId::from(2usize));
+        // a queue of depth 8 shared by 2 peers takes anyone's messages until half full
+        for queued in 0..4 {
+            assert!(shares.try_reserve(greedy, queued, 8, 2));
+        }
+        // then the greedy peer is limited to its fair share, but the other one is not
+        assert!(!shares.try_reserve(greedy, 4, 8, 2));
+        assert!(shares.try_reserve(other, 4, 8, 2));
+        // once a message of the greedy peer is taken off the queue it may add another
+        shares.release(greedy);
+        assert!(shares.try_reserve(greedy, 4, 8, 2));
+        for _ in 0..4 {
+            shares.release(greedy);
+        }
+        shares.release(other);
+        assert!(lock_or_die!(shares.held).is_empty());
+    }
+}
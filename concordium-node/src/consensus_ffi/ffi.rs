@@ -3350,6 +3350,7 @@ macro_rules! sending_callback {
                 full_payload,
                 vec![],
                 $omit_status,
+                None,
             );
 
             match CALLBACK_QUEUE.send_out_blocking_msg(msg) {
@@ -1,6 +1,7 @@
 use crate::{
     common::p2p_peer::RemotePeerId,
     consensus_ffi::{catch_up::PeerStatus, helpers::PacketType},
+    network::frames::ReceivedFrame,
 };
 use std::{fmt, sync::Arc};
 
@@ -9,11 +10,14 @@ use std::{fmt, sync::Arc};
 /// It contains an optional identifier of the source peer if it is not our own
 /// consensus layer.
 pub struct ConsensusMessage {
-    pub direction:     MessageType,
-    pub variant:       PacketType,
-    pub payload:       Arc<[u8]>,
-    pub dont_relay_to: Vec<RemotePeerId>,
-    pub omit_status:   Option<PeerStatus>,
+    pub direction:      MessageType,
+    pub variant:        PacketType,
+    pub payload:        Arc<[u8]>,
+    pub dont_relay_to:  Vec<RemotePeerId>,
+    pub omit_status:    Option<PeerStatus>,
+    /// The frame an inbound broadcast was received in, which is used to relay
+    /// it.
+    pub received_frame: Option<ReceivedFrame>,
 }
 
 impl ConsensusMessage {
@@ -23,6 +27,7 @@ impl ConsensusMessage {
         payload: Arc<[u8]>,
         dont_relay_to: Vec<RemotePeerId>,
         omit_status: Option<PeerStatus>,
+        received_frame: Option<ReceivedFrame>,
     ) -> Self {
         Self {
             direction,
@@ -30,6 +35,7 @@ impl ConsensusMessage {
             payload,
             dont_relay_to,
             omit_status,
+            received_frame,
         }
     }
 
@@ -516,6 +516,179 @@ pub mod types {
         }
     }
 
+    impl From<crate::p2p::allowlist::PeerAllowlist> for PeerAllowlist {
+        fn from(value: crate::p2p::allowlist::PeerAllowlist) -> Self {
+            let peers = value
+                .entries()
+                .iter()
+                .map(|entry| {
+                    let static_key = match entry {
+                        crate::p2p::allowlist::AllowlistEntry::Key(key) => Some(key.to_vec()),
+                        crate::p2p::allowlist::AllowlistEntry::Id(_) => None,
+                    };
+                    AllowlistedPeer {
+                        node_id: Some(PeerId {
+                            value: entry.id().to_string(),
+                        }),
+                        static_key,
+                    }
+                })
+                .collect();
+            Self {
+                peers,
+            }
+        }
+    }
+
+    impl From<crate::common::p2p_peer::PeerCapabilities> for PeerCapabilities {
+        fn from(value: crate::common::p2p_peer::PeerCapabilities) -> Self {
+            use crate::common::p2p_peer::PeerCapabilities as Capabilities;
+            Self {
+                archival:            value.contains(Capabilities::ARCHIVAL),
+                public_grpc2:        value.contains(Capabilities::PUBLIC_GRPC2),
+                relays_transactions: value.contains(Capabilities::RELAYS_TRANSACTIONS),
+            }
+        }
+    }
+
+    impl From<crate::p2p::disconnects::DisconnectRecord> for PeerDisconnect {
+        fn from(value: crate::p2p::disconnects::DisconnectRecord) -> Self {
+            use crate::network::DisconnectReason as Reason;
+            let reason = match value.reason {
+                Reason::Unspecified => DisconnectReason::Unspecified,
+                Reason::TooManyConnections => DisconnectReason::TooManyConnections,
+                Reason::Banned => DisconnectReason::Banned,
+                Reason::IncompatibleGenesis => DisconnectReason::IncompatibleGenesis,
+                Reason::CatchUpTimeout => DisconnectReason::CatchUpTimeout,
+                Reason::Unresponsive => DisconnectReason::Unresponsive,
+                Reason::ShuttingDown => DisconnectReason::ShuttingDown,
+            };
+            Self {
+                reason:      reason.into(),
+                by_peer:     value.by_peer,
+                time:        Some(Timestamp {
+                    value: value.time,
+                }),
+                retry_after: value.retry_after.map(|retry_after| Duration {
+                    value: u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX),
+                }),
+            }
+        }
+    }
+
+    impl From<crate::p2p::shutdown::ShutdownProgress> for ShutdownProgress {
+        fn from(value: crate::p2p::shutdown::ShutdownProgress) -> Self {
+            use crate::p2p::shutdown::ShutdownPhase as Phase;
+            let phase = match value.phase {
+                Phase::Running => ShutdownPhase::Running,
+                Phase::Draining => ShutdownPhase::Draining,
+                Phase::Closing => ShutdownPhase::Closing,
+                Phase::Closed => ShutdownPhase::Closed,
+            };
+            Self {
+                phase:            phase.into(),
+                deadline:         value.deadline.map(|value| Timestamp {
+                    value,
+                }),
+                peers_pending:    value.peers_pending as u64,
+                messages_pending: value.messages_pending as u64,
+                bytes_pending:    value.bytes_pending as u64,
+            }
+        }
+    }
+
+    impl From<crate::p2p::bans::PersistedBan> for Ban {
+        fn from(value: crate::p2p::bans::PersistedBan) -> Self {
+            use crate::p2p::bans::{BanOrigin as Origin, PersistedBanId};
+            let target = match value.id {
+                PersistedBanId::Ip(addr) => ban::Target::IpAddress(IpAddress {
+                    value: addr.to_string(),
+                }),
+                PersistedBanId::Subnet(subnet) => ban::Target::Subnet(subnet.to_string()),
+                PersistedBanId::Node(id) => ban::Target::NodeId(PeerId {
+                    value: id.to_string(),
+                }),
+            };
+            let origin = match value.info.origin {
+                Origin::Manual => BanOrigin::Manual,
+                Origin::Automatic => BanOrigin::Automatic,
+            };
+            Self {
+                target: Some(target),
+                expiry: value.info.expiry.map(|value| Timestamp {
+                    value,
+                }),
+                origin: origin.into(),
+                reason: value.info.reason,
+            }
+        }
+    }
+
+    impl TryFrom<Ban> for crate::p2p::bans::PersistedBan {
+        type Error = tonic::Status;
+
+        fn try_from(value: Ban) -> Result<Self, Self::Error> {
+            use crate::p2p::bans::{BanInfo, BanOrigin as Origin, PersistedBanId};
+            let id = match value.target.require()? {
+                ban::Target::IpAddress(addr) => {
+                    PersistedBanId::Ip(addr.value.parse().map_err(|e| {
+                        tonic::Status::invalid_argument(format!("Invalid IP address: {}", e))
+                    })?)
+                }
+                ban::Target::Subnet(subnet) => {
+                    PersistedBanId::Subnet(subnet.parse().map_err(|e| {
+                        tonic::Status::invalid_argument(format!("Invalid subnet: {:#}", e))
+                    })?)
+                }
+                ban::Target::NodeId(id) => PersistedBanId::Node(id.value.parse().map_err(|e| {
+                    tonic::Status::invalid_argument(format!("Invalid node id: {:#}", e))
+                })?),
+            };
+            if value.reason.len() > crate::p2p::bans::MAX_BAN_REASON_LENGTH {
+                return Err(tonic::Status::invalid_argument(format!(
+                    "The reason of a ban is at most {} bytes long.",
+                    crate::p2p::bans::MAX_BAN_REASON_LENGTH
+                )));
+            }
+            let origin = match value.origin() {
+                BanOrigin::Manual => Origin::Manual,
+                BanOrigin::Automatic => Origin::Automatic,
+            };
+            Ok(Self {
+                id,
+                info: BanInfo {
+                    expiry: value.expiry.map(|expiry| expiry.value),
+                    origin,
+                    reason: value.reason,
+                },
+            })
+        }
+    }
+
+    impl From<crate::consensus_ffi::catch_up::PeerStatus> for peers_info::peer::CatchupStatus {
+        fn from(value: crate::consensus_ffi::catch_up::PeerStatus) -> Self {
+            use crate::consensus_ffi::catch_up::PeerStatus;
+            match value {
+                PeerStatus::CatchingUp => peers_info::peer::CatchupStatus::Catchingup,
+                PeerStatus::Pending => peers_info::peer::CatchupStatus::Pending,
+                PeerStatus::UpToDate => peers_info::peer::CatchupStatus::Uptodate,
+            }
+        }
+    }
+
+    impl From<crate::consensus_ffi::catch_up::PeerCatchUpInfo> for CatchUpPeerState {
+        fn from(value: crate::consensus_ffi::catch_up::PeerCatchUpInfo) -> Self {
+            Self {
+                status:           peers_info::peer::CatchupStatus::from(value.status).into(),
+                catch_up_started: value.catch_up_started.map(|started| Timestamp {
+                    value: started,
+                }),
+                timeouts:         value.timeouts,
+                queue_position:   value.queue_position.map(|position| position as u32),
+            }
+        }
+    }
+
     impl SendBlockItemRequest {
         /// Return the Versioned block item serialized in the V0 format.
         pub(crate) fn get_v0_format(self) -> Result<Vec<u8>, tonic::Status> {
@@ -690,6 +863,30 @@ struct ServiceConfig {
     #[serde(default)]
     unban_peer: bool,
     #[serde(default)]
+    get_peer_allowlist: bool,
+    #[serde(default)]
+    reload_peer_allowlist: bool,
+    #[serde(default)]
+    get_peers_details: bool,
+    #[serde(default)]
+    get_bans: bool,
+    #[serde(default)]
+    add_ban: bool,
+    #[serde(default)]
+    remove_ban: bool,
+    #[serde(default)]
+    export_ban_list: bool,
+    #[serde(default)]
+    import_ban_list: bool,
+    #[serde(default)]
+    get_address_book: bool,
+    #[serde(default)]
+    get_reconnect_backoff: bool,
+    #[serde(default)]
+    get_shutdown_progress: bool,
+    #[serde(default)]
+    get_catch_up_state: bool,
+    #[serde(default)]
     dump_start: bool,
     #[serde(default)]
     dump_stop: bool,
@@ -763,6 +960,18 @@ impl ServiceConfig {
             get_banned_peers: true,
             ban_peer: true,
             unban_peer: true,
+            get_peer_allowlist: true,
+            reload_peer_allowlist: true,
+            get_peers_details: true,
+            get_bans: true,
+            add_ban: true,
+            remove_ban: true,
+            export_ban_list: true,
+            import_ban_list: true,
+            get_address_book: true,
+            get_reconnect_backoff: true,
+            get_shutdown_progress: true,
+            get_catch_up_state: true,
             dump_start: true,
             dump_stop: true,
             get_peers_info: true,
@@ -2163,7 +2372,15 @@ pub mod server {
             if !self.service_config.shutdown {
                 return Err(tonic::Status::unimplemented("`Shutdown` is not enabled."));
             }
-            match self.node.close() {
+            // Only the request that begins the shutdown completes it, the others return
+            // right away and can follow it with `GetShutdownProgress`. It blocks until the
+            // messages queued for peers are written, so it runs on a blocking thread.
+            if !self.node.begin_shutdown() {
+                return Ok(tonic::Response::new(crate::grpc2::types::Empty {}));
+            }
+            let node = Arc::clone(&self.node);
+            let result = tokio::task::spawn_blocking(move || node.complete_shutdown()).await;
+            match result.map_err(anyhow::Error::from).and_then(|result| result) {
                 Ok(_) => Ok(tonic::Response::new(crate::grpc2::types::Empty {})),
                 Err(e) => Err(tonic::Status::internal(format!("Unable to shutdown server {}.", e))),
             }
@@ -2232,15 +2449,16 @@ pub mod server {
             if let Ok(banned_peers) = self.node.get_banlist() {
                 let peers = banned_peers
                     .into_iter()
-                    .map(|banned_peer| {
-                        let ip_address = match banned_peer {
-                            crate::p2p::bans::PersistedBanId::Ip(addr) => addr.to_string(),
-                        };
-                        crate::grpc2::types::BannedPeer {
-                            ip_address: Some(crate::grpc2::types::IpAddress {
-                                value: ip_address,
-                            }),
+                    // only IP bans can be represented in `BannedPeers`, `GetBans` reports all
+                    .filter_map(|banned_peer| match banned_peer.id {
+                        crate::p2p::bans::PersistedBanId::Ip(addr) => {
+                            Some(crate::grpc2::types::BannedPeer {
+                                ip_address: Some(crate::grpc2::types::IpAddress {
+                                    value: addr.to_string(),
+                                }),
+                            })
                         }
+                        _ => None,
                     })
                     .collect();
                 Ok(tonic::Response::new(crate::grpc2::types::BannedPeers {
@@ -2260,10 +2478,20 @@ pub mod server {
             }
             let ip = request.into_inner().ip_address.require()?;
             match ip.value.parse::<std::net::IpAddr>() {
-                Ok(ip_addr) => match self.node.drop_by_ip_and_ban(ip_addr) {
-                    Ok(_) => Ok(tonic::Response::new(crate::grpc2::types::Empty {})),
-                    Err(e) => Err(tonic::Status::internal(format!("Could not ban peer {}.", e))),
-                },
+                Ok(ip_addr) => {
+                    let ban_id = crate::p2p::bans::PersistedBanId::Ip(ip_addr);
+                    let info = crate::p2p::bans::BanInfo {
+                        expiry: None,
+                        origin: crate::p2p::bans::BanOrigin::Manual,
+                        reason: String::new(),
+                    };
+                    match self.node.drop_and_ban(ban_id, info) {
+                        Ok(_) => Ok(tonic::Response::new(crate::grpc2::types::Empty {})),
+                        Err(e) => {
+                            Err(tonic::Status::internal(format!("Could not ban peer {}.", e)))
+                        }
+                    }
+                }
                 Err(e) => Err(tonic::Status::invalid_argument(format!(
                     "Invalid IP address provided {}",
                     e
@@ -2294,6 +2522,295 @@ pub mod server {
             }
         }
 
+        async fn get_peer_allowlist(
+            &self,
+            _request: tonic::Request<crate::grpc2::types::Empty>,
+        ) -> Result<tonic::Response<crate::grpc2::types::PeerAllowlist>, tonic::Status> {
+            if !self.service_config.get_peer_allowlist {
+                return Err(tonic::Status::unimplemented("`GetPeerAllowlist` is not enabled."));
+            }
+            match self.node.get_peer_allowlist() {
+                Some(allowlist) => Ok(tonic::Response::new(allowlist.into())),
+                None => Err(tonic::Status::failed_precondition(
+                    "The node is not running in permissioned mode.",
+                )),
+            }
+        }
+
+        async fn reload_peer_allowlist(
+            &self,
+            _request: tonic::Request<crate::grpc2::types::Empty>,
+        ) -> Result<tonic::Response<crate::grpc2::types::PeerAllowlist>, tonic::Status> {
+            if !self.service_config.reload_peer_allowlist {
+                return Err(tonic::Status::unimplemented("`ReloadPeerAllowlist` is not enabled."));
+            }
+            if !self.node.is_permissioned() {
+                return Err(tonic::Status::failed_precondition(
+                    "The node is not running in permissioned mode.",
+                ));
+            }
+            if let Err(e) = self.node.reload_peer_allowlist() {
+                return Err(tonic::Status::internal(format!(
+                    "Could not reload the peer allowlist: {:#}",
+                    e
+                )));
+            }
+            match self.node.get_peer_allowlist() {
+                Some(allowlist) => Ok(tonic::Response::new(allowlist.into())),
+                None => Err(tonic::Status::internal("The peer allowlist is not loaded.")),
+            }
+        }
+
+        async fn get_peers_details(
+            &self,
+            _request: tonic::Request<crate::grpc2::types::Empty>,
+        ) -> Result<tonic::Response<crate::grpc2::types::PeersDetails>, tonic::Status> {
+            if !self.service_config.get_peers_details {
+                return Err(tonic::Status::unimplemented("`GetPeersDetails` is not enabled."));
+            }
+            // we take a snapshot so we can release the lock quickly.
+            let catch_up_info = crate::read_or_die!(self.node.peers).catch_up_info();
+            let peers = self
+                .node
+                .get_peer_stats(None)
+                .into_iter()
+                .map(|peer_stats| crate::grpc2::types::PeerDetails {
+                    peer_id:          Some(crate::grpc2::types::PeerId {
+                        value: format!("{}", peer_stats.self_id),
+                    }),
+                    capabilities:     Some(peer_stats.capabilities.into()),
+                    grpc2_port:       peer_stats.services.grpc2_port.map(|port| {
+                        crate::grpc2::types::Port {
+                            value: port.into(),
+                        }
+                    }),
+                    reputation_score: peer_stats.score,
+                    last_disconnect:  self
+                        .node
+                        .get_last_disconnect(peer_stats.addr)
+                        .map(Into::into),
+                    catch_up_state:   catch_up_info
+                        .get(&peer_stats.local_id)
+                        .map(|&peer_info| peer_info.into()),
+                })
+                .collect();
+            Ok(tonic::Response::new(crate::grpc2::types::PeersDetails {
+                peers,
+            }))
+        }
+
+        async fn get_bans(
+            &self,
+            _request: tonic::Request<crate::grpc2::types::Empty>,
+        ) -> Result<tonic::Response<crate::grpc2::types::Bans>, tonic::Status> {
+            if !self.service_config.get_bans {
+                return Err(tonic::Status::unimplemented("`GetBans` is not enabled."));
+            }
+            match self.node.get_banlist() {
+                Ok(bans) => Ok(tonic::Response::new(crate::grpc2::types::Bans {
+                    bans: bans.into_iter().map(Into::into).collect(),
+                })),
+                Err(e) => Err(tonic::Status::internal(format!("Could not load the bans {}.", e))),
+            }
+        }
+
+        async fn add_ban(
+            &self,
+            request: tonic::Request<crate::grpc2::types::Ban>,
+        ) -> Result<tonic::Response<crate::grpc2::types::Empty>, tonic::Status> {
+            if !self.service_config.add_ban {
+                return Err(tonic::Status::unimplemented("`AddBan` is not enabled."));
+            }
+            let mut ban: crate::p2p::bans::PersistedBan = request.into_inner().try_into()?;
+            // bans issued over the API are always the operator's
+            ban.info.origin = crate::p2p::bans::BanOrigin::Manual;
+            match self.node.drop_and_ban(ban.id, ban.info) {
+                Ok(_) => Ok(tonic::Response::new(crate::grpc2::types::Empty {})),
+                Err(e) => Err(tonic::Status::internal(format!("Could not ban peer {}.", e))),
+            }
+        }
+
+        async fn remove_ban(
+            &self,
+            request: tonic::Request<crate::grpc2::types::Ban>,
+        ) -> Result<tonic::Response<crate::grpc2::types::Empty>, tonic::Status> {
+            if !self.service_config.remove_ban {
+                return Err(tonic::Status::unimplemented("`RemoveBan` is not enabled."));
+            }
+            let ban: crate::p2p::bans::PersistedBan = request.into_inner().try_into()?;
+            match self.node.unban_node(ban.id) {
+                Ok(_) => Ok(tonic::Response::new(crate::grpc2::types::Empty {})),
+                Err(e) => Err(tonic::Status::internal(format!("Could not unban peer {}.", e))),
+            }
+        }
+
+        async fn export_ban_list(
+            &self,
+            _request: tonic::Request<crate::grpc2::types::Empty>,
+        ) -> Result<tonic::Response<crate::grpc2::types::BanList>, tonic::Status> {
+            if !self.service_config.export_ban_list {
+                return Err(tonic::Status::unimplemented("`ExportBanList` is not enabled."));
+            }
+            match self.node.export_bans() {
+                Ok(json) => Ok(tonic::Response::new(crate::grpc2::types::BanList {
+                    json,
+                })),
+                Err(e) => Err(tonic::Status::internal(format!("Could not export the bans {}.", e))),
+            }
+        }
+
+        async fn import_ban_list(
+            &self,
+            request: tonic::Request<crate::grpc2::types::BanList>,
+        ) -> Result<tonic::Response<crate::grpc2::types::Empty>, tonic::Status> {
+            if !self.service_config.import_ban_list {
+                return Err(tonic::Status::unimplemented("`ImportBanList` is not enabled."));
+            }
+            match self.node.import_bans(&request.into_inner().json) {
+                Ok(_) => Ok(tonic::Response::new(crate::grpc2::types::Empty {})),
+                Err(e) => Err(tonic::Status::invalid_argument(format!(
+                    "Could not import the bans: {:#}",
+                    e
+                ))),
+            }
+        }
+
+        async fn get_address_book(
+            &self,
+            _request: tonic::Request<crate::grpc2::types::Empty>,
+        ) -> Result<tonic::Response<crate::grpc2::types::AddressBook>, tonic::Status> {
+            use crate::p2p::address_book::PeerSource;
+            if !self.service_config.get_address_book {
+                return Err(tonic::Status::unimplemented("`GetAddressBook` is not enabled."));
+            }
+            let mut entries = self.node.get_address_book().map_err(|e| {
+                tonic::Status::internal(format!("Could not read the address book {}.", e))
+            })?;
+            crate::p2p::address_book::sort_by_preference(&mut entries);
+            let entries = entries
+                .into_iter()
+                .map(|entry| crate::grpc2::types::AddressBookEntry {
+                    socket_address: Some(crate::grpc2::types::IpSocketAddress {
+                        ip:   Some(crate::grpc2::types::IpAddress {
+                            value: entry.addr.ip().to_string(),
+                        }),
+                        port: Some(crate::grpc2::types::Port {
+                            value: entry.addr.port().into(),
+                        }),
+                    }),
+                    peer_id:        entry.info.node_id.map(|id| crate::grpc2::types::PeerId {
+                        value: id.to_string(),
+                    }),
+                    source:         match entry.info.source {
+                        PeerSource::Given => types::PeerSource::Given,
+                        PeerSource::Bootstrapper => types::PeerSource::Bootstrapper,
+                        PeerSource::PeerExchange => types::PeerSource::PeerExchange,
+                        PeerSource::Incoming => types::PeerSource::Incoming,
+                        PeerSource::Anchor => types::PeerSource::Anchor,
+                    }
+                    .into(),
+                    first_seen:     Some(types::Timestamp {
+                        value: entry.info.first_seen,
+                    }),
+                    last_seen:      Some(types::Timestamp {
+                        value: entry.info.last_seen,
+                    }),
+                    last_handshake: entry.info.last_handshake.map(|value| types::Timestamp {
+                        value,
+                    }),
+                    failures:       entry.info.failures,
+                    latency:        entry.info.latency,
+                })
+                .collect();
+            Ok(tonic::Response::new(crate::grpc2::types::AddressBook {
+                entries,
+            }))
+        }
+
+        async fn get_reconnect_backoff(
+            &self,
+            _request: tonic::Request<crate::grpc2::types::Empty>,
+        ) -> Result<tonic::Response<crate::grpc2::types::ReconnectBackoff>, tonic::Status> {
+            if !self.service_config.get_reconnect_backoff {
+                return Err(tonic::Status::unimplemented("`GetReconnectBackoff` is not enabled."));
+            }
+            let entries = self
+                .node
+                .get_reconnect_states()
+                .into_iter()
+                .map(|(addr, state, given)| crate::grpc2::types::ReconnectBackoffEntry {
+                    socket_address: Some(crate::grpc2::types::IpSocketAddress {
+                        ip:   Some(crate::grpc2::types::IpAddress {
+                            value: addr.ip().to_string(),
+                        }),
+                        port: Some(crate::grpc2::types::Port {
+                            value: addr.port().into(),
+                        }),
+                    }),
+                    given,
+                    failures: state.failures,
+                    next_attempt: Some(types::Timestamp {
+                        value: state.next_attempt,
+                    }),
+                    last_error: state.last_error,
+                })
+                .collect();
+            Ok(tonic::Response::new(crate::grpc2::types::ReconnectBackoff {
+                entries,
+            }))
+        }
+
+        async fn get_shutdown_progress(
+            &self,
+            _request: tonic::Request<crate::grpc2::types::Empty>,
+        ) -> Result<tonic::Response<crate::grpc2::types::ShutdownProgress>, tonic::Status> {
+            if !self.service_config.get_shutdown_progress {
+                return Err(tonic::Status::unimplemented("`GetShutdownProgress` is not enabled."));
+            }
+            Ok(tonic::Response::new(self.node.get_shutdown_progress().into()))
+        }
+
+        async fn get_catch_up_state(
+            &self,
+            _request: tonic::Request<crate::grpc2::types::Empty>,
+        ) -> Result<tonic::Response<crate::grpc2::types::CatchUpState>, tonic::Status> {
+            if !self.service_config.get_catch_up_state {
+                return Err(tonic::Status::unimplemented("`GetCatchUpState` is not enabled."));
+            }
+            // we take a snapshot so we can release the lock quickly.
+            let info = crate::read_or_die!(self.node.peers).catch_up_info();
+            let now = crate::common::get_current_stamp();
+            let pending_queue_length =
+                info.values().filter(|peer_info| peer_info.queue_position.is_some()).count() as u32;
+            let longest_catch_up = info
+                .values()
+                .filter_map(|peer_info| peer_info.catch_up_started)
+                .map(|started| now.saturating_sub(started))
+                .max()
+                .map(|value| types::Duration {
+                    value,
+                });
+            let peers = self
+                .node
+                .get_peer_stats(Some(crate::common::PeerType::Node))
+                .into_iter()
+                .filter_map(|peer_stats| {
+                    let peer_info = info.get(&peer_stats.local_id)?;
+                    Some(crate::grpc2::types::catch_up_state::Peer {
+                        peer_id: Some(crate::grpc2::types::PeerId {
+                            value: format!("{}", peer_stats.self_id),
+                        }),
+                        state:   Some((*peer_info).into()),
+                    })
+                })
+                .collect();
+            Ok(tonic::Response::new(crate::grpc2::types::CatchUpState {
+                peers,
+                pending_queue_length,
+                longest_catch_up,
+            }))
+        }
+
         #[cfg(feature = "network_dump")]
         async fn dump_start(
             &self,
@@ -2377,15 +2894,10 @@ pub mod server {
                     let consensus_info = match peer_stats.peer_type {
                         // Regular nodes do have a catchup status.
                         crate::common::PeerType::Node => {
-                            let catchup_status = match peer_statuses.get(&peer_stats.local_id) {
-                                Some(crate::consensus_ffi::catch_up::PeerStatus::CatchingUp) => {
-                                    crate::grpc2::types::peers_info::peer::CatchupStatus::Catchingup
-                                }
-                                Some(crate::consensus_ffi::catch_up::PeerStatus::UpToDate) => {
-                                    crate::grpc2::types::peers_info::peer::CatchupStatus::Uptodate
-                                }
-                                _ => crate::grpc2::types::peers_info::peer::CatchupStatus::Pending,
-                            };
+                            let catchup_status = peer_statuses.get(&peer_stats.local_id).map_or(
+                                crate::grpc2::types::peers_info::peer::CatchupStatus::Pending,
+                                |&status| status.into(),
+                            );
                             crate::grpc2::types::peers_info::peer::ConsensusInfo::NodeCatchupStatus(
                                 catchup_status.into(),
                             )
@@ -2592,6 +3104,7 @@ pub mod server {
                     Arc::from(payload),
                     vec![],
                     None,
+                    None,
                 ))
             } else {
                 Err(consensus_result.into())
@@ -2617,6 +3130,11 @@ pub mod server {
                     }))
                 }
                 (Err(e), Success) => {
+                    self.node
+                        .stats
+                        .consensus_dropped_messages
+                        .with_label_values(&[PacketType::Transaction.label(), "outbound"])
+                        .inc();
                     warn!("Couldn't put a transaction in the outbound queue due to {:?}", e);
                     Err(tonic::Status::new(
                         tonic::Code::Internal,
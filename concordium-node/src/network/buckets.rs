@@ -1,18 +1,50 @@
 //! Network bucket handling.
+//!
+//! Peers are kept in Kademlia-style buckets according to the XOR distance
+//! between their node id and ours: bucket `i` holds the peers whose distance
+//! from us has its highest set bit at position `i`, so each bucket covers twice
+//! as much of the id space as the previous one. A bucket holds a limited number
+//! of peers, and when a full bucket receives a new peer the least recently
+//! seen one is replaced. Peer lists are drawn from all the buckets in turn, so
+//! that they cover the whole id space rather than a cluster of nearby nodes.
 
 use prometheus::IntGaugeVec;
-use rand::seq::IteratorRandom;
+use rand::seq::SliceRandom;
 use std::{
     collections::HashSet,
     hash::{Hash, Hasher},
 };
 
 use crate::{
-    common::{get_current_stamp, p2p_peer::RemotePeerId, PeerType, RemotePeer},
+    common::{get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId, P2PPeer, PeerType, RemotePeer},
     network::Networks,
+    p2p::diversity::SubnetLimits,
 };
 
-const BUCKET_COUNT: usize = 1;
+/// The number of buckets, one for each bit of a node id.
+const BUCKET_COUNT: usize = 64;
+
+/// The local id of the peers that were learned from a PeerList rather than
+/// connected to. Connection tokens are allocated from a counter starting at 1,
+/// so it never clashes with the id of a connection.
+const LEARNED_PEER_ID: usize = usize::MAX;
+
+/// The index of the bucket for a peer with the given id, or `None` if the id
+/// is our own.
+fn bucket_index(own_id: P2PNodeId, id: P2PNodeId) -> Option<usize> {
+    let distance = own_id.0 ^ id.0;
+    if distance == 0 {
+        None
+    } else {
+        Some(BUCKET_COUNT - 1 - distance.leading_zeros() as usize)
+    }
+}
+
+/// Check whether the peer was learned from a PeerList rather than connected
+/// to.
+pub fn is_learned_peer(peer: &RemotePeer) -> bool {
+    peer.local_id == RemotePeerId::from(LEARNED_PEER_ID)
+}
 
 /// A representation of a node in a bucket.
 #[derive(Eq, Clone)]
@@ -31,70 +63,153 @@ impl Hash for Node {
     fn hash<H: Hasher>(&self, state: &mut H) { self.peer.external_addr().hash(state) }
 }
 
+impl Node {
+    /// Whether the node may be shared with the sender of a request for peers
+    /// on the given networks.
+    fn is_shareable(&self, sender: Option<RemotePeerId>, networks: &Networks) -> bool {
+        self.peer.peer_type == PeerType::Node
+            && Some(self.peer.local_id) != sender
+            && (networks.is_empty() || !self.networks.is_disjoint(networks))
+    }
+}
+
 /// A bucket of nodes.
 type Bucket = HashSet<Node>;
 
 /// The set of buckets.
 pub struct Buckets {
-    buckets: Vec<Bucket>,
+    /// The id of the node itself, from which the distances are measured.
+    own_id:      P2PNodeId,
+    /// The maximum number of nodes in a bucket.
+    bucket_size: usize,
+    buckets:     Vec<Bucket>,
 }
 
-impl Default for Buckets {
-    fn default() -> Self {
+impl Buckets {
+    /// Create empty buckets for the node with the given id.
+    pub fn new(own_id: P2PNodeId, bucket_size: usize) -> Self {
         Buckets {
+            own_id,
+            bucket_size,
             buckets: vec![HashSet::new(); BUCKET_COUNT],
         }
     }
-}
 
-impl Buckets {
-    /// Adds a peer to a bucket.
+    /// Adds a peer to a bucket. Peers without a node id are ignored.
     pub fn insert_into_bucket(
         &mut self,
         peer: RemotePeer,
         networks: Networks,
         bucket_size_gauge: &IntGaugeVec,
     ) {
-        let bucket = &mut self.buckets[0];
-        if bucket
-            .replace(Node {
+        self.insert_node(
+            Node {
                 peer,
                 networks,
                 last_seen: get_current_stamp(),
-            })
-            .is_none()
-        {
+            },
+            bucket_size_gauge,
+        )
+    }
+
+    /// Adds a peer learned from a PeerList to a bucket, unless the peer is
+    /// already known or its bucket is full. Learned peers never replace the
+    /// peers we were connected to.
+    pub fn insert_learned(
+        &mut self,
+        peer: P2PPeer,
+        networks: Networks,
+        bucket_size_gauge: &IntGaugeVec,
+    ) {
+        let index = match bucket_index(self.own_id, peer.id) {
+            Some(index) => index,
+            None => return,
+        };
+        let node = Node {
+            peer: RemotePeer {
+                self_id:       Some(peer.id),
+                addr:          peer.addr,
+                local_id:      RemotePeerId::from(LEARNED_PEER_ID),
+                external_port: peer.addr.port(),
+                peer_type:     peer.peer_type,
+                capabilities:  peer.capabilities,
+                services:      peer.services,
+            },
+            networks,
+            last_seen: get_current_stamp(),
+        };
+        if self.buckets.iter().any(|bucket| bucket.contains(&node)) {
+            return;
+        }
+        let bucket = &mut self.buckets[index];
+        if bucket.len() < self.bucket_size && bucket.insert(node) {
+            bucket_size_gauge.with_label_values(&[&index.to_string()]).inc();
+        }
+    }
+
+    fn insert_node(&mut self, node: Node, bucket_size_gauge: &IntGaugeVec) {
+        let index = match node.peer.self_id.and_then(|id| bucket_index(self.own_id, id)) {
+            Some(index) => index,
+            None => return,
+        };
+        // If the peer changed its id or address it might still be in the buckets under
+        // its old details; its connection is identified by the local id.
+        for (i, bucket) in self.buckets.iter_mut().enumerate() {
+            let old_len = bucket.len();
+            bucket.retain(|existing| {
+                existing.peer.local_id != node.peer.local_id && (i == index || existing != &node)
+            });
+            let removed = old_len - bucket.len();
+            if removed > 0 {
+                bucket_size_gauge.with_label_values(&[&i.to_string()]).sub(removed as i64);
+            }
+        }
+        let label = index.to_string();
+        let bucket = &mut self.buckets[index];
+        if !bucket.contains(&node) && bucket.len() >= self.bucket_size {
+            // the bucket is full, so replace the least recently seen node
+            if let Some(oldest) = bucket.iter().min_by_key(|node| node.last_seen).cloned() {
+                bucket.remove(&oldest);
+                bucket_size_gauge.with_label_values(&[&label]).dec();
+            }
+        }
+        if bucket.replace(node).is_none() {
             // If the peer is new in the bucket reflect that in the
             // metric.
-            bucket_size_gauge.with_label_values(&["0"]).inc();
+            bucket_size_gauge.with_label_values(&[&label]).inc();
         }
     }
 
-    /// Update the networks of a node in the bucket.
+    /// Update the networks of a node in the buckets.
     pub fn update_network_ids(&mut self, peer: RemotePeer, networks: Networks) {
-        let bucket = &mut self.buckets[0];
-        bucket.replace(Node {
+        let index = match peer.self_id.and_then(|id| bucket_index(self.own_id, id)) {
+            Some(index) => index,
+            None => return,
+        };
+        let node = Node {
             peer,
             networks,
             last_seen: get_current_stamp(),
-        });
+        };
+        let bucket = &mut self.buckets[index];
+        if bucket.contains(&node) {
+            bucket.replace(node);
+        }
     }
 
     /// Returns all the nodes in buckets with the possible exception of the
     /// sender, if it is supplied.
-    fn get_all_nodes(&self, sender: Option<RemotePeerId>, networks: &Networks) -> Vec<RemotePeer> {
-        let mut nodes = Vec::new();
-        let filter_criteria = |node: &&Node| {
-            node.peer.peer_type == PeerType::Node
-                && Some(node.peer.local_id) != sender
-                && (networks.is_empty() || !node.networks.is_disjoint(networks))
-        };
-
-        for bucket in &self.buckets {
-            nodes.extend(bucket.iter().filter(filter_criteria).map(|node| node.peer.to_owned()))
-        }
-
-        nodes
+    pub fn get_all_nodes(
+        &self,
+        sender: Option<RemotePeerId>,
+        networks: &Networks,
+    ) -> Vec<RemotePeer> {
+        self.buckets
+            .iter()
+            .flat_map(HashSet::iter)
+            .filter(|node| node.is_shareable(sender, networks))
+            .map(|node| node.peer)
+            .collect()
     }
 
     /// Returns the number of networks in the buckets.
@@ -105,16 +220,55 @@ impl Buckets {
     /// Checks whether the buckets are empty.
     pub fn is_empty(&self) -> bool { self.len() == 0 }
 
-    /// Returns the desired number of nodes from the buckets.
-    /// This is only used if the node is running as a bootstrapper.
+    /// Returns the desired number of random nodes from the buckets. The nodes
+    /// are taken from the buckets in turn, so that they are spread over as
+    /// many buckets as possible, and nodes that would exceed the subnet limits
+    /// are skipped.
     pub fn get_random_nodes(
         &self,
         sender: RemotePeerId,
         number: usize,
         networks: &Networks,
+        subnet_limits: &SubnetLimits,
     ) -> Vec<RemotePeer> {
         let mut rng = rand::thread_rng();
-        self.get_all_nodes(Some(sender), networks).into_iter().choose_multiple(&mut rng, number)
+        let mut candidates = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                let mut nodes = bucket
+                    .iter()
+                    .filter(|node| node.is_shareable(Some(sender), networks))
+                    .map(|node| node.peer)
+                    .collect::<Vec<_>>();
+                nodes.shuffle(&mut rng);
+                nodes
+            })
+            .filter(|nodes| !nodes.is_empty())
+            .collect::<Vec<_>>();
+        // shuffle the buckets too, so that short lists don't always favour the same
+        // buckets
+        candidates.shuffle(&mut rng);
+
+        let mut selected = Vec::new();
+        let mut selected_ips = Vec::new();
+        while selected.len() < number && !candidates.is_empty() {
+            candidates.retain_mut(|nodes| {
+                while selected.len() < number {
+                    let Some(node) = nodes.pop() else {
+                        break;
+                    };
+                    let ip = node.addr.ip();
+                    if subnet_limits.exceeded_subnet(ip, &selected_ips).is_none() {
+                        selected.push(node);
+                        selected_ips.push(ip);
+                        break;
+                    }
+                }
+                !nodes.is_empty()
+            });
+        }
+        selected
     }
 
     /// Removes the bucket nodes older than then specified amount of time.
@@ -123,25 +277,27 @@ impl Buckets {
         timeout_bucket_entry_period: u64,
         bucket_size_gauge: &IntGaugeVec,
     ) {
-        let clean_before = get_current_stamp() - timeout_bucket_entry_period;
-        let bucket = &mut self.buckets[0];
-        bucket.retain(|entry| entry.last_seen >= clean_before);
-        let new_bucket_size = bucket.len();
-        bucket_size_gauge.with_label_values(&["0"]).set(new_bucket_size as i64);
+        let clean_before = get_current_stamp().saturating_sub(timeout_bucket_entry_period);
+        for (i, bucket) in self.buckets.iter_mut().enumerate() {
+            let old_bucket_size = bucket.len();
+            bucket.retain(|entry| entry.last_seen >= clean_before);
+            if bucket.len() != old_bucket_size {
+                bucket_size_gauge.with_label_values(&[&i.to_string()]).set(bucket.len() as i64);
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::common::P2PNodeId;
-    use prometheus::register_int_gauge_vec;
+    use prometheus::{register_int_gauge_vec, Opts};
     use rand::Rng;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
     #[test]
     pub fn test_buckets_insert_duplicate_peer_id() {
-        let mut buckets = Buckets::default();
+        let mut buckets = Buckets::new(rand::thread_rng().gen(), 20);
 
         let local_id: RemotePeerId = rand::thread_rng().gen();
 
@@ -152,6 +308,8 @@ mod tests {
             local_id,
             external_port: 8888,
             peer_type: PeerType::Node,
+            capabilities: Default::default(),
+            services: Default::default(),
         };
 
         let p2p_duplicate_peer = RemotePeer {
@@ -160,6 +318,8 @@ mod tests {
             local_id,
             external_port: 8889,
             peer_type: PeerType::Node,
+            capabilities: Default::default(),
+            services: Default::default(),
         };
 
         // create a dummy gauge
@@ -169,6 +329,132 @@ mod tests {
         // and check that only one peer is inserted
         buckets.insert_into_bucket(p2p_peer, Default::default(), &dummy_gauge);
         buckets.insert_into_bucket(p2p_duplicate_peer, Default::default(), &dummy_gauge);
-        assert_eq!(buckets.buckets.len(), 1);
+        let nodes = buckets.get_all_nodes(None, &Default::default());
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].addr, p2p_duplicate_peer.addr);
+    }
+
+    fn test_peer(id: u64, port: u16) -> RemotePeer {
+        RemotePeer {
+            self_id:       Some(P2PNodeId(id)),
+            addr:          SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+            local_id:      RemotePeerId::from(usize::from(port)),
+            external_port: port,
+            peer_type:     PeerType::Node,
+            capabilities:  Default::default(),
+            services:      Default::default(),
+        }
+    }
+
+    fn test_gauge() -> IntGaugeVec {
+        IntGaugeVec::new(Opts::new("bucket_test_gauge", "help"), &["bucket"])
+            .expect("Unable to create test gauge.")
+    }
+
+    #[test]
+    pub fn test_buckets_insert_learned() {
+        let gauge = test_gauge();
+        let mut buckets = Buckets::new(P2PNodeId(0), 2);
+        let connected = test_peer(4, 8000);
+        buckets.insert_into_bucket(connected, Default::default(), &gauge);
+        // a learned peer fills the remaining space in the bucket
+        let learned = |id, port| P2PPeer {
+            id:           P2PNodeId(id),
+            addr:         SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+            peer_type:    PeerType::Node,
+            capabilities: Default::default(),
+            services:     Default::default(),
+        };
+        buckets.insert_learned(learned(5, 8001), Default::default(), &gauge);
+        // but does not replace anyone once the bucket is full
+        buckets.insert_learned(learned(6, 8002), Default::default(), &gauge);
+        // and does not overwrite a peer we were connected to
+        buckets.insert_learned(learned(4, 8000), Default::default(), &gauge);
+        let nodes = buckets.get_all_nodes(None, &Default::default());
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes.iter().filter(|peer| is_learned_peer(peer)).count(), 1);
+        assert!(nodes.iter().any(|peer| peer.local_id == connected.local_id));
+
+        // connecting to a learned peer turns it into a regular entry
+        buckets.insert_into_bucket(test_peer(5, 8001), Default::default(), &gauge);
+        let nodes = buckets.get_all_nodes(None, &Default::default());
+        assert_eq!(nodes.len(), 2);
+        assert!(!nodes.iter().any(is_learned_peer));
+    }
+
+    #[test]
+    pub fn test_bucket_index() {
+        let own_id = P2PNodeId(0b1000);
+        assert_eq!(bucket_index(own_id, own_id), None);
+        assert_eq!(bucket_index(own_id, P2PNodeId(0b1001)), Some(0));
+        assert_eq!(bucket_index(own_id, P2PNodeId(0b0000)), Some(3));
+        assert_eq!(bucket_index(own_id, P2PNodeId(u64::MAX)), Some(63));
+    }
+
+    #[test]
+    pub fn test_buckets_replace_least_recently_seen() {
+        let gauge = test_gauge();
+        let mut buckets = Buckets::new(P2PNodeId(0), 2);
+        // all these peers are at a distance in [4, 8), i.e., in bucket 2
+        for (id, port, last_seen) in [(4, 8000, 10), (5, 8001, 5), (6, 8002, 20)] {
+            buckets.insert_node(
+                Node {
+                    peer: test_peer(id, port),
+                    networks: Default::default(),
+                    last_seen,
+                },
+                &gauge,
+            );
+        }
+        let mut ids = buckets.buckets[2]
+            .iter()
+            .filter_map(|node| node.peer.self_id)
+            .map(|id| id.0)
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![4, 6]);
+        assert_eq!(gauge.with_label_values(&["2"]).get(), 2);
+        // our own id is never inserted
+        buckets.insert_into_bucket(test_peer(0, 8003), Default::default(), &gauge);
+        assert_eq!(buckets.get_all_nodes(None, &Default::default()).len(), 2);
+    }
+
+    #[test]
+    pub fn test_buckets_random_nodes_are_diverse() {
+        let gauge = test_gauge();
+        let mut buckets = Buckets::new(P2PNodeId(0), 20);
+        let mut port = 8000;
+        for bucket in [10, 20, 30] {
+            for i in 0..10 {
+                buckets.insert_into_bucket(
+                    test_peer((1 << bucket) + i, port),
+                    Default::default(),
+                    &gauge,
+                );
+                port += 1;
+            }
+        }
+        let selected = buckets.get_random_nodes(
+            RemotePeerId::from(0usize),
+            3,
+            &Default::default(),
+            &Default::default(),
+        );
+        let mut indices = selected
+            .iter()
+            .filter_map(|peer| bucket_index(P2PNodeId(0), peer.self_id?))
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        assert_eq!(indices, vec![10, 20, 30]);
+
+        // all the peers are in the same subnet, so at most one is selected
+        let subnet_limits = SubnetLimits::new(None, Some(1), None, None);
+        let selected = buckets.get_random_nodes(
+            RemotePeerId::from(0usize),
+            3,
+            &Default::default(),
+            &subnet_limits,
+        );
+        assert_eq!(selected.len(), 1);
     }
 }
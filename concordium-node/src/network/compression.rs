@@ -0,0 +1,84 @@
+//! Compression of network packet payloads.
+//!
+//! Starting with wire protocol version 2 the payloads of network packets above
+//! a configurable size are zstd-compressed on the wire. The compression is
+//! transparent to the rest of the node: packets are compressed just before
+//! they are sent and decompressed as soon as they are received.
+
+use crate::{
+    configuration::PROTOCOL_MAX_MESSAGE_SIZE,
+    network::{NetworkPacket, PayloadCompression},
+    stats_export_service::StatsExportService,
+};
+use anyhow::{ensure, Context};
+use std::{io::Read, time::Instant};
+
+/// The zstd compression level used for packet payloads. Low levels are
+/// considerably faster while still compressing blocks well.
+const ZSTD_COMPRESSION_LEVEL: i32 = 3;
+
+/// Compress the payload of the packet in place. If compression does not
+/// reduce the size of the payload the packet is left unchanged and `false` is
+/// returned.
+pub fn compress_packet(
+    packet: &mut NetworkPacket,
+    stats: &StatsExportService,
+) -> anyhow::Result<bool> {
+    ensure!(packet.compression == PayloadCompression::None, "The packet is already compressed.");
+    if let Some(compressed) = compress_payload(&packet.message, stats)? {
+        packet.message = compressed;
+        packet.compression = PayloadCompression::Zstd;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Compress a packet payload. Returns `None` if compression does not reduce
+/// the size of the payload.
+pub fn compress_payload(
+    payload: &[u8],
+    stats: &StatsExportService,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let start = Instant::now();
+    let compressed = zstd::bulk::compress(payload, ZSTD_COMPRESSION_LEVEL)
+        .context("Could not compress the packet payload.")?;
+    stats.observe_compression("sent", payload.len(), compressed.len(), start.elapsed());
+    if compressed.len() < payload.len() {
+        Ok(Some(compressed))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Decompress the payload of the packet in place. The size of the decompressed
+/// payload is subject to the same limit as messages received from the network.
+pub fn decompress_packet(
+    packet: &mut NetworkPacket,
+    stats: &StatsExportService,
+) -> anyhow::Result<()> {
+    match packet.compression {
+        PayloadCompression::None => Ok(()),
+        PayloadCompression::Zstd => {
+            let start = Instant::now();
+            let mut decompressed = Vec::with_capacity(2 * packet.message.len());
+            zstd::stream::read::Decoder::new(&packet.message[..])?
+                .take(u64::from(PROTOCOL_MAX_MESSAGE_SIZE) + 1)
+                .read_to_end(&mut decompressed)
+                .context("Could not decompress the packet payload.")?;
+            ensure!(
+                decompressed.len() <= PROTOCOL_MAX_MESSAGE_SIZE as usize,
+                "The decompressed packet payload exceeds the maximum protocol size."
+            );
+            stats.observe_compression(
+                "received",
+                decompressed.len(),
+                packet.message.len(),
+                start.elapsed(),
+            );
+            packet.message = decompressed;
+            packet.compression = PayloadCompression::None;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Network packets serialized once for all the peers they are sent to.
+//!
+//! A packet sent to several peers, e.g., a broadcast, is serialized once and
+//! the resulting frame is shared by the send queues of all of them; only the
+//! encryption is done per connection. A broadcast received from a peer is
+//! relayed in the frame it was received in, without deserializing and
+//! serializing it again.
+
+use crate::{
+    consensus_ffi::helpers::PacketType,
+    network::{
+        compression::compress_payload, serialization::fbs::serialize_packet_message, NetworkId,
+        PacketDestination, PayloadCompression, WireProtocolVersion,
+        WIRE_PROTOCOL_COMPRESSION_VERSION,
+    },
+    stats_export_service::StatsExportService,
+};
+use std::{convert::TryFrom, sync::Arc};
+
+/// The most by which a received frame may exceed the size of its payload to be
+/// relayed as is. The header of a frame is well below this; a larger frame
+/// carries padding, which the node would otherwise relay to all its peers, so
+/// it is serialized again instead.
+pub const MAX_RELAY_FRAME_OVERHEAD: usize = 128;
+
+/// A packet serialized for sending, possibly in two variants: peers that
+/// support compression get the frame with a compressed payload if there is
+/// one, and the others the frame with an uncompressed payload.
+#[derive(Clone)]
+pub struct PacketFrames {
+    /// The frame with the uncompressed payload.
+    plain:       Arc<[u8]>,
+    /// The frame with the compressed payload, if any.
+    compressed:  Option<Arc<[u8]>>,
+    /// The type of the packet, if the payload starts with a valid one.
+    packet_type: Option<PacketType>,
+}
+
+impl PacketFrames {
+    /// Serialize a packet with the given payload. If the payload is larger
+    /// than the compression threshold and compression reduces its size, a
+    /// frame with the compressed payload is serialized as well.
+    pub fn serialize(
+        destination: &PacketDestination,
+        network_id: NetworkId,
+        payload: &[u8],
+        compression_threshold: usize,
+        stats: &StatsExportService,
+    ) -> anyhow::Result<Self> {
+        let plain = serialize_frame(destination, network_id, payload, PayloadCompression::None)?;
+        let compressed = if payload.len() > compression_threshold {
+            match compress_payload(payload, stats)? {
+                Some(compressed) => Some(serialize_frame(
+                    destination,
+                    network_id,
+                    &compressed,
+                    PayloadCompression::Zstd,
+                )?),
+                None => None,
+            }
+        } else {
+            None
+        };
+        Ok(Self {
+            plain,
+            compressed,
+            packet_type: payload_packet_type(payload),
+        })
+    }
+
+    /// The frames for relaying a broadcast that was received in the given
+    /// frame. A frame with a compressed payload is only relayed as is to the
+    /// peers that support compression, so the uncompressed `payload` is
+    /// serialized for the others. A frame that is larger than its payload and
+    /// [`MAX_RELAY_FRAME_OVERHEAD`] is not relayed as is.
+    pub fn relay(received: &ReceivedFrame, payload: &[u8]) -> anyhow::Result<Self> {
+        if received.bytes.len() > payload.len().saturating_add(MAX_RELAY_FRAME_OVERHEAD) {
+            Ok(Self {
+                plain:       serialize_frame(
+                    &PacketDestination::Broadcast(Vec::new()),
+                    received.network_id,
+                    payload,
+                    PayloadCompression::None,
+                )?,
+                compressed:  None,
+                packet_type: payload_packet_type(payload),
+            })
+        } else if received.compressed {
+            let plain = serialize_frame(
+                &PacketDestination::Broadcast(Vec::new()),
+                received.network_id,
+                payload,
+                PayloadCompression::None,
+            )?;
+            Ok(Self {
+                plain,
+                compressed: Some(Arc::clone(&received.bytes)),
+                packet_type: payload_packet_type(payload),
+            })
+        } else {
+            Ok(Self {
+                plain:       Arc::clone(&received.bytes),
+                compressed:  None,
+                packet_type: payload_packet_type(payload),
+            })
+        }
+    }
+
+    /// The frame to send to a peer using the given wire protocol version.
+    pub fn for_wire_version(&self, wire_version: WireProtocolVersion) -> &Arc<[u8]> {
+        match self.compressed {
+            Some(ref compressed) if wire_version >= WIRE_PROTOCOL_COMPRESSION_VERSION => compressed,
+            _ => &self.plain,
+        }
+    }
+
+    /// The type of the packet, which selects the outbound queue it is put in.
+    pub fn packet_type(&self) -> Option<PacketType> { self.packet_type }
+}
+
+/// A broadcast packet as it was received from a peer.
+#[derive(Clone)]
+pub struct ReceivedFrame {
+    /// The serialized network message containing the packet.
+    pub bytes:      Arc<[u8]>,
+    pub network_id: NetworkId,
+    /// Whether the payload in the frame is compressed.
+    pub compressed: bool,
+}
+
+fn payload_packet_type(payload: &[u8]) -> Option<PacketType> {
+    payload.first().and_then(|&tag| PacketType::try_from(tag).ok())
+}
+
+fn serialize_frame(
+    destination: &PacketDestination,
+    network_id: NetworkId,
+    payload: &[u8],
+    compression: PayloadCompression,
+) -> anyhow::Result<Arc<[u8]>> {
+    let mut buf = Vec::with_capacity(payload.len() + 64);
+    serialize_packet_message(destination, network_id, payload, compression, &mut buf)?;
+    Ok(Arc::from(buf))
+}
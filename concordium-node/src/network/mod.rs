@@ -1,17 +1,19 @@
 //! Network-related objects.
 
 pub mod buckets;
+pub mod compression;
+pub mod frames;
 pub mod serialization;
 
 pub use self::buckets::Buckets;
 use crate::common::{
-    p2p_peer::{P2PPeer, RemotePeerId},
+    p2p_peer::{P2PPeer, PeerCapabilities, PeerServices, RemotePeerId},
     P2PNodeId,
 };
 use concordium_base::hashes::BlockHash;
 use nohash_hasher::BuildNoHashHasher;
 use semver::Version;
-use std::collections::HashSet;
+use std::{collections::HashSet, fmt, time::Duration};
 
 /// Wire protocol version number. Nodes must agree on a common wire protocol in
 /// order to communicate. This should be the highest protocol version supported
@@ -21,11 +23,29 @@ use std::collections::HashSet;
 /// protocol version.
 pub type WireProtocolVersion = u8;
 
-/// The current wire protocol version, currently 1.
-pub const WIRE_PROTOCOL_CURRENT_VERSION: WireProtocolVersion = 1;
+/// The current wire protocol version, currently 4.
+pub const WIRE_PROTOCOL_CURRENT_VERSION: WireProtocolVersion = 4;
+
+/// The first wire protocol version in which the node id a peer claims in its
+/// handshake must be the one derived from its static Noise key. Nodes that only
+/// support older versions use a throwaway key and a random node id.
+pub const WIRE_PROTOCOL_AUTHENTICATED_ID_VERSION: WireProtocolVersion = 2;
+
+/// The first wire protocol version in which the payloads of network packets
+/// may be compressed.
+pub const WIRE_PROTOCOL_COMPRESSION_VERSION: WireProtocolVersion = 2;
+
+/// The first wire protocol version in which nodes understand the `Disconnect`
+/// request.
+pub const WIRE_PROTOCOL_DISCONNECT_VERSION: WireProtocolVersion = 3;
+
+/// The first wire protocol version in which nodes announce transactions and
+/// blocks with `Inventory` requests instead of pushing them.
+pub const WIRE_PROTOCOL_INVENTORY_VERSION: WireProtocolVersion = 4;
 
 /// The supported write protocol versions in descending order.
-pub const WIRE_PROTOCOL_VERSIONS: [WireProtocolVersion; 1] = [WIRE_PROTOCOL_CURRENT_VERSION];
+pub const WIRE_PROTOCOL_VERSIONS: [WireProtocolVersion; 4] =
+    [WIRE_PROTOCOL_CURRENT_VERSION, 3, 2, 1];
 
 /// Identifies a network.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -85,6 +105,8 @@ pub struct Handshake {
     pub wire_versions:  Vec<WireProtocolVersion>,
     pub genesis_blocks: Vec<BlockHash>,
     pub proof:          Vec<u8>,
+    pub capabilities:   PeerCapabilities,
+    pub services:       PeerServices,
 }
 
 /// A network message serving a specified purpose.
@@ -100,6 +122,81 @@ pub enum NetworkRequest {
     JoinNetwork(NetworkId),
     /// Notifies that a node left a specific network.
     LeaveNetwork(NetworkId),
+    /// Notifies that the sender is about to close the connection.
+    Disconnect(Disconnect),
+    /// Announces objects the sender has.
+    Inventory(Vec<ObjectHash>),
+    /// Asks for announced objects, which are sent back as broadcast packets.
+    GetObjects(Vec<ObjectHash>),
+}
+
+/// The SHA-256 hash of the payload of a network packet, which identifies the
+/// object, e.g., a block or a transaction, the packet carries.
+pub type ObjectHash = [u8; 32];
+
+/// Why a node closes a connection to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// No specific reason, e.g., the node operator asked for the peer to be
+    /// dropped.
+    Unspecified,
+    /// The node has more peers than it wants.
+    TooManyConnections,
+    /// The peer is banned.
+    Banned,
+    /// The peer's genesis blocks are incompatible with the node's.
+    IncompatibleGenesis,
+    /// The peer took too long to catch the node up.
+    CatchUpTimeout,
+    /// The peer's latency is too high or the peer stopped responding.
+    Unresponsive,
+    /// The node is shutting down.
+    ShuttingDown,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisconnectReason::Unspecified => write!(f, "unspecified"),
+            DisconnectReason::TooManyConnections => write!(f, "too many connections"),
+            DisconnectReason::Banned => write!(f, "banned"),
+            DisconnectReason::IncompatibleGenesis => write!(f, "incompatible genesis"),
+            DisconnectReason::CatchUpTimeout => write!(f, "catch-up timeout"),
+            DisconnectReason::Unresponsive => write!(f, "unresponsive"),
+            DisconnectReason::ShuttingDown => write!(f, "shutting down"),
+        }
+    }
+}
+
+/// The payload of a `Disconnect` request. It is sent on a best-effort basis
+/// before a connection is closed, and only to peers that support at least
+/// `WIRE_PROTOCOL_DISCONNECT_VERSION`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnect {
+    pub reason:      DisconnectReason,
+    /// How long the peer should wait before reconnecting, if the sender has a
+    /// preference. It has a resolution of seconds on the wire.
+    pub retry_after: Option<Duration>,
+}
+
+impl Disconnect {
+    pub fn new(reason: DisconnectReason, retry_after: Option<Duration>) -> Self {
+        Self {
+            reason,
+            retry_after,
+        }
+    }
+}
+
+impl fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.retry_after {
+            Some(retry_after) => {
+                write!(f, "{} (retry after {}s)", self.reason, retry_after.as_secs())
+            }
+            None => write!(f, "{}", self.reason),
+        }
+    }
 }
 
 /// A network message sent only in response to a network request.
@@ -117,6 +214,19 @@ pub struct NetworkPacket {
     pub destination: PacketDestination,
     pub network_id:  NetworkId,
     pub message:     Vec<u8>,
+    /// The compression applied to the `message`. Packets are only ever
+    /// compressed on the wire; they are decompressed upon receipt.
+    pub compression: PayloadCompression,
+}
+
+/// The compression of the payload of a network packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadCompression {
+    /// The payload is not compressed.
+    None,
+    /// The payload is compressed with zstd. Only used with peers that support
+    /// at least `WIRE_PROTOCOL_COMPRESSION_VERSION`.
+    Zstd,
 }
 
 /// The desired target of a network packet.
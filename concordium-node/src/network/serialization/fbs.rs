@@ -3,16 +3,17 @@
 use crate::{
     common::{
         get_current_stamp,
-        p2p_peer::{P2PPeer, PeerType},
+        p2p_peer::{P2PPeer, PeerCapabilities, PeerServices, PeerType},
         P2PNodeId,
     },
     flatbuffers_shim::network,
     network::{
-        Handshake, NetworkId, NetworkMessage, NetworkPacket, NetworkPayload, NetworkRequest,
-        NetworkResponse, PacketDestination,
+        Disconnect, DisconnectReason, Handshake, NetworkId, NetworkMessage, NetworkPacket,
+        NetworkPayload, NetworkRequest, NetworkResponse, ObjectHash, PacketDestination,
+        PayloadCompression,
     },
 };
-use anyhow::{bail, Context, Error};
+use anyhow::{bail, ensure, Context, Error};
 use concordium_base::hashes::BlockHash;
 use flatbuffers::FlatBufferBuilder;
 use semver::Version;
@@ -21,12 +22,17 @@ use std::{
     io::{self, Read, Write},
     net::{IpAddr, SocketAddr},
     panic,
+    time::Duration,
 };
 
 /// The HANDSHAKE message version. In order to make the handshake robust, we
 /// need to version the message itself. Higher versions are assumed to append
 /// new fields at the end of the message so it should be still deserializable
 /// even if the new fields are not understood, but a warning will be emitted.
+///
+/// The capabilities and service endpoints of the sender were appended without
+/// a version bump so that older nodes do not warn on every handshake; they
+/// simply leave those fields unset.
 pub const HANDSHAKE_MESSAGE_VERSION: u8 = 0;
 
 impl NetworkMessage {
@@ -47,9 +53,16 @@ impl NetworkMessage {
         let mut builder = FlatBufferBuilder::with_capacity(capacity);
 
         let (payload_type, payload_offset) = match self.payload {
-            NetworkPayload::NetworkPacket(ref packet) => {
-                (network::NetworkPayload::NetworkPacket, serialize_packet(&mut builder, packet)?)
-            }
+            NetworkPayload::NetworkPacket(ref packet) => (
+                network::NetworkPayload::NetworkPacket,
+                serialize_packet(
+                    &mut builder,
+                    &packet.destination,
+                    packet.network_id,
+                    &packet.message,
+                    packet.compression,
+                )?,
+            ),
             NetworkPayload::NetworkRequest(ref request) => {
                 (network::NetworkPayload::NetworkRequest, serialize_request(&mut builder, request)?)
             }
@@ -59,19 +72,44 @@ impl NetworkMessage {
             ),
         };
 
-        let message_offset =
-            network::NetworkMessage::create(&mut builder, &network::NetworkMessageArgs {
-                timestamp: get_current_stamp(),
-                payload_type,
-                payload: Some(payload_offset),
-            });
+        finish_message(builder, payload_type, payload_offset, target)
+    }
+}
 
-        network::finish_size_prefixed_network_message_buffer(&mut builder, message_offset);
+/// Serialize a network message containing a packet with the given payload.
+/// Unlike `NetworkMessage::serialize` it does not require the payload to be
+/// copied into a `NetworkPacket` first.
+pub fn serialize_packet_message<T: Write>(
+    destination: &PacketDestination,
+    network_id: NetworkId,
+    payload: &[u8],
+    compression: PayloadCompression,
+    target: &mut T,
+) -> anyhow::Result<()> {
+    let mut builder = FlatBufferBuilder::with_capacity(payload.len() + 64);
+    let payload_offset =
+        serialize_packet(&mut builder, destination, network_id, payload, compression)?;
+    finish_message(builder, network::NetworkPayload::NetworkPacket, payload_offset, target)
+}
 
-        target.write_all(builder.finished_data()).map_err(Error::from)?;
+fn finish_message<T: Write>(
+    mut builder: FlatBufferBuilder,
+    payload_type: network::NetworkPayload,
+    payload_offset: flatbuffers::WIPOffset<flatbuffers::UnionWIPOffset>,
+    target: &mut T,
+) -> anyhow::Result<()> {
+    let message_offset =
+        network::NetworkMessage::create(&mut builder, &network::NetworkMessageArgs {
+            timestamp: get_current_stamp(),
+            payload_type,
+            payload: Some(payload_offset),
+        });
 
-        Ok(())
-    }
+    network::finish_size_prefixed_network_message_buffer(&mut builder, message_offset);
+
+    target.write_all(builder.finished_data()).map_err(Error::from)?;
+
+    Ok(())
 }
 
 // deserialization
@@ -128,10 +166,17 @@ fn deserialize_packet(root: &network::NetworkMessage) -> anyhow::Result<NetworkP
         bail!("missing packet payload")
     };
 
+    let compression = match packet.compression() {
+        network::PayloadCompression::None => PayloadCompression::None,
+        network::PayloadCompression::Zstd => PayloadCompression::Zstd,
+        compression => bail!("Unsupported payload compression {:?}", compression),
+    };
+
     Ok(NetworkPayload::NetworkPacket(NetworkPacket {
         destination,
         network_id,
         message: payload,
+        compression,
     }))
 }
 
@@ -207,6 +252,8 @@ fn deserialize_request(root: &network::NetworkMessage) -> anyhow::Result<Network
                     wire_versions,
                     genesis_blocks,
                     proof: Vec::new(),
+                    capabilities: PeerCapabilities(handshake.capabilities()),
+                    services: deserialize_services(handshake.services()),
                 })))
             } else {
                 bail!("missing handshake payload")
@@ -223,6 +270,50 @@ fn deserialize_request(root: &network::NetworkMessage) -> anyhow::Result<Network
                 bail!("missing network id in a join/leave network request")
             }
         }
+        network::RequestVariant::Disconnect => {
+            if let Some(disconnect) = request.payload_as_disconnect() {
+                let reason = match disconnect.reason() {
+                    network::DisconnectReason::TooManyConnections => {
+                        DisconnectReason::TooManyConnections
+                    }
+                    network::DisconnectReason::Banned => DisconnectReason::Banned,
+                    network::DisconnectReason::IncompatibleGenesis => {
+                        DisconnectReason::IncompatibleGenesis
+                    }
+                    network::DisconnectReason::CatchUpTimeout => DisconnectReason::CatchUpTimeout,
+                    network::DisconnectReason::Unresponsive => DisconnectReason::Unresponsive,
+                    network::DisconnectReason::ShuttingDown => DisconnectReason::ShuttingDown,
+                    // reasons added in later versions are not an error
+                    _ => DisconnectReason::Unspecified,
+                };
+                let retry_after = match disconnect.retry_after() {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                };
+                Ok(NetworkPayload::NetworkRequest(NetworkRequest::Disconnect(Disconnect {
+                    reason,
+                    retry_after,
+                })))
+            } else {
+                bail!("missing disconnect payload")
+            }
+        }
+        network::RequestVariant::Inventory | network::RequestVariant::GetObjects => {
+            if let Some(hashes) =
+                request.payload_as_object_hashes().and_then(|payload| payload.hashes())
+            {
+                let hashes = hashes.bytes().chunks_exact(32);
+                ensure!(hashes.remainder().is_empty(), "mangled object hashes");
+                let hashes = hashes.map(|hash| ObjectHash::try_from(hash).unwrap()).collect();
+                Ok(NetworkPayload::NetworkRequest(match request.variant() {
+                    network::RequestVariant::Inventory => NetworkRequest::Inventory(hashes),
+                    network::RequestVariant::GetObjects => NetworkRequest::GetObjects(hashes),
+                    _ => unreachable!(),
+                }))
+            } else {
+                bail!("missing object hashes in an inventory request")
+            }
+        }
         msg => bail!("Unsupported request variant {:?}", msg),
     }
 }
@@ -274,6 +365,8 @@ fn deserialize_response(root: &network::NetworkMessage) -> anyhow::Result<Networ
                         id: P2PNodeId(peer.id()),
                         addr,
                         peer_type,
+                        capabilities: PeerCapabilities(peer.capabilities()),
+                        services: deserialize_services(peer.services()),
                     };
 
                     list.push(peer);
@@ -288,13 +381,54 @@ fn deserialize_response(root: &network::NetworkMessage) -> anyhow::Result<Networ
     }
 }
 
+/// Read the advertised service endpoints. Endpoints of kinds that are not known
+/// to this version of the node are ignored.
+fn deserialize_services(
+    services: Option<
+        flatbuffers::Vector<'_, flatbuffers::ForwardsUOffset<network::ServiceEndpoint<'_>>>,
+    >,
+) -> PeerServices {
+    let mut result = PeerServices::default();
+    for endpoint in services.iter().flat_map(|services| services.iter()) {
+        if endpoint.kind() == network::ServiceKind::Grpc2 {
+            result.grpc2_port = Some(endpoint.port());
+        }
+    }
+    result
+}
+
 // serialization
 
+fn serialize_services<'a>(
+    builder: &mut FlatBufferBuilder<'a>,
+    services: &PeerServices,
+) -> Option<
+    flatbuffers::WIPOffset<
+        flatbuffers::Vector<'a, flatbuffers::ForwardsUOffset<network::ServiceEndpoint<'a>>>,
+    >,
+> {
+    let mut endpoints = Vec::new();
+    if let Some(port) = services.grpc2_port {
+        endpoints.push(network::ServiceEndpoint::create(builder, &network::ServiceEndpointArgs {
+            kind: network::ServiceKind::Grpc2,
+            port,
+        }));
+    }
+    if endpoints.is_empty() {
+        None
+    } else {
+        Some(builder.create_vector(&endpoints))
+    }
+}
+
 fn serialize_packet(
     builder: &mut FlatBufferBuilder,
-    packet: &NetworkPacket,
+    destination: &PacketDestination,
+    network_id: NetworkId,
+    payload: &[u8],
+    compression: PayloadCompression,
 ) -> io::Result<flatbuffers::WIPOffset<flatbuffers::UnionWIPOffset>> {
-    let destination_offset = match packet.destination {
+    let destination_offset = match destination {
         PacketDestination::Direct(target_id) => {
             network::Destination::create(builder, &network::DestinationArgs {
                 variant: network::Direction::Direct,
@@ -309,12 +443,19 @@ fn serialize_packet(
         }
     };
 
-    let payload_offset = builder.create_vector(&packet.message);
+    // the builder aligns the vector, whether the payload is compressed or not
+    let payload_offset = builder.create_vector(payload);
+
+    let payload_compression = match compression {
+        PayloadCompression::None => network::PayloadCompression::None,
+        PayloadCompression::Zstd => network::PayloadCompression::Zstd,
+    };
 
     let packet_offset = network::NetworkPacket::create(builder, &network::NetworkPacketArgs {
         destination: Some(destination_offset),
-        network_id:  packet.network_id.id,
+        network_id:  network_id.id,
         payload:     Some(payload_offset),
+        compression: payload_compression,
     })
     .as_union_value();
 
@@ -388,8 +529,10 @@ fn serialize_request(
             }
             let genesis_blocks_offset = Some(builder.end_vector(genesis_blocks.len()));
 
+            let services_offset = serialize_services(builder, &handshake.services);
+
             let offset = network::Handshake::create(builder, &network::HandshakeArgs {
-                version:        0,
+                version:        HANDSHAKE_MESSAGE_VERSION,
                 node_id:        handshake.remote_id.as_raw(),
                 port:           handshake.remote_port,
                 network_ids:    nets_offset,
@@ -397,6 +540,8 @@ fn serialize_request(
                 wire_versions:  wire_version_offset,
                 genesis_blocks: genesis_blocks_offset,
                 zk:             None,
+                capabilities:   handshake.capabilities.0,
+                services:       services_offset,
             });
             (
                 network::RequestVariant::Handshake,
@@ -424,6 +569,45 @@ fn serialize_request(
                 Some(offset.as_union_value()),
             )
         }
+        NetworkRequest::Disconnect(disconnect) => {
+            let reason = match disconnect.reason {
+                DisconnectReason::Unspecified => network::DisconnectReason::Unspecified,
+                DisconnectReason::TooManyConnections => {
+                    network::DisconnectReason::TooManyConnections
+                }
+                DisconnectReason::Banned => network::DisconnectReason::Banned,
+                DisconnectReason::IncompatibleGenesis => {
+                    network::DisconnectReason::IncompatibleGenesis
+                }
+                DisconnectReason::CatchUpTimeout => network::DisconnectReason::CatchUpTimeout,
+                DisconnectReason::Unresponsive => network::DisconnectReason::Unresponsive,
+                DisconnectReason::ShuttingDown => network::DisconnectReason::ShuttingDown,
+            };
+            let offset = network::Disconnect::create(builder, &network::DisconnectArgs {
+                reason,
+                // sub-second delays are rounded up so that they are not lost
+                retry_after: disconnect.retry_after.map_or(0, |retry_after| {
+                    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+                }),
+            });
+            (
+                network::RequestVariant::Disconnect,
+                network::RequestPayload::Disconnect,
+                Some(offset.as_union_value()),
+            )
+        }
+        NetworkRequest::Inventory(hashes) | NetworkRequest::GetObjects(hashes) => {
+            let hashes_offset = Some(builder.create_vector(&hashes.concat()));
+            let offset = network::ObjectHashes::create(builder, &network::ObjectHashesArgs {
+                hashes: hashes_offset,
+            });
+            let variant = if let NetworkRequest::Inventory(_) = request {
+                network::RequestVariant::Inventory
+            } else {
+                network::RequestVariant::GetObjects
+            };
+            (variant, network::RequestPayload::ObjectHashes, Some(offset.as_union_value()))
+        }
     };
 
     let request_offset = network::NetworkRequest::create(builder, &network::NetworkRequestArgs {
@@ -468,11 +652,15 @@ fn serialize_response(
                     PeerType::Bootstrapper => network::PeerVariant::Bootstrapper,
                 };
 
+                let services_offset = serialize_services(builder, &peer.services);
+
                 let peer = network::P2PPeer::create(builder, &network::P2PPeerArgs {
-                    id:      peer.id.as_raw(),
-                    addr:    Some(ip_offset),
-                    port:    peer.addr.port(),
-                    variant: peer_type,
+                    id:           peer.id.as_raw(),
+                    addr:         Some(ip_offset),
+                    port:         peer.addr.port(),
+                    variant:      peer_type,
+                    capabilities: peer.capabilities.0,
+                    services:     services_offset,
                 });
                 peers.push(peer);
             }
@@ -1,16 +1,26 @@
 use semver::Version;
 
 use crate::{
-    common::{get_current_stamp, p2p_peer::P2PPeer, P2PNodeId, PeerType},
+    common::{
+        get_current_stamp,
+        p2p_peer::{P2PPeer, PeerCapabilities, PeerServices, RemotePeerId},
+        P2PNodeId, PeerType,
+    },
     network::{
-        Handshake, NetworkId, NetworkMessage, NetworkPayload, NetworkRequest, NetworkResponse,
+        compression::{compress_packet, decompress_packet},
+        frames::{PacketFrames, ReceivedFrame, MAX_RELAY_FRAME_OVERHEAD},
+        Disconnect, DisconnectReason, Handshake, NetworkId, NetworkMessage, NetworkPacket,
+        NetworkPayload, NetworkRequest, NetworkResponse, PacketDestination, PayloadCompression,
+        WIRE_PROTOCOL_CURRENT_VERSION,
     },
+    stats_export_service::StatsExportService,
     test_utils::{create_random_packet, dummy_regenesis_blocks},
 };
 
 use std::{
     io::Cursor,
     net::{IpAddr, SocketAddr},
+    time::Duration,
 };
 
 macro_rules! test_s11n {
@@ -48,6 +58,12 @@ test_s11n!(
         wire_versions:  vec![0, 1, 2],
         genesis_blocks: dummy_regenesis_blocks(),
         proof:          Vec::new(),
+        capabilities:   PeerCapabilities(
+            PeerCapabilities::ARCHIVAL.0 | PeerCapabilities::PUBLIC_GRPC2.0 | 1 << 40,
+        ),
+        services:       PeerServices {
+            grpc2_port: Some(20000),
+        },
     }))
 );
 test_s11n!(
@@ -58,6 +74,28 @@ test_s11n!(
     s11n_req_leave_net,
     NetworkPayload::NetworkRequest(NetworkRequest::LeaveNetwork(NetworkId::from(1337),))
 );
+test_s11n!(
+    s11n_req_disconnect,
+    NetworkPayload::NetworkRequest(NetworkRequest::Disconnect(Disconnect::new(
+        DisconnectReason::Banned,
+        Some(Duration::from_secs(600)),
+    )))
+);
+test_s11n!(
+    s11n_req_disconnect_without_retry,
+    NetworkPayload::NetworkRequest(NetworkRequest::Disconnect(Disconnect::new(
+        DisconnectReason::ShuttingDown,
+        None,
+    )))
+);
+test_s11n!(
+    s11n_req_inventory,
+    NetworkPayload::NetworkRequest(NetworkRequest::Inventory(vec![[1; 32], [2; 32]]))
+);
+test_s11n!(
+    s11n_req_get_objects,
+    NetworkPayload::NetworkRequest(NetworkRequest::GetObjects(vec![[3; 32]]))
+);
 
 test_s11n!(s11n_resp_pong, NetworkPayload::NetworkResponse(NetworkResponse::Pong));
 
@@ -66,14 +104,20 @@ test_s11n!(
     NetworkPayload::NetworkResponse(NetworkResponse::PeerList(
         [
             P2PPeer {
-                id:        P2PNodeId(1234567890123),
-                addr:      SocketAddr::new(IpAddr::from([1, 2, 3, 4]), 80),
-                peer_type: PeerType::Bootstrapper,
+                id:           P2PNodeId(1234567890123),
+                addr:         SocketAddr::new(IpAddr::from([1, 2, 3, 4]), 80),
+                peer_type:    PeerType::Bootstrapper,
+                capabilities: Default::default(),
+                services:     Default::default(),
             },
             P2PPeer {
-                id:        P2PNodeId(1),
-                addr:      SocketAddr::new(IpAddr::from([8, 7, 6, 5, 4, 3, 2, 1]), 8080),
-                peer_type: PeerType::Node,
+                id:           P2PNodeId(1),
+                addr:         SocketAddr::new(IpAddr::from([8, 7, 6, 5, 4, 3, 2, 1]), 8080),
+                peer_type:    PeerType::Node,
+                capabilities: PeerCapabilities::RELAYS_TRANSACTIONS,
+                services:     PeerServices {
+                    grpc2_port: Some(20000),
+                },
             },
         ]
         .to_vec(),
@@ -90,6 +134,111 @@ fn s11n_packet() {
     assert_eq!(deserialized.payload, msg.payload);
 }
 
+#[test]
+fn s11n_compressed_packet() {
+    let stats = StatsExportService::new(Vec::new()).unwrap();
+    let mut msg = create_random_packet(64 * 1024);
+    let original = if let NetworkPayload::NetworkPacket(ref mut packet) = msg.payload {
+        let original = packet.message.clone();
+        assert!(compress_packet(packet, &stats).unwrap());
+        assert_eq!(packet.compression, PayloadCompression::Zstd);
+        original
+    } else {
+        unreachable!()
+    };
+    let mut buffer = Cursor::new(Vec::new());
+
+    msg.serialize(&mut buffer).unwrap();
+    let mut deserialized = NetworkMessage::deserialize(buffer.get_ref()).unwrap();
+    assert_eq!(deserialized.payload, msg.payload);
+    if let NetworkPayload::NetworkPacket(ref mut packet) = deserialized.payload {
+        decompress_packet(packet, &stats).unwrap();
+        assert_eq!(packet.compression, PayloadCompression::None);
+        assert_eq!(packet.message, original);
+    }
+}
+
+#[test]
+fn s11n_packet_alignment() {
+    // payloads of every length modulo the alignment of the fields, compressed or
+    // not, give buffers that pass the verifier, which checks the alignment
+    for len in 0..16 {
+        for compression in [PayloadCompression::None, PayloadCompression::Zstd] {
+            let msg = NetworkMessage {
+                created:  get_current_stamp(),
+                received: None,
+                payload:  NetworkPayload::NetworkPacket(NetworkPacket {
+                    destination: PacketDestination::Direct(RemotePeerId::from(3)),
+                    network_id: NetworkId::from(100),
+                    message: vec![7u8; len],
+                    compression,
+                }),
+            };
+            let mut buffer = Cursor::new(Vec::new());
+            msg.serialize(&mut buffer).unwrap();
+            let deserialized = NetworkMessage::deserialize(buffer.get_ref()).unwrap();
+            assert_eq!(deserialized.payload, msg.payload);
+        }
+    }
+}
+
+#[test]
+fn s11n_packet_frames() {
+    let stats = StatsExportService::new(Vec::new()).unwrap();
+    let payload =
+        if let NetworkPayload::NetworkPacket(packet) = create_random_packet(64 * 1024).payload {
+            packet.message
+        } else {
+            unreachable!()
+        };
+    let destination = PacketDestination::Broadcast(Vec::new());
+    let network_id = NetworkId::from(100);
+    let frames = PacketFrames::serialize(&destination, network_id, &payload, 1024, &stats).unwrap();
+
+    let packet = |frame: &[u8]| {
+        if let NetworkPayload::NetworkPacket(packet) =
+            NetworkMessage::deserialize(frame).unwrap().payload
+        {
+            packet
+        } else {
+            panic!("The frame does not contain a packet.")
+        }
+    };
+    // peers that do not support compression get the uncompressed payload
+    let plain = packet(frames.for_wire_version(1));
+    assert_eq!(plain, NetworkPacket {
+        destination: PacketDestination::Broadcast(Vec::new()),
+        network_id,
+        message: payload.clone(),
+        compression: PayloadCompression::None,
+    });
+    let compressed_frame = frames.for_wire_version(WIRE_PROTOCOL_CURRENT_VERSION);
+    assert_eq!(packet(compressed_frame).compression, PayloadCompression::Zstd);
+
+    // a compressed frame is relayed as is, and serialized again without compression
+    let received = ReceivedFrame {
+        bytes: compressed_frame.clone(),
+        network_id,
+        compressed: true,
+    };
+    let relayed = PacketFrames::relay(&received, &payload).unwrap();
+    assert_eq!(relayed.for_wire_version(WIRE_PROTOCOL_CURRENT_VERSION), compressed_frame);
+    assert_eq!(packet(relayed.for_wire_version(1)), plain);
+
+    // a frame that is much larger than its payload is serialized again
+    let mut padded = frames.for_wire_version(1).to_vec();
+    padded.resize(payload.len() + MAX_RELAY_FRAME_OVERHEAD + 1, 0);
+    let received = ReceivedFrame {
+        bytes: padded.into(),
+        network_id,
+        compressed: false,
+    };
+    let relayed = PacketFrames::relay(&received, &payload).unwrap();
+    let relayed_frame = relayed.for_wire_version(WIRE_PROTOCOL_CURRENT_VERSION);
+    assert!(relayed_frame.len() <= payload.len() + MAX_RELAY_FRAME_OVERHEAD);
+    assert_eq!(packet(relayed_frame), plain);
+}
+
 quickcheck! {
     fn s11n_fuzzed(bytes: Vec<u8>) -> bool {
         let _ = NetworkMessage::deserialize(&bytes);
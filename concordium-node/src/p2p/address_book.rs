@@ -0,0 +1,523 @@
+//! The persisted address book of peers.
+//!
+//! The node remembers the addresses of the peers it learns about and connects
+//! to in the `peers` store, keyed by the address. Each entry records where the
+//! address was learned from, when the peer was first and last seen, when the
+//! last handshake with it succeeded, how many connection attempts have failed
+//! since then, and its latency. Entries of peers that have not been seen for
+//! too long, or that failed too many connection attempts in a row, age out, and
+//! on startup the node first dials the peers that were good most recently.
+//!
+//! Since any peer can share addresses with the node, only a limited number of
+//! addresses is taken from each peer list, unroutable addresses are ignored,
+//! and the size of the address book is bounded. When it is full, shared
+//! addresses the node never completed a handshake with make room for new ones,
+//! but peers the node did connect to are never evicted by shared addresses.
+
+use crate::{
+    common::{get_current_stamp, p2p_peer::P2PPeer, P2PNodeId, PeerStats},
+    p2p::P2PNode,
+};
+use anyhow::bail;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use concordium_base::common::{Buffer, Deserial, Serial};
+use rkv::{StoreOptions, Value};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+/// The lmdb store name of the address book.
+const PEERS_STORE_NAME: &str = "peers";
+
+/// The number of consecutive failed connection attempts after which a peer is
+/// removed from the address book.
+pub const MAX_CONNECTION_FAILURES: u32 = 10;
+
+/// The maximum number of addresses taken from a single peer list. Peer lists
+/// are normally much shorter than this.
+pub const MAX_LEARNED_PEERS_PER_LIST: usize = 32;
+
+/// The maximum number of entries in the address book.
+pub const MAX_ADDRESS_BOOK_SIZE: usize = 2048;
+
+/// Where the address of a peer was learned from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerSource {
+    /// The address was given in the configuration or via the API.
+    Given,
+    /// The address was shared by a bootstrapper.
+    Bootstrapper,
+    /// The address was shared by a regular node.
+    PeerExchange,
+    /// The peer connected to the node.
+    Incoming,
+    /// The peer was one of the anchors saved when the node last shut down.
+    Anchor,
+}
+
+impl fmt::Display for PeerSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerSource::Given => write!(f, "given"),
+            PeerSource::Bootstrapper => write!(f, "bootstrapper"),
+            PeerSource::PeerExchange => write!(f, "peer-exchange"),
+            PeerSource::Incoming => write!(f, "incoming"),
+            PeerSource::Anchor => write!(f, "anchor"),
+        }
+    }
+}
+
+/// What is known about the peer at an address. All timestamps are in
+/// milliseconds since the Unix epoch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressInfo {
+    /// The node id the peer last presented, if known.
+    pub node_id:        Option<P2PNodeId>,
+    pub source:         PeerSource,
+    pub first_seen:     u64,
+    /// When the node was last connected to the peer or, if it never was, when
+    /// the address was learned.
+    pub last_seen:      u64,
+    /// When the last handshake with the peer succeeded, if ever.
+    pub last_handshake: Option<u64>,
+    /// The number of failed connection attempts since the last successful
+    /// handshake.
+    pub failures:       u32,
+    /// The last measured latency of the connection to the peer, in
+    /// milliseconds.
+    pub latency:        Option<u64>,
+}
+
+impl AddressInfo {
+    fn new(node_id: Option<P2PNodeId>, source: PeerSource, now: u64) -> Self {
+        AddressInfo {
+            node_id,
+            source,
+            first_seen: now,
+            last_seen: now,
+            last_handshake: None,
+            failures: 0,
+            latency: None,
+        }
+    }
+
+    /// Whether the entry should be removed from the address book.
+    pub fn is_stale(&self, now: u64, max_age: u64) -> bool {
+        self.failures >= MAX_CONNECTION_FAILURES || now.saturating_sub(self.last_seen) > max_age
+    }
+
+    /// Peers were stored without any details before the address book was
+    /// introduced. Those entries are read as peers that were connected at
+    /// startup.
+    fn from_stored(value: Value, now: u64) -> anyhow::Result<Self> {
+        match value {
+            Value::U64(_) => Ok(AddressInfo {
+                last_handshake: Some(now),
+                ..AddressInfo::new(None, PeerSource::PeerExchange, now)
+            }),
+            Value::Blob(mut bytes) => AddressInfo::deserial(&mut bytes),
+            _ => bail!("Unexpected type of a stored peer"),
+        }
+    }
+}
+
+fn serial_option_u64<W: Buffer + WriteBytesExt>(value: Option<u64>, target: &mut W) {
+    match value {
+        None => target.write_u8(0).expect("Writing to memory is infallible."),
+        Some(value) => {
+            target.write_u8(1).expect("Writing to memory is infallible.");
+            value.serial(target);
+        }
+    }
+}
+
+fn deserial_option_u64<R: ReadBytesExt>(source: &mut R) -> anyhow::Result<Option<u64>> {
+    match source.read_u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(u64::deserial(source)?)),
+        _ => bail!("Invalid optional value"),
+    }
+}
+
+impl Serial for AddressInfo {
+    fn serial<W: Buffer + WriteBytesExt>(&self, target: &mut W) {
+        serial_option_u64(self.node_id.map(|id| id.0), target);
+        let source = match self.source {
+            PeerSource::Given => 0u8,
+            PeerSource::Bootstrapper => 1u8,
+            PeerSource::PeerExchange => 2u8,
+            PeerSource::Incoming => 3u8,
+            PeerSource::Anchor => 4u8,
+        };
+        source.serial(target);
+        self.first_seen.serial(target);
+        self.last_seen.serial(target);
+        serial_option_u64(self.last_handshake, target);
+        self.failures.serial(target);
+        serial_option_u64(self.latency, target);
+    }
+}
+
+impl Deserial for AddressInfo {
+    fn deserial<R: ReadBytesExt>(source: &mut R) -> anyhow::Result<Self> {
+        let node_id = deserial_option_u64(source)?.map(P2PNodeId);
+        let peer_source = match source.read_u8()? {
+            0 => PeerSource::Given,
+            1 => PeerSource::Bootstrapper,
+            2 => PeerSource::PeerExchange,
+            3 => PeerSource::Incoming,
+            4 => PeerSource::Anchor,
+            _ => bail!("Invalid peer source"),
+        };
+        Ok(AddressInfo {
+            node_id,
+            source: peer_source,
+            first_seen: u64::deserial(source)?,
+            last_seen: u64::deserial(source)?,
+            last_handshake: deserial_option_u64(source)?,
+            failures: u32::deserial(source)?,
+            latency: deserial_option_u64(source)?,
+        })
+    }
+}
+
+/// An entry of the address book.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressBookEntry {
+    pub addr: SocketAddr,
+    pub info: AddressInfo,
+}
+
+/// Sort the entries so that the peers with the most recent successful
+/// handshake come first. Ties are broken by the number of failures and then
+/// by the latency.
+pub fn sort_by_preference(entries: &mut [AddressBookEntry]) {
+    entries.sort_by_key(|entry| {
+        (
+            Reverse(entry.info.last_handshake),
+            entry.info.failures,
+            entry.info.latency.unwrap_or(u64::MAX),
+        )
+    });
+}
+
+impl P2PNode {
+    /// Apply the updates to the entries of the given addresses in a single
+    /// transaction. The update receives the data of the address, its current
+    /// entry, if there is one, and the current time, and returns the new
+    /// entry, or `None` to leave the entry as it is.
+    fn update_address_book<T>(
+        &self,
+        updates: impl IntoIterator<Item = (SocketAddr, T)>,
+        update: impl Fn(T, Option<AddressInfo>, u64) -> Option<AddressInfo>,
+    ) -> anyhow::Result<()> {
+        let Ok(kvs_env) = self.kvs.read() else {
+            bail!("Couldn't update the address book: couldn't obtain a lock over the kvs");
+        };
+        let peers_store = kvs_env.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
+        let mut writer = kvs_env.write()?;
+        let now = get_current_stamp();
+        for (addr, data) in updates {
+            let key = serde_json::to_vec::<SocketAddr>(&addr)?;
+            let current = peers_store
+                .get(&writer, &key)?
+                .map(|value| AddressInfo::from_stored(value, now))
+                .transpose()?;
+            if let Some(info) = update(data, current, now) {
+                let mut value = Vec::new();
+                info.serial(&mut value);
+                peers_store.put(&mut writer, &key, &Value::Blob(&value))?;
+            }
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Check whether a shared address is worth keeping, i.e., whether it can
+    /// be connected to and is not the node's own address. Loopback addresses
+    /// are only accepted if the node itself listens on one.
+    fn is_routable(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip();
+        let is_broadcast = match ip {
+            IpAddr::V4(ip) => ip.is_broadcast(),
+            IpAddr::V6(_) => false,
+        };
+        addr.port() != 0
+            && !ip.is_unspecified()
+            && !ip.is_multicast()
+            && !is_broadcast
+            && (!ip.is_loopback() || self.self_peer.addr.ip().is_loopback())
+            && addr != self.self_peer.addr
+    }
+
+    /// Add the peers shared with the node to the address book, unless they are
+    /// already in it. At most [`MAX_LEARNED_PEERS_PER_LIST`] routable
+    /// addresses are taken from the list. If the address book is full, shared
+    /// addresses the node never completed a handshake with are evicted, least
+    /// recently seen first, to make room; if there are none, the remaining
+    /// peers are dropped.
+    pub fn record_learned_peers(
+        &self,
+        peers: &[P2PPeer],
+        source: PeerSource,
+    ) -> anyhow::Result<()> {
+        let current = self.get_address_book()?;
+        let known = current.iter().map(|entry| entry.addr).collect::<HashSet<_>>();
+        let mut seen = HashSet::new();
+        let new_peers = peers
+            .iter()
+            .filter(|peer| {
+                self.is_routable(peer.addr) && !known.contains(&peer.addr) && seen.insert(peer.addr)
+            })
+            .take(MAX_LEARNED_PEERS_PER_LIST)
+            .collect::<Vec<_>>();
+        if new_peers.is_empty() {
+            return Ok(());
+        }
+
+        let free = MAX_ADDRESS_BOOK_SIZE.saturating_sub(current.len());
+        let mut evictable = current
+            .into_iter()
+            .filter(|entry| {
+                entry.info.last_handshake.is_none() && entry.info.source != PeerSource::Given
+            })
+            .collect::<Vec<_>>();
+        evictable.sort_by_key(|entry| entry.info.last_seen);
+        let evicted = evictable
+            .into_iter()
+            .take(new_peers.len().saturating_sub(free))
+            .map(|entry| entry.addr)
+            .collect::<Vec<_>>();
+        let new_peers = &new_peers[..new_peers.len().min(free + evicted.len())];
+
+        let Ok(kvs_env) = self.kvs.read() else {
+            bail!("Couldn't update the address book: couldn't obtain a lock over the kvs");
+        };
+        let peers_store = kvs_env.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
+        let mut writer = kvs_env.write()?;
+        let now = get_current_stamp();
+        for addr in evicted {
+            debug!("Evicting {} from the full address book", addr);
+            let key = serde_json::to_vec::<SocketAddr>(&addr)?;
+            peers_store.delete(&mut writer, key)?;
+        }
+        for peer in new_peers {
+            let key = serde_json::to_vec::<SocketAddr>(&peer.addr)?;
+            let mut value = Vec::new();
+            AddressInfo::new(Some(peer.id), source, now).serial(&mut value);
+            peers_store.put(&mut writer, key, &Value::Blob(&value))?;
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Record a successful handshake with the peer at the given address. The
+    /// source is only used if the address is not in the address book yet, or
+    /// if the address is given, which takes precedence over any other source.
+    pub fn record_handshake(
+        &self,
+        addr: SocketAddr,
+        node_id: Option<P2PNodeId>,
+        source: PeerSource,
+    ) -> anyhow::Result<()> {
+        self.update_address_book([(addr, node_id)], |node_id, current, now| {
+            let mut info = current.unwrap_or_else(|| AddressInfo::new(node_id, source, now));
+            if source == PeerSource::Given {
+                info.source = source;
+            }
+            info.node_id = node_id.or(info.node_id);
+            info.last_seen = now;
+            info.last_handshake = Some(now);
+            info.failures = 0;
+            Some(info)
+        })
+    }
+
+    /// Record failed connection attempts to the given addresses. Addresses
+    /// that are not in the address book are ignored.
+    pub fn record_failed_connections(&self, addrs: &[SocketAddr]) -> anyhow::Result<()> {
+        if addrs.is_empty() {
+            return Ok(());
+        }
+        self.update_address_book(addrs.iter().map(|&addr| (addr, ())), |(), current, _| {
+            current.map(|mut info| {
+                info.failures = info.failures.saturating_add(1);
+                info
+            })
+        })
+    }
+
+    /// Refresh the entries of the currently connected peers.
+    pub fn record_connected_peers(&self, peers: &[PeerStats]) -> anyhow::Result<()> {
+        if peers.is_empty() {
+            return Ok(());
+        }
+        self.update_address_book(
+            peers.iter().map(|peer| (peer.external_address(), peer)),
+            |peer, current, now| {
+                current.map(|mut info| {
+                    info.node_id = Some(peer.self_id);
+                    info.last_seen = now;
+                    info.latency = Some(peer.latency);
+                    info
+                })
+            },
+        )
+    }
+
+    /// Obtain all the entries of the address book.
+    pub fn get_address_book(&self) -> anyhow::Result<Vec<AddressBookEntry>> {
+        let Ok(kvs_env) = self.kvs.read() else {
+            bail!("Couldn't read the address book: couldn't obtain a lock over the kvs");
+        };
+        let peers_store = kvs_env.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
+        let peers_reader = kvs_env.read()?;
+        let now = get_current_stamp();
+        let mut entries = Vec::new();
+        for entry in peers_store.iter_start(&peers_reader)? {
+            let (addr_bytes, value) = entry?;
+            entries.push(AddressBookEntry {
+                addr: serde_json::from_slice(addr_bytes)?,
+                info: AddressInfo::from_stored(value, now)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Delete the stale entries from the address book, i.e., the peers that
+    /// have not been seen for longer than the configured maximum age or that
+    /// failed too many connection attempts. Returns the number of deleted
+    /// entries.
+    pub fn remove_stale_peers(&self) -> anyhow::Result<usize> {
+        let now = get_current_stamp();
+        let max_age = self.config.address_book_max_age * 1000;
+        let stale = self
+            .get_address_book()?
+            .into_iter()
+            .filter(|entry| entry.info.is_stale(now, max_age))
+            .collect::<Vec<_>>();
+        if stale.is_empty() {
+            return Ok(0);
+        }
+        let Ok(kvs_env) = self.kvs.read() else {
+            bail!("Couldn't remove stale peers: couldn't obtain a lock over the kvs");
+        };
+        let peers_store = kvs_env.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
+        let mut writer = kvs_env.write()?;
+        for entry in stale.iter() {
+            debug!("Removing stale peer {} from the address book", entry.addr);
+            let key = serde_json::to_vec::<SocketAddr>(&entry.addr)?;
+            peers_store.delete(&mut writer, key)?;
+        }
+        writer.commit()?;
+        Ok(stale.len())
+    }
+
+    /// Remove a peer from the address book.
+    pub fn remove_persisted_peer(&self, peer_addr: SocketAddr) -> anyhow::Result<()> {
+        let Ok(kv) = self.kvs.read() else {
+            bail!("Could not acqure lock over lmdb");
+        };
+        let peers_store = kv.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
+        let key = serde_json::to_vec::<SocketAddr>(&peer_addr)?;
+        let mut writer = kv.write()?;
+        match peers_store.delete(&mut writer, key) {
+            // the peer might not be in the address book
+            Ok(()) | Err(rkv::StoreError::KeyValuePairNotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Clear all peers in the address book.
+    pub fn clear_persisted_peers(&self) -> anyhow::Result<()> {
+        let Ok(kvs_env) = self.kvs.read() else {
+            bail!("Couldn't clear the address book: couldn't obtain a lock over the kvs");
+        };
+        let peers_store = kvs_env.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
+        let mut writer = kvs_env.write()?;
+        peers_store.clear(&mut writer)?;
+        writer.commit().map_err(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{common::PeerType, test_utils::*};
+
+    #[test]
+    fn test_address_book() -> anyhow::Result<()> {
+        let (node, dp) =
+            make_node_and_sync(next_available_port(), vec![100], PeerType::Node, vec![])?;
+        let good: SocketAddr = "192.0.2.1:8888".parse()?;
+        let learned: SocketAddr = "192.0.2.2:8888".parse()?;
+        let failing: SocketAddr = "192.0.2.3:8888".parse()?;
+
+        node.record_handshake(failing, Some(P2PNodeId(3)), PeerSource::Given)?;
+        node.record_learned_peers(
+            &[P2PPeer {
+                id:           P2PNodeId(2),
+                addr:         learned,
+                peer_type:    PeerType::Node,
+                capabilities: Default::default(),
+                services:     Default::default(),
+            }],
+            PeerSource::Bootstrapper,
+        )?;
+        node.record_handshake(good, Some(P2PNodeId(1)), PeerSource::Incoming)?;
+        node.record_failed_connections(&[failing])?;
+
+        let mut entries = node.get_address_book()?;
+        sort_by_preference(&mut entries);
+        let addrs = entries.iter().map(|entry| entry.addr).collect::<Vec<_>>();
+        assert_eq!(addrs, vec![good, failing, learned]);
+        assert_eq!(entries[1].info.source, PeerSource::Given);
+        assert_eq!(entries[1].info.failures, 1);
+        assert_eq!(entries[2].info.source, PeerSource::Bootstrapper);
+        assert_eq!(entries[2].info.node_id, Some(P2PNodeId(2)));
+        assert_eq!(entries[2].info.last_handshake, None);
+
+        // a successful handshake resets the failures
+        node.record_handshake(failing, None, PeerSource::Incoming)?;
+        let entry = node.get_address_book()?.into_iter().find(|entry| entry.addr == failing);
+        assert_eq!(
+            entry.map(|entry| (entry.info.failures, entry.info.node_id)),
+            Some((0, Some(P2PNodeId(3))))
+        );
+
+        // peers that fail too often age out
+        for _ in 0..MAX_CONNECTION_FAILURES {
+            node.record_failed_connections(&[learned])?;
+        }
+        assert_eq!(node.remove_stale_peers()?, 1);
+        assert_eq!(node.get_address_book()?.len(), 2);
+
+        // unroutable addresses are ignored, and only a limited number of addresses is
+        // taken from a single peer list
+        let shared = |addr: SocketAddr| P2PPeer {
+            id: P2PNodeId(u64::from(addr.port())),
+            addr,
+            peer_type: PeerType::Node,
+            capabilities: Default::default(),
+            services: Default::default(),
+        };
+        node.record_learned_peers(
+            &[shared("0.0.0.0:8888".parse()?), shared("192.0.2.4:0".parse()?)],
+            PeerSource::PeerExchange,
+        )?;
+        assert_eq!(node.get_address_book()?.len(), 2);
+        let peers = (0..2 * MAX_LEARNED_PEERS_PER_LIST as u16)
+            .map(|i| shared(SocketAddr::new("198.51.100.1".parse().unwrap(), 9000 + i)))
+            .collect::<Vec<_>>();
+        node.record_learned_peers(&peers, PeerSource::PeerExchange)?;
+        assert_eq!(node.get_address_book()?.len(), 2 + MAX_LEARNED_PEERS_PER_LIST);
+
+        stop_node_delete_dirs(dp, node);
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+//! Peer allowlist handling for permissioned networks.
+//!
+//! If the node is configured with a peer allowlist file it only completes
+//! handshakes with peers whose static key, or the node id derived from it, is
+//! listed in that file.
+
+use crate::{
+    common::P2PNodeId,
+    connection::ConnChange,
+    network::{Disconnect, DisconnectReason},
+    p2p::{
+        connectivity::AcceptFailureReason,
+        identity::{node_id_from_public_key, NodePublicKey},
+        P2PNode,
+    },
+    read_or_die, write_or_die,
+};
+use anyhow::{bail, Context};
+use noiseexplorer_xx::consts::DHLEN;
+use std::{path::Path, str::FromStr};
+
+/// A single entry of the peer allowlist.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AllowlistEntry {
+    /// A peer identified by its static key.
+    Key(NodePublicKey),
+    /// A peer identified by its node id.
+    Id(P2PNodeId),
+}
+
+impl AllowlistEntry {
+    /// The node id of the peer the entry refers to.
+    pub fn id(&self) -> P2PNodeId {
+        match self {
+            AllowlistEntry::Key(key) => node_id_from_public_key(key),
+            AllowlistEntry::Id(id) => *id,
+        }
+    }
+}
+
+impl FromStr for AllowlistEntry {
+    type Err = anyhow::Error;
+
+    /// Static keys are given as 64 hex characters, node ids as at most 16 hex
+    /// characters.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.len() == 2 * DHLEN {
+            let mut key = [0u8; DHLEN];
+            hex::decode_to_slice(s, &mut key).context("Invalid static key.")?;
+            Ok(AllowlistEntry::Key(key))
+        } else if s.len() <= 16 {
+            Ok(AllowlistEntry::Id(s.parse()?))
+        } else {
+            bail!("Expected either a static key ({} hex characters) or a node id.", 2 * DHLEN)
+        }
+    }
+}
+
+/// The set of peers the node is allowed to connect to in permissioned mode.
+#[derive(Debug, Default, Clone)]
+pub struct PeerAllowlist {
+    entries: Vec<AllowlistEntry>,
+}
+
+impl PeerAllowlist {
+    /// Read the allowlist from a file. The file contains one entry per line,
+    /// either a hex encoded static key or a node id. Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read the peer allowlist {}.", path.display()))?;
+        let mut entries = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = line.parse().with_context(|| {
+                format!("Invalid entry on line {} of the peer allowlist {}.", i + 1, path.display())
+            })?;
+            entries.push(entry);
+        }
+        Ok(Self {
+            entries,
+        })
+    }
+
+    /// Check whether the peer with the given static key is allowed.
+    pub fn allows_key(&self, key: &NodePublicKey) -> bool {
+        let id = node_id_from_public_key(key);
+        self.entries.iter().any(|entry| match entry {
+            AllowlistEntry::Key(k) => k == key,
+            AllowlistEntry::Id(i) => *i == id,
+        })
+    }
+
+    /// Check whether the peer with the given node id is allowed. Since node
+    /// ids can only be checked against the static key once the handshake is
+    /// done, this is only meant to avoid dialing peers that are certain to be
+    /// rejected.
+    pub fn allows_id(&self, id: P2PNodeId) -> bool {
+        self.entries.iter().any(|entry| entry.id() == id)
+    }
+
+    /// The entries of the allowlist.
+    pub fn entries(&self) -> &[AllowlistEntry] { &self.entries }
+}
+
+impl P2PNode {
+    /// Whether the node is running in permissioned mode, i.e., with a peer
+    /// allowlist.
+    pub fn is_permissioned(&self) -> bool { self.config.peer_allowlist_file.is_some() }
+
+    /// Check whether the peer with the given (authenticated) static key may
+    /// complete the handshake.
+    pub fn check_peer_allowed(&self, key: &NodePublicKey) -> Result<(), AcceptFailureReason> {
+        match &*read_or_die!(self.connection_handler.peer_allowlist) {
+            Some(allowlist) if !allowlist.allows_key(key) => {
+                Err(AcceptFailureReason::NotAllowlisted {
+                    id: node_id_from_public_key(key),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check whether a peer with the given node id may be connected to. This
+    /// always holds if the node is not running in permissioned mode.
+    pub fn is_id_allowed(&self, id: P2PNodeId) -> bool {
+        read_or_die!(self.connection_handler.peer_allowlist)
+            .as_ref()
+            .map_or(true, |allowlist| allowlist.allows_id(id))
+    }
+
+    /// Get the current allowlist, if the node is running in permissioned mode.
+    pub fn get_peer_allowlist(&self) -> Option<PeerAllowlist> {
+        read_or_die!(self.connection_handler.peer_allowlist).clone()
+    }
+
+    /// Reread the allowlist from the configured file and drop the connections
+    /// to peers that are no longer allowed. Connections that are still in the
+    /// handshake are left alone, since the new allowlist is checked when their
+    /// handshake completes. Returns the number of entries in the new
+    /// allowlist.
+    pub fn reload_peer_allowlist(&self) -> anyhow::Result<usize> {
+        let path = if let Some(ref path) = self.config.peer_allowlist_file {
+            path
+        } else {
+            bail!("The node is not running in permissioned mode.");
+        };
+        let allowlist = PeerAllowlist::from_file(path)?;
+        let num_entries = allowlist.entries().len();
+        info!("Reloaded the peer allowlist with {} entries", num_entries);
+
+        // the new allowlist is installed first so that the handshakes completing
+        // in the meantime are checked against it
+        *write_or_die!(self.connection_handler.peer_allowlist) = Some(allowlist.clone());
+        let tokens = read_or_die!(self.connections())
+            .values()
+            .filter(|conn| {
+                conn.is_post_handshake()
+                    && !allowlist.allows_key(&conn.low_level.remote_static_key())
+            })
+            .map(|conn| conn.token())
+            .collect::<Vec<_>>();
+        if !tokens.is_empty() {
+            info!("Dropping {} peers that are no longer in the allowlist", tokens.len());
+            let disconnect = Disconnect::new(DisconnectReason::Banned, None);
+            self.register_conn_change(ConnChange::RemoveAllByTokens(tokens, disconnect));
+        }
+        Ok(num_entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_allowlist_parsing() -> anyhow::Result<()> {
+        let key = [7u8; DHLEN];
+        let other_key = [8u8; DHLEN];
+        let id = P2PNodeId(0x1234);
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(file, "# a comment")?;
+        writeln!(file, "{}", hex::encode(key))?;
+        writeln!(file)?;
+        writeln!(file, "  {}  ", id)?;
+        let allowlist = PeerAllowlist::from_file(file.path())?;
+        assert_eq!(allowlist.entries(), &[AllowlistEntry::Key(key), AllowlistEntry::Id(id)]);
+        assert!(allowlist.allows_key(&key));
+        assert!(!allowlist.allows_key(&other_key));
+        assert!(allowlist.allows_id(node_id_from_public_key(&key)));
+        assert!(allowlist.allows_id(id));
+        assert!(!allowlist.allows_id(node_id_from_public_key(&other_key)));
+
+        writeln!(file, "not an entry")?;
+        assert!(PeerAllowlist::from_file(file.path()).is_err());
+        Ok(())
+    }
+}
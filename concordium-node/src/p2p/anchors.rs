@@ -0,0 +1,141 @@
+//! Anchor peers.
+//!
+//! When the node shuts down it remembers a few of its best outbound peers as
+//! anchors, and on the next startup it connects to them before any other
+//! peers. To eclipse a restarted node an attacker would therefore also have to
+//! control its anchors. Anchors, like all outbound peers, are never evicted to
+//! make room for other peers.
+
+use crate::{
+    common::{P2PNodeId, PeerType},
+    connection::Connection,
+    p2p::{connectivity::connect, P2PNode},
+    read_or_die, write_or_die,
+};
+use anyhow::bail;
+use rkv::{StoreOptions, Value};
+use std::{
+    cmp::Reverse,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+};
+
+/// The lmdb store name of the anchors.
+const ANCHORS_STORE_NAME: &str = "anchors";
+
+impl P2PNode {
+    /// Persist the best outbound peers as the anchors, replacing the previous
+    /// ones. The peers are ranked by their reputation score and then by their
+    /// latency. Given addresses are not anchors since the node connects to
+    /// them anyway. If there are no outbound peers, e.g. because the node lost
+    /// its connectivity just before shutting down, the previous anchors are
+    /// kept.
+    pub fn save_anchors(&self) -> anyhow::Result<()> {
+        let mut outbound = read_or_die!(self.connections())
+            .values()
+            .filter(|conn| {
+                conn.remote_peer_type() == PeerType::Node
+                    && conn.low_level.is_initiator()
+                    && !self.is_given_connection(conn)
+            })
+            .filter_map(|conn| {
+                Some((
+                    conn.remote_peer.external_addr(),
+                    conn.remote_id()?,
+                    conn.stats.score.load(Ordering::Relaxed),
+                    conn.get_latency(),
+                ))
+            })
+            .collect::<Vec<_>>();
+        if outbound.is_empty() {
+            info!("No outbound peers to save as anchors; keeping the previous anchors");
+            return Ok(());
+        }
+        outbound.sort_by_key(|&(_, _, score, latency)| (Reverse(score), latency));
+
+        let Ok(kvs_env) = self.kvs.read() else {
+            bail!("Couldn't save the anchors: couldn't obtain a lock over the kvs");
+        };
+        let anchors_store = kvs_env.open_single(ANCHORS_STORE_NAME, StoreOptions::create())?;
+        let mut writer = kvs_env.write()?;
+        anchors_store.clear(&mut writer)?;
+        for (addr, id, ..) in outbound.into_iter().take(self.config.anchor_count) {
+            info!("Saving {} ({}) as an anchor", addr, id);
+            let key = serde_json::to_vec::<SocketAddr>(&addr)?;
+            anchors_store.put(&mut writer, key, &Value::U64(id.0))?;
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Obtain the anchors saved when the node last shut down.
+    pub fn get_anchors(&self) -> anyhow::Result<Vec<(SocketAddr, P2PNodeId)>> {
+        let Ok(kvs_env) = self.kvs.read() else {
+            bail!("Couldn't read the anchors: couldn't obtain a lock over the kvs");
+        };
+        let anchors_store = kvs_env.open_single(ANCHORS_STORE_NAME, StoreOptions::create())?;
+        let reader = kvs_env.read()?;
+        let mut anchors = Vec::new();
+        for entry in anchors_store.iter_start(&reader)? {
+            let (addr_bytes, value) = entry?;
+            let Value::U64(id) = value else {
+                bail!("Unexpected type of a stored anchor");
+            };
+            anchors.push((serde_json::from_slice(addr_bytes)?, P2PNodeId(id)));
+        }
+        Ok(anchors)
+    }
+
+    /// Forget the saved anchors.
+    pub fn clear_anchors(&self) -> anyhow::Result<()> {
+        let Ok(kvs_env) = self.kvs.read() else {
+            bail!("Couldn't clear the anchors: couldn't obtain a lock over the kvs");
+        };
+        let anchors_store = kvs_env.open_single(ANCHORS_STORE_NAME, StoreOptions::create())?;
+        let mut writer = kvs_env.write()?;
+        anchors_store.clear(&mut writer)?;
+        writer.commit().map_err(|e| e.into())
+    }
+
+    /// Check whether the connection is to one of the anchors the node
+    /// connected to on startup.
+    pub fn is_anchor_connection(&self, conn: &Connection) -> bool {
+        read_or_die!(self.connection_handler.anchors).contains(&conn.remote_peer.external_addr())
+    }
+}
+
+/// Connect to the anchors saved when the node last shut down.
+pub fn connect_to_anchors(node: &Arc<P2PNode>) -> anyhow::Result<()> {
+    let anchors = node.get_anchors()?;
+    if anchors.is_empty() {
+        return Ok(());
+    }
+    info!("Connecting to {} anchor peers", anchors.len());
+    write_or_die!(node.connection_handler.anchors).extend(anchors.iter().map(|&(addr, _)| addr));
+    for (addr, id) in anchors {
+        if let Err(e) = connect(node, PeerType::Node, addr, Some(id), true) {
+            warn!("Could not connect to the anchor {}: {}", addr, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_anchors_are_outbound_peers() -> anyhow::Result<()> {
+        let ((node, dp), (peer, peer_dp)) = make_connected_node_pair()?;
+
+        node.save_anchors()?;
+        peer.save_anchors()?;
+        assert_eq!(node.get_anchors()?, vec![(peer.self_peer.addr, peer.self_peer.id)]);
+        // the connection is inbound for the peer
+        assert!(peer.get_anchors()?.is_empty());
+
+        stop_node_delete_dirs(dp, node);
+        stop_node_delete_dirs(peer_dp, peer);
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+//! Import and export of the ban list as JSON, and its periodic synchronisation
+//! from a shared source.
+//!
+//! A ban list is a JSON array of bans. Each ban names exactly one of an `ip`, a
+//! `subnet` (in CIDR notation) or a `nodeId`, and optionally the `expiry` (in
+//! milliseconds since the Unix epoch), the `origin` (`manual` or `automatic`)
+//! and the `reason` of the ban, e.g.,
+//!
+//! ```json
+//! [
+//!   { "ip": "192.0.2.1", "reason": "invalid blocks" },
+//!   { "subnet": "10.0.0.0/8", "expiry": 1700000000000 },
+//!   { "nodeId": "00000000075bcd15", "origin": "automatic" }
+//! ]
+//! ```
+
+use crate::{
+    common::get_current_stamp,
+    p2p::{
+        bans::{BanInfo, BanOrigin, PersistedBan, PersistedBanId, MAX_BAN_REASON_LENGTH},
+        P2PNode,
+    },
+};
+use anyhow::{bail, ensure, Context};
+use std::{
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+/// A ban as represented in a JSON ban list.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct BanListEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip:      Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subnet:  Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    node_id: Option<String>,
+    #[serde(default)]
+    expiry:  Option<u64>,
+    #[serde(default = "default_origin")]
+    origin:  BanOrigin,
+    #[serde(default)]
+    reason:  String,
+}
+
+fn default_origin() -> BanOrigin { BanOrigin::Manual }
+
+impl From<PersistedBan> for BanListEntry {
+    fn from(ban: PersistedBan) -> Self {
+        let (ip, subnet, node_id) = match ban.id {
+            PersistedBanId::Ip(ip) => (Some(ip), None, None),
+            PersistedBanId::Subnet(subnet) => (None, Some(subnet.to_string()), None),
+            PersistedBanId::Node(id) => (None, None, Some(id.to_string())),
+        };
+        Self {
+            ip,
+            subnet,
+            node_id,
+            expiry: ban.info.expiry,
+            origin: ban.info.origin,
+            reason: ban.info.reason,
+        }
+    }
+}
+
+impl TryFrom<BanListEntry> for PersistedBan {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: BanListEntry) -> anyhow::Result<Self> {
+        let id = match (entry.ip, entry.subnet, entry.node_id) {
+            (Some(ip), None, None) => PersistedBanId::Ip(ip),
+            (None, Some(subnet), None) => PersistedBanId::Subnet(subnet.parse()?),
+            (None, None, Some(id)) => PersistedBanId::Node(id.parse()?),
+            _ => bail!("Each ban must have exactly one of `ip`, `subnet` and `nodeId`."),
+        };
+        ensure!(
+            entry.reason.len() <= MAX_BAN_REASON_LENGTH,
+            "The reason of the ban of {} is longer than {} bytes.",
+            id,
+            MAX_BAN_REASON_LENGTH
+        );
+        Ok(PersistedBan {
+            id,
+            info: BanInfo {
+                expiry: entry.expiry,
+                origin: entry.origin,
+                reason: entry.reason,
+            },
+        })
+    }
+}
+
+impl P2PNode {
+    /// Serialize the bans in force as a JSON ban list.
+    pub fn export_bans(&self) -> anyhow::Result<String> {
+        let entries = self.get_banlist()?.into_iter().map(BanListEntry::from).collect::<Vec<_>>();
+        Ok(serde_json::to_string_pretty(&entries)?)
+    }
+
+    /// Merge the bans of a JSON ban list into the node's bans. Bans that are
+    /// already in force with the same details and bans that have expired are
+    /// skipped. The whole list is validated first and then applied in a
+    /// single database transaction, so either all or none of the bans are
+    /// applied. Returns the number of bans that were added or updated.
+    pub fn import_bans(&self, ban_list: &str) -> anyhow::Result<usize> {
+        let entries: Vec<BanListEntry> =
+            serde_json::from_str(ban_list).context("Invalid ban list.")?;
+        let mut bans =
+            entries.into_iter().map(PersistedBan::try_from).collect::<anyhow::Result<Vec<_>>>()?;
+        let current = self.get_banlist()?;
+        let now = get_current_stamp();
+        bans.retain(|ban| !ban.info.is_expired(now) && !current.contains(ban));
+        if !bans.is_empty() {
+            self.drop_and_ban_all(&bans)?;
+        }
+        Ok(bans.len())
+    }
+
+    /// Write the bans in force to the given file as a JSON ban list.
+    pub fn export_bans_to_file(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, self.export_bans()?)
+            .with_context(|| format!("Could not write the ban list to {}.", path.display()))
+    }
+
+    /// Merge the bans of the JSON ban list in the given file into the node's
+    /// bans. Returns the number of bans that were added or updated.
+    pub fn import_bans_from_file(&self, path: &Path) -> anyhow::Result<usize> {
+        let ban_list = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read the ban list from {}.", path.display()))?;
+        self.import_bans(&ban_list)
+    }
+}
+
+/// The maximum size in bytes of a ban list downloaded from a URL. Larger
+/// responses are rejected without reading them in full.
+pub const MAX_BAN_LIST_SIZE: usize = 16 * 1024 * 1024;
+
+/// Where a shared ban list is synchronised from.
+#[derive(Clone, Debug)]
+pub enum BanListSource {
+    File(PathBuf),
+    Url(url::Url),
+}
+
+impl FromStr for BanListSource {
+    type Err = anyhow::Error;
+
+    /// Sources starting with `http://` or `https://` are URLs, anything else
+    /// is a path to a local file.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(BanListSource::Url(s.parse().context("Invalid ban list URL.")?))
+        } else {
+            Ok(BanListSource::File(PathBuf::from(s)))
+        }
+    }
+}
+
+impl fmt::Display for BanListSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BanListSource::File(path) => write!(f, "{}", path.display()),
+            BanListSource::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+impl BanListSource {
+    async fn fetch(&self, http_client: &reqwest::Client) -> anyhow::Result<String> {
+        match self {
+            BanListSource::File(path) => {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || std::fs::read_to_string(path))
+                    .await?
+                    .with_context(|| format!("Could not read the ban list from {}.", self))
+            }
+            BanListSource::Url(url) => {
+                let mut response = http_client.get(url.clone()).send().await?;
+                ensure!(
+                    response.status().is_success(),
+                    "Unable to download the ban list from {}: {}",
+                    url,
+                    response.status()
+                );
+                let too_large = || {
+                    anyhow::anyhow!(
+                        "The ban list at {} is larger than {} bytes.",
+                        url,
+                        MAX_BAN_LIST_SIZE
+                    )
+                };
+                if response.content_length().map_or(false, |len| len > MAX_BAN_LIST_SIZE as u64) {
+                    return Err(too_large());
+                }
+                // the content length is only a hint, so the size is also checked while reading
+                let mut body = Vec::new();
+                while let Some(chunk) = response.chunk().await? {
+                    if body.len() + chunk.len() > MAX_BAN_LIST_SIZE {
+                        return Err(too_large());
+                    }
+                    body.extend_from_slice(&chunk);
+                }
+                String::from_utf8(body).context("The ban list is not valid UTF-8.")
+            }
+        }
+    }
+}
+
+/// Periodically fetch the ban list from the source and merge it into the
+/// node's bans, until the node is stopped.
+pub async fn sync_ban_list(node: Arc<P2PNode>, source: BanListSource, interval: Duration) {
+    let http_client = match reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(Duration::from_secs(60))
+        .build()
+    {
+        Ok(http_client) => http_client,
+        Err(e) => {
+            error!("Cannot synchronise the ban list: {}", e);
+            return;
+        }
+    };
+    if let BanListSource::Url(url) = &source {
+        if url.scheme() != "https" {
+            warn!(
+                "The ban list is synchronised from {} without TLS, so it can be tampered with in \
+                 transit. Use an https:// URL instead.",
+                url
+            );
+        }
+    }
+    let mut interval = tokio::time::interval(interval);
+    while !node.is_network_stopped() {
+        interval.tick().await;
+        match source.fetch(&http_client).await.and_then(|ban_list| node.import_bans(&ban_list)) {
+            Ok(0) => debug!("The ban list from {} is up to date", source),
+            Ok(merged) => info!("Merged {} bans from the ban list at {}", merged, source),
+            Err(e) => warn!("Could not synchronise the ban list from {}: {:#}", source, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        common::PeerType,
+        p2p::bans::{BanOrigin, PersistedBanId, MAX_BAN_REASON_LENGTH},
+        test_utils::*,
+    };
+
+    #[test]
+    fn test_ban_list_import_export() -> anyhow::Result<()> {
+        let (node, dp) =
+            make_node_and_sync(next_available_port(), vec![100], PeerType::Node, vec![])?;
+
+        let ban_list = r#"[
+            { "ip": "192.0.2.1", "reason": "invalid blocks" },
+            { "subnet": "10.1.2.3/16", "origin": "automatic" },
+            { "nodeId": "00000000075bcd15", "expiry": 1 }
+        ]"#;
+        // the expired ban is skipped
+        assert_eq!(node.import_bans(ban_list)?, 2);
+        // importing the same list again changes nothing
+        assert_eq!(node.import_bans(ban_list)?, 0);
+        // lists with invalid bans are rejected as a whole
+        assert!(node
+            .import_bans(r#"[{ "ip": "192.0.2.2" }, { "subnet": "10.0.0.0/33" }]"#)
+            .is_err());
+        assert!(!node.is_ip_banned("192.0.2.2".parse()?));
+        let long_reason = "x".repeat(MAX_BAN_REASON_LENGTH + 1);
+        assert!(node
+            .import_bans(&format!(
+                r#"[{{ "ip": "192.0.2.2" }}, {{ "ip": "192.0.2.3", "reason": "{}" }}]"#,
+                long_reason
+            ))
+            .is_err());
+        assert!(!node.is_ip_banned("192.0.2.2".parse()?));
+
+        let exported = node.export_bans()?;
+        node.clear_bans()?;
+        assert_eq!(node.import_bans(&exported)?, 2);
+        let mut banlist = node.get_banlist()?;
+        banlist.sort_by_key(|ban| ban.info.reason.clone());
+        assert_eq!(banlist[0].id, PersistedBanId::Subnet("10.1.0.0/16".parse()?));
+        assert_eq!(banlist[0].info.origin, BanOrigin::Automatic);
+        assert_eq!(banlist[1].id, PersistedBanId::Ip("192.0.2.1".parse()?));
+        assert_eq!(banlist[1].info.reason, "invalid blocks");
+
+        stop_node_delete_dirs(dp, node);
+        Ok(())
+    }
+}
@@ -1,11 +1,24 @@
 //! Peer ban handling.
 
-use crate::{common::p2p_peer::RemotePeerId, connection::ConnChange, p2p::P2PNode, write_or_die};
-use anyhow::bail;
+use crate::{
+    common::{get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId},
+    connection::ConnChange,
+    consensus_ffi::helpers::PacketType,
+    network::{Disconnect, DisconnectReason},
+    p2p::P2PNode,
+    read_or_die, write_or_die,
+};
+use anyhow::{bail, ensure, Context};
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use concordium_base::common::{Buffer, Deserial, Serial};
 use rkv::{StoreOptions, Value};
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 const BAN_STORE_NAME: &str = "bans";
 
@@ -18,17 +31,148 @@ pub enum BanId {
     Socket(SocketAddr),
 }
 
+/// The reason a peer was soft-banned.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SoftBanReason {
+    /// The peer sent a message that breaches the protocol.
+    ProtocolBreach,
+    /// Catching up with the peer resulted in an incompatible global state.
+    IncompatibleState,
+    /// The peer sent a malformed catch-up message.
+    MalformedCatchUp,
+    /// The peer kept exceeding the inbound rate limit for the packet type.
+    RateLimitExceeded(PacketType),
+    /// The peer could not be reached.
+    Unreachable,
+    /// The peer's reputation score fell to the ban threshold.
+    LowReputation,
+}
+
+impl fmt::Display for SoftBanReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SoftBanReason::ProtocolBreach => write!(f, "a breach of protocol"),
+            SoftBanReason::IncompatibleState => write!(f, "an incompatible global state"),
+            SoftBanReason::MalformedCatchUp => write!(f, "a malformed catch-up message"),
+            SoftBanReason::RateLimitExceeded(packet_type) => {
+                write!(f, "exceeding the rate limit for {}s", packet_type)
+            }
+            SoftBanReason::Unreachable => write!(f, "being unreachable"),
+            SoftBanReason::LowReputation => write!(f, "a low reputation score"),
+        }
+    }
+}
+
+/// A soft ban in force.
+#[derive(Copy, Clone, Debug)]
+pub struct SoftBan {
+    /// When the ban is lifted.
+    pub expiry: Instant,
+    /// Why the peer was banned.
+    pub reason: SoftBanReason,
+}
+
+/// A range of IP addresses given by a network address and a prefix length,
+/// e.g., `10.0.0.0/8`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct IpSubnet {
+    addr:       IpAddr,
+    prefix_len: u8,
+}
+
+fn ipv4_mask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0)
+}
+
+fn ipv6_mask(prefix_len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(prefix_len)).unwrap_or(0)
+}
+
+impl IpSubnet {
+    /// Construct a subnet. The bits of the address beyond the prefix are
+    /// cleared.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> anyhow::Result<Self> {
+        let addr = match addr {
+            IpAddr::V4(addr) => {
+                ensure!(prefix_len <= 32, "The prefix length of an IPv4 subnet is at most 32.");
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & ipv4_mask(prefix_len)))
+            }
+            IpAddr::V6(addr) => {
+                ensure!(prefix_len <= 128, "The prefix length of an IPv6 subnet is at most 128.");
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & ipv6_mask(prefix_len)))
+            }
+        };
+        Ok(Self {
+            addr,
+            prefix_len,
+        })
+    }
+
+    /// The network address of the subnet.
+    pub fn addr(&self) -> IpAddr { self.addr }
+
+    /// The number of leading bits of the address that determine the subnet.
+    pub fn prefix_len(&self) -> u8 { self.prefix_len }
+
+    /// Check whether the address belongs to the subnet.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                u32::from(ip) & ipv4_mask(self.prefix_len) == u32::from(net)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(ip) & ipv6_mask(self.prefix_len) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpSubnet {
+    type Err = anyhow::Error;
+
+    /// Parse a subnet in CIDR notation, e.g., `10.0.0.0/8`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix_len) = s.split_once('/').context("Expected ADDRESS/PREFIX_LENGTH.")?;
+        let addr = addr.parse().context("Invalid subnet address.")?;
+        let prefix_len = prefix_len.parse().context("Invalid prefix length.")?;
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl fmt::Display for IpSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
 /// Some bans are persisted to the database so we block reconnects from those
 /// peers.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum PersistedBanId {
     Ip(IpAddr),
+    Subnet(IpSubnet),
+    Node(P2PNodeId),
 }
 
-impl From<PersistedBanId> for BanId {
-    fn from(pbid: PersistedBanId) -> Self {
-        match pbid {
-            PersistedBanId::Ip(ip) => Self::Ip(ip),
+impl PersistedBanId {
+    /// Check whether the ban applies to a peer with the given IP address and,
+    /// if known, node id.
+    pub fn covers(&self, ip: IpAddr, id: Option<P2PNodeId>) -> bool {
+        match self {
+            PersistedBanId::Ip(addr) => *addr == ip,
+            PersistedBanId::Subnet(subnet) => subnet.contains(ip),
+            PersistedBanId::Node(node_id) => id == Some(*node_id),
+        }
+    }
+}
+
+impl fmt::Display for PersistedBanId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PersistedBanId::Ip(addr) => write!(f, "IP {}", addr),
+            PersistedBanId::Subnet(subnet) => write!(f, "subnet {}", subnet),
+            PersistedBanId::Node(id) => write!(f, "node {}", id),
         }
     }
 }
@@ -40,6 +184,15 @@ impl Serial for PersistedBanId {
                 target.write_u8(0).expect("Writing to memory is infallible.");
                 addr.serial(target);
             }
+            PersistedBanId::Subnet(subnet) => {
+                target.write_u8(1).expect("Writing to memory is infallible.");
+                subnet.addr.serial(target);
+                subnet.prefix_len.serial(target);
+            }
+            PersistedBanId::Node(id) => {
+                target.write_u8(2).expect("Writing to memory is infallible.");
+                id.serial(target);
+            }
         }
     }
 }
@@ -48,6 +201,12 @@ impl Deserial for PersistedBanId {
     fn deserial<R: ReadBytesExt>(source: &mut R) -> anyhow::Result<Self> {
         let bn = match source.read_u8()? {
             0 => Self::Ip(IpAddr::deserial(source)?),
+            1 => {
+                let addr = IpAddr::deserial(source)?;
+                let prefix_len = u8::deserial(source)?;
+                Self::Subnet(IpSubnet::new(addr, prefix_len)?)
+            }
+            2 => Self::Node(P2PNodeId::deserial(source)?),
             _ => bail!("Unsupported type of `BanNode`"),
         };
 
@@ -55,45 +214,230 @@ impl Deserial for PersistedBanId {
     }
 }
 
+/// The maximum length, in bytes, of the reason of a persisted ban.
+pub const MAX_BAN_REASON_LENGTH: usize = 256;
+
+/// Who created a persisted ban.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BanOrigin {
+    /// The ban was requested by the operator of the node.
+    Manual,
+    /// The ban was created by the node itself.
+    Automatic,
+}
+
+/// The details of a persisted ban.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BanInfo {
+    /// When the ban is lifted, in milliseconds since the Unix epoch. Bans
+    /// without an expiry are in force until they are lifted manually.
+    pub expiry: Option<u64>,
+    pub origin: BanOrigin,
+    /// A free-text description of why the peer was banned.
+    pub reason: String,
+}
+
+impl BanInfo {
+    /// Check whether the ban has expired at the given time (in milliseconds
+    /// since the Unix epoch).
+    pub fn is_expired(&self, now: u64) -> bool { self.expiry.map_or(false, |expiry| expiry <= now) }
+
+    /// Read the details of a ban from the database. Bans that were stored
+    /// before bans had details are permanent manual bans without a reason.
+    fn from_stored(value: Value) -> anyhow::Result<Self> {
+        match value {
+            Value::U64(_) => Ok(BanInfo {
+                expiry: None,
+                origin: BanOrigin::Manual,
+                reason: String::new(),
+            }),
+            Value::Blob(mut bytes) => BanInfo::deserial(&mut bytes),
+            _ => bail!("Unexpected type of a stored ban"),
+        }
+    }
+}
+
+impl Serial for BanInfo {
+    fn serial<W: Buffer + WriteBytesExt>(&self, target: &mut W) {
+        match self.expiry {
+            None => target.write_u8(0).expect("Writing to memory is infallible."),
+            Some(expiry) => {
+                target.write_u8(1).expect("Writing to memory is infallible.");
+                expiry.serial(target);
+            }
+        }
+        let origin = match self.origin {
+            BanOrigin::Manual => 0u8,
+            BanOrigin::Automatic => 1u8,
+        };
+        origin.serial(target);
+        // the length of the reason is checked when the ban is created
+        (self.reason.len() as u16).serial(target);
+        target.write_all(self.reason.as_bytes()).expect("Writing to memory is infallible.");
+    }
+}
+
+impl Deserial for BanInfo {
+    fn deserial<R: ReadBytesExt>(source: &mut R) -> anyhow::Result<Self> {
+        let expiry = match source.read_u8()? {
+            0 => None,
+            1 => Some(u64::deserial(source)?),
+            _ => bail!("Invalid ban expiry"),
+        };
+        let origin = match source.read_u8()? {
+            0 => BanOrigin::Manual,
+            1 => BanOrigin::Automatic,
+            _ => bail!("Invalid ban origin"),
+        };
+        let len = u16::deserial(source)?;
+        let mut reason = vec![0; usize::from(len)];
+        source.read_exact(&mut reason)?;
+        Ok(BanInfo {
+            expiry,
+            origin,
+            reason: String::from_utf8(reason)?,
+        })
+    }
+}
+
+/// A ban stored in the database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PersistedBan {
+    pub id:   PersistedBanId,
+    pub info: BanInfo,
+}
+
+/// An in-memory copy of the persisted IP and subnet bans and their expiry, so
+/// that incoming connections can be checked without reading the database.
+#[derive(Debug, Default)]
+pub struct IpBans {
+    ips:     HashMap<IpAddr, Option<u64>>,
+    subnets: HashMap<IpSubnet, Option<u64>>,
+}
+
+impl IpBans {
+    /// Record a ban. Node id bans are not tracked.
+    pub fn insert(&mut self, id: PersistedBanId, expiry: Option<u64>) {
+        match id {
+            PersistedBanId::Ip(ip) => {
+                self.ips.insert(ip, expiry);
+            }
+            PersistedBanId::Subnet(subnet) => {
+                self.subnets.insert(subnet, expiry);
+            }
+            PersistedBanId::Node(_) => {}
+        }
+    }
+
+    /// Forget a ban.
+    pub fn remove(&mut self, id: PersistedBanId) {
+        match id {
+            PersistedBanId::Ip(ip) => {
+                self.ips.remove(&ip);
+            }
+            PersistedBanId::Subnet(subnet) => {
+                self.subnets.remove(&subnet);
+            }
+            PersistedBanId::Node(_) => {}
+        }
+    }
+
+    /// Forget all bans.
+    pub fn clear(&mut self) {
+        self.ips.clear();
+        self.subnets.clear();
+    }
+
+    /// Check whether the IP address is banned at the given time (in
+    /// milliseconds since the Unix epoch), either by itself or as part of a
+    /// banned subnet.
+    pub fn is_banned(&self, ip: IpAddr, now: u64) -> bool {
+        let in_force = |expiry: &Option<u64>| expiry.map_or(true, |expiry| expiry > now);
+        self.ips.get(&ip).map_or(false, in_force)
+            || self.subnets.iter().any(|(subnet, expiry)| subnet.contains(ip) && in_force(expiry))
+    }
+}
+
 impl P2PNode {
     /// Register the node's connection to be closed.
     pub fn drop_by_id(&self, id: RemotePeerId) -> bool {
         let maybe_token = self.find_conn_token_by_id(id);
         if let Some(token) = maybe_token {
-            self.register_conn_change(ConnChange::RemovalByToken(token));
+            let disconnect = Disconnect::new(DisconnectReason::Unspecified, None);
+            self.register_conn_change(ConnChange::RemovalByToken(token, Some(disconnect)));
             true
         } else {
             false
         }
     }
 
-    /// Register the node's connection to be closed and ban the IP.
-    pub fn drop_by_ip_and_ban(&self, ip_addr: IpAddr) -> anyhow::Result<bool> {
-        info!("Banning IP {}", ip_addr);
+    /// Persist the ban and register the connections it applies to to be
+    /// closed. Returns whether there were any such connections.
+    pub fn drop_and_ban(&self, id: PersistedBanId, info: BanInfo) -> anyhow::Result<bool> {
+        self.drop_and_ban_all(&[PersistedBan {
+            id,
+            info,
+        }])
+    }
+
+    /// Persist the bans in a single database transaction, so that either all or
+    /// none of them are applied, and register the connections they apply to
+    /// to be closed. Returns whether there were any such connections.
+    pub fn drop_and_ban_all(&self, bans: &[PersistedBan]) -> anyhow::Result<bool> {
+        for ban in bans {
+            ensure!(
+                ban.info.reason.len() <= MAX_BAN_REASON_LENGTH,
+                "The reason of a ban is at most {} bytes long.",
+                MAX_BAN_REASON_LENGTH
+            );
+        }
 
-        let bid = PersistedBanId::Ip(ip_addr);
         if let Ok(ban_kvs_env) = self.kvs.read() {
-            let mut store_key = Vec::new();
-            bid.serial(&mut store_key);
             let ban_store = ban_kvs_env.open_single(BAN_STORE_NAME, StoreOptions::create())?;
             let mut writer = ban_kvs_env.write()?;
-            // TODO: insert ban expiry timestamp as the Value
-            ban_store.put(&mut writer, store_key, &Value::U64(0))?;
+            for ban in bans {
+                let mut store_key = Vec::new();
+                ban.id.serial(&mut store_key);
+                let mut store_value = Vec::new();
+                ban.info.serial(&mut store_value);
+                ban_store.put(&mut writer, store_key, &Value::Blob(&store_value))?;
+            }
             writer.commit()?;
         } else {
             bail!("Couldn't ban a peer: couldn't obtain a lock over the kvs");
         };
 
-        // Remove all given addresses to that IP address.
-        // This implies that after unbanning we will need to issue `ConnectTo` calls to
-        // re-establish them. Removing all the given addresses is the most
-        // consistent behaviour. It means that we won't repeately
-        // try to reconnect to them and then failing because they are banned.
-        write_or_die!(self.config.given_addresses).retain(|addr| addr.ip() != ip_addr);
+        let mut res = false;
+        for PersistedBan {
+            id,
+            info,
+        } in bans
+        {
+            match info.expiry {
+                Some(expiry) => {
+                    info!("Banning {} until {} ({:?}): {}", id, expiry, info.origin, info.reason)
+                }
+                None => info!("Banning {} ({:?}): {}", id, info.origin, info.reason),
+            }
+            write_or_die!(self.ip_bans).insert(*id, info.expiry);
+
+            // Remove all given addresses the ban applies to.
+            // This implies that after unbanning we will need to issue `ConnectTo` calls
+            // to re-establish them. Removing all the given addresses is the most
+            // consistent behaviour. It means that we won't repeately
+            // try to reconnect to them and then failing because they are banned.
+            write_or_die!(self.config.given_addresses).retain(|addr| !id.covers(addr.ip(), None));
 
-        let tokens = self.find_conn_tokens_by_ip(ip_addr);
-        let res = !tokens.is_empty();
-        self.register_conn_change(ConnChange::RemoveAllByTokens(tokens));
+            let tokens = self.find_conn_tokens_by_ban(*id);
+            res |= !tokens.is_empty();
+            // the peers are told when the ban expires, if it does
+            let retry_after = info
+                .expiry
+                .map(|expiry| Duration::from_millis(expiry.saturating_sub(get_current_stamp())));
+            let disconnect = Disconnect::new(DisconnectReason::Banned, retry_after);
+            self.register_conn_change(ConnChange::RemoveAllByTokens(tokens, disconnect));
+        }
         Ok(res)
     }
 
@@ -101,7 +445,8 @@ impl P2PNode {
         write_or_die!(self.config.given_addresses).remove(&addr);
         let maybe_token = self.find_conn_to(addr);
         if let Some(token) = maybe_token {
-            self.register_conn_change(ConnChange::RemovalByToken(token));
+            let disconnect = Disconnect::new(DisconnectReason::Unspecified, None);
+            self.register_conn_change(ConnChange::RemovalByToken(token, Some(disconnect)));
             true
         } else {
             false
@@ -123,10 +468,11 @@ impl P2PNode {
         } else {
             bail!("Couldn't unban a peer: couldn't obtain a lock over the kvs");
         }
+        write_or_die!(self.ip_bans).remove(peer);
         Ok(())
     }
 
-    /// Check whether a specified id has been banned.
+    /// Check whether a specified id has been banned. Expired bans are ignored.
     pub fn is_banned(&self, peer: PersistedBanId) -> anyhow::Result<bool> {
         if let Ok(ban_kvs_env) = self.kvs.read() {
             let ban_store = ban_kvs_env.open_single(BAN_STORE_NAME, StoreOptions::create())?;
@@ -134,14 +480,35 @@ impl P2PNode {
             let mut store_key = Vec::new();
             peer.serial(&mut store_key);
 
-            Ok(ban_store.get(&ban_reader, store_key)?.is_some())
+            match ban_store.get(&ban_reader, store_key)? {
+                Some(value) => Ok(!BanInfo::from_stored(value)?.is_expired(get_current_stamp())),
+                None => Ok(false),
+            }
         } else {
             bail!("Couldn't check if a peer is banned: read from the ban database.");
         }
     }
 
-    /// Obtain the list of banned nodes.
-    pub fn get_banlist(&self) -> anyhow::Result<Vec<PersistedBanId>> {
+    /// Check whether the IP address is banned, either by itself or as part of
+    /// a banned subnet. This only consults the in-memory copy of the bans, so
+    /// it is cheap enough to be called for every incoming connection.
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        read_or_die!(self.ip_bans).is_banned(ip, get_current_stamp())
+    }
+
+    /// Replace the in-memory copy of the IP and subnet bans with the contents
+    /// of the database.
+    pub fn load_ip_bans(&self) -> anyhow::Result<()> {
+        let mut ip_bans = IpBans::default();
+        for ban in self.get_banlist()? {
+            ip_bans.insert(ban.id, ban.info.expiry);
+        }
+        *write_or_die!(self.ip_bans) = ip_bans;
+        Ok(())
+    }
+
+    /// Obtain all the stored bans, including expired ones.
+    fn read_bans(&self) -> anyhow::Result<Vec<PersistedBan>> {
         if let Ok(ban_kvs_env) = self.kvs.read() {
             let ban_store = ban_kvs_env.open_single(BAN_STORE_NAME, StoreOptions::create())?;
 
@@ -150,9 +517,11 @@ impl P2PNode {
 
             let mut banlist = Vec::new();
             for entry in ban_iter {
-                let (mut id_bytes, _expiry) = entry?;
-                let node_to_ban = PersistedBanId::deserial(&mut id_bytes)?;
-                banlist.push(node_to_ban);
+                let (mut id_bytes, value) = entry?;
+                banlist.push(PersistedBan {
+                    id:   PersistedBanId::deserial(&mut id_bytes)?,
+                    info: BanInfo::from_stored(value)?,
+                });
             }
 
             Ok(banlist)
@@ -161,13 +530,55 @@ impl P2PNode {
         }
     }
 
+    /// Obtain the list of bans in force.
+    pub fn get_banlist(&self) -> anyhow::Result<Vec<PersistedBan>> {
+        let now = get_current_stamp();
+        let mut banlist = self.read_bans()?;
+        banlist.retain(|ban| !ban.info.is_expired(now));
+        Ok(banlist)
+    }
+
+    /// Delete the expired bans from the database. Returns the number of
+    /// deleted bans.
+    pub fn remove_expired_bans(&self) -> anyhow::Result<usize> {
+        let now = get_current_stamp();
+        let expired = self
+            .read_bans()?
+            .into_iter()
+            .filter(|ban| ban.info.is_expired(now))
+            .collect::<Vec<_>>();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        if let Ok(ban_kvs_env) = self.kvs.read() {
+            let ban_store = ban_kvs_env.open_single(BAN_STORE_NAME, StoreOptions::create())?;
+            let mut writer = ban_kvs_env.write()?;
+            for ban in expired.iter() {
+                info!("The ban of {} has expired", ban.id);
+                let mut store_key = Vec::new();
+                ban.id.serial(&mut store_key);
+                ban_store.delete(&mut writer, store_key)?;
+            }
+            writer.commit()?;
+            let mut ip_bans = write_or_die!(self.ip_bans);
+            for ban in expired.iter() {
+                ip_bans.remove(ban.id);
+            }
+            Ok(expired.len())
+        } else {
+            bail!("Couldn't remove expired bans: couldn't obtain a lock over the kvs");
+        }
+    }
+
     /// Lift all existing bans.
     pub fn clear_bans(&self) -> anyhow::Result<()> {
         if let Ok(kvs_env) = self.kvs.read() {
             let ban_store = kvs_env.open_single(BAN_STORE_NAME, StoreOptions::create())?;
             let mut writer = kvs_env.write()?;
             ban_store.clear(&mut writer)?;
-            writer.commit().map_err(|e| e.into())
+            writer.commit()?;
+            write_or_die!(self.ip_bans).clear();
+            Ok(())
         } else {
             bail!("Couldn't clear the bans: couldn't obtain a lock over the kvs");
         }
@@ -3,22 +3,26 @@
 use crate::{
     common::{get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId, PeerType, RemotePeer},
     configuration as config,
-    connection::{ConnChange, Connection, MessageSendingPriority},
+    connection::{
+        inbound_limits::packet_type_name, ConnChange, Connection, MessageSendingPriority,
+    },
     lock_or_die, netmsg,
     network::{
-        Handshake, NetworkId, NetworkPacket, NetworkRequest, PacketDestination,
-        WIRE_PROTOCOL_VERSIONS,
+        frames::{PacketFrames, ReceivedFrame},
+        Disconnect, DisconnectReason, Handshake, NetworkId, NetworkRequest, ObjectHash,
+        PacketDestination, WIRE_PROTOCOL_INVENTORY_VERSION,
     },
     p2p::{
-        bans::{BanId, PersistedBanId},
+        bans::{BanId, IpSubnet, PersistedBanId, SoftBan, SoftBanReason},
+        inventory::announced_hash,
         maintenance::attempt_bootstrap,
+        reputation::{apply_reputation_event, ReputationEvent},
         P2PNode,
     },
     read_or_die, write_or_die,
 };
 use anyhow::bail;
 use mio::{event::Event, net::TcpStream, Events, Token};
-use rand::seq::IteratorRandom;
 use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
 use semver::Version;
 use std::{
@@ -122,15 +126,15 @@ impl P2PNode {
             })
     }
 
-    /// Find connection tokens for all connections to the given ip address.
+    /// Find connection tokens for all connections the given ban applies to.
     /// This acquires a read lock on the node's connections and
     /// connection_candidates objects.
-    pub fn find_conn_tokens_by_ip(&self, ip_addr: IpAddr) -> Vec<Token> {
+    pub fn find_conn_tokens_by_ban(&self, ban_id: PersistedBanId) -> Vec<Token> {
         lock_or_die!(self.conn_candidates())
             .values()
             .chain(read_or_die!(self.connections()).values())
             .filter_map(|conn| {
-                if conn.remote_peer.addr.ip() == ip_addr {
+                if ban_id.covers(conn.remote_peer.addr.ip(), conn.remote_peer.self_id) {
                     Some(conn.token())
                 } else {
                     None
@@ -146,16 +150,27 @@ impl P2PNode {
     /// The bool indicates whether the removed peer was a connected peer or just
     /// a candidate, i.e. if the first component of the tuple is true, then a
     /// connected peer was removed.
-    pub fn remove_connection(&self, token: Token) -> Option<(bool, RemotePeer)> {
+    /// If a `Disconnect` is given, the peer is notified before the connection
+    /// is closed.
+    pub fn remove_connection(
+        &self,
+        token: Token,
+        disconnect: Option<Disconnect>,
+    ) -> Option<(bool, RemotePeer)> {
         // First attempt to remove connection in the handshake phase.
-        if let Some(removed_cand) = lock_or_die!(self.conn_candidates()).remove(&token) {
-            Some((false, removed_cand.remote_peer))
+        let removed_cand = lock_or_die!(self.conn_candidates()).remove(&token);
+        let (is_conn, mut removed) = if let Some(removed_cand) = removed_cand {
+            (false, removed_cand)
         } else {
             // otherwise try to remove a full peer
             let removed_conn = write_or_die!(self.connections()).remove(&token)?;
             self.bump_last_peer_update();
-            Some((true, removed_conn.remote_peer))
+            (true, removed_conn)
+        };
+        if let Some(disconnect) = disconnect {
+            removed.send_disconnect(disconnect);
         }
+        Some((is_conn, removed.remote_peer))
     }
 
     /// Shut down connections with the given poll tokens.
@@ -164,8 +179,13 @@ impl P2PNode {
     /// vector containing the connected peers that were removed.
     /// A `connection` is either a peer that the node simply knows about or a
     /// `connected peer` that the node exchanges messages with (i.e. a handshake
-    /// has been concluded)
-    pub fn remove_connections(&self, tokens: &[Token]) -> (bool, Vec<RemotePeer>) {
+    /// has been concluded). The connected peers are notified with the
+    /// `Disconnect` before the connections are closed.
+    pub fn remove_connections(
+        &self,
+        tokens: &[Token],
+        disconnect: Disconnect,
+    ) -> (bool, Vec<RemotePeer>) {
         // This is not implemented as a simple iteration using remove_connection because
         // that would require more lock acquisitions and calls to bump_last_peer_update.
         let conn_candidates = &mut lock_or_die!(self.conn_candidates());
@@ -177,7 +197,8 @@ impl P2PNode {
         for token in tokens {
             if conn_candidates.remove(token).is_some() {
                 removed_candidates = true;
-            } else if let Some(removed_peer) = connections.remove(token) {
+            } else if let Some(mut removed_peer) = connections.remove(token) {
+                removed_peer.send_disconnect(disconnect);
                 removed_peers.push(removed_peer.remote_peer);
                 has_removed_peers = true;
             }
@@ -194,52 +215,89 @@ impl P2PNode {
         write_or_die!(self.connections()).retain(|_, conn| conn.remote_addr() != addr);
     }
 
-    fn process_network_packet(&self, inner_pkt: NetworkPacket) -> anyhow::Result<usize> {
-        let peers_to_skip = match inner_pkt.destination {
-            PacketDestination::Direct(..) => vec![],
-            PacketDestination::Broadcast(ref dont_relay_to) => {
-                if self.config.relay_broadcast_percentage < 1.0 {
-                    use rand::seq::SliceRandom;
-                    let mut rng = rand::thread_rng();
-                    let mut peers = self.get_node_peer_tokens();
-                    peers.retain(|token| !dont_relay_to.contains(token));
-                    let peers_to_take = f64::floor(
-                        f64::from(peers.len() as u32) * self.config.relay_broadcast_percentage,
-                    );
-                    peers
-                        .choose_multiple(&mut rng, peers_to_take as usize)
-                        .copied()
-                        .collect::<Vec<_>>()
-                } else {
-                    dont_relay_to.to_owned()
-                }
+    /// Queue the serialized packet for sending to the given peer or, if there
+    /// is no target, broadcast it to the peers that are not excluded. All the
+    /// peers share the same frames. If the packet is announced, the peers that
+    /// support announcements only get its hash, except for the mesh peers in
+    /// the mesh relay mode. Packets that are not announced are pushed to all
+    /// the peers in either mode. Returns the number of peers the packet or its
+    /// announcement is queued for.
+    fn send_packet_frames(
+        &self,
+        target: Option<RemotePeerId>,
+        dont_relay_to: &[RemotePeerId],
+        network_id: NetworkId,
+        frames: &PacketFrames,
+        announcement: Option<ObjectHash>,
+    ) -> usize {
+        let inventory = if let Some(hash) = announcement {
+            self.store_announced_object(hash, frames);
+            let message = netmsg!(NetworkRequest, NetworkRequest::Inventory(vec![hash]));
+            let mut serialized = Vec::with_capacity(128);
+            if let Err(e) = message.serialize(&mut serialized) {
+                error!("Could not serialize an inventory request: {}", e);
+                return 0;
             }
+            Some(Arc::<[u8]>::from(serialized))
+        } else {
+            None
         };
 
-        let target = if let PacketDestination::Direct(receiver) = inner_pkt.destination {
-            Some(receiver)
+        // in the mesh relay mode announced broadcasts are only pushed to the mesh peers
+        let mesh = if target.is_none() {
+            self.mesh_peers(network_id)
         } else {
             None
         };
-        let network_id = inner_pkt.network_id;
 
-        let message = netmsg!(NetworkPacket, inner_pkt);
-        let mut serialized = Vec::with_capacity(256);
-        message.serialize(&mut serialized)?;
+        let peers_to_skip =
+            if target.is_none() && mesh.is_none() && self.config.relay_broadcast_percentage < 1.0 {
+                use rand::seq::SliceRandom;
+                let mut rng = rand::thread_rng();
+                let mut peers = self.get_node_peer_tokens();
+                peers.retain(|token| !dont_relay_to.contains(token));
+                let peers_to_take = f64::floor(
+                    f64::from(peers.len() as u32) * self.config.relay_broadcast_percentage,
+                );
+                peers.choose_multiple(&mut rng, peers_to_take as usize).copied().collect::<Vec<_>>()
+            } else {
+                dont_relay_to.to_owned()
+            };
+
+        let filter = |conn: &Connection| {
+            if let Some(target_token) = target {
+                // direct messages
+                conn.remote_peer.local_id == target_token
+            } else {
+                // broadcast messages
+                is_valid_broadcast_target(conn, &peers_to_skip, network_id)
+            }
+        };
 
         let mut sent = 0;
-        if let Some(target_token) = target {
-            // direct messages
-            let filter = |conn: &Connection| conn.remote_peer.local_id == target_token;
-            sent += self.send_over_all_connections(&serialized, &filter);
-        } else {
-            // broadcast messages
-            let filter =
-                |conn: &Connection| is_valid_broadcast_target(conn, &peers_to_skip, network_id);
-            sent += self.send_over_all_connections(&serialized, &filter);
+        for conn in write_or_die!(self.connections()).values_mut().filter(|conn| filter(conn)) {
+            let supports_inventory = conn.wire_version >= WIRE_PROTOCOL_INVENTORY_VERSION;
+            let in_mesh =
+                mesh.as_ref().map_or(false, |mesh| mesh.contains(&conn.remote_peer.local_id));
+            match inventory {
+                Some(ref inventory) if supports_inventory && !in_mesh => {
+                    conn.async_send(Arc::clone(inventory), MessageSendingPriority::Normal)
+                }
+                // the meshes are not negotiated, so a peer outside the mesh may not be in
+                // the mesh of any other peer either, and other broadcasts are flooded
+                // direct packets, e.g., catch-up responses, keep their order
+                _ if target.is_some() => conn.async_send_direct_packet(Arc::clone(
+                    frames.for_wire_version(conn.wire_version),
+                )),
+                _ => conn.async_send_packet(
+                    Arc::clone(frames.for_wire_version(conn.wire_version)),
+                    frames.packet_type(),
+                ),
+            }
+            sent += 1;
         }
 
-        Ok(sent)
+        sent
     }
 
     /// Send queued messages to and then receive any pending messages from all
@@ -262,22 +320,35 @@ impl P2PNode {
                 {
                     error!("[sending to {}] {}", conn, e);
                     if let Ok(_io_err) = e.downcast::<io::Error>() {
-                        self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
+                        self.register_conn_change(ConnChange::RemovalByToken(conn.token(), None));
                     } else {
-                        self.register_conn_change(ConnChange::ExpulsionByToken(conn.token()));
+                        self.register_conn_change(ConnChange::ExpulsionByToken(
+                            conn.token(),
+                            SoftBanReason::ProtocolBreach,
+                        ));
                     }
                     return;
                 }
 
-                if events.iter().any(|event| event.token() == conn.token() && event.is_readable()) {
+                // connections that ran out of download budget are read from again without
+                // waiting for a readiness event, since the remaining data would not trigger one
+                if conn.is_read_throttled()
+                    || events
+                        .iter()
+                        .any(|event| event.token() == conn.token() && event.is_readable())
+                {
                     match conn.read_stream(&conn_stats) {
                         Err(e) => {
                             error!("[receiving from {}] {}", conn, e);
                             if let Ok(_io_err) = e.downcast::<io::Error>() {
-                                self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
+                                self.register_conn_change(ConnChange::RemovalByToken(
+                                    conn.token(),
+                                    None,
+                                ));
                             } else {
                                 self.register_conn_change(ConnChange::ExpulsionByToken(
                                     conn.token(),
+                                    SoftBanReason::ProtocolBreach,
                                 ));
                             }
                             return;
@@ -285,7 +356,10 @@ impl P2PNode {
                         Ok(false) => {
                             // The connection was closed by the peer.
                             debug!("Connection to {} closed by peer", conn);
-                            self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
+                            self.register_conn_change(ConnChange::RemovalByToken(
+                                conn.token(),
+                                None,
+                            ));
                             return;
                         }
                         Ok(true) => {}
@@ -303,7 +377,7 @@ impl P2PNode {
                     // and might catch a failure sooner in the case where we do not currently have
                     // anything to write.
                     debug!("Closing connection to {}", conn);
-                    self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
+                    self.register_conn_change(ConnChange::RemovalByToken(conn.token(), None));
                 }
             })
     }
@@ -317,9 +391,11 @@ impl P2PNode {
                 remote_port:    self.self_peer.port(),
                 networks:       read_or_die!(self.networks()).iter().copied().collect(),
                 node_version:   Version::parse(env!("CARGO_PKG_VERSION"))?,
-                wire_versions:  WIRE_PROTOCOL_VERSIONS.to_vec(),
+                wire_versions:  self.wire_versions().to_vec(),
                 genesis_blocks: read_or_die!(self.config.regenesis_arc.blocks).clone(),
                 proof:          vec![],
+                capabilities:   self.self_peer.capabilities,
+                services:       self.self_peer.services,
             })
         );
         let mut serialized = Vec::with_capacity(128);
@@ -345,6 +421,13 @@ pub enum AcceptFailureReason {
     TooManyConnections {
         addr: SocketAddr,
     },
+    #[error(
+        "Too many inbound connections; the remaining slots are reserved for outbound ones. Not \
+         accepting an additional one from {addr}."
+    )]
+    TooManyInboundConnections {
+        addr: SocketAddr,
+    },
     #[error("Already connected to IP {ip}.")]
     AlreadyConnectedToIP {
         ip: IpAddr,
@@ -353,10 +436,19 @@ pub enum AcceptFailureReason {
     DuplicateConnection {
         addr: SocketAddr,
     },
+    #[error("Too many peers from subnet {subnet}. Not accepting an additional one from {addr}.")]
+    SubnetLimit {
+        addr:   SocketAddr,
+        subnet: IpSubnet,
+    },
     #[error("Connection attempt from a banned address.")]
     Banned,
     #[error("Connection attempt from a soft-banned address.")]
     SoftBanned,
+    #[error("Peer {id} is not in the peer allowlist.")]
+    NotAllowlisted {
+        id: P2PNodeId,
+    },
     #[error("{err}")]
     Other {
         #[from]
@@ -376,9 +468,7 @@ pub fn accept(
 ) -> Result<Token, AcceptFailureReason> {
     node.stats.connections_received.inc();
 
-    // if we fail to read the database we allow the connection.
-    // This is fine as long as we assume that nobody can corrupt our ban database.
-    if node.is_banned(PersistedBanId::Ip(addr.ip())).unwrap_or(false) {
+    if node.is_ip_banned(addr.ip()) {
         warn!("Connection attempt from a banned IP {}.", addr.ip());
         return Err(AcceptFailureReason::Banned);
     }
@@ -405,6 +495,22 @@ pub fn accept(
             });
         }
 
+        // Inbound connections may not use the slots reserved for outbound ones.
+        if node.self_peer.peer_type == PeerType::Node {
+            let inbound = candidates_lock
+                .values()
+                .chain(conn_read_lock.values())
+                .filter(|conn| !conn.low_level.is_initiator())
+                .count();
+            let max_inbound =
+                node.config.max_allowed_nodes.saturating_sub(node.config.reserved_outbound_slots);
+            if inbound >= usize::from(max_inbound) {
+                return Err(AcceptFailureReason::TooManyInboundConnections {
+                    addr,
+                });
+            }
+        }
+
         for conn in candidates_lock.values().chain(conn_read_lock.values()) {
             if conn.remote_addr().ip() == addr.ip() {
                 if node.config.disallow_multiple_peers_on_ip {
@@ -421,6 +527,20 @@ pub fn accept(
             }
         }
 
+        if !node.config.subnet_limits.is_empty() {
+            let existing = candidates_lock
+                .values()
+                .chain(conn_read_lock.values())
+                .map(|conn| conn.remote_addr().ip())
+                .collect::<Vec<_>>();
+            if let Some(subnet) = node.config.subnet_limits.exceeded_subnet(addr.ip(), &existing) {
+                return Err(AcceptFailureReason::SubnetLimit {
+                    addr,
+                    subnet,
+                });
+            }
+        }
+
         if node.connection_handler.is_soft_banned(addr) {
             warn!("Connection attempt from a soft-banned IP ({}); rejecting", addr.ip());
             return Err(AcceptFailureReason::SoftBanned);
@@ -437,6 +557,8 @@ pub fn accept(
         local_id: token.into(),
         external_port: addr.port(),
         peer_type: PeerType::Node,
+        capabilities: Default::default(),
+        services: Default::default(),
     };
 
     let conn = Connection::new(node, socket, token, remote_peer, false)?;
@@ -465,6 +587,10 @@ pub fn connect(
         }
     );
 
+    if node.is_shutting_down() {
+        bail!("Not connecting to {} since the node is shutting down", peer_addr);
+    }
+
     if respect_max_peers && peer_type == PeerType::Node {
         let current_peer_count = node.get_peer_stats(Some(PeerType::Node)).len() as u16;
         if current_peer_count >= node.config.max_allowed_nodes {
@@ -482,15 +608,31 @@ pub fn connect(
     }
 
     // Don't connect to banned IPs.
-    if node.is_banned(PersistedBanId::Ip(peer_addr.ip())).unwrap_or(false) {
+    if node.is_ip_banned(peer_addr.ip()) {
         bail!("Refusing to connect to a banned IP ({})", peer_addr.ip());
     }
 
+    // Or to banned nodes, if we know the id of the peer.
+    if let Some(id) = peer_id {
+        if node.is_banned(PersistedBanId::Node(id)).unwrap_or(false) {
+            bail!("Refusing to connect to a banned node ({})", id);
+        }
+    }
+
     // Or to soft-banned nodes.
     if node.connection_handler.is_soft_banned(peer_addr) {
         bail!("Refusing to connect to a soft-banned IP ({})", peer_addr.ip());
     }
 
+    // In permissioned mode there is no point in connecting to peers that are
+    // known not to be in the allowlist. Peers whose id is not known are checked
+    // during the handshake.
+    if let Some(id) = peer_id {
+        if !node.is_id_allowed(id) {
+            bail!("Refusing to connect to a peer that is not in the allowlist ({})", id);
+        }
+    }
+
     // Lock the candidate list for added safety against duplicate connections
     let mut candidates_lock = lock_or_die!(node.conn_candidates());
 
@@ -505,6 +647,18 @@ pub fn connect(
         }
     }
 
+    // Given addresses are exempt from the subnet limits.
+    if respect_max_peers && !node.config.subnet_limits.is_empty() {
+        let existing = read_or_die!(node.connections())
+            .values()
+            .chain(candidates_lock.values())
+            .map(|conn| conn.remote_addr().ip())
+            .collect::<Vec<_>>();
+        if let Some(subnet) = node.config.subnet_limits.exceeded_subnet(peer_addr.ip(), &existing) {
+            bail!("Too many peers from subnet {}; not connecting to {}", subnet, peer_addr);
+        }
+    }
+
     match TcpStream::connect(peer_addr) {
         Ok(socket) => {
             trace!("Connected to {}", peer_addr);
@@ -518,6 +672,8 @@ pub fn connect(
                 local_id: token.into(),
                 external_port: peer_addr.port(),
                 peer_type,
+                capabilities: Default::default(),
+                services: Default::default(),
             };
 
             let mut conn = Connection::new(node, socket, token, remote_peer, true)?;
@@ -534,7 +690,11 @@ pub fn connect(
             if peer_type == PeerType::Node {
                 write_or_die!(node.connection_handler.soft_bans).insert(
                     BanId::Socket(peer_addr),
-                    Instant::now() + Duration::from_secs(config::UNREACHABLE_EXPIRATION_SECS),
+                    SoftBan {
+                        expiry: Instant::now()
+                            + Duration::from_secs(config::UNREACHABLE_EXPIRATION_SECS),
+                        reason: SoftBanReason::Unreachable,
+                    },
                 );
                 node.stats.soft_banned_peers.inc();
                 node.stats.soft_banned_peers_total.inc();
@@ -571,14 +731,34 @@ pub fn connection_housekeeping(node: &Arc<P2PNode>) -> bool {
         conn.stats.created + config::MAX_PREHANDSHAKE_KEEP_ALIVE < curr_stamp
     };
 
-    // remove connections without handshakes
-    lock_or_die!(node.conn_candidates()).retain(|_, conn| !is_conn_without_handshake(conn));
+    // remove connections without handshakes; for the ones we initiated this counts
+    // as a failed connection attempt
+    {
+        let mut failed = Vec::new();
+        lock_or_die!(node.conn_candidates()).retain(|_, conn| {
+            if is_conn_without_handshake(conn) {
+                if conn.low_level.is_initiator() {
+                    failed.push(conn.remote_peer.external_addr());
+                }
+                false
+            } else {
+                true
+            }
+        });
+        if let Err(e) = node.record_failed_connections(&failed) {
+            error!("Couldn't record failed connections: {}", e);
+        }
+        for addr in failed {
+            node.record_reconnect_failure(addr, "The handshake timed out.".to_owned());
+        }
+    }
 
     // remove faulty and inactive connections
     {
         let mut faulty_removed = false;
         write_or_die!(node.connections()).retain(|_, conn| {
             if is_conn_faulty(conn) || is_conn_inactive(conn) {
+                conn.send_disconnect(Disconnect::new(DisconnectReason::Unresponsive, None));
                 faulty_removed = true;
                 false
             } else {
@@ -590,56 +770,131 @@ pub fn connection_housekeeping(node: &Arc<P2PNode>) -> bool {
         }
     }
 
-    // if the number of peers exceeds the desired value, close a random selection of
-    // post-handshake non-given connections to lower it
+    // lower the reputation of peers with a high latency and export the score
+    // distribution; the scores are aggregated so that the metric's label values
+    // don't grow with every peer that ever connected
+    {
+        let mut scores = Vec::new();
+        for (&token, conn) in read_or_die!(node.connections()).iter() {
+            if conn.get_latency() > node.config.reputation_latency_threshold {
+                apply_reputation_event(node, token, &conn.stats, ReputationEvent::HighLatency);
+            }
+            scores.push(conn.stats.score.load(Ordering::Relaxed));
+        }
+        scores.sort_unstable();
+        if let (Some(&min), Some(&max)) = (scores.first(), scores.last()) {
+            node.stats.peer_score.with_label_values(&["min"]).set(min);
+            node.stats.peer_score.with_label_values(&["median"]).set(scores[scores.len() / 2]);
+            node.stats.peer_score.with_label_values(&["max"]).set(max);
+        } else {
+            node.stats.peer_score.reset();
+        }
+    }
+
+    // if the number of peers exceeds the desired value, close the post-handshake
+    // inbound connections with the lowest reputation scores to lower it. Given
+    // peers, anchors and other outbound peers are never evicted.
     if peer_type == PeerType::Node {
         let max_allowed_nodes = node.config.max_allowed_nodes;
         let peer_count = node.get_peer_stats(Some(PeerType::Node)).len() as u16;
         if peer_count > max_allowed_nodes {
-            // drop connections to any non-given peers.
-            let mut rng = rand::thread_rng();
-            let to_drop = read_or_die!(node.connections())
+            use rand::seq::SliceRandom;
+            let mut candidates = read_or_die!(node.connections())
                 .iter()
                 .filter_map(|(&token, conn)| {
-                    // only consider non-given connections for removal
-                    if node.is_given_connection(conn) {
+                    if conn.low_level.is_initiator()
+                        || node.is_given_connection(conn)
+                        || node.is_anchor_connection(conn)
+                    {
                         None
                     } else {
-                        Some(token)
+                        Some((token, conn.stats.score.load(Ordering::Relaxed)))
                     }
                 })
-                .choose_multiple(&mut rng, (peer_count - max_allowed_nodes) as usize);
-
-            node.remove_connections(&to_drop);
+                .collect::<Vec<_>>();
+            // shuffle first so that ties between equal scores are broken randomly
+            candidates.shuffle(&mut rand::thread_rng());
+            candidates.sort_by_key(|&(_, score)| score);
+            let to_drop = candidates
+                .into_iter()
+                .take((peer_count - max_allowed_nodes) as usize)
+                .map(|(token, _)| token)
+                .collect::<Vec<_>>();
+
+            node.remove_connections(
+                &to_drop,
+                Disconnect::new(DisconnectReason::TooManyConnections, None),
+            );
         }
     }
 
-    // periodically lift soft bans
+    // periodically remove expired persisted bans and lift soft bans
+    if let Err(e) = node.remove_expired_bans() {
+        error!("Couldn't remove expired bans: {}", e);
+    }
     {
         let mut soft_bans = write_or_die!(node.connection_handler.soft_bans);
         if !soft_bans.is_empty() {
             let now = Instant::now();
-            soft_bans.retain(|_, expiry| *expiry > now);
+            soft_bans.retain(|_, ban| ban.expiry > now);
             node.stats.soft_banned_peers.set(soft_bans.len() as i64);
         }
     }
 
-    // Try to connect to any given addresses we are not connected to.
+    // refresh the address book entries of the connected peers and age out the
+    // stale ones
+    if peer_type == PeerType::Node {
+        if let Err(e) = node.record_connected_peers(&node.get_peer_stats(Some(PeerType::Node))) {
+            error!("Couldn't update the address book: {}", e);
+        }
+        match node.remove_stale_peers() {
+            Ok(0) => {}
+            Ok(removed) => debug!("Removed {} stale peers from the address book", removed),
+            Err(e) => error!("Couldn't remove stale peers from the address book: {}", e),
+        }
+    }
+
+    // Try to connect to any given addresses we are not connected to, unless we are
+    // backing off from them after failed attempts.
     for given in node.unconnected_given_addresses() {
+        if !node.try_reconnect_attempt(given) {
+            continue;
+        }
         if let Err(e) = connect(node, PeerType::Node, given, None, false) {
-            warn!("Cannot establish connection to a given address {}: {}", given, e)
+            warn!("Cannot establish connection to a given address {}: {}", given, e);
+            node.record_reconnect_failure(given, e.to_string());
         }
     }
+    {
+        let given = read_or_die!(node.config.given_addresses);
+        lock_or_die!(node.connection_handler.reconnect_backoff)
+            .prune(get_current_stamp(), |addr| given.contains(addr));
+    }
+    // disconnects are remembered as long as the peers stay in the address book
+    node.prune_disconnects(node.config.address_book_max_age * 1000);
+    node.prune_inventory();
+    node.maintain_mesh();
 
     // Log all the bad events that happened and reset all their counters.
     for (peer_id, invalid_msgs) in lock_or_die!(node.bad_events.invalid_messages).drain() {
         warn!("Received {} invalid messages from peer {}", invalid_msgs, peer_id);
     }
-    for (peer_id, dropped) in lock_or_die!(node.bad_events.dropped_high_queue).drain() {
-        warn!("Dropped {} high priority messages from peer {}.", dropped, peer_id);
+    for ((peer_id, packet_type), dropped) in lock_or_die!(node.bad_events.dropped_packets).drain() {
+        warn!(
+            "Dropped {} {} packets from peer {} because the queue was full.",
+            dropped,
+            packet_type_name(packet_type),
+            peer_id
+        );
+    }
+    for (peer_id, dropped) in lock_or_die!(node.bad_events.dropped_outbound_messages).drain() {
+        warn!(
+            "Dropped {} messages for peer {} because the outbound queues were full.",
+            dropped, peer_id
+        );
     }
-    for (peer_id, dropped) in lock_or_die!(node.bad_events.dropped_low_queue).drain() {
-        warn!("Dropped {} low priority messages from peer {}.", dropped, peer_id);
+    for (peer_id, dropped) in lock_or_die!(node.bad_events.rate_limited_messages).drain() {
+        warn!("Dropped {} messages from peer {} that exceeded the rate limits.", dropped, peer_id);
     }
 
     // Reconnect to bootstrappers after a specified amount of time.
@@ -705,22 +960,64 @@ fn send_message_over_network(
         PacketDestination::Broadcast(dont_relay_to)
     };
 
-    let message = message.to_vec();
-
-    // Create packet.
-    let packet = NetworkPacket {
-        destination,
+    // Serialize the packet once for all the peers it is sent to.
+    let frames = match PacketFrames::serialize(
+        &destination,
         network_id,
-        message,
+        &message,
+        node.config.packet_compression_threshold,
+        &node.stats,
+    ) {
+        Ok(frames) => frames,
+        Err(e) => {
+            error!("Couldn't send a packet: {}", e);
+            return 0;
+        }
+    };
+    let dont_relay_to = match destination {
+        PacketDestination::Broadcast(ref dont_relay_to) => &dont_relay_to[..],
+        PacketDestination::Direct(_) => &[],
     };
 
-    if let Ok(sent_packets) = node.process_network_packet(packet) {
-        if sent_packets > 0 {
-            trace!("{} peer(s) will receive the packet", sent_packets);
-        }
-        sent_packets
+    // broadcast transactions and blocks are announced to the peers that support it
+    let announcement = if target_id.is_none() {
+        announced_hash(&message)
     } else {
-        error!("Couldn't send a packet");
-        0
+        None
+    };
+
+    let sent_packets =
+        node.send_packet_frames(target_id, dont_relay_to, network_id, &frames, announcement);
+    if sent_packets > 0 {
+        trace!("{} peer(s) will receive the packet", sent_packets);
+    }
+    sent_packets
+}
+
+/// Relay a broadcast received from a peer in the frame it was received in.
+pub fn relay_broadcast_frame(
+    node: &P2PNode,
+    dont_relay_to: Vec<RemotePeerId>,
+    frame: &ReceivedFrame,
+    msg: &[u8],
+) -> usize {
+    let frames = match PacketFrames::relay(frame, msg) {
+        Ok(frames) => frames,
+        Err(e) => {
+            error!("Couldn't relay a packet: {}", e);
+            return 0;
+        }
+    };
+
+    let sent_packets = node.send_packet_frames(
+        None,
+        &dont_relay_to,
+        frame.network_id,
+        &frames,
+        announced_hash(msg),
+    );
+    if sent_packets > 0 {
+        trace!("{} peer(s) will receive the relayed packet", sent_packets);
     }
+    sent_packets
 }
@@ -0,0 +1,120 @@
+//! The reasons connections to peers were closed.
+//!
+//! Before closing a connection to a peer the node sends it a `Disconnect`
+//! request with the reason and, optionally, how long the peer should wait
+//! before reconnecting. The node remembers the most recent disconnect of each
+//! peer, whichever side closed the connection, and a `Disconnect` received
+//! from a peer delays the next attempt to reconnect to it, by at most the
+//! maximum reconnection backoff and never for given addresses.
+
+use crate::{
+    common::get_current_stamp,
+    lock_or_die,
+    network::{Disconnect, DisconnectReason},
+    p2p::P2PNode,
+    read_or_die,
+};
+use std::{net::SocketAddr, time::Duration};
+
+/// The maximum number of peers whose most recent disconnect is remembered.
+/// When it is reached, the oldest disconnect is forgotten to make room.
+pub const MAX_DISCONNECT_RECORDS: usize = 2048;
+
+/// The most recent disconnect of a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DisconnectRecord {
+    pub reason:      DisconnectReason,
+    /// Whether the peer closed the connection, as opposed to the node.
+    pub by_peer:     bool,
+    /// When the connection was closed, in milliseconds since the Unix epoch.
+    pub time:        u64,
+    /// How long the side that closed the connection asked the other to wait
+    /// before reconnecting, if at all.
+    pub retry_after: Option<Duration>,
+}
+
+impl P2PNode {
+    /// Record that the connection to the peer with the given address is
+    /// closed, either by the peer or by the node. If the peer closes it, the
+    /// node does not dial the address again until the reconnection backoff and
+    /// the delay the peer asked for have passed. Given addresses are not
+    /// deferred beyond the backoff.
+    pub fn record_disconnect(&self, addr: SocketAddr, disconnect: Disconnect, by_peer: bool) {
+        let now = get_current_stamp();
+        if by_peer {
+            info!("Peer {} is closing the connection: {}", addr, disconnect);
+            let is_given = read_or_die!(self.config.given_addresses).contains(&addr);
+            let mut backoff = lock_or_die!(self.connection_handler.reconnect_backoff);
+            backoff.record_failure(addr, format!("The peer disconnected: {}.", disconnect), now);
+            if let Some(retry_after) = disconnect.retry_after.filter(|_| !is_given) {
+                let delay = u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX);
+                backoff.defer(addr, now, delay);
+            }
+        } else {
+            info!("Closing the connection to {}: {}", addr, disconnect);
+        }
+        let mut last_disconnects = lock_or_die!(self.connection_handler.last_disconnects);
+        if last_disconnects.len() >= MAX_DISCONNECT_RECORDS && !last_disconnects.contains_key(&addr)
+        {
+            let oldest = last_disconnects
+                .iter()
+                .min_by_key(|(_, record)| record.time)
+                .map(|(&addr, _)| addr);
+            if let Some(oldest) = oldest {
+                last_disconnects.remove(&oldest);
+            }
+        }
+        last_disconnects.insert(addr, DisconnectRecord {
+            reason: disconnect.reason,
+            by_peer,
+            time: now,
+            retry_after: disconnect.retry_after,
+        });
+    }
+
+    /// Obtain the most recent disconnect of the peer with the given address,
+    /// if any.
+    pub fn get_last_disconnect(&self, addr: SocketAddr) -> Option<DisconnectRecord> {
+        lock_or_die!(self.connection_handler.last_disconnects).get(&addr).copied()
+    }
+
+    /// Forget the disconnects that are older than the given number of
+    /// milliseconds.
+    pub fn prune_disconnects(&self, max_age: u64) {
+        let cutoff = get_current_stamp().saturating_sub(max_age);
+        lock_or_die!(self.connection_handler.last_disconnects)
+            .retain(|_, record| record.time >= cutoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{network::DisconnectReason, read_or_die, test_utils::*};
+
+    #[test]
+    fn test_disconnect_is_recorded_by_both_sides() -> anyhow::Result<()> {
+        let ((node, dp), (peer, peer_dp)) = make_connected_node_pair()?;
+
+        let peer_id = read_or_die!(node.connections())
+            .values()
+            .next()
+            .map(|conn| conn.remote_peer.local_id)
+            .unwrap();
+        assert!(node.drop_by_id(peer_id));
+
+        let received = await_condition(|| peer.get_last_disconnect(node.self_peer.addr))
+            .expect("The peer did not receive the Disconnect.");
+        assert!(received.by_peer);
+        assert_eq!(received.reason, DisconnectReason::Unspecified);
+
+        let sent = node.get_last_disconnect(peer.self_peer.addr).unwrap();
+        assert!(!sent.by_peer);
+        assert_eq!(sent.reason, DisconnectReason::Unspecified);
+        // the peer backs off before reconnecting
+        assert!(!peer.try_reconnect_attempt(node.self_peer.addr));
+
+        stop_node_delete_dirs(dp, node);
+        stop_node_delete_dirs(peer_dp, peer);
+        Ok(())
+    }
+}
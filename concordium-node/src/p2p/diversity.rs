@@ -0,0 +1,116 @@
+//! Limits on the number of peers in the same subnet.
+//!
+//! An attacker controlling a range of addresses should not be able to occupy
+//! all of the node's connections. The number of peers in each IPv4 /16 and /24
+//! and in each IPv6 /32 and /48 subnet can therefore be limited. The limits
+//! apply to incoming connections, to outgoing connections to discovered peers
+//! and to the peers a bootstrapper shares. Given addresses are exempt.
+
+use crate::p2p::bans::IpSubnet;
+use std::net::IpAddr;
+
+/// Convert an IPv4-mapped IPv6 address to the IPv4 address, so that a peer
+/// counts towards the same subnet however it connects. This is
+/// `IpAddr::to_canonical`, which is not stable in our minimum supported Rust
+/// version.
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ipv6) => ipv6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// The maximum numbers of peers per subnet, keyed by the prefix length of the
+/// subnet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SubnetLimits {
+    ipv4: Vec<(u8, usize)>,
+    ipv6: Vec<(u8, usize)>,
+}
+
+impl SubnetLimits {
+    /// Construct the limits from the maximum numbers of peers per IPv4 /16 and
+    /// /24 and per IPv6 /32 and /48 subnet. Subnets without a maximum are not
+    /// limited.
+    pub fn new(
+        ipv4_16: Option<usize>,
+        ipv4_24: Option<usize>,
+        ipv6_32: Option<usize>,
+        ipv6_48: Option<usize>,
+    ) -> Self {
+        let limits = |limits: [(u8, Option<usize>); 2]| {
+            limits.into_iter().filter_map(|(len, max)| Some((len, max?))).collect()
+        };
+        Self {
+            ipv4: limits([(16, ipv4_16), (24, ipv4_24)]),
+            ipv6: limits([(32, ipv6_32), (48, ipv6_48)]),
+        }
+    }
+
+    /// Whether no subnet is limited.
+    pub fn is_empty(&self) -> bool { self.ipv4.is_empty() && self.ipv6.is_empty() }
+
+    /// Check whether a peer with the given address would exceed a limit given
+    /// the addresses of the existing peers. Returns the first subnet whose
+    /// limit would be exceeded.
+    pub fn exceeded_subnet(&self, ip: IpAddr, existing: &[IpAddr]) -> Option<IpSubnet> {
+        let ip = canonical_ip(ip);
+        let limits = match ip {
+            IpAddr::V4(_) => &self.ipv4,
+            IpAddr::V6(_) => &self.ipv6,
+        };
+        limits.iter().find_map(|&(prefix_len, max)| {
+            let subnet = IpSubnet::new(ip, prefix_len).ok()?;
+            let count =
+                existing.iter().filter(|&&other| subnet.contains(canonical_ip(other))).count();
+            if count >= max {
+                Some(subnet)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subnet_limits() -> anyhow::Result<()> {
+        let limits = SubnetLimits::new(Some(3), Some(1), None, Some(1));
+        let existing: Vec<IpAddr> =
+            vec!["10.1.1.1".parse()?, "10.1.2.1".parse()?, "2001:db8:1::1".parse()?];
+        // one peer per /24
+        assert_eq!(
+            limits.exceeded_subnet("10.1.1.2".parse()?, &existing),
+            Some("10.1.1.0/24".parse()?)
+        );
+        assert_eq!(limits.exceeded_subnet("10.1.3.1".parse()?, &existing), None);
+        // three peers per /16
+        let mut more = existing.clone();
+        more.push("10.1.3.1".parse()?);
+        assert_eq!(
+            limits.exceeded_subnet("10.1.4.1".parse()?, &more),
+            Some("10.1.0.0/16".parse()?)
+        );
+        // IPv6 /32 subnets are not limited, but /48 subnets are
+        assert_eq!(limits.exceeded_subnet("2001:db8:2::1".parse()?, &existing), None);
+        assert_eq!(
+            limits.exceeded_subnet("2001:db8:1::2".parse()?, &existing),
+            Some("2001:db8:1::/48".parse()?)
+        );
+        // IPv4-mapped IPv6 addresses count as IPv4 addresses
+        assert_eq!(
+            limits.exceeded_subnet("::ffff:10.1.1.2".parse()?, &existing),
+            Some("10.1.1.0/24".parse()?)
+        );
+        let mapped: Vec<IpAddr> = vec!["::ffff:10.1.1.1".parse()?];
+        assert_eq!(
+            limits.exceeded_subnet("10.1.1.2".parse()?, &mapped),
+            Some("10.1.1.0/24".parse()?)
+        );
+        assert!(SubnetLimits::default().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! The node's long-lived identity.
+//!
+//! The node holds a static Noise keypair that is stored in the data directory
+//! and used in every handshake. The node id is derived from the public part of
+//! that key, so peers can verify that the id a node claims in its handshake is
+//! in fact the one bound to the key it authenticated with.
+
+use crate::{common::P2PNodeId, configuration::NODE_KEY_FILE_NAME};
+use anyhow::{ensure, Context};
+use noiseexplorer_xx::{
+    consts::DHLEN,
+    types::{Keypair, PrivateKey},
+};
+use sha2::{Digest, Sha256};
+use std::{convert::TryInto, fs, io::Write, path::Path};
+
+/// The public part of a node's static key.
+pub type NodePublicKey = [u8; DHLEN];
+
+/// Derive the node id from the public part of the node's static key. This is
+/// the first 8 bytes of the SHA256 hash of the key, interpreted as a big endian
+/// integer.
+pub fn node_id_from_public_key(key: &NodePublicKey) -> P2PNodeId {
+    let hash = Sha256::digest(key);
+    // the hash is 32 bytes long, so taking the first 8 cannot fail
+    P2PNodeId(u64::from_be_bytes(hash[..8].try_into().unwrap()))
+}
+
+/// The node's static keypair together with the derived node id.
+pub struct NodeIdentity {
+    keypair:    Keypair,
+    public_key: NodePublicKey,
+    id:         P2PNodeId,
+}
+
+impl NodeIdentity {
+    /// Load the static key from the data directory, or generate a fresh one
+    /// and store it there if the key file does not exist yet.
+    pub fn load_or_generate(data_dir: &Path) -> anyhow::Result<Self> {
+        let key_path = data_dir.join(NODE_KEY_FILE_NAME);
+        let keypair = if key_path.exists() {
+            let contents = fs::read_to_string(&key_path).with_context(|| {
+                format!("Could not read the node key file {}.", key_path.display())
+            })?;
+            let bytes = hex::decode(contents.trim()).with_context(|| {
+                format!("The node key file {} is not valid hex.", key_path.display())
+            })?;
+            ensure!(
+                bytes.len() == DHLEN,
+                "The node key file {} must contain a {} byte key.",
+                key_path.display(),
+                DHLEN
+            );
+            let mut key = [0u8; DHLEN];
+            key.copy_from_slice(&bytes);
+            let private_key = PrivateKey::from_bytes(key)
+                .map_err(|e| anyhow::anyhow!("Invalid node key: {:?}", e))?;
+            Keypair::from_key(private_key)
+                .map_err(|e| anyhow::anyhow!("Invalid node key: {:?}", e))?
+        } else {
+            info!("Generating a new node key in {}", key_path.display());
+            let keypair = Keypair::default();
+            write_key_file(&key_path, &hex::encode(keypair.get_private_key().as_bytes()))?;
+            keypair
+        };
+        Ok(Self::from_keypair(keypair))
+    }
+
+    /// Construct the identity from the given keypair.
+    pub fn from_keypair(keypair: Keypair) -> Self {
+        let public_key = keypair.get_public_key().as_bytes();
+        let id = node_id_from_public_key(&public_key);
+        Self {
+            keypair,
+            public_key,
+            id,
+        }
+    }
+
+    /// Construct an identity with a fresh key and the given node id, which is
+    /// not derived from the key. This is what nodes that only support wire
+    /// protocol versions before `WIRE_PROTOCOL_AUTHENTICATED_ID_VERSION` use,
+    /// and it is only needed to test the compatibility with such nodes.
+    #[cfg(any(test, feature = "test_utils"))]
+    pub fn unbound(id: P2PNodeId) -> Self {
+        let keypair = Keypair::default();
+        let public_key = keypair.get_public_key().as_bytes();
+        Self {
+            keypair,
+            public_key,
+            id,
+        }
+    }
+
+    /// Whether the node id is the one derived from the public key.
+    pub fn is_bound(&self) -> bool { node_id_from_public_key(&self.public_key) == self.id }
+
+    /// A copy of the static keypair to be used in a Noise session.
+    pub fn keypair(&self) -> Keypair { self.keypair.clone() }
+
+    /// The public part of the static key.
+    pub fn public_key(&self) -> &NodePublicKey { &self.public_key }
+
+    /// The node id derived from the public key.
+    pub fn id(&self) -> P2PNodeId { self.id }
+}
+
+/// Write the key file, making it readable only by the owner where supported.
+fn write_key_file(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Could not create the node key file {}.", path.display()))?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("Could not write the node key file {}.", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_is_persisted() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let first = NodeIdentity::load_or_generate(dir.path())?;
+        let second = NodeIdentity::load_or_generate(dir.path())?;
+        assert_eq!(first.public_key(), second.public_key());
+        assert_eq!(first.id(), second.id());
+        assert_eq!(first.id(), node_id_from_public_key(first.public_key()));
+
+        let other_dir = tempfile::tempdir()?;
+        let other = NodeIdentity::load_or_generate(other_dir.path())?;
+        assert_ne!(first.id(), other.id());
+        assert!(first.is_bound());
+        assert!(!NodeIdentity::unbound(first.id()).is_bound());
+        Ok(())
+    }
+}
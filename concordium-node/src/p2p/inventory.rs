@@ -0,0 +1,368 @@
+//! Announce-then-fetch relay of transactions and blocks.
+//!
+//! Instead of pushing transactions and blocks in full to the peers that
+//! support `WIRE_PROTOCOL_INVENTORY_VERSION`, the node announces their hashes
+//! in an `Inventory` request and keeps the packets for a while. A peer asks
+//! for the announced objects it has not seen according to its deduplication
+//! queues with a `GetObjects` request, and gets them as broadcast packets that
+//! are sent to it only. If an object does not arrive in time, it is asked for
+//! from the next peer that announced it. Each peer gets an object at most once,
+//! and only up to a limited number of objects per second. Peers that use older
+//! wire protocol versions still get the packets pushed.
+
+use crate::{
+    common::{get_current_stamp, p2p_peer::RemotePeerId},
+    consensus_ffi::helpers::PacketType,
+    lock_or_die,
+    network::{frames::PacketFrames, ObjectHash},
+    p2p::P2PNode,
+    write_or_die,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+};
+
+/// The maximum number of hashes in an `Inventory` or a `GetObjects` request.
+pub const MAX_INVENTORY_HASHES: usize = 1024;
+
+/// The maximum number of objects a peer may ask for per second. Further
+/// `GetObjects` requests in the same second are ignored.
+pub const MAX_REQUESTED_OBJECTS_PER_SEC: usize = MAX_INVENTORY_HASHES;
+
+/// The maximum number of announced objects the node keeps for its peers.
+const MAX_STORED_OBJECTS: usize = 16 * 1024;
+
+/// The maximum number of further peers that announced a requested object
+/// which the node remembers, to ask them if the object does not arrive.
+const MAX_ALTERNATE_ANNOUNCERS: usize = 8;
+
+/// The number of milliseconds the announced objects are kept for.
+const OBJECT_RETENTION_MS: u64 = 120_000;
+
+/// The number of milliseconds the node waits for a requested object before it
+/// asks the next peer that announces it.
+const REQUEST_TIMEOUT_MS: u64 = 10_000;
+
+/// The hash of the object carried by a packet with the given payload.
+pub fn object_hash(payload: &[u8]) -> ObjectHash { Sha256::digest(payload).into() }
+
+/// The hash under which a broadcast with the given payload is announced, or
+/// `None` if the packet is pushed to all the peers. Only transactions and
+/// blocks are announced.
+pub fn announced_hash(payload: &[u8]) -> Option<ObjectHash> {
+    match payload.first().map(|&tag| PacketType::try_from(tag)) {
+        Some(Ok(PacketType::Transaction)) | Some(Ok(PacketType::Block)) => {
+            Some(object_hash(payload))
+        }
+        _ => None,
+    }
+}
+
+/// An object the node announced.
+struct AnnouncedObject {
+    frames:  PacketFrames,
+    /// When the object was announced.
+    time:    u64,
+    /// The peers the object was sent to.
+    sent_to: HashSet<RemotePeerId>,
+}
+
+/// An object the node asked a peer for.
+struct RequestedObject {
+    /// The peer that was asked for the object.
+    peer:       RemotePeerId,
+    /// When the peer was asked for the object.
+    time:       u64,
+    /// The other peers that announced the object, in the order they did.
+    announcers: VecDeque<RemotePeerId>,
+}
+
+impl RequestedObject {
+    fn new(peer: RemotePeerId, time: u64) -> Self {
+        Self {
+            peer,
+            time,
+            announcers: VecDeque::new(),
+        }
+    }
+
+    /// Remember another peer that announced the object.
+    fn add_announcer(&mut self, peer: RemotePeerId) {
+        if peer != self.peer
+            && self.announcers.len() < MAX_ALTERNATE_ANNOUNCERS
+            && !self.announcers.contains(&peer)
+        {
+            self.announcers.push_back(peer);
+        }
+    }
+}
+
+/// The objects the node announced and the objects it asked its peers for.
+#[derive(Default)]
+pub struct Inventory {
+    /// The announced objects.
+    objects:   HashMap<ObjectHash, AnnouncedObject>,
+    /// The hashes of the announced objects, oldest first.
+    order:     VecDeque<ObjectHash>,
+    /// The requested objects.
+    requested: HashMap<ObjectHash, RequestedObject>,
+}
+
+impl Inventory {
+    /// Keep an announced object, evicting the oldest one if there are too
+    /// many.
+    fn store(&mut self, hash: ObjectHash, frames: &PacketFrames, now: u64) {
+        if let Entry::Vacant(entry) = self.objects.entry(hash) {
+            entry.insert(AnnouncedObject {
+                frames:  frames.clone(),
+                time:    now,
+                sent_to: HashSet::new(),
+            });
+            self.order.push_back(hash);
+        }
+        while self.order.len() > MAX_STORED_OBJECTS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.objects.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget the objects that were announced too long ago. Requests that
+    /// timed out are passed on to the next peer that announced the object, or
+    /// forgotten if there is none. Returns the objects to ask the next peers
+    /// for and the hashes of the forgotten requests.
+    fn prune(&mut self, now: u64) -> (Vec<(RemotePeerId, ObjectHash)>, Vec<ObjectHash>) {
+        while let Some(oldest) = self.order.front() {
+            match self.objects.get(oldest) {
+                Some(object) if object.time + OBJECT_RETENTION_MS > now => break,
+                _ => {
+                    self.objects.remove(oldest);
+                    self.order.pop_front();
+                }
+            }
+        }
+        let mut retries = Vec::new();
+        let mut expired = Vec::new();
+        self.requested.retain(|hash, request| {
+            if request.time + REQUEST_TIMEOUT_MS > now {
+                return true;
+            }
+            match request.announcers.pop_front() {
+                Some(peer) => {
+                    request.peer = peer;
+                    request.time = now;
+                    retries.push((peer, *hash));
+                    true
+                }
+                None => {
+                    expired.push(*hash);
+                    false
+                }
+            }
+        });
+        (retries, expired)
+    }
+}
+
+impl P2PNode {
+    /// Keep an object the node announces to its peers, so that it can be sent
+    /// to the ones that ask for it.
+    pub(crate) fn store_announced_object(&self, hash: ObjectHash, frames: &PacketFrames) {
+        let _ = write_or_die!(self.connection_handler.deduplication_queues.inventory)
+            .check_and_insert(&hash);
+        lock_or_die!(self.connection_handler.inventory).store(hash, frames, get_current_stamp());
+    }
+
+    /// Obtain an object the node announced, if it still has it.
+    pub fn get_announced_object(&self, hash: &ObjectHash) -> Option<PacketFrames> {
+        lock_or_die!(self.connection_handler.inventory)
+            .objects
+            .get(hash)
+            .map(|object| object.frames.clone())
+    }
+
+    /// Check whether an object the node announced was sent to the given peer.
+    pub fn is_announced_object_sent_to(&self, hash: &ObjectHash, peer: RemotePeerId) -> bool {
+        lock_or_die!(self.connection_handler.inventory)
+            .objects
+            .get(hash)
+            .map_or(false, |object| object.sent_to.contains(&peer))
+    }
+
+    /// Obtain an object the node announced for sending it to the given peer,
+    /// if the node still has it and has not sent it to the peer before.
+    pub(crate) fn take_announced_object(
+        &self,
+        hash: &ObjectHash,
+        peer: RemotePeerId,
+    ) -> Option<PacketFrames> {
+        let mut inventory = lock_or_die!(self.connection_handler.inventory);
+        let object = inventory.objects.get_mut(hash)?;
+        if object.sent_to.insert(peer) {
+            Some(object.frames.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Select the objects announced by the given peer that the node has
+    /// neither seen nor asked for, and record that they are requested from the
+    /// peer. For the objects that are already requested from other peers, the
+    /// peer is remembered as an alternative.
+    pub(crate) fn wanted_objects(
+        &self,
+        peer: RemotePeerId,
+        hashes: &[ObjectHash],
+    ) -> Vec<ObjectHash> {
+        let (wanted, known): (Vec<ObjectHash>, Vec<ObjectHash>) = {
+            let mut seen = write_or_die!(self.connection_handler.deduplication_queues.inventory);
+            hashes
+                .iter()
+                .copied()
+                .partition(|hash| matches!(seen.check_and_insert(&hash[..]), Ok(false)))
+        };
+        let now = get_current_stamp();
+        let mut inventory = lock_or_die!(self.connection_handler.inventory);
+        for &hash in &wanted {
+            inventory.requested.insert(hash, RequestedObject::new(peer, now));
+        }
+        for hash in known {
+            if let Some(request) = inventory.requested.get_mut(&hash) {
+                request.add_announcer(peer);
+            }
+        }
+        wanted
+    }
+
+    /// Check whether the node asked a peer for the object and is still waiting
+    /// for it.
+    pub fn is_object_requested(&self, hash: &ObjectHash) -> bool {
+        lock_or_die!(self.connection_handler.inventory).requested.contains_key(hash)
+    }
+
+    /// Record that a broadcast with the given payload was received, so that
+    /// its announcements are ignored.
+    pub(crate) fn record_received_object(&self, payload: &[u8]) {
+        if let Some(hash) = announced_hash(payload) {
+            let _ = write_or_die!(self.connection_handler.deduplication_queues.inventory)
+                .check_and_insert(&hash);
+            lock_or_die!(self.connection_handler.inventory).requested.remove(&hash);
+        }
+    }
+
+    /// Forget the objects that were announced too long ago. Objects that were
+    /// requested but did not arrive in time are asked for from the next peer
+    /// that announced them. If there is none, the request is forgotten, so
+    /// that the object is asked for again when another peer announces it.
+    pub(crate) fn prune_inventory(&self) {
+        let (retries, expired) =
+            lock_or_die!(self.connection_handler.inventory).prune(get_current_stamp());
+        if !retries.is_empty() {
+            debug!("Asking other peers for {} objects that did not arrive in time", retries.len());
+            let mut by_peer: HashMap<RemotePeerId, Vec<ObjectHash>> = HashMap::new();
+            for (peer, hash) in retries {
+                by_peer.entry(peer).or_default().push(hash);
+            }
+            // a request to a peer that is no longer connected times out in turn
+            for conn in write_or_die!(self.connections()).values_mut() {
+                if let Some(hashes) = by_peer.remove(&conn.remote_peer.local_id) {
+                    for hashes in hashes.chunks(MAX_INVENTORY_HASHES) {
+                        if let Err(e) = conn.send_get_objects(hashes.to_vec()) {
+                            error!("Can't ask {} for objects: {}", conn, e);
+                        }
+                    }
+                }
+            }
+        }
+        if !expired.is_empty() {
+            debug!("{} requested objects did not arrive in time", expired.len());
+            let mut seen = write_or_die!(self.connection_handler.deduplication_queues.inventory);
+            for hash in expired {
+                seen.invalidate_if_exists(&hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        network::{NetworkId, PacketDestination},
+        p2p::connectivity::send_broadcast_message,
+        read_or_die,
+        stats_export_service::StatsExportService,
+        test_utils::*,
+    };
+    use std::sync::Arc;
+
+    #[test]
+    fn test_inventory_pruning() -> anyhow::Result<()> {
+        let stats = StatsExportService::new(Vec::new())?;
+        let payload = [PacketType::Transaction as u8, 1, 2, 3];
+        let hash = announced_hash(&payload).unwrap();
+        assert_eq!(announced_hash(&[PacketType::FinalizationMessage as u8, 1, 2, 3]), None);
+        let frames = PacketFrames::serialize(
+            &PacketDestination::Broadcast(Vec::new()),
+            NetworkId::from(100),
+            &payload,
+            usize::MAX,
+            &stats,
+        )?;
+
+        let mut inventory = Inventory::default();
+        inventory.store(hash, &frames, 0);
+        let (peer, other_peer) = (RemotePeerId::from(1), RemotePeerId::from(2));
+        let mut request = RequestedObject::new(peer, 0);
+        request.add_announcer(peer);
+        request.add_announcer(other_peer);
+        request.add_announcer(other_peer);
+        assert_eq!(request.announcers, [other_peer]);
+        inventory.requested.insert([1; 32], request);
+        assert_eq!(inventory.prune(REQUEST_TIMEOUT_MS - 1), (vec![], vec![]));
+        // the object is asked for from the other peer that announced it
+        assert_eq!(inventory.prune(REQUEST_TIMEOUT_MS), (vec![(other_peer, [1; 32])], vec![]));
+        assert_eq!(inventory.prune(2 * REQUEST_TIMEOUT_MS - 1), (vec![], vec![]));
+        assert_eq!(inventory.prune(2 * REQUEST_TIMEOUT_MS), (vec![], vec![[1; 32]]));
+        assert!(inventory.objects.contains_key(&hash));
+        inventory.prune(OBJECT_RETENTION_MS);
+        assert!(inventory.objects.is_empty());
+        assert!(inventory.order.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_announced_objects_are_fetched() -> anyhow::Result<()> {
+        let ((node, dp), (peer, peer_dp)) = make_connected_node_pair()?;
+        let peer_id = read_or_die!(node.connections())
+            .values()
+            .next()
+            .map(|conn| conn.remote_peer.local_id)
+            .unwrap();
+
+        let payload = [PacketType::Transaction as u8, 4, 5, 6];
+        let hash = object_hash(&payload);
+        assert_eq!(
+            send_broadcast_message(&node, vec![], NetworkId::from(100), Arc::from(&payload[..])),
+            1
+        );
+        assert!(node.get_announced_object(&hash).is_some());
+
+        // the peer only gets the transaction if it asks for it, and the request is
+        // settled once the transaction arrives
+        let sent =
+            await_condition(|| node.is_announced_object_sent_to(&hash, peer_id).then_some(()));
+        assert!(sent.is_some(), "The peer did not ask for the transaction.");
+        let received = await_condition(|| (!peer.is_object_requested(&hash)).then_some(()));
+        assert!(received.is_some(), "The peer did not receive the transaction.");
+        assert!(peer.wanted_objects(RemotePeerId::from(0), &[hash]).is_empty());
+        // the object is sent to a peer only once
+        assert!(node.take_announced_object(&hash, peer_id).is_none());
+
+        stop_node_delete_dirs(dp, node);
+        stop_node_delete_dirs(peer_dp, peer);
+        Ok(())
+    }
+}
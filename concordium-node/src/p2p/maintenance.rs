@@ -5,7 +5,7 @@ use chrono::prelude::*;
 use crossbeam_channel::{self, Receiver, Sender};
 use mio::{net::TcpListener, Events, Interest, Poll, Registry, Token};
 use nohash_hasher::BuildNoHashHasher;
-use rand::{prelude::SliceRandom, thread_rng, Rng};
+use rand::{prelude::SliceRandom, thread_rng};
 use rkv::{
     backend::{Lmdb, LmdbEnvironment},
     Manager, Rkv,
@@ -14,21 +14,43 @@ use rkv::{
 #[cfg(feature = "network_dump")]
 use crate::dumper::{create_dump_thread, DumpItem};
 use crate::{
-    common::{get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId, P2PPeer, PeerType},
+    common::{
+        get_current_stamp,
+        p2p_peer::{PeerCapabilities, PeerServices, RemotePeerId},
+        P2PNodeId, P2PPeer, PeerType,
+    },
     configuration::{self as config, Config},
-    connection::{ConnChange, Connection, DeduplicationHashAlgorithm, DeduplicationQueues},
+    connection::{
+        inbound_limits::PacketRateLimit,
+        outbound_queues::PacketQueueLimit,
+        rate_limit::{GlobalRateLimits, RateLimitConfig},
+        ConnChange, Connection, DeduplicationHashAlgorithm, DeduplicationQueues,
+    },
     consensus_ffi::{
         catch_up::PeerList,
         consensus::{ConsensusContainer, Regenesis, CALLBACK_QUEUE},
+        helpers::PacketType,
     },
     lock_or_die,
-    network::{Buckets, NetworkId, Networks},
+    network::{
+        Buckets, Disconnect, DisconnectReason, NetworkId, Networks, WireProtocolVersion,
+        WIRE_PROTOCOL_AUTHENTICATED_ID_VERSION, WIRE_PROTOCOL_VERSIONS,
+    },
     p2p::{
-        bans::BanId,
+        address_book::PeerSource,
+        allowlist::PeerAllowlist,
+        bans::{BanId, IpBans, SoftBan},
         connectivity::{accept, connect, connection_housekeeping, AcceptFailureReason, SELF_TOKEN},
+        disconnects::DisconnectRecord,
+        diversity::SubnetLimits,
+        identity::NodeIdentity,
+        inventory::Inventory,
+        mesh::{Mesh, RelayMode},
         peers::check_peers,
+        reconnect::ReconnectBackoff,
+        shutdown::{ShutdownPhase, ShutdownProgress},
     },
-    plugins::consensus::{check_peer_states, update_peer_list},
+    plugins::consensus::{check_peer_states, export_catch_up_metrics, update_peer_list},
     read_or_die, spawn_or_die,
     stats_export_service::StatsExportService,
     utils, write_or_die,
@@ -59,7 +81,24 @@ pub struct NodeConfig {
     pub no_bootstrap_dns: bool,
     /// Clear persistent bans on startup.
     pub clear_bans: bool,
+    /// A JSON ban list to merge into the ban database on start.
+    pub import_bans: Option<PathBuf>,
+    /// A file to write the bans in force to on start.
+    pub export_bans: Option<PathBuf>,
+    /// The maximum number of peers a node shares in a PeerList.
+    pub peer_exchange_list_size: usize,
+    /// The minimum time (in seconds) between two PeerLists sent to a peer.
+    pub peer_exchange_interval: u64,
     pub disallow_multiple_peers_on_ip: bool,
+    /// The maximum numbers of peers per subnet.
+    pub subnet_limits: SubnetLimits,
+    /// The number of peer slots that only outbound connections may use.
+    pub reserved_outbound_slots: u16,
+    /// The number of outbound peers saved as anchors on shutdown.
+    pub anchor_count: usize,
+    /// The maximum number of milliseconds spent writing the messages queued
+    /// for peers on shutdown.
+    pub shutdown_drain_timeout: u64,
     pub bootstrap_nodes: Vec<String>,
     /// Nodes to try and keep the connections to. A node will maintain two
     /// classes of connections, one which is explicitly given, and one which is
@@ -69,6 +108,10 @@ pub struct NodeConfig {
     pub given_addresses: RwLock<HashSet<SocketAddr>>,
     pub max_allowed_nodes: u16,
     pub relay_broadcast_percentage: f64,
+    /// How broadcasts are relayed to the peers.
+    pub relay_mode: RelayMode,
+    /// The number of peers the relay mesh of a network is kept at.
+    pub mesh_degree: usize,
     pub poll_interval: u64,
     pub housekeeping_interval: u64,
     pub bootstrapping_interval: u64,
@@ -79,6 +122,10 @@ pub struct NodeConfig {
     pub hard_connection_limit: u16,
     pub conn_requests_batch_limit: u16,
     pub catch_up_batch_limit: i64,
+    /// The maximum number of peers to catch up with at the same time.
+    pub catch_up_parallelism: usize,
+    /// Time (in ms) allowed for catching up with a peer.
+    pub catch_up_timeout: u64,
     pub timeout_bucket_entry_period: u64,
     pub bucket_cleanup_interval: u64,
     pub thread_pool_size: usize,
@@ -95,6 +142,31 @@ pub struct NodeConfig {
     pub regenesis_arc: Arc<Regenesis>,
     pub max_normal_keep_alive_ms: u64,
     pub clear_persisted_peers: bool,
+    /// The number of seconds after which peers that have not been seen are
+    /// removed from the address book.
+    pub address_book_max_age: u64,
+    /// If set, the node runs in permissioned mode and only completes
+    /// handshakes with peers listed in this file.
+    pub peer_allowlist_file: Option<PathBuf>,
+    /// Packet payloads larger than this (in bytes) are compressed when sent to
+    /// peers that support it.
+    pub packet_compression_threshold: usize,
+    /// Byte-rate limits for all connections together.
+    pub global_rate_limits: RateLimitConfig,
+    /// Byte-rate limits for each connection.
+    pub peer_rate_limits: RateLimitConfig,
+    /// Limits on the rate of incoming packets of each type from a peer.
+    pub inbound_packet_limits: Vec<PacketRateLimit>,
+    /// The number of windows in which a peer may exceed the inbound packet
+    /// limits before it is expelled.
+    pub inbound_limit_violations: u32,
+    /// The weights and capacities of the outbound queues of each packet type.
+    pub outbound_queue_limits: Vec<PacketQueueLimit>,
+    /// Peers whose reputation score falls to this value are expelled.
+    pub reputation_ban_threshold: i64,
+    /// Peers with a latency (in milliseconds) above this value lose
+    /// reputation.
+    pub reputation_latency_threshold: u64,
 }
 
 /// The collection of connections to peer nodes.
@@ -115,17 +187,39 @@ pub struct ConnectionHandler {
     pub conn_candidates:      Mutex<Connections>,
     pub connections:          RwLock<Connections>,
     pub conn_changes:         ConnChanges,
-    pub soft_bans:            RwLock<HashMap<BanId, Instant>>, // (id, expiry)
+    pub soft_bans:            RwLock<HashMap<BanId, SoftBan>>,
+    /// The backoff for reconnecting to the addresses the node dials
+    /// repeatedly.
+    pub reconnect_backoff:    Mutex<ReconnectBackoff>,
+    /// The addresses of the anchors the node connected to on startup.
+    pub anchors:              RwLock<HashSet<SocketAddr>>,
+    /// The most recent disconnect of each peer.
+    pub last_disconnects:     Mutex<HashMap<SocketAddr, DisconnectRecord>>,
+    /// The progress of the shutdown of the network layer.
+    pub shutdown:             RwLock<ShutdownProgress>,
+    /// The objects the node announced and the objects it asked for.
+    pub inventory:            Mutex<Inventory>,
+    /// The mesh peers broadcasts are pushed to in the `mesh` relay mode.
+    pub mesh:                 RwLock<Mesh>,
+    /// The allowed peers if the node is running in permissioned mode.
+    pub peer_allowlist:       RwLock<Option<PeerAllowlist>>,
     pub networks:             RwLock<Networks>,
     pub deduplication_queues: DeduplicationQueues,
     pub last_bootstrap:       AtomicU64,
     pub last_peer_update:     AtomicU64,
     pub total_received:       AtomicU64,
     pub total_sent:           AtomicU64,
+    /// The byte-rate limits shared by all connections.
+    pub global_rate_limits:   Arc<GlobalRateLimits>,
 }
 
 impl ConnectionHandler {
-    fn new(conf: &Config) -> Self {
+    fn new(
+        conf: &Config,
+        buckets: Buckets,
+        peer_allowlist: Option<PeerAllowlist>,
+        global_rate_limits: &RateLimitConfig,
+    ) -> Self {
         let networks = conf.common.network_ids.iter().cloned().map(NetworkId::from).collect();
         let (sndr, rcvr) =
             crossbeam_channel::bounded(conf.connection.hard_connection_limit as usize);
@@ -142,19 +236,30 @@ impl ConnectionHandler {
 
         ConnectionHandler {
             next_token: AtomicUsize::new(1),
-            buckets: Default::default(),
+            buckets: RwLock::new(buckets),
             #[cfg(feature = "network_dump")]
             log_dumper: Default::default(),
             conn_candidates: Default::default(),
             connections: Default::default(),
             conn_changes,
             soft_bans: Default::default(),
+            reconnect_backoff: Mutex::new(ReconnectBackoff::new(
+                Duration::from_secs(conf.connection.reconnect_backoff_initial),
+                Duration::from_secs(conf.connection.reconnect_backoff_max),
+            )),
+            anchors: Default::default(),
+            last_disconnects: Default::default(),
+            shutdown: Default::default(),
+            inventory: Default::default(),
+            mesh: Default::default(),
+            peer_allowlist: RwLock::new(peer_allowlist),
             networks: RwLock::new(networks),
             deduplication_queues,
             last_bootstrap: Default::default(),
             last_peer_update: Default::default(),
             total_received: Default::default(),
             total_sent: Default::default(),
+            global_rate_limits: Arc::new(GlobalRateLimits::new(global_rate_limits)),
         }
     }
 
@@ -202,27 +307,36 @@ impl NetworkDumper {
 /// interval.
 #[derive(Debug, Default)]
 pub struct BadEvents {
-    /// Number of high priority messages that were dropped because they could
-    /// not be enqueued.
-    pub dropped_high_queue: Mutex<HashMap<RemotePeerId, u64>>,
-    /// Number of low priority messages that were dropped because they could not
-    /// be enqueued.
-    pub dropped_low_queue:  Mutex<HashMap<RemotePeerId, u64>>,
+    /// Number of packets of each type that were received from the given peer
+    /// and dropped because the consensus queue they were put in was full.
+    pub dropped_packets:           Mutex<HashMap<(RemotePeerId, PacketType), u64>>,
+    /// Number of messages that were queued for the given peer and dropped
+    /// because the outbound queue they were put in was full.
+    pub dropped_outbound_messages: Mutex<HashMap<RemotePeerId, u64>>,
     /// Number of invalid messages received from the given peer.
-    pub invalid_messages:   Mutex<HashMap<RemotePeerId, u64>>,
+    pub invalid_messages:          Mutex<HashMap<RemotePeerId, u64>>,
+    /// Number of messages from the given peer that were dropped because they
+    /// exceeded the inbound rate limits.
+    pub rate_limited_messages:     Mutex<HashMap<RemotePeerId, u64>>,
 }
 
 impl BadEvents {
-    /// Register a new dropped value for the given peer and return the amount of
-    /// dropped high priority messages for the peer.
-    pub fn inc_dropped_high_queue(&self, peer_id: RemotePeerId) -> u64 {
-        *lock_or_die!(self.dropped_high_queue).entry(peer_id).and_modify(|x| *x += 1).or_insert(1)
+    /// Register a new dropped packet of the given type for the given peer and
+    /// return the amount of dropped packets of that type for the peer.
+    pub fn inc_dropped_packets(&self, peer_id: RemotePeerId, packet_type: PacketType) -> u64 {
+        *lock_or_die!(self.dropped_packets)
+            .entry((peer_id, packet_type))
+            .and_modify(|x| *x += 1)
+            .or_insert(1)
     }
 
-    /// Register a new dropped value for the given peer and return the amount of
-    /// dropped high priority messages for the peer.
-    pub fn inc_dropped_low_queue(&self, peer_id: RemotePeerId) -> u64 {
-        *lock_or_die!(self.dropped_low_queue).entry(peer_id).and_modify(|x| *x += 1).or_insert(1)
+    /// Register a new dropped outbound message for the given peer and return
+    /// the amount of dropped outbound messages for the peer.
+    pub fn inc_dropped_outbound_messages(&self, peer_id: RemotePeerId) -> u64 {
+        *lock_or_die!(self.dropped_outbound_messages)
+            .entry(peer_id)
+            .and_modify(|x| *x += 1)
+            .or_insert(1)
     }
 
     /// Register a new dropped value for the given peer and return the amount of
@@ -230,6 +344,15 @@ impl BadEvents {
     pub fn inc_invalid_messages(&self, peer_id: RemotePeerId) -> u64 {
         *lock_or_die!(self.invalid_messages).entry(peer_id).and_modify(|x| *x += 1).or_insert(1)
     }
+
+    /// Register a new rate limited message for the given peer and return the
+    /// amount of rate limited messages from the peer.
+    pub fn inc_rate_limited_messages(&self, peer_id: RemotePeerId) -> u64 {
+        *lock_or_die!(self.rate_limited_messages)
+            .entry(peer_id)
+            .and_modify(|x| *x += 1)
+            .or_insert(1)
+    }
 }
 
 /// The central object belonging to a node in the network; it handles
@@ -249,25 +372,44 @@ pub struct P2PNode {
     pub start_time:         DateTime<Utc>,
     /// The key-value store holding the node's persistent data.
     pub kvs:                Arc<RwLock<Rkv<LmdbEnvironment>>>,
+    /// The persisted IP and subnet bans, kept in memory so that incoming
+    /// connections can be checked without reading the database.
+    pub ip_bans:            RwLock<IpBans>,
     /// The catch-up list of peers.
     pub peers:              RwLock<PeerList>,
     /// Cache of bad events that we report on each connection housekeeping
     /// interval to avoid spamming the logs in case of failure.
     pub bad_events:         BadEvents,
+    /// The node's static key used in the Noise handshake, from which the node
+    /// id is derived.
+    pub identity:           NodeIdentity,
 }
 
 impl P2PNode {
-    /// Creates a new node and its Poll. If the node id is provided the node
-    /// will be started with that Peer ID. If it is not a fresh one will be
-    /// generated. The return value is a triple of the node, the socket on which
-    /// the node is listening for incoming connections, and the mio poll
-    /// that can be used to notify/poll for incoming connections.
+    /// Creates a new node and its Poll. The node id is derived from the node's
+    /// static key, which is loaded from the data directory, or generated and
+    /// stored there if not present. The return value is a triple of the node,
+    /// the socket on which the node is listening for incoming connections,
+    /// and the mio poll that can be used to notify/poll for incoming
+    /// connections.
     pub fn new(
-        supplied_id: Option<P2PNodeId>,
         conf: &Config,
         peer_type: PeerType,
         stats: Arc<StatsExportService>,
         regenesis_arc: Arc<Regenesis>,
+    ) -> anyhow::Result<(Arc<Self>, TcpListener, Poll)> {
+        let identity = NodeIdentity::load_or_generate(&conf.common.data_dir)
+            .context("Could not load or generate the node's static key.")?;
+        Self::new_with_identity(conf, peer_type, stats, regenesis_arc, identity)
+    }
+
+    /// Creates a new node like `new`, but with the given identity.
+    pub fn new_with_identity(
+        conf: &Config,
+        peer_type: PeerType,
+        stats: Arc<StatsExportService>,
+        regenesis_arc: Arc<Regenesis>,
+        identity: NodeIdentity,
     ) -> anyhow::Result<(Arc<Self>, TcpListener, Poll)> {
         let addr = if let Some(ref addy) = conf.common.listen_address {
             let ip_addr = addy.parse::<IpAddr>().context(
@@ -288,7 +430,14 @@ impl P2PNode {
                 .context("Could not compute my own ip. Use `--listen-address` to specify it.")?
         };
 
-        let id = supplied_id.unwrap_or_else(|| rand::thread_rng().gen::<P2PNodeId>());
+        let id = identity.id();
+        if let Some(supplied_id) = conf.common.id {
+            warn!(
+                "The `--id` option is deprecated and ignored. The supplied id {} is not used, the \
+                 node id {} is derived from the node's static key.",
+                supplied_id, id
+            );
+        }
 
         info!("My Node ID is {}", id);
         info!("Listening on {}:{}", ip, conf.common.listen_port);
@@ -311,10 +460,31 @@ impl P2PNode {
             conf.common.listen_port
         };
 
+        let mut capabilities = PeerCapabilities::default();
+        if conf.connection.advertise_archival {
+            capabilities.insert(PeerCapabilities::ARCHIVAL);
+        }
+        if conf.connection.advertise_grpc2_port.is_some() {
+            capabilities.insert(PeerCapabilities::PUBLIC_GRPC2);
+        }
+        // bootstrappers do not run consensus, so they do not relay anything; nodes
+        // only relay if they forward broadcasts to at least some of their peers
+        let relays_broadcasts = conf.connection.relay_mode == RelayMode::Mesh
+            || conf.connection.relay_broadcast_percentage > 0.0;
+        let drops_rebroadcasts = conf.cli.drop_rebroadcast_probability.map_or(false, |p| p >= 1.0);
+        if peer_type == PeerType::Node && relays_broadcasts && !drops_rebroadcasts {
+            capabilities.insert(PeerCapabilities::RELAYS_TRANSACTIONS);
+        }
+        let services = PeerServices {
+            grpc2_port: conf.connection.advertise_grpc2_port,
+        };
+
         let self_peer = P2PPeer {
             id,
             peer_type,
             addr: SocketAddr::new(ip, own_peer_port),
+            capabilities,
+            services,
         };
 
         let given_addresses = RwLock::new(parse_config_nodes(&conf.connection)?);
@@ -324,7 +494,20 @@ impl P2PNode {
             desired_nodes_count: conf.connection.desired_nodes,
             no_bootstrap_dns: conf.connection.no_bootstrap_dns,
             clear_bans: conf.connection.clear_bans,
+            import_bans: conf.connection.import_bans.clone(),
+            export_bans: conf.connection.export_bans.clone(),
+            peer_exchange_list_size: conf.connection.peer_exchange_list_size,
+            peer_exchange_interval: conf.connection.peer_exchange_interval,
             disallow_multiple_peers_on_ip: conf.connection.disallow_multiple_peers_on_ip,
+            subnet_limits: SubnetLimits::new(
+                conf.connection.max_peers_per_ipv4_16,
+                conf.connection.max_peers_per_ipv4_24,
+                conf.connection.max_peers_per_ipv6_32,
+                conf.connection.max_peers_per_ipv6_48,
+            ),
+            reserved_outbound_slots: conf.connection.reserved_outbound_slots,
+            anchor_count: conf.connection.anchor_count,
+            shutdown_drain_timeout: conf.connection.shutdown_drain_timeout,
             bootstrap_nodes: conf.connection.bootstrap_nodes.clone(),
             given_addresses,
             max_allowed_nodes: if let Some(max) = conf.connection.max_allowed_nodes {
@@ -336,6 +519,8 @@ impl P2PNode {
                 ) as u16
             },
             relay_broadcast_percentage: conf.connection.relay_broadcast_percentage,
+            relay_mode: conf.connection.relay_mode,
+            mesh_degree: conf.connection.mesh_degree,
             poll_interval: conf.cli.poll_interval,
             housekeeping_interval: conf.connection.housekeeping_interval,
             bootstrapping_interval: conf.connection.bootstrapping_interval,
@@ -349,6 +534,8 @@ impl P2PNode {
             conn_requests_batch_limit: conf.connection.conn_requests_batch_limit,
             hard_connection_limit: conf.connection.hard_connection_limit,
             catch_up_batch_limit: conf.connection.catch_up_batch_limit,
+            catch_up_parallelism: conf.connection.catch_up_parallelism,
+            catch_up_timeout: conf.connection.catch_up_timeout,
             timeout_bucket_entry_period: if peer_type == PeerType::Bootstrapper {
                 conf.bootstrapper.bootstrapper_timeout_bucket_entry_period
             } else {
@@ -372,9 +559,45 @@ impl P2PNode {
             regenesis_arc,
             max_normal_keep_alive_ms: conf.connection.max_normal_keep_alive * 1000,
             clear_persisted_peers: conf.connection.clear_persisted_peers,
+            address_book_max_age: conf.connection.address_book_max_age,
+            peer_allowlist_file: conf.connection.peer_allowlist.clone(),
+            packet_compression_threshold: conf.connection.packet_compression_threshold,
+            global_rate_limits: RateLimitConfig {
+                upload_high: conf.connection.upload_limit_high,
+                upload_low:  conf.connection.upload_limit_low,
+                download:    conf.connection.download_limit,
+            },
+            peer_rate_limits: RateLimitConfig {
+                upload_high: conf.connection.peer_upload_limit_high,
+                upload_low:  conf.connection.peer_upload_limit_low,
+                download:    conf.connection.peer_download_limit,
+            },
+            inbound_packet_limits: conf.connection.inbound_packet_limits.clone(),
+            inbound_limit_violations: conf.connection.inbound_limit_violations,
+            outbound_queue_limits: conf.connection.outbound_queue_limits.clone(),
+            reputation_ban_threshold: conf.connection.reputation_ban_threshold,
+            reputation_latency_threshold: conf.connection.reputation_latency_threshold,
         };
 
-        let connection_handler = ConnectionHandler::new(conf);
+        let peer_allowlist = if let Some(ref path) = config.peer_allowlist_file {
+            let allowlist = PeerAllowlist::from_file(path)?;
+            info!("Running in permissioned mode with {} allowed peers", allowlist.entries().len());
+            Some(allowlist)
+        } else {
+            None
+        };
+        // A bootstrapper keeps every node it knows of, so that it can share them with
+        // new nodes.
+        let bucket_size = match peer_type {
+            PeerType::Bootstrapper => usize::from(conf.bootstrapper.max_nodes),
+            PeerType::Node => conf.common.bucket_size,
+        };
+        let connection_handler = ConnectionHandler::new(
+            conf,
+            Buckets::new(id, bucket_size),
+            peer_allowlist,
+            &config.global_rate_limits,
+        );
 
         // Create the node key-value store environment
         let kvs = Manager::<LmdbEnvironment>::singleton()
@@ -394,19 +617,38 @@ impl P2PNode {
             self_peer,
             stats,
             kvs,
+            ip_bans: Default::default(),
             peers: Default::default(),
             bad_events: BadEvents::default(),
+            identity,
         });
 
+        if let Err(err) = node.load_ip_bans() {
+            error!("Couldn't load the bans: {}", err);
+        }
         if node.config.clear_bans {
             if let Err(err) = node.clear_bans() {
                 error!("Couldn't reset the ban list: {}", err);
             }
         }
+        if let Some(ref path) = node.config.import_bans {
+            match node.import_bans_from_file(path) {
+                Ok(merged) => info!("Imported {} bans from {}", merged, path.display()),
+                Err(err) => error!("Couldn't import the ban list: {:#}", err),
+            }
+        }
+        if let Some(ref path) = node.config.export_bans {
+            if let Err(err) = node.export_bans_to_file(path) {
+                error!("Couldn't export the ban list: {:#}", err);
+            }
+        }
         if node.config.clear_persisted_peers {
             if let Err(err) = node.clear_persisted_peers() {
                 error!("Couldn't reset the persisted peers: {}", err);
             }
+            if let Err(err) = node.clear_anchors() {
+                error!("Couldn't reset the anchors: {}", err);
+            }
         }
 
         Ok((node, server, poll))
@@ -520,6 +762,21 @@ impl P2PNode {
     /// Get the node's identifier.
     pub fn id(&self) -> P2PNodeId { self.self_peer.id }
 
+    /// The wire protocol versions supported by the node, in descending order.
+    /// A node whose id is not derived from its static key only supports the
+    /// versions before `WIRE_PROTOCOL_AUTHENTICATED_ID_VERSION`.
+    pub fn wire_versions(&self) -> &'static [WireProtocolVersion] {
+        if self.identity.is_bound() {
+            &WIRE_PROTOCOL_VERSIONS
+        } else {
+            let unauthenticated = WIRE_PROTOCOL_VERSIONS
+                .iter()
+                .position(|&version| version < WIRE_PROTOCOL_AUTHENTICATED_ID_VERSION)
+                .unwrap_or(WIRE_PROTOCOL_VERSIONS.len());
+            &WIRE_PROTOCOL_VERSIONS[unauthenticated..]
+        }
+    }
+
     /// Get the node's `PeerType`.
     #[inline]
     pub fn peer_type(&self) -> PeerType { self.self_peer.peer_type }
@@ -576,9 +833,26 @@ impl P2PNode {
     pub fn internal_addr(&self) -> SocketAddr { self.self_peer.addr }
 
     /// Shut the node down gracefully without terminating its threads.
+    /// This blocks until the messages queued for peers are written or the
+    /// shutdown deadline passes. Does nothing if the node is already shutting
+    /// down.
     pub fn close(&self) -> anyhow::Result<()> {
-        // First notify the maintenance thread to stop processing new connections or
-        // network packets.
+        // First stop accepting and making connections.
+        if !self.begin_shutdown() {
+            return Ok(());
+        }
+        self.complete_shutdown()
+    }
+
+    /// Complete a shutdown that was begun with `begin_shutdown`. This must only
+    /// be called by the caller that began it.
+    pub fn complete_shutdown(&self) -> anyhow::Result<()> {
+        // Give the maintenance thread until the deadline to write the messages
+        // queued for peers.
+        self.drain_connections();
+        // Then notify the maintenance thread to stop processing network packets. It
+        // notifies the peers and closes the connections.
+        self.set_shutdown_phase(ShutdownPhase::Closing);
         self.stop_network();
         // Then process all messages we still have in the inbound Consensus queues.
         CALLBACK_QUEUE.stop()
@@ -667,8 +941,10 @@ pub fn spawn(
                 continue;
             }
 
-            // check for new connections
-            if unprocessed_attempts || events.iter().any(|event| event.token() == SELF_TOKEN) {
+            // check for new connections, unless the node is shutting down
+            if !node.is_shutting_down()
+                && (unprocessed_attempts || events.iter().any(|event| event.token() == SELF_TOKEN))
+            {
                 let mut attempt_number = 0;
                 unprocessed_attempts = true;
                 while attempt_number < max_num_requests {
@@ -678,6 +954,9 @@ pub fn spawn(
                                 error!("{}", e);
                                 if let AcceptFailureReason::TooManyConnections {
                                     addr: _,
+                                }
+                                | AcceptFailureReason::TooManyInboundConnections {
+                                    addr: _,
                                 } = e
                                 {
                                     break;
@@ -730,6 +1009,9 @@ pub fn spawn(
                     if node.peer_type() != PeerType::Bootstrapper {
                         node.measure_connection_latencies()
                     }
+                    if consensus.is_some() {
+                        export_catch_up_metrics(&node);
+                    }
 
                     let peer_stat_list = node.get_peer_stats(None);
                     check_peers(&node, &peer_stat_list, attempted_bootstrap);
@@ -755,8 +1037,16 @@ pub fn spawn(
                 last_buckets_cleaned = Instant::now();
             }
         }
+        // remember the best outbound peers so that they can be reconnected to first
+        // on the next startup
+        if node.peer_type() == PeerType::Node {
+            if let Err(e) = node.save_anchors() {
+                error!("Could not save the anchors: {}", e);
+            }
+        }
         // close all connections. At this point no data will be read or written
-        // to connections since the connection loop has terminated. This frees up
+        // to connections since the connection loop has terminated, apart from the
+        // best-effort notification that the node is shutting down. This frees up
         // resources.
         // TODO: This is ugly. Ideally we'd drop the entire connection handler here with
         // all the data that pertains to it, including all connections and
@@ -765,7 +1055,15 @@ pub fn spawn(
         // module. If we did that there would be no need for clearing connection
         // collections here.
         lock_or_die!(node.conn_candidates()).clear();
-        write_or_die!(node.connections()).clear();
+        {
+            let mut conns = write_or_die!(node.connections());
+            for conn in conns.values_mut() {
+                conn.send_disconnect(Disconnect::new(DisconnectReason::ShuttingDown, None));
+                conn.low_level.shutdown_socket();
+            }
+            conns.clear();
+        }
+        node.set_shutdown_phase(ShutdownPhase::Closed);
         write_or_die!(node.peers).clear();
         // Stop listening and close the socket. The socket is closed when the thread
         // terminates via drop.
@@ -808,13 +1106,27 @@ fn process_conn_change(node: &Arc<P2PNode>, conn_change: ConnChange) {
                     existing.remote_addr() == addr || existing.remote_peer.external_addr() == addr
                 });
                 if !is_connected {
+                    // record the peer in the address book so the node can
+                    // reconnect to the peer if the node restarts. Peers learned from peer
+                    // lists were recorded with the source of the list when the list was
+                    // received, and the source of existing entries is kept.
+                    if conn.remote_peer_type() == PeerType::Node {
+                        let source = if read_or_die!(node.config.given_addresses).contains(&addr) {
+                            PeerSource::Given
+                        } else if !conn.low_level.is_initiator() {
+                            PeerSource::Incoming
+                        } else if read_or_die!(node.connection_handler.anchors).contains(&addr) {
+                            PeerSource::Anchor
+                        } else {
+                            PeerSource::PeerExchange
+                        };
+                        if let Err(err) = node.record_handshake(addr, conn.remote_id(), source) {
+                            error!("Could not record the peer in the address book {}", err);
+                        }
+                    }
+                    lock_or_die!(node.connection_handler.reconnect_backoff).reset(addr);
                     conns.insert(conn.token(), conn);
                     node.bump_last_peer_update();
-                    // insert the peer in the lmdb store so the node can
-                    // reconnect to the peer if the node restarts.
-                    if let Err(err) = node.persist_peer(addr) {
-                        error!("Could not persist peer to database {}", err);
-                    }
                 } else {
                     warn!("Already connected to a peer on the given address.")
                 }
@@ -846,14 +1158,18 @@ fn process_conn_change(node: &Arc<P2PNode>, conn_change: ConnChange) {
                 }
             }
         }
-        ConnChange::ExpulsionByToken(token) => {
-            if let Some((is_conn, remote_peer)) = node.remove_connection(token) {
+        ConnChange::ExpulsionByToken(token, reason) => {
+            let disconnect = Disconnect::new(
+                DisconnectReason::Banned,
+                Some(Duration::from_secs(config::SOFT_BAN_DURATION_SECS)),
+            );
+            if let Some((is_conn, remote_peer)) = node.remove_connection(token, Some(disconnect)) {
                 let ip = remote_peer.addr.ip();
-                warn!("Soft-banning {} due to a breach of protocol", ip);
-                write_or_die!(node.connection_handler.soft_bans).insert(
-                    BanId::Ip(ip),
-                    Instant::now() + Duration::from_secs(config::SOFT_BAN_DURATION_SECS),
-                );
+                warn!("Soft-banning {} due to {}", ip, reason);
+                write_or_die!(node.connection_handler.soft_bans).insert(BanId::Ip(ip), SoftBan {
+                    expiry: Instant::now() + Duration::from_secs(config::SOFT_BAN_DURATION_SECS),
+                    reason,
+                });
                 node.stats.soft_banned_peers.inc();
                 node.stats.soft_banned_peers_total.inc();
                 // If the peer was connected then also expunge it from
@@ -865,21 +1181,27 @@ fn process_conn_change(node: &Arc<P2PNode>, conn_change: ConnChange) {
                 }
             }
         }
-        ConnChange::RemovalByToken(token) => {
+        ConnChange::RemovalByToken(token, disconnect) => {
             trace!("Removing connection with token {:?}", token);
-            if let Some((is_conn, remote_peer)) = node.remove_connection(token) {
-                // If the peer was connected then also expunge it from
-                // the database so the node does not try to reconnect to it upon a restart.
-                if is_conn {
-                    if let Err(err) = node.remove_persisted_peer(remote_peer.external_addr()) {
-                        error!("Unable to remove 'StoredPeer' from database {}", err);
+            if let Some((is_conn, remote_peer)) = node.remove_connection(token, disconnect) {
+                // Peers that were connected stay in the address book and age out if they
+                // are not seen again. A connection that is removed before the handshake
+                // completed counts as a failed connection attempt.
+                if !is_conn {
+                    let addr = remote_peer.external_addr();
+                    if let Err(err) = node.record_failed_connections(&[addr]) {
+                        error!("Could not record a failed connection {}", err);
                     }
+                    node.record_reconnect_failure(
+                        addr,
+                        "The connection was closed before the handshake completed.".to_owned(),
+                    );
                 }
             }
         }
-        ConnChange::RemoveAllByTokens(tokens) => {
+        ConnChange::RemoveAllByTokens(tokens, disconnect) => {
             trace!("Removing connections with tokens {:?}", tokens);
-            let (_, removed_connected_peers) = node.remove_connections(&tokens);
+            let (_, removed_connected_peers) = node.remove_connections(&tokens, disconnect);
             for p in removed_connected_peers {
                 // If any connections were dropped, remove them
                 // from the database.
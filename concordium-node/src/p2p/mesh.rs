@@ -0,0 +1,224 @@
+//! Mesh-based relay of broadcasts.
+//!
+//! In the `mesh` relay mode the node keeps, for each of its networks, a stable
+//! set of peers of about `--mesh-degree` members, the mesh. Transactions and
+//! blocks are pushed to the mesh peers, while the other peers that support
+//! `WIRE_PROTOCOL_INVENTORY_VERSION` only get them announced and fetch them if
+//! they have not seen them. Since the meshes are local to the node and not
+//! negotiated with the peers, a peer outside the mesh may not be in the mesh of
+//! any other peer either, so the broadcasts that are not announced, e.g.,
+//! finalization messages, are still pushed to all the peers, and so are all
+//! broadcasts to peers that use older wire protocol versions. The meshes are
+//! maintained during connection housekeeping: peers that disconnected or whose
+//! reputation score turned negative are pruned, and a mesh that became too
+//! small is grafted with the best-scoring peers, while the lowest-scoring
+//! members of a mesh that became too large are pruned. Until the mesh of a
+//! network is formed, its broadcasts are flooded.
+
+use crate::{
+    common::{p2p_peer::RemotePeerId, PeerType},
+    network::NetworkId,
+    p2p::P2PNode,
+    read_or_die, write_or_die,
+};
+use anyhow::bail;
+use rand::seq::SliceRandom;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::atomic::Ordering,
+};
+
+/// How the node relays broadcasts to its peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayMode {
+    /// Push broadcasts to all the peers, or to the share of them set with
+    /// `--relay-broadcast-percentage`.
+    Flood,
+    /// Push broadcasts to the mesh peers and announce them to the others.
+    Mesh,
+}
+
+impl FromStr for RelayMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "flood" => Ok(RelayMode::Flood),
+            "mesh" => Ok(RelayMode::Mesh),
+            _ => bail!("Could not parse relay mode"),
+        }
+    }
+}
+
+/// The size below which a mesh of the given degree is grafted.
+fn low_watermark(degree: usize) -> usize { degree - degree / 3 }
+
+/// The size above which a mesh of the given degree is pruned.
+fn high_watermark(degree: usize) -> usize { 2 * degree }
+
+/// The mesh peers of each network.
+#[derive(Default)]
+pub struct Mesh {
+    members: HashMap<NetworkId, HashSet<RemotePeerId>>,
+}
+
+/// The peers added to and removed from a mesh by its maintenance.
+#[derive(Debug, Default)]
+struct MeshUpdate {
+    grafted: Vec<RemotePeerId>,
+    pruned:  Vec<RemotePeerId>,
+}
+
+impl Mesh {
+    /// Maintain the mesh of a network given the peers in the network with
+    /// their reputation scores.
+    fn update(
+        &mut self,
+        network_id: NetworkId,
+        candidates: &[(RemotePeerId, i64)],
+        degree: usize,
+    ) -> MeshUpdate {
+        let members = self.members.entry(network_id).or_default();
+        let mut update = MeshUpdate::default();
+
+        members.retain(|member| {
+            let keep = candidates.iter().any(|&(id, score)| id == *member && score >= 0);
+            if !keep {
+                update.pruned.push(*member);
+            }
+            keep
+        });
+
+        // shuffle first so that ties between equal scores are broken randomly
+        let mut rng = rand::thread_rng();
+        if members.len() < low_watermark(degree) {
+            let mut grafts = candidates
+                .iter()
+                .filter(|(id, score)| *score >= 0 && !members.contains(id))
+                .copied()
+                .collect::<Vec<_>>();
+            grafts.shuffle(&mut rng);
+            grafts.sort_by_key(|&(_, score)| Reverse(score));
+            for (id, _) in grafts.into_iter().take(degree - members.len()) {
+                members.insert(id);
+                update.grafted.push(id);
+            }
+        } else if members.len() > high_watermark(degree) {
+            let mut prunes = candidates
+                .iter()
+                .filter(|(id, _)| members.contains(id))
+                .copied()
+                .collect::<Vec<_>>();
+            prunes.shuffle(&mut rng);
+            prunes.sort_by_key(|&(_, score)| score);
+            for (id, _) in prunes.into_iter().take(members.len() - degree) {
+                members.remove(&id);
+                update.pruned.push(id);
+            }
+        }
+
+        update
+    }
+}
+
+impl P2PNode {
+    /// The mesh peers of the given network, or `None` if its broadcasts are
+    /// flooded, either because of the relay mode or because the mesh is not
+    /// formed yet.
+    pub(crate) fn mesh_peers(&self, network_id: NetworkId) -> Option<HashSet<RemotePeerId>> {
+        if self.config.relay_mode != RelayMode::Mesh {
+            return None;
+        }
+        read_or_die!(self.connection_handler.mesh)
+            .members
+            .get(&network_id)
+            .filter(|members| !members.is_empty())
+            .cloned()
+    }
+
+    /// Graft peers onto and prune peers from the meshes of the node's
+    /// networks, and export the sizes of the meshes.
+    pub(crate) fn maintain_mesh(&self) {
+        if self.config.relay_mode != RelayMode::Mesh {
+            return;
+        }
+
+        let mut candidates: HashMap<NetworkId, Vec<(RemotePeerId, i64)>> = HashMap::new();
+        for conn in read_or_die!(self.connections()).values() {
+            if conn.remote_peer.peer_type == PeerType::Bootstrapper {
+                continue;
+            }
+            let score = conn.stats.score.load(Ordering::Relaxed);
+            for &network_id in conn.remote_end_networks.iter() {
+                candidates.entry(network_id).or_default().push((conn.remote_peer.local_id, score));
+            }
+        }
+
+        let networks = read_or_die!(self.networks()).iter().copied().collect::<Vec<_>>();
+        let mut mesh = write_or_die!(self.connection_handler.mesh);
+        mesh.members.retain(|network_id, _| networks.contains(network_id));
+        for network_id in networks {
+            let update = mesh.update(
+                network_id,
+                candidates.get(&network_id).map_or(&[][..], Vec::as_slice),
+                self.config.mesh_degree,
+            );
+            if !update.grafted.is_empty() || !update.pruned.is_empty() {
+                debug!(
+                    "Updated the mesh of network {}: grafted {:?}, pruned {:?}",
+                    network_id.id, update.grafted, update.pruned
+                );
+            }
+            self.stats
+                .mesh_peers
+                .with_label_values(&[&network_id.id.to_string()])
+                .set(mesh.members.get(&network_id).map_or(0, HashSet::len) as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mesh_maintenance() {
+        let network_id = NetworkId::from(100);
+        let peer = |id: usize| RemotePeerId::from(id);
+        let mut mesh = Mesh::default();
+
+        // the mesh is grafted with the best-scoring peers, skipping negative scores
+        let mut candidates = (0..10).map(|id| (peer(id), id as i64)).collect::<Vec<_>>();
+        candidates[9].1 = -1;
+        let update = mesh.update(network_id, &candidates, 4);
+        assert_eq!(update.grafted.len(), 4);
+        assert!(update.pruned.is_empty());
+        let expected = [5, 6, 7, 8].iter().copied().map(peer).collect::<HashSet<_>>();
+        assert_eq!(mesh.members[&network_id], expected);
+
+        // a mesh within the watermarks is left as it is
+        candidates.push((peer(10), 100));
+        let update = mesh.update(network_id, &candidates, 4);
+        assert!(update.grafted.is_empty() && update.pruned.is_empty());
+
+        // members that disconnected or misbehaved are pruned, and the mesh is
+        // grafted once it is below the low watermark
+        candidates.retain(|&(id, _)| id != peer(8));
+        candidates.iter_mut().find(|(id, _)| *id == peer(7)).unwrap().1 = -5;
+        let update = mesh.update(network_id, &candidates, 4);
+        assert_eq!(update.pruned.len(), 2);
+        assert_eq!(update.grafted, vec![peer(10), peer(4)]);
+        let expected = [4, 5, 6, 10].iter().copied().map(peer).collect::<HashSet<_>>();
+        assert_eq!(mesh.members[&network_id], expected);
+
+        // a mesh above the high watermark loses its lowest-scoring members
+        let many = (0..20).map(|id| (peer(id), id as i64)).collect::<Vec<_>>();
+        mesh.members.insert(network_id, many.iter().map(|&(id, _)| id).collect());
+        let update = mesh.update(network_id, &many, 4);
+        assert_eq!(update.pruned.len(), 16);
+        let expected = [16, 17, 18, 19].iter().copied().map(peer).collect::<HashSet<_>>();
+        assert_eq!(mesh.members[&network_id], expected);
+    }
+}
@@ -1,17 +1,29 @@
 //! Central node object handling.
 
+pub mod address_book;
+pub mod allowlist;
+pub mod anchors;
+pub mod ban_list;
 pub mod bans;
 pub mod connectivity;
+pub mod disconnects;
+pub mod diversity;
+pub mod identity;
+pub mod inventory;
 pub mod maintenance;
+pub mod mesh;
 pub mod peers;
+pub mod reconnect;
+pub mod reputation;
+pub mod shutdown;
 
 pub use self::maintenance::{Connections, P2PNode};
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        common::{p2p_peer::RemotePeerId, PeerType},
-        p2p::bans::PersistedBanId,
+        common::{get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId, PeerType},
+        p2p::bans::{BanInfo, BanOrigin, PersistedBanId},
         test_utils::*,
     };
     use std::net::IpAddr;
@@ -37,30 +49,56 @@ mod tests {
         assert_eq!(reply.len(), 0);
 
         let to_ban2 = "127.0.0.1".parse::<IpAddr>()?;
+        let permanent = BanInfo {
+            expiry: None,
+            origin: BanOrigin::Manual,
+            reason: "testing".to_string(),
+        };
 
         // Insertion by ip
         assert!(
-            !node.drop_by_ip_and_ban(to_ban2)?,
+            !node.drop_and_ban(PersistedBanId::Ip(to_ban2), permanent.clone())?,
             "Should have returned false since the peer does not exist."
         );
         let reply = node.get_banlist()?;
         assert_eq!(reply.len(), 1);
-        assert_eq!(reply[0], PersistedBanId::Ip(to_ban2));
+        assert_eq!(reply[0].id, PersistedBanId::Ip(to_ban2));
+        assert_eq!(reply[0].info, permanent);
 
         // Duplicates check
         assert!(
-            !node.drop_by_ip_and_ban(to_ban2)?,
+            !node.drop_and_ban(PersistedBanId::Ip(to_ban2), permanent.clone())?,
             "Should have banned the same IP again, returning false since no peer exists."
         );
         let reply = node.get_banlist()?;
         assert_eq!(reply.len(), 1);
-        assert_eq!(reply[0], PersistedBanId::Ip(to_ban2));
+        assert_eq!(reply[0].id, PersistedBanId::Ip(to_ban2));
 
         // Deletion by ip
         node.unban_node(PersistedBanId::Ip(to_ban2))?;
         let reply = node.get_banlist()?;
         assert!(reply.is_empty());
 
+        // Subnet bans cover all the addresses in the subnet
+        let subnet = PersistedBanId::Subnet("10.1.0.0/16".parse()?);
+        node.drop_and_ban(subnet, permanent.clone())?;
+        assert!(node.is_ip_banned("10.1.2.3".parse()?));
+        assert!(!node.is_ip_banned("10.2.2.3".parse()?));
+        node.unban_node(subnet)?;
+        assert!(!node.is_ip_banned("10.1.2.3".parse()?));
+
+        // Expired bans are not in force and are removed during housekeeping
+        let expired = BanInfo {
+            expiry: Some(get_current_stamp() - 1),
+            origin: BanOrigin::Automatic,
+            reason: String::new(),
+        };
+        let banned_node = PersistedBanId::Node(P2PNodeId(42));
+        node.drop_and_ban(banned_node, expired)?;
+        assert!(!node.is_banned(banned_node)?);
+        assert!(node.get_banlist()?.is_empty());
+        assert_eq!(node.remove_expired_bans()?, 1);
+
         stop_node_delete_dirs(dp, node);
 
         Ok(())
@@ -1,21 +1,20 @@
 //! Peer handling.
 
 use crate::{
-    common::{get_current_stamp, p2p_peer::RemotePeerId, PeerStats, PeerType},
-    connection::Connection,
+    common::{get_current_stamp, p2p_peer::RemotePeerId, PeerStats, PeerType, RemotePeer},
+    connection::{ConnChange, Connection},
     netmsg,
     network::NetworkRequest,
-    p2p::{connectivity::connect, maintenance::attempt_bootstrap, P2PNode},
+    p2p::{
+        address_book::sort_by_preference, connectivity::connect, maintenance::attempt_bootstrap,
+        P2PNode,
+    },
     read_or_die,
 };
 use anyhow::ensure;
 use chrono::Utc;
 use prometheus::core::Atomic;
-use rkv::{StoreOptions, Value};
-use std::{
-    net::SocketAddr,
-    sync::{atomic::Ordering, Arc},
-};
+use std::sync::{atomic::Ordering, Arc};
 
 impl P2PNode {
     /// Obtain the list of statistics from all the peers, optionally of a
@@ -24,16 +23,7 @@ impl P2PNode {
         read_or_die!(self.connections())
             .values()
             .filter(|conn| peer_type.is_none() || peer_type == Some(conn.remote_peer_type()))
-            .map(|conn| {
-                PeerStats::new(
-                    conn.remote_peer.local_id,
-                    conn.remote_peer.self_id.unwrap(), // safe - always available post-handshake
-                    conn.remote_addr(),
-                    conn.remote_peer_external_port(),
-                    conn.remote_peer_type(),
-                    &conn.stats,
-                )
-            })
+            .map(|conn| PeerStats::new(&conn.remote_peer, &conn.stats))
             .collect()
     }
 
@@ -83,6 +73,27 @@ impl P2PNode {
         Ok(())
     }
 
+    /// Try to connect to the peers in the buckets, i.e., peers we were
+    /// connected to or learned about recently.
+    fn connect_to_known_peers(&self) {
+        let known_peers = {
+            let networks = read_or_die!(self.networks());
+            read_or_die!(self.buckets()).get_all_nodes(None, &networks)
+        };
+        let mut peers = Vec::new();
+        for peer in known_peers.iter().filter_map(RemotePeer::peer) {
+            // this records the attempt, so it must only be made for the peers that are
+            // actually dialled
+            if self.try_reconnect_attempt(peer.addr) {
+                peers.push(peer);
+            }
+        }
+        if !peers.is_empty() {
+            info!("Trying to connect to {} previously known peers", peers.len());
+            self.register_conn_change(ConnChange::NewPeers(peers));
+        }
+    }
+
     fn send_get_peers(&self) {
         let request =
             NetworkRequest::GetPeers(read_or_die!(self.networks()).iter().copied().collect());
@@ -142,6 +153,9 @@ pub fn check_peers(node: &Arc<P2PNode>, peer_stats: &[PeerStats], attempted_boot
                         );
                     }
                 }
+                // the bootstrappers might be unreachable, so also try the peers we
+                // learned about from peer exchange
+                node.connect_to_known_peers();
             } else {
                 info!("Not enough peers - sending GetPeers requests");
                 node.send_get_peers();
@@ -173,68 +187,26 @@ fn calculate_average_throughput(
     Ok((avg_bps_in, avg_bps_out))
 }
 
-/// The lmdb store name of the persisted peers.
-const PEERS_STORE_NAME: &str = "peers";
-
-/// Try connect to previously connected peers if any.
+/// Try connect to the best peers in the address book, i.e., the peers the node
+/// had a successful handshake with most recently, up to the desired number of
+/// peers.
 /// Note that as opposed to [`connect_to_config_nodes`] this function respects
 /// the maximum peers configured for the node.
 pub fn connect_to_stored_nodes(node: &Arc<P2PNode>) -> anyhow::Result<()> {
-    let Ok(kvs_env) = node.kvs.read() else {
-        anyhow::bail!("could not read previously connected peers: cannot obtain lock for the kvs");
-    };
-    let peers_store = kvs_env.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
-    let peers_reader = kvs_env.read()?;
-    let peers_iter = peers_store.iter_start(&peers_reader)?;
-    for entry in peers_iter {
-        let (peer_bytes, _expiry) = entry?;
-        let stored_peer: SocketAddr = serde_json::from_slice(peer_bytes)?;
-        if let Err(e) = connect(node, PeerType::Node, stored_peer, None, true) {
+    let mut entries = node.get_address_book()?;
+    sort_by_preference(&mut entries);
+    for entry in entries.into_iter().take(usize::from(node.config.desired_nodes_count)) {
+        if !node.try_reconnect_attempt(entry.addr) {
+            continue;
+        }
+        if let Err(e) = connect(node, PeerType::Node, entry.addr, entry.info.node_id, true) {
             warn!("could not connect to previously connected peer {}", e);
+            node.record_reconnect_failure(entry.addr, e.to_string());
         }
     }
     Ok(())
 }
 
-impl P2PNode {
-    /// Persist the [`SocketAddr`] of a peer.
-    pub fn persist_peer(&self, peer_addr: SocketAddr) -> anyhow::Result<()> {
-        let Ok(kv) = self.kvs.read() else {
-            anyhow::bail!("Could not acquire lock over lmdb");
-        };
-        let peers_store = kv.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
-        let buf = serde_json::to_vec::<SocketAddr>(&peer_addr)?;
-        let mut writer = kv.write()?;
-        peers_store.put(&mut writer, buf, &Value::U64(0))?;
-        writer.commit()?;
-        Ok(())
-    }
-
-    /// Remove a peer from the persisted peer database.
-    pub fn remove_persisted_peer(&self, peer_addr: SocketAddr) -> anyhow::Result<()> {
-        let Ok(kv) = self.kvs.read() else {
-            anyhow::bail!("Could not acqure lock over lmdb");
-        };
-        let peers_store = kv.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
-        let key = serde_json::to_vec::<SocketAddr>(&peer_addr)?;
-        let mut writer = kv.write()?;
-        peers_store.delete(&mut writer, key)?;
-        writer.commit()?;
-        Ok(())
-    }
-
-    /// Clear all peers in the lmdb peers store.
-    pub fn clear_persisted_peers(&self) -> anyhow::Result<()> {
-        let Ok(kvs_env) = self.kvs.read() else {
-            anyhow::bail!("Couldn't clear the bans: couldn't obtain a lock over the kvs");
-        };
-        let peers_store = kvs_env.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
-        let mut writer = kvs_env.write()?;
-        peers_store.clear(&mut writer)?;
-        writer.commit().map_err(|e| e.into())
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,181 @@
+//! Backoff for reconnecting to addresses.
+//!
+//! The node keeps dialling the given addresses it is not connected to, and on
+//! startup or when it has no peers at all, the addresses in its address book.
+//! Every failed attempt, i.e., one that fails immediately or whose connection
+//! is dropped before the handshake completes, doubles the delay before the
+//! address is dialled again, up to a configurable maximum. The delays are
+//! jittered so that nodes do not retry in lockstep, and the state of an
+//! address is reset after a successful handshake.
+
+use crate::{common::get_current_stamp, lock_or_die, p2p::P2PNode, read_or_die};
+use rand::Rng;
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+/// The reconnection state of an address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReconnectState {
+    /// The number of failed attempts since the last successful handshake.
+    pub failures:     u32,
+    /// The earliest time, in milliseconds since the Unix epoch, at which the
+    /// address may be dialled again.
+    pub next_attempt: u64,
+    /// Why the last attempt failed, if it did.
+    pub last_error:   Option<String>,
+}
+
+impl ReconnectState {
+    fn new(now: u64) -> Self {
+        Self {
+            failures:     0,
+            next_attempt: now,
+            last_error:   None,
+        }
+    }
+}
+
+/// The reconnection states of the addresses the node dials repeatedly.
+#[derive(Debug)]
+pub struct ReconnectBackoff {
+    /// The delay after the first failure, in milliseconds.
+    initial: u64,
+    /// The maximum delay, in milliseconds.
+    max:     u64,
+    states:  HashMap<SocketAddr, ReconnectState>,
+}
+
+/// The delay after the given number of failures, jittered to between half and
+/// all of the exponential delay.
+fn backoff_delay(initial: u64, max: u64, failures: u32) -> u64 {
+    let exponent = failures.saturating_sub(1).min(32);
+    let delay = initial.saturating_mul(1u64 << exponent).min(max);
+    delay / 2 + rand::thread_rng().gen_range(0..=delay - delay / 2)
+}
+
+impl ReconnectBackoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial: initial.as_millis() as u64,
+            max:     max.as_millis() as u64,
+            states:  HashMap::new(),
+        }
+    }
+
+    /// Check whether the address may be dialled now, and if so, record the
+    /// attempt so that the address is not dialled again while the attempt is
+    /// in progress.
+    pub fn try_attempt(&mut self, addr: SocketAddr, now: u64) -> bool {
+        let state = self.states.entry(addr).or_insert_with(|| ReconnectState::new(now));
+        if now < state.next_attempt {
+            return false;
+        }
+        state.next_attempt = now + backoff_delay(self.initial, self.max, state.failures.max(1));
+        true
+    }
+
+    /// Record a failed attempt to connect to the address.
+    pub fn record_failure(&mut self, addr: SocketAddr, error: String, now: u64) {
+        let state = self.states.entry(addr).or_insert_with(|| ReconnectState::new(now));
+        state.failures = state.failures.saturating_add(1);
+        state.next_attempt = now + backoff_delay(self.initial, self.max, state.failures);
+        state.last_error = Some(error);
+    }
+
+    /// Make sure that the address is not dialled again for the given number of
+    /// milliseconds, but for no longer than the maximum delay, so that a peer
+    /// cannot keep the node from reconnecting to it indefinitely.
+    pub fn defer(&mut self, addr: SocketAddr, now: u64, delay: u64) {
+        let until = now.saturating_add(delay.min(self.max));
+        let state = self.states.entry(addr).or_insert_with(|| ReconnectState::new(now));
+        state.next_attempt = state.next_attempt.max(until);
+    }
+
+    /// Forget the state of the address, e.g., after a successful handshake.
+    pub fn reset(&mut self, addr: SocketAddr) { self.states.remove(&addr); }
+
+    /// Forget the states of the addresses that are not to be kept and that
+    /// have not been dialled for longer than the maximum delay.
+    pub fn prune(&mut self, now: u64, keep: impl Fn(&SocketAddr) -> bool) {
+        let max = self.max;
+        self.states.retain(|addr, state| keep(addr) || now < state.next_attempt + max);
+    }
+}
+
+impl P2PNode {
+    /// Check whether the address may be dialled now, and if so, record the
+    /// attempt.
+    pub fn try_reconnect_attempt(&self, addr: SocketAddr) -> bool {
+        lock_or_die!(self.connection_handler.reconnect_backoff)
+            .try_attempt(addr, get_current_stamp())
+    }
+
+    /// Record a failed attempt to connect to the address.
+    pub fn record_reconnect_failure(&self, addr: SocketAddr, error: String) {
+        lock_or_die!(self.connection_handler.reconnect_backoff).record_failure(
+            addr,
+            error,
+            get_current_stamp(),
+        )
+    }
+
+    /// Obtain the reconnection states of the addresses, along with whether each
+    /// address is a given one.
+    pub fn get_reconnect_states(&self) -> Vec<(SocketAddr, ReconnectState, bool)> {
+        let given = read_or_die!(self.config.given_addresses);
+        lock_or_die!(self.connection_handler.reconnect_backoff)
+            .states
+            .iter()
+            .map(|(addr, state)| (*addr, state.clone(), given.contains(addr)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let addr: SocketAddr = "192.0.2.1:8888".parse().unwrap();
+        let mut backoff = ReconnectBackoff::new(Duration::from_secs(10), Duration::from_secs(60));
+        assert!(backoff.try_attempt(addr, 0));
+        // the address is not dialled again while the attempt is in progress
+        assert!(!backoff.try_attempt(addr, 1000));
+
+        let mut now = 0;
+        let mut delays = Vec::new();
+        for _ in 0..5 {
+            backoff.record_failure(addr, "connection refused".to_owned(), now);
+            let next_attempt = backoff.states[&addr].next_attempt;
+            delays.push(next_attempt - now);
+            now = next_attempt;
+        }
+        // the delays double up to the maximum, with jitter of up to half the delay
+        for (delay, max) in delays.into_iter().zip([10_000, 20_000, 40_000, 60_000, 60_000]) {
+            assert!(max / 2 <= delay && delay <= max, "{} not in [{}, {}]", delay, max / 2, max);
+        }
+        assert_eq!(backoff.states[&addr].failures, 5);
+        assert_eq!(backoff.states[&addr].last_error.as_deref(), Some("connection refused"));
+
+        // the state is forgotten eventually unless it is to be kept
+        backoff.prune(now + 60_000, |_| true);
+        assert!(backoff.states.contains_key(&addr));
+        backoff.prune(now + 60_000, |_| false);
+        assert!(backoff.states.is_empty());
+
+        // a delay the peer asked for is respected even if it exceeds the backoff
+        backoff.record_failure(addr, "connection refused".to_owned(), now);
+        backoff.defer(addr, now, 50_000);
+        assert!(!backoff.try_attempt(addr, now + 49_999));
+        assert!(backoff.try_attempt(addr, now + 50_000));
+        // but only up to the maximum delay
+        backoff.record_failure(addr, "connection refused".to_owned(), now);
+        backoff.defer(addr, now, u64::MAX);
+        assert!(!backoff.try_attempt(addr, now + 59_999));
+        assert!(backoff.try_attempt(addr, now + 60_000));
+
+        backoff.record_failure(addr, "connection refused".to_owned(), now);
+        backoff.reset(addr);
+        assert!(backoff.try_attempt(addr, now));
+    }
+}
@@ -0,0 +1,141 @@
+//! Peer reputation scores.
+//!
+//! Every connection has a score that is lowered by misbehaviour, such as
+//! invalid messages or catch-up timeouts, and raised by useful behaviour, such
+//! as delivering new blocks. When the node has too many peers it evicts the
+//! ones with the lowest scores, and peers whose score falls to the ban
+//! threshold are dropped and soft-banned.
+
+use crate::{
+    common::p2p_peer::RemotePeerId,
+    connection::{ConnChange, ConnectionStats},
+    p2p::{bans::SoftBanReason, P2PNode},
+    read_or_die,
+};
+use mio::Token;
+use std::sync::atomic::Ordering;
+
+/// The lowest possible score.
+pub const MIN_REPUTATION_SCORE: i64 = -1000;
+/// The highest possible score. Bounding the score keeps a peer that was
+/// useful for a long time from being able to misbehave indefinitely.
+pub const MAX_REPUTATION_SCORE: i64 = 1000;
+
+/// An event that affects a peer's reputation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReputationEvent {
+    /// The peer sent a message that consensus rejected.
+    InvalidMessage,
+    /// The peer sent a packet exceeding the inbound rate limits.
+    RateLimited,
+    /// Catching up with the peer took too long.
+    CatchUpTimeout,
+    /// Catching up with the peer resulted in an incompatible global state.
+    IncompatibleState,
+    /// The peer sent a malformed catch-up message.
+    MalformedCatchUp,
+    /// The latency of the connection is above the configured threshold.
+    HighLatency,
+    /// The peer delivered a block that was new to us.
+    UsefulBlock,
+}
+
+impl ReputationEvent {
+    /// The change to the score caused by the event.
+    pub fn score_delta(self) -> i64 {
+        match self {
+            ReputationEvent::InvalidMessage => -20,
+            ReputationEvent::RateLimited => -5,
+            ReputationEvent::CatchUpTimeout => -100,
+            ReputationEvent::IncompatibleState => -200,
+            ReputationEvent::MalformedCatchUp => -200,
+            ReputationEvent::HighLatency => -10,
+            ReputationEvent::UsefulBlock => 5,
+        }
+    }
+
+    /// The label of the event in metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            ReputationEvent::InvalidMessage => "invalid-message",
+            ReputationEvent::RateLimited => "rate-limited",
+            ReputationEvent::CatchUpTimeout => "catch-up-timeout",
+            ReputationEvent::IncompatibleState => "incompatible-state",
+            ReputationEvent::MalformedCatchUp => "malformed-catch-up",
+            ReputationEvent::HighLatency => "high-latency",
+            ReputationEvent::UsefulBlock => "useful-block",
+        }
+    }
+}
+
+/// Apply the event to the score of the connection with the given token and
+/// stats. If the score falls to the ban threshold the connection is expelled.
+/// This does not acquire any locks on the connections, so it can be used while
+/// holding them.
+pub fn apply_reputation_event(
+    node: &P2PNode,
+    token: Token,
+    stats: &ConnectionStats,
+    event: ReputationEvent,
+) {
+    node.stats.peer_reputation_events.with_label_values(&[event.label()]).inc();
+    let delta = event.score_delta();
+    // the closure always returns `Some`, so the update cannot fail
+    let old_score = stats
+        .score
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |score| {
+            Some((score + delta).clamp(MIN_REPUTATION_SCORE, MAX_REPUTATION_SCORE))
+        })
+        .unwrap();
+    let new_score = (old_score + delta).clamp(MIN_REPUTATION_SCORE, MAX_REPUTATION_SCORE);
+    let threshold = node.config.reputation_ban_threshold;
+    if new_score <= threshold && old_score > threshold {
+        node.register_conn_change(ConnChange::ExpulsionByToken(
+            token,
+            SoftBanReason::LowReputation,
+        ));
+    }
+}
+
+impl P2PNode {
+    /// Apply the event to the score of the given peer, if it is still
+    /// connected. This acquires a read lock on the connections.
+    pub fn record_reputation_event(&self, peer_id: RemotePeerId, event: ReputationEvent) {
+        let token = peer_id.to_token();
+        if let Some(conn) = read_or_die!(self.connections()).get(&token) {
+            apply_reputation_event(self, token, &conn.stats, event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::PeerType,
+        test_utils::{
+            dummy_regenesis_blocks, make_node_and_sync, next_available_port, stop_node_delete_dirs,
+        },
+    };
+
+    #[test]
+    fn test_reputation_score_is_bounded() -> anyhow::Result<()> {
+        let (node, dp) = make_node_and_sync(
+            next_available_port(),
+            vec![100],
+            PeerType::Node,
+            dummy_regenesis_blocks(),
+        )?;
+        let stats = ConnectionStats::new(0);
+        for _ in 0..1000 {
+            apply_reputation_event(&node, Token(1), &stats, ReputationEvent::UsefulBlock);
+        }
+        assert_eq!(stats.score.load(Ordering::Relaxed), MAX_REPUTATION_SCORE);
+        for _ in 0..1000 {
+            apply_reputation_event(&node, Token(1), &stats, ReputationEvent::MalformedCatchUp);
+        }
+        assert_eq!(stats.score.load(Ordering::Relaxed), MIN_REPUTATION_SCORE);
+        stop_node_delete_dirs(dp, node);
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+//! Graceful shutdown of the network layer.
+//!
+//! When the node is closed it first stops accepting and making connections,
+//! while the poll loop keeps writing the messages that are queued for its
+//! peers, e.g., blocks or finalization messages the node just produced. Once
+//! all of them are written, or a configurable deadline has passed, the poll
+//! loop stops, the peers are notified that the node is shutting down and the
+//! sockets are closed.
+
+use crate::{common::get_current_stamp, p2p::P2PNode, read_or_die, write_or_die};
+use std::{thread, time::Duration};
+
+/// The phase of the shutdown of the network layer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShutdownPhase {
+    /// The node is not shutting down.
+    #[default]
+    Running,
+    /// No connections are accepted or made, and the messages queued for peers
+    /// are being written.
+    Draining,
+    /// The queued messages were written or the deadline passed, and the
+    /// connections are being closed.
+    Closing,
+    /// The connections are closed.
+    Closed,
+}
+
+/// The progress of the shutdown of the network layer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownProgress {
+    pub phase:            ShutdownPhase,
+    /// Until when the queued messages are written at the latest, in
+    /// milliseconds since the Unix epoch. Only set once the node is shutting
+    /// down.
+    pub deadline:         Option<u64>,
+    /// The number of peers that still have messages queued for them.
+    pub peers_pending:    usize,
+    /// The number of messages that are still queued for peers.
+    pub messages_pending: usize,
+    /// The number of bytes that are still to be written to the peers.
+    pub bytes_pending:    usize,
+}
+
+impl ShutdownProgress {
+    /// Whether everything that was queued for the peers has been written.
+    pub fn is_drained(&self) -> bool { self.messages_pending == 0 && self.bytes_pending == 0 }
+}
+
+impl P2PNode {
+    /// Stop accepting and making connections, and start the deadline for
+    /// writing the messages queued for peers. Returns whether the node was
+    /// not shutting down already.
+    pub fn begin_shutdown(&self) -> bool {
+        let mut progress = write_or_die!(self.connection_handler.shutdown);
+        if progress.phase != ShutdownPhase::Running {
+            return false;
+        }
+        info!("Shutting down the network; writing the messages queued for peers");
+        progress.phase = ShutdownPhase::Draining;
+        progress.deadline = Some(get_current_stamp() + self.config.shutdown_drain_timeout);
+        true
+    }
+
+    /// Whether the node is shutting down, in which case it no longer accepts or
+    /// makes connections.
+    pub fn is_shutting_down(&self) -> bool {
+        read_or_die!(self.connection_handler.shutdown).phase != ShutdownPhase::Running
+    }
+
+    /// Obtain the progress of the shutdown.
+    pub fn get_shutdown_progress(&self) -> ShutdownProgress {
+        *read_or_die!(self.connection_handler.shutdown)
+    }
+
+    /// Set the phase of the shutdown.
+    pub(crate) fn set_shutdown_phase(&self, phase: ShutdownPhase) {
+        write_or_die!(self.connection_handler.shutdown).phase = phase;
+    }
+
+    /// Count what is still queued for the peers and record it in the progress
+    /// of the shutdown.
+    fn update_shutdown_progress(&self) -> ShutdownProgress {
+        let (mut peers_pending, mut messages_pending, mut bytes_pending) = (0, 0, 0);
+        for conn in read_or_die!(self.connections()).values() {
+            let (messages, bytes) = conn.pending_output();
+            if messages > 0 || bytes > 0 {
+                peers_pending += 1;
+                messages_pending += messages;
+                bytes_pending += bytes;
+            }
+        }
+        let mut progress = write_or_die!(self.connection_handler.shutdown);
+        progress.peers_pending = peers_pending;
+        progress.messages_pending = messages_pending;
+        progress.bytes_pending = bytes_pending;
+        *progress
+    }
+
+    /// Wait until the messages queued for peers are written or the deadline of
+    /// the shutdown passes. The messages are written by the poll loop.
+    pub(crate) fn drain_connections(&self) {
+        loop {
+            let progress = self.update_shutdown_progress();
+            if progress.is_drained() {
+                info!("Wrote all the messages queued for peers");
+                return;
+            }
+            if progress.deadline.map_or(true, |deadline| get_current_stamp() >= deadline) {
+                warn!(
+                    "Could not write {} messages ({} bytes) queued for {} peers before the \
+                     shutdown deadline",
+                    progress.messages_pending, progress.bytes_pending, progress.peers_pending
+                );
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{common::PeerType, network::DisconnectReason, p2p::connectivity, test_utils::*};
+
+    #[test]
+    fn test_shutdown_phases() -> anyhow::Result<()> {
+        let ((node, dp), (peer, peer_dp)) = make_connected_node_pair()?;
+        assert_eq!(node.get_shutdown_progress().phase, ShutdownPhase::Running);
+
+        assert!(node.begin_shutdown());
+        assert!(!node.begin_shutdown());
+        let progress = node.get_shutdown_progress();
+        assert_eq!(progress.phase, ShutdownPhase::Draining);
+        assert!(progress.deadline.is_some());
+        // no connections are made while shutting down
+        let addr = format!("127.0.0.1:{}", next_available_port()).parse()?;
+        assert!(connectivity::connect(&node, PeerType::Node, addr, None, false).is_err());
+
+        // closing does nothing more once the shutdown has begun
+        node.close()?;
+        assert_eq!(node.get_shutdown_progress().phase, ShutdownPhase::Draining);
+        node.complete_shutdown()?;
+        node.join()?;
+        let progress = node.get_shutdown_progress();
+        assert_eq!(progress.phase, ShutdownPhase::Closed);
+        assert!(progress.is_drained());
+
+        // the peer was told that the node is shutting down
+        let disconnect = await_condition(|| peer.get_last_disconnect(node.self_peer.addr));
+        assert_eq!(disconnect.map(|d| d.reason), Some(DisconnectReason::ShuttingDown));
+
+        stop_node_delete_dirs(dp, node);
+        stop_node_delete_dirs(peer_dp, peer);
+        Ok(())
+    }
+}
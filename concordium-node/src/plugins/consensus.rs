@@ -4,10 +4,10 @@ use crossbeam_channel::TrySendError;
 
 use crate::{
     common::{get_current_stamp, p2p_peer::RemotePeerId},
-    configuration::{self, MAX_CATCH_UP_TIME},
+    configuration,
     connection::ConnChange,
     consensus_ffi::{
-        catch_up::{PeerList, PeerStatus},
+        catch_up::{PeerList, PeerStatus, MAX_CATCH_UP_TIMEOUTS},
         consensus::{ConsensusContainer, ConsensusRuntimeParameters, CALLBACK_QUEUE},
         ffi::{self, ExecuteBlockCallback, StartConsensusConfig},
         helpers::{
@@ -17,8 +17,11 @@ use crate::{
         },
         messaging::{ConsensusMessage, DistributionMode, MessageType},
     },
+    network::{frames::ReceivedFrame, Disconnect, DisconnectReason},
     p2p::{
-        connectivity::{send_broadcast_message, send_direct_message},
+        bans::SoftBanReason,
+        connectivity::{relay_broadcast_frame, send_broadcast_message, send_direct_message},
+        reputation::ReputationEvent,
         P2PNode,
     },
     read_or_die, write_or_die,
@@ -108,6 +111,7 @@ pub fn handle_pkt_out(
     peer_id: RemotePeerId, // id of the peer that sent the message.
     msg: Vec<u8>,
     is_broadcast: bool,
+    received_frame: Option<ReceivedFrame>,
 ) -> anyhow::Result<()> {
     ensure!(!msg.is_empty(), "Packet payload can't be empty");
     let consensus_type = u8::deserial(&mut Cursor::new(&msg[..1]))?;
@@ -127,44 +131,33 @@ pub fn handle_pkt_out(
         Arc::from(msg),
         dont_relay_to,
         None,
+        received_frame,
     );
 
-    if packet_type == PacketType::Transaction {
-        if payload_len > configuration::PROTOCOL_MAX_TRANSACTION_SIZE {
-            bail!(
-                "Transaction size exceeds {} bytes.",
-                configuration::PROTOCOL_MAX_TRANSACTION_SIZE
-            )
-        }
-        if let Err(e) = CALLBACK_QUEUE.send_in_low_priority_message(request) {
-            match e.downcast::<TrySendError<QueueMsg<ConsensusMessage>>>()? {
-                TrySendError::Full(_) => {
-                    node.stats
-                        .received_consensus_messages
-                        .with_label_values(&[packet_type.label(), "dropped"])
-                        .inc();
-                    node.bad_events.inc_dropped_low_queue(peer_id);
-                }
-                TrySendError::Disconnected(_) => {
-                    panic!("Low priority consensus queue has been shutdown!")
-                }
+    if packet_type == PacketType::Transaction
+        && payload_len > configuration::PROTOCOL_MAX_TRANSACTION_SIZE
+    {
+        bail!("Transaction size exceeds {} bytes.", configuration::PROTOCOL_MAX_TRANSACTION_SIZE)
+    }
+
+    // transactions go to the low priority queue and all the other messages to the
+    // high priority one
+    let peers = node.stats.connected_peers.get().max(0) as usize;
+    if let Err(e) = CALLBACK_QUEUE.send_in_message(request, peers) {
+        match e.downcast::<TrySendError<QueueMsg<ConsensusMessage>>>()? {
+            TrySendError::Full(_) => {
+                node.stats
+                    .received_consensus_messages
+                    .with_label_values(&[packet_type.label(), "dropped"])
+                    .inc();
+                node.stats
+                    .consensus_dropped_messages
+                    .with_label_values(&[packet_type.label(), "inbound"])
+                    .inc();
+                node.bad_events.inc_dropped_packets(peer_id, packet_type);
             }
-        }
-    } else {
-        // high priority message
-        if let Err(e) = CALLBACK_QUEUE.send_in_high_priority_message(request) {
-            match e.downcast::<TrySendError<QueueMsg<ConsensusMessage>>>()? {
-                TrySendError::Full(_) => {
-                    node.stats
-                        .received_consensus_messages
-                        .with_label_values(&[packet_type.label(), "dropped"])
-                        .inc();
-
-                    node.bad_events.inc_dropped_high_queue(peer_id);
-                }
-                TrySendError::Disconnected(_) => {
-                    panic!("High priority consensus queue has been shutdown!")
-                }
+            TrySendError::Disconnected(_) => {
+                panic!("The inbound consensus queue has been shut down!")
             }
         }
     }
@@ -210,6 +203,9 @@ pub fn handle_consensus_inbound_msg(
     consensus: &ConsensusContainer,
     request: ConsensusMessage,
 ) -> anyhow::Result<()> {
+    // The message is off the queue, whatever happens to it from here on.
+    CALLBACK_QUEUE.on_in_message_dequeued(&request);
+
     // If the drop_rebroadcast_probability parameter is set, do not
     // rebroadcast the packet to the network with the given chance.
     let drop_message = match node.config.drop_rebroadcast_probability {
@@ -243,12 +239,23 @@ pub fn handle_consensus_inbound_msg(
         && request.variant.is_rebroadcastable()
         && consensus_result.is_rebroadcastable(request.variant)
     {
-        send_consensus_msg_to_net(
-            node,
-            request.dont_relay_to(),
-            None,
-            (request.payload, request.variant),
-        );
+        // relay the broadcast in the frame it was received in if possible, so
+        // that it does not have to be serialized again
+        match request.received_frame {
+            Some(ref frame) if frame.network_id == node.config.default_network => {
+                let sent =
+                    relay_broadcast_frame(node, request.dont_relay_to(), frame, &request.payload);
+                if sent > 0 {
+                    debug!("Relayed a broadcast containing a {}", request.variant);
+                }
+            }
+            _ => send_consensus_msg_to_net(
+                node,
+                request.dont_relay_to(),
+                None,
+                (request.payload, request.variant),
+            ),
+        }
     }
     // Execute any finalizer returned from the consensus layer.
     if let Some(callback) = finalizer {
@@ -298,7 +305,11 @@ fn send_msg_to_consensus(
 
     if consensus_response.0.is_acceptable() {
         debug!("Processed a {} from {}", message.variant, source_id);
+        if message.variant == Block && consensus_response.0 == ConsensusFfiResponse::Success {
+            node.record_reputation_event(source_id, ReputationEvent::UsefulBlock);
+        }
     } else {
+        node.record_reputation_event(source_id, ReputationEvent::InvalidMessage);
         let num_bad_events = node.bad_events.inc_invalid_messages(source_id);
         // we do log some invalid messages to both ease debugging and see problems in
         // normal circumstances
@@ -347,11 +358,9 @@ pub fn update_peer_list(node: &P2PNode) {
     // remove global state peers whose connections were dropped
     peers.peer_states.retain(|id, _| peer_ids.contains(id));
     peers.pending_queue.retain(|id| peer_ids.contains(id));
-    if let Some(in_progress) = peers.catch_up_peer {
-        if !peers.peer_states.contains_key(&in_progress) {
-            peers.catch_up_peer = None;
-        }
-    }
+    peers.catch_up_peers.retain(|id, _| peer_ids.contains(id));
+    peers.catch_up_timeouts.retain(|id, _| peer_ids.contains(id));
+    peers.demoted_until.retain(|id, _| peer_ids.contains(id));
 
     // include newly added peers
     let new_peers = peer_ids.len() - peers.peer_states.len();
@@ -365,16 +374,21 @@ pub fn update_peer_list(node: &P2PNode) {
     }
 }
 
-/// Try to catch up with a peer, if one is pending.
+/// Try to catch up with pending peers, until we are catching up with
+/// `catch_up_parallelism` peers at once.
 fn try_catch_up(node: &P2PNode, consensus: &ConsensusContainer, peers: &mut PeerList) {
-    if let Some(id) = peers.next_pending() {
+    let mut catch_up_status = None;
+    while peers.catch_up_peers.len() < node.config.catch_up_parallelism {
+        let id = match peers.next_pending(get_current_stamp()) {
+            Some(id) => id,
+            None => break,
+        };
         debug!("Attempting to catch up with peer {}", id);
-        peers.catch_up_stamp = get_current_stamp();
         let sent = send_direct_message(
             node,
             id,
             node.config.default_network,
-            consensus.get_catch_up_status(),
+            catch_up_status.get_or_insert_with(|| consensus.get_catch_up_status()).clone(),
         );
         if sent > 0 {
             info!(
@@ -386,8 +400,7 @@ fn try_catch_up(node: &P2PNode, consensus: &ConsensusContainer, peers: &mut Peer
             // If no packets were sent, then this must not be a valid peer,
             // so remove it from the peers.
             debug!("Could not send catch-up message to peer {}", id);
-            peers.catch_up_peer = None;
-            peers.peer_states.remove(&id);
+            peers.remove_peer(id);
         }
     }
 }
@@ -406,32 +419,72 @@ pub fn check_peer_states(node: &P2PNode, consensus: &ConsensusContainer) {
         write_or_die!(node.peers).mark_all_pending();
     }
 
-    // If we are catching-up with a peer, check if the peer has timed-out.
+    // This is called on every iteration of the poll loop, and most of the time
+    // there is nothing to do, so check that before taking the write lock. The
+    // peers whose connections were dropped are removed by `update_peer_list`,
+    // and the metrics are exported by `export_catch_up_metrics` during
+    // housekeeping.
     let now = get_current_stamp();
-    let (catch_up_peer, catch_up_stamp) = {
-        let peers = read_or_die!(node.peers);
-        (peers.catch_up_peer, peers.catch_up_stamp)
-    };
-    if let Some(peer_id) = catch_up_peer {
-        if read_or_die!(node.connections()).get(&peer_id.to_token()).is_some() {
-            if now > catch_up_stamp + MAX_CATCH_UP_TIME {
-                // Try to remove the peer since it timed-out.
-                debug!("Peer {} took too long to catch up; dropping", peer_id);
-                // This function may not actually remove the peer, so we do not assume
-                // that it will be removed.
-                node.register_conn_change(ConnChange::RemovalByToken(peer_id.to_token()));
-            }
+    let timeout = node.config.catch_up_timeout;
+    if !read_or_die!(node.peers).needs_update(now, timeout, node.config.catch_up_parallelism) {
+        return;
+    }
+    let peers = &mut write_or_die!(node.peers);
+
+    // Demote the peers that took too long to catch up with, so that we try the
+    // other pending peers first, and drop the ones that did so repeatedly. A
+    // demoted peer is not selected again for as long as the catch-up timeout.
+    for peer_id in peers.timed_out_peers(now, timeout) {
+        let timeouts = peers.demote(peer_id, now + timeout);
+        node.stats.catch_up_attempts.with_label_values(&["timeout"]).inc();
+        node.record_reputation_event(peer_id, ReputationEvent::CatchUpTimeout);
+        if timeouts >= MAX_CATCH_UP_TIMEOUTS {
+            // This function may not actually remove the peer, so we do not assume
+            // that it will be removed.
+            debug!(
+                "Peer {} took too long to catch up {} times in a row; dropping",
+                peer_id, timeouts
+            );
+            let disconnect = Disconnect::new(DisconnectReason::CatchUpTimeout, None);
+            node.register_conn_change(ConnChange::RemovalByToken(
+                peer_id.to_token(),
+                Some(disconnect),
+            ));
         } else {
-            // Connection no longer exists
-            debug!("Connection to catch-up-in-progress peer {} no longer exists", peer_id);
-            let peers = &mut write_or_die!(node.peers);
-            peers.catch_up_peer = None;
-            peers.peer_states.remove(&peer_id);
-            try_catch_up(node, consensus, peers);
+            debug!("Peer {} took too long to catch up; demoting", peer_id);
         }
-    } else {
-        try_catch_up(node, consensus, &mut write_or_die!(node.peers));
     }
+
+    try_catch_up(node, consensus, peers);
+}
+
+/// Export the state of the catch-up with the peers.
+pub fn export_catch_up_metrics(node: &P2PNode) {
+    let now = get_current_stamp();
+    let peers = read_or_die!(node.peers);
+    let info = peers.catch_up_info();
+    for status in [PeerStatus::Pending, PeerStatus::CatchingUp, PeerStatus::UpToDate] {
+        let count = info.values().filter(|peer_info| peer_info.status == status).count();
+        node.stats.catch_up_peer_states.with_label_values(&[status.label()]).set(count as i64);
+    }
+    let queued = info.values().filter(|peer_info| peer_info.queue_position.is_some()).count();
+    node.stats.catch_up_pending_queue_length.set(queued as i64);
+    node.stats.catch_up_peers.set(peers.catch_up_peers.len() as i64);
+    let longest = peers
+        .catch_up_peers
+        .values()
+        .map(|&started| now.saturating_sub(started))
+        .max()
+        .unwrap_or(0);
+    node.stats.catch_up_longest_duration.set(longest as f64 / 1000.0);
+}
+
+/// Update the catch-up metrics upon a response to our catch-up status that
+/// took `elapsed` milliseconds. The `result` is either `"up-to-date"` or
+/// `"behind"`.
+fn record_catch_up_response(node: &P2PNode, result: &str, elapsed: u64) {
+    node.stats.catch_up_attempts.with_label_values(&[result]).inc();
+    node.stats.catch_up_duration.observe(elapsed as f64 / 1000.0);
 }
 
 fn update_peer_states(
@@ -449,8 +502,8 @@ fn update_peer_states(
             Success => {
                 // We are up-to-date with the peer.
                 peers.peer_states.insert(source_peer, UpToDate);
-                if peers.catch_up_peer == Some(source_peer) {
-                    peers.catch_up_peer = None;
+                if let Some(elapsed) = peers.finish_catch_up(source_peer, get_current_stamp()) {
+                    record_catch_up_response(node, "up-to-date", elapsed);
                 }
             }
             PendingBlock | PendingFinalization => {
@@ -459,8 +512,8 @@ fn update_peer_states(
                     Some(Pending) => {}
                     _ => peers.pending_queue.push_back(source_peer),
                 }
-                if peers.catch_up_peer == Some(source_peer) {
-                    peers.catch_up_peer = None;
+                if let Some(elapsed) = peers.finish_catch_up(source_peer, get_current_stamp()) {
+                    record_catch_up_response(node, "behind", elapsed);
                 }
             }
             ContinueCatchUp => {
@@ -480,18 +533,32 @@ fn update_peer_states(
                      soft-banning",
                     source_peer
                 );
-                node.register_conn_change(ConnChange::ExpulsionByToken(source_peer.to_token()));
+                node.record_reputation_event(source_peer, ReputationEvent::IncompatibleState);
+                node.register_conn_change(ConnChange::ExpulsionByToken(
+                    source_peer.to_token(),
+                    SoftBanReason::IncompatibleState,
+                ));
             }
             ConsensusFfiResponse::DeserializationError => {
                 debug!(
                     "The peer {} sent a malformed catchup message, dropping and soft-banning",
                     source_peer
                 );
-                node.register_conn_change(ConnChange::ExpulsionByToken(source_peer.to_token()));
+                node.record_reputation_event(source_peer, ReputationEvent::MalformedCatchUp);
+                node.register_conn_change(ConnChange::ExpulsionByToken(
+                    source_peer.to_token(),
+                    SoftBanReason::MalformedCatchUp,
+                ));
             }
             e => error!("Unexpected return from `receiveCatchUpStatus`: {:?}", e),
         }
     } else if [Block, FinalizationRecord, FinalizationMessage].contains(&request.variant) {
+        // Count the data sent to us by the peers we are catching up with.
+        if request.distribution_mode() == DistributionMode::Direct
+            && peers.catch_up_peers.contains_key(&source_peer)
+        {
+            node.stats.catch_up_received_bytes.inc_by(request.payload.len() as u64);
+        }
         match request.distribution_mode() {
             DistributionMode::Direct if consensus_result.is_successful() => {
                 // Directly sent blocks, finalization records and finalization messages that are
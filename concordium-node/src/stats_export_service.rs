@@ -18,13 +18,14 @@ use hyper::Body;
 use prometheus::{
     self,
     core::{Atomic, AtomicI64, AtomicU64, GenericGauge},
-    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
-    Opts, Registry, TextEncoder,
+    CounterVec, Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
 };
 use std::{
     net::SocketAddr,
     sync::{Arc, RwLock},
-    thread, time,
+    thread,
+    time::{self, Duration},
 };
 use tower_http::metrics::in_flight_requests::InFlightRequestsCounter;
 
@@ -220,6 +221,10 @@ pub struct StatsExportService {
     /// - `"finalization message"`
     /// - `"catch-up status message"`
     pub sent_consensus_messages: IntCounterVec,
+    /// Total number of consensus messages dropped because the queue for them
+    /// was full. Labelled with message type (`message=<type>`) and direction
+    /// (`direction=<direction>`), either `"inbound"` or `"outbound"`.
+    pub consensus_dropped_messages: IntCounterVec,
     /// Current number of soft banned peers.
     pub soft_banned_peers: IntGauge,
     /// The total number of soft banned peers since startup.
@@ -277,6 +282,54 @@ pub struct StatsExportService {
     pub peer_bucket_size: IntGaugeVec,
     /// The number of connections maintained by the GRPC V2 server.
     pub grpc_connected_clients: GenericGauge<AtomicU64>,
+    /// Total number of uncompressed bytes of compressed packet payloads.
+    /// Labelled with the direction (`direction=<direction>`), either `"sent"`
+    /// or `"received"`.
+    pub compression_uncompressed_bytes: IntCounterVec,
+    /// Total number of compressed bytes of compressed packet payloads.
+    /// Labelled with the direction (`direction=<direction>`), either `"sent"`
+    /// or `"received"`.
+    pub compression_compressed_bytes: IntCounterVec,
+    /// Total time spent compressing (`direction="sent"`) and decompressing
+    /// (`direction="received"`) packet payloads, in seconds.
+    pub compression_seconds: CounterVec,
+    /// Total number of incoming packets dropped because the peer exceeded the
+    /// inbound rate limit for the packet type.
+    pub inbound_rate_limited_packets: IntCounterVec,
+    /// Total number of outgoing messages dropped because the queue for their
+    /// packet type was full, labelled by the packet type (`packet=<type>`).
+    pub outbound_dropped_packets: IntCounterVec,
+    /// Total number of events affecting the reputation of peers, labelled by
+    /// the event (`event=<event>`).
+    pub peer_reputation_events: IntCounterVec,
+    /// The lowest, median and highest reputation score of the connected peers,
+    /// labelled by the statistic (`stat=<min|median|max>`). Updated during
+    /// connection housekeeping.
+    pub peer_score: IntGaugeVec,
+    /// The number of peers in the relay mesh, labelled by the network id
+    /// (`network=<network>`). Only set in the `mesh` relay mode.
+    pub mesh_peers: IntGaugeVec,
+    /// The number of peers the node is currently catching up with.
+    pub catch_up_peers: IntGauge,
+    /// Total number of finished attempts to catch up with a peer, labelled by
+    /// the result (`result=<result>`), either `"up-to-date"`, `"behind"` or
+    /// `"timeout"`.
+    pub catch_up_attempts: IntCounterVec,
+    /// Time it took peers to respond to our catch-up status, in seconds.
+    pub catch_up_duration: Histogram,
+    /// Total number of bytes of the blocks and finalization messages and
+    /// records sent directly to the node by the peers it is catching up with.
+    pub catch_up_received_bytes: IntCounter,
+    /// The number of peers in each catch-up state, labelled by the state
+    /// (`status=<status>`), either `"pending"`, `"catching-up"` or
+    /// `"up-to-date"`.
+    pub catch_up_peer_states: IntGaugeVec,
+    /// The number of peers queued for catching up with.
+    pub catch_up_pending_queue_length: IntGauge,
+    /// The time, in seconds, for which the node has been catching up with the
+    /// peer it started catching up with the longest ago, or 0 if it is not
+    /// catching up with any peer.
+    pub catch_up_longest_duration: Gauge,
 }
 
 impl StatsExportService {
@@ -400,6 +453,18 @@ impl StatsExportService {
         )?;
         registry.register(Box::new(sent_consensus_messages.clone()))?;
 
+        let consensus_dropped_messages = IntCounterVec::new(
+            Opts::new(
+                "consensus_dropped_messages_total",
+                "Total number of consensus messages dropped because the queue for them was full, \
+                 labelled by the type of messages and the direction",
+            )
+            .variable_label("message")
+            .variable_label("direction"),
+            &["message", "direction"],
+        )?;
+        registry.register(Box::new(consensus_dropped_messages.clone()))?;
+
         let soft_banned_peers = IntGauge::with_opts(Opts::new(
             "network_soft_banned_peers",
             "Current number of soft banned peers",
@@ -489,6 +554,143 @@ impl StatsExportService {
         ))?;
         registry.register(Box::new(grpc_connected_clients.clone()))?;
 
+        let compression_uncompressed_bytes = IntCounterVec::new(
+            Opts::new(
+                "network_compression_uncompressed_bytes_total",
+                "Total number of uncompressed bytes of compressed packet payloads",
+            )
+            .variable_label("direction"),
+            &["direction"],
+        )?;
+        registry.register(Box::new(compression_uncompressed_bytes.clone()))?;
+
+        let compression_compressed_bytes = IntCounterVec::new(
+            Opts::new(
+                "network_compression_compressed_bytes_total",
+                "Total number of compressed bytes of compressed packet payloads",
+            )
+            .variable_label("direction"),
+            &["direction"],
+        )?;
+        registry.register(Box::new(compression_compressed_bytes.clone()))?;
+
+        let compression_seconds = CounterVec::new(
+            Opts::new(
+                "network_compression_seconds_total",
+                "Total time spent compressing and decompressing packet payloads in seconds",
+            )
+            .variable_label("direction"),
+            &["direction"],
+        )?;
+        registry.register(Box::new(compression_seconds.clone()))?;
+
+        let inbound_rate_limited_packets = IntCounterVec::new(
+            Opts::new(
+                "network_inbound_rate_limited_packets_total",
+                "Total number of incoming packets dropped because they exceeded the inbound rate \
+                 limits",
+            )
+            .variable_label("packet"),
+            &["packet"],
+        )?;
+        registry.register(Box::new(inbound_rate_limited_packets.clone()))?;
+
+        let outbound_dropped_packets = IntCounterVec::new(
+            Opts::new(
+                "network_outbound_dropped_packets_total",
+                "Total number of outgoing messages dropped because the queue for their packet \
+                 type was full",
+            )
+            .variable_label("packet"),
+            &["packet"],
+        )?;
+        registry.register(Box::new(outbound_dropped_packets.clone()))?;
+
+        let peer_reputation_events = IntCounterVec::new(
+            Opts::new(
+                "network_peer_reputation_events_total",
+                "Total number of events affecting the reputation of peers",
+            )
+            .variable_label("event"),
+            &["event"],
+        )?;
+        registry.register(Box::new(peer_reputation_events.clone()))?;
+
+        let peer_score = IntGaugeVec::new(
+            Opts::new(
+                "network_peer_score",
+                "The lowest, median and highest reputation score of the connected peers",
+            )
+            .variable_label("stat"),
+            &["stat"],
+        )?;
+        registry.register(Box::new(peer_score.clone()))?;
+
+        let mesh_peers = IntGaugeVec::new(
+            Opts::new(
+                "network_mesh_peers",
+                "The number of peers in the relay mesh of each network",
+            )
+            .variable_label("network"),
+            &["network"],
+        )?;
+        registry.register(Box::new(mesh_peers.clone()))?;
+
+        let catch_up_peers = IntGauge::with_opts(Opts::new(
+            "consensus_catch_up_peers",
+            "The number of peers the node is currently catching up with",
+        ))?;
+        registry.register(Box::new(catch_up_peers.clone()))?;
+
+        let catch_up_attempts = IntCounterVec::new(
+            Opts::new(
+                "consensus_catch_up_attempts_total",
+                "Total number of finished attempts to catch up with a peer, labelled by the result",
+            )
+            .variable_label("result"),
+            &["result"],
+        )?;
+        registry.register(Box::new(catch_up_attempts.clone()))?;
+
+        let catch_up_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "consensus_catch_up_duration_seconds",
+                "Time it took peers to respond to the catch-up status of the node in seconds",
+            )
+            .buckets(vec![0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0]),
+        )?;
+        registry.register(Box::new(catch_up_duration.clone()))?;
+
+        let catch_up_received_bytes = IntCounter::with_opts(Opts::new(
+            "consensus_catch_up_received_bytes_total",
+            "Total number of bytes of blocks and finalization messages and records received \
+             directly from the peers the node is catching up with",
+        ))?;
+        registry.register(Box::new(catch_up_received_bytes.clone()))?;
+
+        let catch_up_peer_states = IntGaugeVec::new(
+            Opts::new(
+                "consensus_catch_up_peer_states",
+                "The number of peers in each catch-up state",
+            )
+            .variable_label("status"),
+            &["status"],
+        )?;
+        registry.register(Box::new(catch_up_peer_states.clone()))?;
+
+        let catch_up_pending_queue_length = IntGauge::with_opts(Opts::new(
+            "consensus_catch_up_pending_queue_length",
+            "The number of peers queued for catching up with",
+        ))?;
+        registry.register(Box::new(catch_up_pending_queue_length.clone()))?;
+
+        let catch_up_longest_duration = Gauge::with_opts(Opts::new(
+            "consensus_catch_up_longest_duration_seconds",
+            "Time in seconds for which the node has been catching up with the peer it started \
+             catching up with the longest ago",
+        ))?;
+        registry.register(Box::new(catch_up_longest_duration.clone()))?;
+
         Ok(StatsExportService {
             registry,
             packets_received,
@@ -509,6 +711,7 @@ impl StatsExportService {
             finalized_baked_blocks,
             received_consensus_messages,
             sent_consensus_messages,
+            consensus_dropped_messages,
             soft_banned_peers,
             soft_banned_peers_total,
             total_peers,
@@ -524,9 +727,42 @@ impl StatsExportService {
             avg_bps_out,
             peer_bucket_size,
             grpc_connected_clients,
+            compression_uncompressed_bytes,
+            compression_compressed_bytes,
+            compression_seconds,
+            inbound_rate_limited_packets,
+            outbound_dropped_packets,
+            peer_reputation_events,
+            peer_score,
+            mesh_peers,
+            catch_up_peers,
+            catch_up_attempts,
+            catch_up_duration,
+            catch_up_received_bytes,
+            catch_up_peer_states,
+            catch_up_pending_queue_length,
+            catch_up_longest_duration,
         })
     }
 
+    /// Record the sizes and the time spent on compressing or decompressing a
+    /// packet payload. The `direction` is either `"sent"` or `"received"`.
+    pub fn observe_compression(
+        &self,
+        direction: &str,
+        uncompressed_size: usize,
+        compressed_size: usize,
+        elapsed: Duration,
+    ) {
+        self.compression_uncompressed_bytes
+            .with_label_values(&[direction])
+            .inc_by(uncompressed_size as u64);
+        self.compression_compressed_bytes
+            .with_label_values(&[direction])
+            .inc_by(compressed_size as u64);
+        self.compression_seconds.with_label_values(&[direction]).inc_by(elapsed.as_secs_f64());
+    }
+
     fn metrics(state: State) -> (State, String) {
         let state_data = PrometheusStateData::borrow_from(&state);
         let encoder = TextEncoder::new();
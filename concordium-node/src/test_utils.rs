@@ -4,13 +4,13 @@ use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use structopt::StructOpt;
 
 use crate::{
-    common::{get_current_stamp, p2p_peer::RemotePeerId, PeerType},
+    common::{get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId, PeerType},
     configuration::Config,
     connection::ConnChange,
     consensus_ffi::{consensus::Regenesis, helpers::PacketType},
     netmsg,
-    network::{NetworkId, NetworkMessage, NetworkPacket, PacketDestination},
-    p2p::{maintenance::spawn, P2PNode},
+    network::{NetworkId, NetworkMessage, NetworkPacket, PacketDestination, PayloadCompression},
+    p2p::{identity::NodeIdentity, maintenance::spawn, P2PNode},
     read_or_die,
     stats_export_service::StatsExportService,
 };
@@ -90,6 +90,10 @@ pub fn wait_node_delete_dirs(_: DeletePermission, node: Arc<P2PNode>) {
         .expect("Could not delete node's data directory");
 }
 
+/// A node created with `make_node_and_sync`, along with the permission to
+/// delete its directories.
+pub type TestNode = (Arc<P2PNode>, DeletePermission);
+
 pub fn dummy_regenesis_blocks() -> Vec<BlockHash> {
     vec![BlockHash::new([0u8; 32]), BlockHash::new([1u8; 32]), BlockHash::new([2u8; 32])]
 }
@@ -102,6 +106,29 @@ pub fn make_node_and_sync(
     networks: Vec<u16>,
     node_type: PeerType,
     regenesis_blocks: Vec<BlockHash>,
+) -> anyhow::Result<(Arc<P2PNode>, DeletePermission)> {
+    make_node_with_identity_and_sync(port, networks, node_type, regenesis_blocks, None)
+}
+
+/// Creates a `P2PNode` like `make_node_and_sync`, but with a throwaway static
+/// key and a random node id that is not derived from it, like the nodes that
+/// only support wire protocol version 1.
+pub fn make_unbound_node_and_sync(
+    port: u16,
+    networks: Vec<u16>,
+    node_type: PeerType,
+    regenesis_blocks: Vec<BlockHash>,
+) -> anyhow::Result<(Arc<P2PNode>, DeletePermission)> {
+    let identity = NodeIdentity::unbound(P2PNodeId(thread_rng().gen()));
+    make_node_with_identity_and_sync(port, networks, node_type, regenesis_blocks, Some(identity))
+}
+
+fn make_node_with_identity_and_sync(
+    port: u16,
+    networks: Vec<u16>,
+    node_type: PeerType,
+    regenesis_blocks: Vec<BlockHash>,
+    identity: Option<NodeIdentity>,
 ) -> anyhow::Result<(Arc<P2PNode>, DeletePermission)> {
     // locally-run tests and benches can be polled with a much greater frequency
     let mut config = get_test_config(port, networks);
@@ -111,7 +138,12 @@ pub fn make_node_and_sync(
     let regenesis_arc = Arc::new(Regenesis::from_blocks(regenesis_blocks));
 
     let stats = Arc::new(StatsExportService::new(Vec::new()).unwrap());
-    let (node, server, poll) = P2PNode::new(None, &config, node_type, stats, regenesis_arc)?;
+    let (node, server, poll) = match identity {
+        Some(identity) => {
+            P2PNode::new_with_identity(&config, node_type, stats, regenesis_arc, identity)?
+        }
+        None => P2PNode::new(&config, node_type, stats, regenesis_arc)?,
+    };
 
     spawn(&node, server, poll, None);
     Ok((node, DeletePermission {
@@ -137,6 +169,40 @@ pub fn await_handshakes(node: &P2PNode) {
     }
 }
 
+/// Polls `condition` every 10ms until it returns `Some`, and returns that
+/// value. Returns `None` if that does not happen within 5 seconds.
+pub fn await_condition<T>(mut condition: impl FnMut() -> Option<T>) -> Option<T> {
+    for _ in 0..500 {
+        if let Some(value) = condition() {
+            return Some(value);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    None
+}
+
+/// Creates two `P2PNode`s with `make_node_and_sync` on network 100, connects
+/// the first one to the second one and waits until the handshakes have
+/// concluded on both sides.
+pub fn make_connected_node_pair() -> anyhow::Result<(TestNode, TestNode)> {
+    let (node, dp) = make_node_and_sync(
+        next_available_port(),
+        vec![100],
+        PeerType::Node,
+        dummy_regenesis_blocks(),
+    )?;
+    let (peer, peer_dp) = make_node_and_sync(
+        next_available_port(),
+        vec![100],
+        PeerType::Node,
+        dummy_regenesis_blocks(),
+    )?;
+    connect(&node, &peer);
+    await_handshakes(&node);
+    await_handshakes(&peer);
+    Ok(((node, dp), (peer, peer_dp)))
+}
+
 /// Creates a vector of given size containing random bytes.
 pub fn generate_random_data(size: usize) -> Vec<u8> {
     thread_rng().sample_iter(&Alphanumeric).take(size).map(|c| c as u32 as u8).collect()
@@ -156,5 +222,6 @@ pub fn create_random_packet(size: usize) -> NetworkMessage {
         destination: PacketDestination::Direct(rand::thread_rng().gen::<RemotePeerId>()),
         network_id:  NetworkId::from(thread_rng().gen::<u16>()),
         message:     generate_fake_block(size).unwrap(),
+        compression: PayloadCompression::None,
     })
 }